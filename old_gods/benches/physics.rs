@@ -0,0 +1,142 @@
+//! Benchmarks for loading maps, stepping physics and querying the AABBTree
+//! at 1k, 10k and 100k entities.
+//!
+//! Run with `cargo bench -p old_gods`.
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use old_gods::prelude::*;
+
+
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+
+
+/// Where the nth of `n` things goes, in a square grid with room between
+/// each thing.
+fn grid_position(i: usize, n: usize) -> V2 {
+    let columns = (n as f32).sqrt().ceil() as usize;
+    V2::new((i % columns) as f32 * 16.0, (i / columns) as f32 * 16.0)
+}
+
+
+/// A map with `n` barrier objects.
+fn map_with_barriers(n: usize) -> Tiledmap {
+    let mut objects = LayerBuilder::objects("objects");
+    for i in 0..n {
+        let position = grid_position(i, n);
+        objects = objects.object(
+            ObjectBuilder::rect(position.x, position.y, 10.0, 10.0)
+                .type_is("barrier")
+                .build(),
+        );
+    }
+    let columns = (n as f32).sqrt().ceil() as u32;
+    TiledmapBuilder::new(columns, columns, 16, 16)
+        .layer(objects.build())
+        .build()
+}
+
+
+/// A world with `n` barriers, one in ten of them moving, and a physics system
+/// that has already put them all in the AABBTree.
+fn world_with_bodies(n: usize) -> (World, Physics) {
+    let mut world = World::new();
+    let mut physics = Physics::default();
+    System::setup(&mut physics, &mut world);
+    world
+        .write_resource::<FPSCounter>()
+        .set_last_delta(1.0 / 60.0);
+    for i in 0..n {
+        let mut builder = world
+            .create_entity()
+            .with(Barrier)
+            .with(Shape::box_with_size(10.0, 10.0))
+            .with(Position(grid_position(i, n)))
+            .with(ZLevel(0.0));
+        if i % 10 == 0 {
+            builder = builder.with(Velocity(V2::new(30.0, 20.0))).with(Mass(1.0));
+        }
+        builder.build();
+    }
+    physics.run_now(&world);
+    world.maintain();
+    (world, physics)
+}
+
+
+fn insert_map_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_map");
+    group.sample_size(10);
+    for n in SIZES.iter() {
+        let map = map_with_barriers(*n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &map, |b, map| {
+            b.iter_batched(
+                || {
+                    let mut world = World::new();
+                    InsertMapData::setup(&mut world);
+                    world
+                },
+                |world| {
+                    insert_map(map, &mut world.system_data());
+                    world
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+
+fn physics_run_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("physics_run");
+    group.sample_size(10);
+    for n in SIZES.iter() {
+        let (mut world, mut physics) = world_with_bodies(*n);
+        group.bench_function(BenchmarkId::from_parameter(n), |b| {
+            b.iter(|| {
+                physics.run_now(&world);
+                world.maintain();
+            })
+        });
+    }
+    group.finish();
+}
+
+
+fn query_intersecting_shapes_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("query_intersecting_shapes");
+    group.sample_size(10);
+    for n in SIZES.iter() {
+        let (world, _) = world_with_bodies(*n);
+        let (entities, shapes, positions, aabb_tree): (
+            Entities,
+            ReadStorage<Shape>,
+            ReadStorage<Position>,
+            Read<AABBTree>,
+        ) = world.system_data();
+        // Query around the same thousand things at every size
+        let queried: Vec<Entity> = (&entities, &shapes)
+            .join()
+            .map(|(ent, _)| ent)
+            .step_by(n / 1_000)
+            .collect();
+        group.bench_function(BenchmarkId::from_parameter(n), |b| {
+            b.iter(|| {
+                for ent in queried.iter() {
+                    black_box(
+                        aabb_tree.query_intersecting_shapes(&entities, ent, &shapes, &positions),
+                    );
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+
+criterion_group!(
+    benches,
+    insert_map_bench,
+    physics_run_bench,
+    query_intersecting_shapes_bench
+);
+criterion_main!(benches);
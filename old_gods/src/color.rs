@@ -4,7 +4,7 @@ use wasm_bindgen::JsValue;
 pub mod css;
 
 /// A color.
-#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
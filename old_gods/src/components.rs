@@ -34,6 +34,9 @@ pub use player::*;
 mod rendering;
 pub use rendering::*;
 
+mod route;
+pub use route::*;
+
 mod sprite;
 pub use sprite::*;
 
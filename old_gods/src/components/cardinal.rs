@@ -24,6 +24,15 @@ impl Cardinal {
         }
     }
 
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Cardinal::North => "north",
+            Cardinal::East => "east",
+            Cardinal::South => "south",
+            Cardinal::West => "west",
+        }
+    }
+
     //pub fn from_keycode(keycode: &Keycode) -> Option<Cardinal> {
     //  match keycode {
     //    Keycode::J => {Some(Cardinal::South)}
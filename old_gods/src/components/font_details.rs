@@ -1,7 +1,7 @@
 //! Information needed to load a Font
 
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct FontDetails {
     pub path: String,
     pub size: u16,
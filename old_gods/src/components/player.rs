@@ -1,4 +1,8 @@
+use serde_json::Value;
 use specs::prelude::{Component, Entities, Entity, HashMapStorage, Join, ReadStorage};
+use std::collections::HashMap;
+
+use super::{route_from_values, RouteMode, RouteRef};
 
 
 /// A component for designating the maximum velocity of an entity.
@@ -18,11 +22,96 @@ impl Component for MaxSpeed {
 }
 
 
-#[derive(Debug, Clone, PartialEq, Hash, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 /// All the AIs in our game.
 pub enum AI {
-    /// An AI that just walks left.
-    WalksLeft,
+    /// Stands still.
+    Idle,
+
+    /// Walks to random spots within `radius` of where it started, waiting a
+    /// little at each.
+    Wander { radius: f32 },
+
+    /// Walks along a route, or any polyline object, the way `mode` says to.
+    Patrol { path: RouteRef, mode: RouteMode },
+
+    /// Walks towards the entity with the given name until it is within
+    /// `distance`.
+    Follow { target: String, distance: f32 },
+
+    /// Runs away from the entity with the given name while it is within
+    /// `distance`.
+    Flee { target: String, distance: f32 },
+}
+
+
+impl AI {
+    pub fn tiled_key() -> String {
+        "ai".to_string()
+    }
+
+    pub fn tiled_wander_radius_key() -> String {
+        "wander_radius".to_string()
+    }
+
+    pub fn tiled_patrol_path_key() -> String {
+        "patrol_path".to_string()
+    }
+
+    pub fn tiled_target_key() -> String {
+        "target".to_string()
+    }
+
+    pub fn tiled_distance_key() -> String {
+        "distance".to_string()
+    }
+
+    /// Read an AI out of a character's properties. Characters without an
+    /// "ai" property are idle.
+    pub fn from_properties(properties: &HashMap<String, Value>) -> Result<AI, String> {
+        let float = |key: String, default: f32| {
+            properties
+                .get(&key)
+                .and_then(|v| v.as_f64())
+                .map(|f| f as f32)
+                .unwrap_or(default)
+        };
+        let string = |key: String| {
+            properties
+                .get(&key)
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+                .ok_or(format!("'{}' property must be a string", key))
+        };
+        let ai = properties
+            .get(&AI::tiled_key())
+            .and_then(|v| v.as_str())
+            .unwrap_or("idle");
+        match ai {
+            "idle" => Ok(AI::Idle),
+            "wander" => Ok(AI::Wander {
+                radius: float(AI::tiled_wander_radius_key(), 64.0),
+            }),
+            "patrol" => {
+                let path = properties.get(&AI::tiled_patrol_path_key()).ok_or(format!(
+                    "'{}' property is missing",
+                    AI::tiled_patrol_path_key()
+                ))?;
+                let (path, mode) =
+                    route_from_values(path, properties.get(&RouteMode::tiled_key()))?;
+                Ok(AI::Patrol { path, mode })
+            }
+            "follow" => Ok(AI::Follow {
+                target: string(AI::tiled_target_key())?,
+                distance: float(AI::tiled_distance_key(), 16.0),
+            }),
+            "flee" => Ok(AI::Flee {
+                target: string(AI::tiled_target_key())?,
+                distance: float(AI::tiled_distance_key(), 128.0),
+            }),
+            ai => Err(format!("unsupported ai '{}'", ai)),
+        }
+    }
 }
 
 
@@ -194,6 +194,9 @@ pub enum RenderingToggles {
     /// Display shapes
     Shapes,
 
+    /// Display what can be seen
+    Vision,
+
     /// Display something else.
     /// Used for extension.
     Other(String),
@@ -228,6 +231,7 @@ impl RenderingToggles {
             EntityCount,
             CollisionInfo,
             Shapes,
+            Vision,
         ];
         props
             .into_iter()
@@ -253,6 +257,7 @@ impl RenderingToggles {
             EntityCount => "toggle_rendering_entity_count",
             CollisionInfo => "toggle_rendering_collision_info",
             Shapes => "toggle_rendering_shapes",
+            Vision => "toggle_rendering_vision",
             Other(s) => s,
         }
     }
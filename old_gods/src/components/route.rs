@@ -0,0 +1,177 @@
+//! Routes drawn as "path" polylines in Tiled.
+use serde_json::Value;
+use specs::prelude::{Component, HashMapStorage};
+use std::collections::HashMap;
+
+use super::V2;
+
+
+/// How something following a route carries on once it reaches the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RouteMode {
+    /// Heads back to the first waypoint and goes around again.
+    Loop,
+
+    /// Turns back at either end.
+    PingPong,
+
+    /// Stops at the last waypoint.
+    Once,
+}
+
+
+impl RouteMode {
+    pub fn try_from_str(s: &str) -> Option<RouteMode> {
+        match s {
+            "loop" => Some(RouteMode::Loop),
+            "ping_pong" => Some(RouteMode::PingPong),
+            "once" => Some(RouteMode::Once),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteMode::Loop => "loop",
+            RouteMode::PingPong => "ping_pong",
+            RouteMode::Once => "once",
+        }
+    }
+
+    pub fn tiled_key() -> String {
+        "route_mode".to_string()
+    }
+}
+
+
+/// A reference to a route object by its name or its Tiled object id.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RouteRef {
+    Name(String),
+    Id(u32),
+}
+
+
+impl RouteRef {
+    /// Numbers are object ids and strings are names.
+    pub fn from_value(value: &Value) -> Option<RouteRef> {
+        match value {
+            Value::String(name) => Some(RouteRef::Name(name.clone())),
+            Value::Number(id) => id.as_u64().map(|id| RouteRef::Id(id as u32)),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            RouteRef::Name(name) => Value::from(name.as_str()),
+            RouteRef::Id(id) => Value::from(*id),
+        }
+    }
+}
+
+
+/// Read a route reference and a mode, ping pong if there is none.
+pub fn route_from_values(
+    route: &Value,
+    mode: Option<&Value>,
+) -> Result<(RouteRef, RouteMode), String> {
+    let route = RouteRef::from_value(route)
+        .ok_or_else(|| format!("route {} must be a name or an object id", route))?;
+    let mode = match mode {
+        Some(mode) => mode
+            .as_str()
+            .and_then(RouteMode::try_from_str)
+            .ok_or_else(|| format!("'{}' is not a route mode", mode))?,
+        None => RouteMode::PingPong,
+    };
+    Ok((route, mode))
+}
+
+
+/// The waypoints of a route, relative to its position, and how long to wait
+/// at each.
+///
+/// In Tiled a route is a polyline object with the type "path". A "wait"
+/// property sets how many seconds to wait at every waypoint and "wait_N"
+/// properties set the wait at the waypoint with index N.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub points: Vec<V2>,
+    pub waits: Vec<f32>,
+}
+
+
+impl Route {
+    pub fn new(points: Vec<V2>) -> Route {
+        let waits = vec![0.0; points.len()];
+        Route { points, waits }
+    }
+
+    pub fn tiled_type() -> String {
+        "path".to_string()
+    }
+
+    pub fn tiled_wait_property() -> String {
+        "wait".to_string()
+    }
+
+    pub fn tiled_wait_at_property(ndx: usize) -> String {
+        format!("wait_{}", ndx)
+    }
+
+    /// Make a route out of a polyline, reading and removing its wait
+    /// properties.
+    pub fn from_properties(points: Vec<V2>, properties: &mut HashMap<String, Value>) -> Route {
+        let mut take = |name: String| {
+            properties
+                .remove(&name)
+                .and_then(|v| v.as_f64())
+                .map(|f| f as f32)
+        };
+        let wait = take(Route::tiled_wait_property()).unwrap_or(0.0);
+        let waits = (0..points.len())
+            .map(|ndx| take(Route::tiled_wait_at_property(ndx)).unwrap_or(wait))
+            .collect();
+        Route { points, waits }
+    }
+
+    /// Write the route's waits back out as properties.
+    pub fn wait_properties(&self) -> HashMap<String, Value> {
+        let mut properties = HashMap::new();
+        let first = self.waits.first().cloned().unwrap_or(0.0);
+        if self.waits.iter().all(|wait| *wait == first) {
+            if first != 0.0 {
+                properties.insert(Route::tiled_wait_property(), Value::from(first as f64));
+            }
+        } else {
+            for (ndx, wait) in self.waits.iter().enumerate() {
+                if *wait != 0.0 {
+                    properties.insert(
+                        Route::tiled_wait_at_property(ndx),
+                        Value::from(*wait as f64),
+                    );
+                }
+            }
+        }
+        properties
+    }
+
+    /// How long to wait at the waypoint with the given index.
+    pub fn wait_at(&self, ndx: usize) -> f32 {
+        self.waits.get(ndx).cloned().unwrap_or(0.0)
+    }
+
+    /// The route moved to the given position.
+    pub fn translated(&self, position: V2) -> Route {
+        Route {
+            points: self.points.iter().map(|p| position + *p).collect(),
+            waits: self.waits.clone(),
+        }
+    }
+}
+
+
+impl Component for Route {
+    type Storage = HashMapStorage<Self>;
+}
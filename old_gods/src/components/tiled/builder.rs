@@ -0,0 +1,586 @@
+//! Builders for creating Tiled maps in code.
+//!
+//! These fill in all the bookkeeping fields Tiled expects (versions, render
+//! order, object ids, first gids) so that tests and generators can create maps
+//! without JSON fixtures. The resulting `Tiledmap` is the same as one loaded
+//! from a file and can be given to `insert_map`.
+use serde_json::Value;
+use std::collections::HashMap;
+
+use super::{
+    Frame, GlobalId, GlobalTileIndex, Layer, LayerData, LayerLayerData, LocalId, Object,
+    ObjectGroup, ObjectLayerData, Point, Property, Terrain, Tile, TileLayerData, Tiledmap, Tileset,
+    TilesetItem, TilesetPayload,
+};
+
+
+/// Builds a Tiledmap.
+///
+/// Tilesets are embedded and given first gids in the order they are added.
+/// Any objects with an id of `0` are given a fresh id when the map is built.
+#[derive(Debug, Clone)]
+pub struct TiledmapBuilder {
+    map: Tiledmap,
+}
+
+
+impl TiledmapBuilder {
+    /// Start a new orthogonal map `width` by `height` tiles.
+    pub fn new(width: u32, height: u32, tilewidth: u32, tileheight: u32) -> Self {
+        TiledmapBuilder {
+            map: Tiledmap {
+                version: 1.2,
+                tiledversion: "1.3.1".to_string(),
+                width: width as i32,
+                height: height as i32,
+                tilewidth: tilewidth as i32,
+                tileheight: tileheight as i32,
+                orientation: "orthogonal".to_string(),
+                layers: vec![],
+                tilesets: vec![],
+                backgroundcolor: None,
+                renderorder: "right-down".to_string(),
+                properties: vec![],
+                nextobjectid: 1,
+            },
+        }
+    }
+
+    /// Set the background color, eg "#ff336699".
+    pub fn background_color(mut self, color: &str) -> Self {
+        self.map.backgroundcolor = Some(color.to_string());
+        self
+    }
+
+    /// Add a custom property to the map.
+    pub fn property<V: Into<Value>>(mut self, name: &str, value: V) -> Self {
+        self.map
+            .properties
+            .push(Property::from_value(name, value.into()));
+        self
+    }
+
+    /// Embed a tileset in the map.
+    pub fn tileset(mut self, tileset: Tileset) -> Self {
+        let firstgid = self.next_firstgid();
+        self.map.tilesets.push(TilesetItem {
+            firstgid,
+            payload: TilesetPayload::Embedded(tileset),
+        });
+        self
+    }
+
+    /// Add a layer on top of the other layers.
+    pub fn layer(mut self, layer: Layer) -> Self {
+        self.map.layers.push(layer);
+        self
+    }
+
+    /// The first gid the next added tileset will receive.
+    pub fn next_firstgid(&self) -> GlobalId {
+        GlobalId(
+            self.map
+                .tilesets
+                .iter()
+                .map(|item| {
+                    let GlobalId(firstgid) = item.firstgid;
+                    firstgid + item.tileset().map(|set| set.tilecount).unwrap_or(0)
+                })
+                .max()
+                .unwrap_or(1),
+        )
+    }
+
+    /// Return the GlobalId of the tile with the given LocalId in the tileset
+    /// with the given name, if possible.
+    pub fn gid(&self, tileset_name: &str, LocalId(lid): LocalId) -> Option<GlobalId> {
+        self.map.tilesets.iter().find_map(|item| {
+            let set = item.tileset()?;
+            if set.name == tileset_name && lid < set.tilecount {
+                let GlobalId(firstgid) = item.firstgid;
+                Some(GlobalId(firstgid + lid))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Finish the map, assigning ids to any objects that don't have one.
+    pub fn build(self) -> Tiledmap {
+        fn max_id(layers: &[Layer]) -> u32 {
+            layers
+                .iter()
+                .map(|layer| match &layer.layer_data {
+                    LayerData::Objects(data) => {
+                        data.objects.iter().map(|obj| obj.id).max().unwrap_or(0)
+                    }
+                    LayerData::Layers(group) => max_id(&group.layers),
+                    LayerData::Tiles(_) => 0,
+                })
+                .max()
+                .unwrap_or(0)
+        }
+
+        fn assign_ids(layers: &mut [Layer], next_id: &mut u32) {
+            for layer in layers.iter_mut() {
+                match &mut layer.layer_data {
+                    LayerData::Objects(data) => {
+                        for obj in data.objects.iter_mut().filter(|obj| obj.id == 0) {
+                            obj.id = *next_id;
+                            *next_id += 1;
+                        }
+                    }
+                    LayerData::Layers(group) => assign_ids(&mut group.layers, next_id),
+                    LayerData::Tiles(_) => {}
+                }
+            }
+        }
+
+        let mut map = self.map;
+        let mut next_id = (max_id(&map.layers) + 1).max(map.nextobjectid as u32);
+        assign_ids(&mut map.layers, &mut next_id);
+        map.nextobjectid = next_id as i32;
+        map
+    }
+}
+
+
+/// Builds a Layer of tiles, objects or other layers.
+#[derive(Debug, Clone)]
+pub struct LayerBuilder {
+    layer: Layer,
+}
+
+
+impl LayerBuilder {
+    fn new(name: &str, type_is: &str, layer_data: LayerData) -> Self {
+        LayerBuilder {
+            layer: Layer {
+                name: name.to_string(),
+                type_is: type_is.to_string(),
+                visible: true,
+                x: 0,
+                y: 0,
+                properties: None,
+                opacity: 1.0,
+                layer_data,
+            },
+        }
+    }
+
+    /// Start an empty tile layer `width` by `height` tiles.
+    pub fn tiles(name: &str, width: u32, height: u32) -> Self {
+        LayerBuilder::new(
+            name,
+            "tilelayer",
+            LayerData::Tiles(TileLayerData {
+                width,
+                height,
+                data: vec![GlobalTileIndex::from_bits(0); (width * height) as usize],
+            }),
+        )
+    }
+
+    /// Start an empty object layer.
+    pub fn objects(name: &str) -> Self {
+        LayerBuilder::new(
+            name,
+            "objectgroup",
+            LayerData::Objects(ObjectLayerData {
+                draworder: "topdown".to_string(),
+                objects: vec![],
+            }),
+        )
+    }
+
+    /// Start an empty group layer.
+    pub fn group(name: &str) -> Self {
+        LayerBuilder::new(
+            name,
+            "group",
+            LayerData::Layers(LayerLayerData { layers: vec![] }),
+        )
+    }
+
+    /// Set the tile at the given cell of a tile layer.
+    ///
+    /// Cells outside the layer and layers that are not tile layers are
+    /// ignored.
+    pub fn tile(mut self, x: u32, y: u32, gid: GlobalTileIndex) -> Self {
+        if let LayerData::Tiles(data) = &mut self.layer.layer_data {
+            if x < data.width && y < data.height {
+                data.data[(y * data.width + x) as usize] = gid;
+            }
+        }
+        self
+    }
+
+    /// Set all the tiles of a tile layer from Tiled's packed gid bits, in
+    /// row-major order.
+    pub fn tile_bits(mut self, bits: &[u32]) -> Self {
+        if let LayerData::Tiles(data) = &mut self.layer.layer_data {
+            for (cell, bits) in data.data.iter_mut().zip(bits.iter()) {
+                *cell = GlobalTileIndex::from_bits(*bits);
+            }
+        }
+        self
+    }
+
+    /// Add an object to an object layer.
+    pub fn object(mut self, object: Object) -> Self {
+        if let LayerData::Objects(data) = &mut self.layer.layer_data {
+            data.objects.push(object);
+        }
+        self
+    }
+
+    /// Add a layer to a group layer.
+    pub fn layer(mut self, layer: Layer) -> Self {
+        if let LayerData::Layers(group) = &mut self.layer.layer_data {
+            group.layers.push(layer);
+        }
+        self
+    }
+
+    /// Add a custom property to the layer.
+    pub fn property<V: Into<Value>>(mut self, name: &str, value: V) -> Self {
+        self.layer
+            .properties
+            .get_or_insert_with(Vec::new)
+            .push(Property::from_value(name, value.into()));
+        self
+    }
+
+    pub fn visible(mut self, visible: bool) -> Self {
+        self.layer.visible = visible;
+        self
+    }
+
+    pub fn opacity(mut self, opacity: f32) -> Self {
+        self.layer.opacity = opacity;
+        self
+    }
+
+    pub fn build(self) -> Layer {
+        self.layer
+    }
+}
+
+
+/// Builds a Tileset from a single image.
+#[derive(Debug, Clone)]
+pub struct TilesetBuilder {
+    tileset: Tileset,
+}
+
+
+impl TilesetBuilder {
+    /// Start a tileset cut from an image of the given size.
+    pub fn new(
+        name: &str,
+        image: &str,
+        imagewidth: u32,
+        imageheight: u32,
+        tilewidth: u32,
+        tileheight: u32,
+    ) -> Self {
+        TilesetBuilder {
+            tileset: Tileset {
+                image: image.to_string(),
+                name: name.to_string(),
+                tilewidth,
+                tileheight,
+                imagewidth,
+                imageheight,
+                properties: HashMap::new(),
+                propertytypes: HashMap::new(),
+                margin: 0,
+                spacing: 0,
+                tileproperties: HashMap::new(),
+                terrains: vec![],
+                columns: 0,
+                tilecount: 0,
+                tiles: vec![],
+                wangsets: vec![],
+            },
+        }
+    }
+
+    pub fn margin(mut self, margin: u32) -> Self {
+        self.tileset.margin = margin;
+        self
+    }
+
+    pub fn spacing(mut self, spacing: u32) -> Self {
+        self.tileset.spacing = spacing;
+        self
+    }
+
+    fn tile_mut(&mut self, id: u32) -> &mut Tile {
+        let ndx = if let Some(ndx) = self.tileset.tiles.iter().position(|t| t.id == LocalId(id)) {
+            ndx
+        } else {
+            self.tileset.tiles.push(Tile {
+                id: LocalId(id),
+                type_is: String::new(),
+                properties: vec![],
+                object_group: None,
+                animation: None,
+                terrain: None,
+            });
+            self.tileset.tiles.len() - 1
+        };
+        &mut self.tileset.tiles[ndx]
+    }
+
+    /// Add a terrain type represented by the tile with the given local id.
+    pub fn terrain(mut self, name: &str, tile: u32) -> Self {
+        self.tileset.terrains.push(Terrain {
+            name: name.to_string(),
+            tile: LocalId(tile),
+        });
+        self
+    }
+
+    /// Set the terrain indices at the corners of the tile with the given
+    /// local id: top left, top right, bottom left and bottom right. -1 means
+    /// no terrain.
+    pub fn tile_terrain(mut self, id: u32, corners: [i32; 4]) -> Self {
+        self.tile_mut(id).terrain = Some(corners);
+        self
+    }
+
+    /// Set the type of the tile with the given local id.
+    pub fn tile_type(mut self, id: u32, type_is: &str) -> Self {
+        self.tile_mut(id).type_is = type_is.to_string();
+        self
+    }
+
+    /// Add a custom property to the tile with the given local id.
+    pub fn tile_property<V: Into<Value>>(mut self, id: u32, name: &str, value: V) -> Self {
+        self.tile_mut(id)
+            .properties
+            .push(Property::from_value(name, value.into()));
+        self
+    }
+
+    /// Animate the tile with the given local id through the given
+    /// `(local id, milliseconds)` frames.
+    pub fn tile_animation(mut self, id: u32, frames: &[(u32, u32)]) -> Self {
+        self.tile_mut(id).animation = Some(
+            frames
+                .iter()
+                .map(|(tileid, duration)| Frame {
+                    duration: *duration,
+                    tileid: LocalId(*tileid),
+                })
+                .collect(),
+        );
+        self
+    }
+
+    /// Add an object to the tile with the given local id, eg. a "barrier" or
+    /// an "origin_offset".
+    pub fn tile_object(mut self, id: u32, object: Object) -> Self {
+        self.tile_mut(id)
+            .object_group
+            .get_or_insert_with(|| ObjectGroup {
+                draworder: "index".to_string(),
+                name: String::new(),
+                objects: vec![],
+                opacity: 1.0,
+                type_is: "objectgroup".to_string(),
+                visible: true,
+                x: 0,
+                y: 0,
+            })
+            .objects
+            .push(object);
+        self
+    }
+
+    /// Finish the tileset, calculating its columns and tile count.
+    pub fn build(mut self) -> Tileset {
+        let set = &mut self.tileset;
+        let cols = |image: u32, tile: u32| {
+            (image + set.spacing)
+                .saturating_sub(2 * set.margin)
+                .checked_div(tile + set.spacing)
+                .unwrap_or(0)
+        };
+        let columns = cols(set.imagewidth, set.tilewidth);
+        let rows = cols(set.imageheight, set.tileheight);
+        set.columns = columns;
+        set.tilecount = columns * rows;
+        set.tiles.sort_by_key(|tile| tile.id.0);
+        self.tileset
+    }
+}
+
+
+/// Builds a Tiled object.
+#[derive(Debug, Clone)]
+pub struct ObjectBuilder {
+    object: Object,
+}
+
+
+impl ObjectBuilder {
+    /// Start a rectangle object with its top left at the given point.
+    pub fn rect(x: f32, y: f32, width: f32, height: f32) -> Self {
+        ObjectBuilder {
+            object: Object {
+                id: 0,
+                width,
+                height,
+                name: String::new(),
+                type_is: String::new(),
+                properties: vec![],
+                visible: true,
+                x,
+                y,
+                rotation: 0.0,
+                gid: None,
+                ellipse: false,
+                point: false,
+                polygon: None,
+                polyline: None,
+                text: HashMap::new(),
+            },
+        }
+    }
+
+    /// Start a point object.
+    pub fn point(x: f32, y: f32) -> Self {
+        let mut builder = ObjectBuilder::rect(x, y, 0.0, 0.0);
+        builder.object.point = true;
+        builder
+    }
+
+    /// Start a tile object. Like Tiled, the given point is the bottom left of
+    /// the tile.
+    pub fn tile(gid: GlobalTileIndex, x: f32, y: f32, width: f32, height: f32) -> Self {
+        let mut builder = ObjectBuilder::rect(x, y, width, height);
+        builder.object.gid = Some(gid);
+        builder
+    }
+
+    /// Start a polygon object with points relative to the given origin.
+    pub fn polygon(x: f32, y: f32, points: &[(f32, f32)]) -> Self {
+        let mut builder = ObjectBuilder::rect(x, y, 0.0, 0.0);
+        builder.object.polygon = Some(points.iter().map(|&(x, y)| Point { x, y }).collect());
+        builder
+    }
+
+    /// Start a polyline object with points relative to the given origin.
+    pub fn polyline(x: f32, y: f32, points: &[(f32, f32)]) -> Self {
+        let mut builder = ObjectBuilder::rect(x, y, 0.0, 0.0);
+        builder.object.polyline = Some(points.iter().map(|&(x, y)| Point { x, y }).collect());
+        builder
+    }
+
+    /// Set the object's id. Objects without an id are given one when the map
+    /// is built.
+    pub fn id(mut self, id: u32) -> Self {
+        self.object.id = id;
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.object.name = name.to_string();
+        self
+    }
+
+    /// Set the object's type, eg. "zone", "barrier" or "fence".
+    pub fn type_is(mut self, type_is: &str) -> Self {
+        self.object.type_is = type_is.to_string();
+        self
+    }
+
+    /// Add a custom property to the object.
+    pub fn property<V: Into<Value>>(mut self, name: &str, value: V) -> Self {
+        self.object
+            .properties
+            .push(Property::from_value(name, value.into()));
+        self
+    }
+
+    pub fn visible(mut self, visible: bool) -> Self {
+        self.object.visible = visible;
+        self
+    }
+
+    pub fn rotation(mut self, rotation: f32) -> Self {
+        self.object.rotation = rotation;
+        self
+    }
+
+    pub fn build(self) -> Object {
+        self.object
+    }
+}
+
+
+#[cfg(test)]
+mod builder_tests {
+    use super::*;
+
+    #[test]
+    fn can_build_a_map() {
+        let tiles = TilesetBuilder::new("tiles", "tiles.png", 64, 32, 16, 16)
+            .tile_type(1, "wall")
+            .tile_animation(2, &[(2, 100), (3, 100)])
+            .tile_object(
+                1,
+                ObjectBuilder::rect(0.0, 0.0, 16.0, 16.0)
+                    .type_is("barrier")
+                    .build(),
+            )
+            .build();
+        assert_eq!(tiles.columns, 4);
+        assert_eq!(tiles.tilecount, 8);
+
+        let builder = TiledmapBuilder::new(4, 4, 16, 16)
+            .property("toggle_rendering_fps", true)
+            .tileset(tiles.clone())
+            .tileset(tiles);
+        let wall = builder.gid("tiles", LocalId(1)).unwrap();
+        assert_eq!(wall, GlobalId(2));
+        assert_eq!(builder.next_firstgid(), GlobalId(17));
+
+        let map = builder
+            .layer(
+                LayerBuilder::tiles("floor", 4, 4)
+                    .tile(1, 1, GlobalTileIndex::from_bits(wall.0))
+                    .build(),
+            )
+            .layer(
+                LayerBuilder::objects("objects")
+                    .object(
+                        ObjectBuilder::rect(0.0, 0.0, 32.0, 32.0)
+                            .type_is("zone")
+                            .build(),
+                    )
+                    .object(ObjectBuilder::point(8.0, 8.0).id(10).name("spawn").build())
+                    .object(
+                        ObjectBuilder::polyline(0.0, 0.0, &[(0.0, 0.0), (16.0, 0.0)])
+                            .type_is("fence")
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+        assert_eq!(map.nextobjectid, 13);
+        let ids = map.layers[1]
+            .objects()
+            .iter()
+            .map(|obj| obj.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![11, 10, 12]);
+
+        let text = map.to_text().unwrap();
+        let map = Tiledmap::from_text(&text).unwrap();
+        assert_eq!(map.get_tile(&wall).unwrap().type_is, "wall");
+        assert!(map.get_tile(&GlobalId(11)).unwrap().animation.is_some());
+    }
+}
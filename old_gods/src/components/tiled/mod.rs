@@ -2,7 +2,10 @@
 //!
 //! TODO: Investigate whether we can support external tilesets on web
 use log::trace;
-use serde::de::{Deserialize, Deserializer};
+use serde::{
+    de::{Deserialize, Deserializer},
+    ser::{Serialize, Serializer},
+};
 use serde_json::Value;
 use specs::prelude::{Component as SpecsComponent, HashMapStorage};
 use std::{
@@ -17,6 +20,9 @@ use std::{
 
 use super::super::geom::V2;
 
+mod builder;
+pub use self::builder::*;
+
 
 #[cfg(feature = "serde_path_to_error")]
 /// Deserialize a json file.
@@ -39,7 +45,7 @@ const FLIPPED_VERTICALLY_FLAG: u32 = 0x40000000;
 const FLIPPED_DIAGONALLY_FLAG: u32 = 0x20000000;
 
 
-#[derive(Deserialize, Clone, Hash, PartialEq, Eq, Debug)]
+#[derive(Deserialize, Serialize, Clone, Hash, PartialEq, Eq, Debug)]
 pub struct GlobalId(pub u32);
 
 impl GlobalId {
@@ -66,36 +72,70 @@ impl<'de> Deserialize<'de> for GlobalTileIndex {
         D: Deserializer<'de>,
     {
         let bits: u32 = u32::deserialize(deserializer)?;
+        Ok(GlobalTileIndex::from_bits(bits))
+    }
+}
+
+
+impl GlobalTileIndex {
+    /// Unpack the id and flip flags from the bits Tiled stores.
+    pub fn from_bits(bits: u32) -> Self {
         let is_flipped_horizontally = (bits & FLIPPED_HORIZONTALLY_FLAG) > 0;
         let is_flipped_vertically = (bits & FLIPPED_VERTICALLY_FLAG) > 0;
         let is_flipped_diagonally = (bits & FLIPPED_DIAGONALLY_FLAG) > 0;
         let id = GlobalId(
             bits & !(FLIPPED_HORIZONTALLY_FLAG | FLIPPED_VERTICALLY_FLAG | FLIPPED_DIAGONALLY_FLAG),
         );
-        Ok(GlobalTileIndex {
+        GlobalTileIndex {
             id,
             is_flipped_diagonally,
             is_flipped_vertically,
             is_flipped_horizontally,
-        })
+        }
+    }
+
+    /// Pack this index and its flip flags back into the bits Tiled stores.
+    pub fn to_bits(&self) -> u32 {
+        let GlobalId(id) = self.id;
+        let mut bits = id;
+        if self.is_flipped_horizontally {
+            bits |= FLIPPED_HORIZONTALLY_FLAG;
+        }
+        if self.is_flipped_vertically {
+            bits |= FLIPPED_VERTICALLY_FLAG;
+        }
+        if self.is_flipped_diagonally {
+            bits |= FLIPPED_DIAGONALLY_FLAG;
+        }
+        bits
     }
 }
 
 
-#[derive(Deserialize, Clone, Hash, PartialEq, Eq, Debug)]
+impl Serialize for GlobalTileIndex {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u32(self.to_bits())
+    }
+}
+
+
+#[derive(Deserialize, Serialize, Clone, Hash, PartialEq, Eq, Debug)]
 pub struct LocalId(pub u32);
 
 fn no() -> bool {
     false
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Point<T> {
     pub x: T,
     pub y: T,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum TextValue {
     String(String),
@@ -128,7 +168,7 @@ impl TextValue {
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Object {
     pub id: u32,
 
@@ -152,6 +192,7 @@ pub struct Object {
 
     pub rotation: f32,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub gid: Option<GlobalTileIndex>,
 
     #[serde(default = "no")]
@@ -160,11 +201,13 @@ pub struct Object {
     #[serde(default = "no")]
     pub point: bool,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub polygon: Option<Vec<Point<f32>>>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub polyline: Option<Vec<Point<f32>>>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub text: HashMap<String, TextValue>,
 }
 
@@ -223,7 +266,7 @@ fn empty() -> String {
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct TileLayerData {
     /// Column count. Same as map width for fixed-size maps.
     pub width: u32,
@@ -236,7 +279,7 @@ pub struct TileLayerData {
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ObjectLayerData {
     /// “topdown” (default) or “index”. objectgroup only.
     #[serde(default = "topdown")]
@@ -247,13 +290,13 @@ pub struct ObjectLayerData {
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct LayerLayerData {
     pub layers: Vec<Layer>,
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum LayerData {
     Tiles(TileLayerData),
@@ -262,7 +305,7 @@ pub enum LayerData {
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Layer {
     /// Name assigned to this layer
     pub name: String,
@@ -281,7 +324,7 @@ pub struct Layer {
     pub y: i32,
 
     /// string key-value pairs.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub properties: Option<Vec<Property>>,
 
     /// Value between 0 and 1
@@ -417,7 +460,7 @@ mod layer_tests {
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Terrain {
     /// Name of terrain
     pub name: String,
@@ -426,14 +469,58 @@ pub struct Terrain {
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct WangColor {
+    /// Hex-formatted color (#RRGGBB or #AARRGGBB)
+    pub color: String,
+    /// Name of the Wang color
+    pub name: String,
+    /// Probability used when randomizing
+    pub probability: f32,
+    /// Local ID of tile representing the Wang color
+    pub tile: i32,
+}
+
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct WangTile {
+    /// Local ID of the tile
+    pub tileid: LocalId,
+    /// The Wang color indices of the tile's edges and corners, clockwise
+    /// starting from the top edge. 0 means unset.
+    pub wangid: Vec<u32>,
+    #[serde(default = "no")]
+    pub dflip: bool,
+    #[serde(default = "no")]
+    pub hflip: bool,
+    #[serde(default = "no")]
+    pub vflip: bool,
+}
+
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct WangSet {
+    /// Name of the Wang set
+    pub name: String,
+    /// Local ID of tile representing the Wang set
+    pub tile: i32,
+    #[serde(default)]
+    pub cornercolors: Vec<WangColor>,
+    #[serde(default)]
+    pub edgecolors: Vec<WangColor>,
+    #[serde(default)]
+    pub wangtiles: Vec<WangTile>,
+}
+
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Frame {
     pub duration: u32,
     pub tileid: LocalId,
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ObjectGroup {
     pub draworder: String,
 
@@ -453,7 +540,7 @@ pub struct ObjectGroup {
     pub y: u32,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Property {
     pub name: String,
 
@@ -464,7 +551,25 @@ pub struct Property {
 }
 
 
-#[derive(Deserialize, Clone, Debug)]
+impl Property {
+    /// Create a property, inferring its Tiled type from the JSON value.
+    pub fn from_value(name: &str, value: Value) -> Property {
+        let type_is = match &value {
+            Value::Bool(_) => "bool",
+            Value::Number(n) if n.is_f64() => "float",
+            Value::Number(_) => "int",
+            _ => "string",
+        };
+        Property {
+            name: name.to_string(),
+            type_is: type_is.to_string(),
+            value,
+        }
+    }
+}
+
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Tile {
     pub id: LocalId,
 
@@ -475,10 +580,16 @@ pub struct Tile {
     #[serde(default)]
     pub properties: Vec<Property>,
 
-    #[serde(rename = "objectgroup")]
+    #[serde(rename = "objectgroup", skip_serializing_if = "Option::is_none")]
     pub object_group: Option<ObjectGroup>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub animation: Option<Vec<Frame>>,
+
+    /// Index of the terrain at each corner of the tile: top left, top right,
+    /// bottom left and bottom right. -1 means no terrain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terrain: Option<[i32; 4]>,
 }
 
 impl Tile {
@@ -505,7 +616,7 @@ impl Tile {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Tileset {
     /// Image used for tiles in this set
     pub image: String,
@@ -540,11 +651,11 @@ pub struct Tileset {
     pub spacing: u32,
 
     /// Per-tile properties
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub tileproperties: HashMap<LocalId, Vec<Property>>,
 
     /// Array of Terrains (optional)
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub terrains: Vec<Terrain>,
 
     /// The number of tile columns in the tileset
@@ -554,8 +665,12 @@ pub struct Tileset {
     pub tilecount: u32,
 
     /// Tiles (optional)
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tiles: Vec<Tile>,
+
+    /// Wang sets (optional)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wangsets: Vec<WangSet>,
 }
 
 
@@ -609,6 +724,32 @@ impl Tileset {
     }
 
 
+    /// Given the source rectangle of a tile within this Tileset's image, return
+    /// the LocalId of that tile, if possible. This is the inverse of
+    /// `aabb_local`.
+    pub fn local_id_of_aabb(&self, aabb: &AABB<u32>) -> Option<LocalId> {
+        let tw = self.tilewidth;
+        let th = self.tileheight;
+        let m = self.margin;
+        let s = self.spacing;
+        if aabb.w != tw || aabb.h != th || aabb.x < m || aabb.y < m {
+            return None;
+        }
+        let (dx, dy) = (aabb.x - m, aabb.y - m);
+        if dx % (tw + s) != 0 || dy % (th + s) != 0 {
+            return None;
+        }
+        let xndx = dx / (tw + s);
+        let yndx = dy / (th + s);
+        let ndx = yndx * self.columns + xndx;
+        if xndx < self.columns && ndx < self.tilecount {
+            Some(LocalId(ndx))
+        } else {
+            None
+        }
+    }
+
+
     /// Return the Tile with the given gid in this Tileet, if possible.
     pub fn tile(&self, firstgid: &GlobalId, tilegid: &GlobalId) -> Option<&Tile> {
         let l = firstgid.convert_to_local(tilegid);
@@ -621,6 +762,40 @@ impl Tileset {
     }
 
 
+    /// Return the names of the terrains at the corners of each tile that has
+    /// any, from either the terrain or the corner Wang set data. Corners are
+    /// ordered top left, top right, bottom left and bottom right.
+    pub fn tile_corners(&self) -> Vec<(LocalId, [Option<&str>; 4])> {
+        let mut corners = vec![];
+        for tile in self.tiles.iter() {
+            if let Some(terrain) = &tile.terrain {
+                let mut names = [None; 4];
+                for (name, ndx) in names.iter_mut().zip(terrain.iter()) {
+                    *name = self.terrains.get(*ndx as usize).map(|t| t.name.as_str());
+                }
+                corners.push((tile.id.clone(), names));
+            }
+        }
+        for set in self.wangsets.iter() {
+            let color = |wangid: &[u32], ndx: usize| {
+                let c = *wangid.get(ndx)? as usize;
+                set.cornercolors
+                    .get(c.checked_sub(1)?)
+                    .map(|color| color.name.as_str())
+            };
+            for tile in set.wangtiles.iter() {
+                let id = &tile.wangid;
+                // Wang ids go clockwise from the top edge, corners are odd
+                let names = [color(id, 7), color(id, 1), color(id, 5), color(id, 3)];
+                if names.iter().any(Option::is_some) {
+                    corners.push((tile.tileid.clone(), names));
+                }
+            }
+        }
+        corners
+    }
+
+
     pub fn extend_tiles_with_tileproperties(&mut self) {
         for tile in self.tiles.iter_mut() {
             let mut props = self
@@ -635,7 +810,7 @@ impl Tileset {
 
 
 /// An externally defined tileset.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 // #[serde(transparent)]
 pub struct TilesetSource {
     /// A path to a tileset file, relative to its owner.
@@ -643,7 +818,7 @@ pub struct TilesetSource {
 }
 
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum TilesetPayload {
     Embedded(Tileset),
@@ -654,7 +829,7 @@ pub enum TilesetPayload {
 /// When a Tileset lives in a Tiledmap it may be an embedded
 /// Tileset or a link to an external file, but both live inline with a
 /// firstgid property.
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct TilesetItem {
     /// GID corresponding to the first tile in the set
     pub firstgid: GlobalId,
@@ -702,7 +877,7 @@ impl TilesetItem {
 
 
 /// Our top level tiled map.
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Tiledmap {
     /// The JSON format version
     pub version: f32,
@@ -732,6 +907,7 @@ pub struct Tiledmap {
     pub tilesets: Vec<TilesetItem>,
 
     /// Hex-formatted color (#RRGGBB or #AARRGGBB) (optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub backgroundcolor: Option<String>,
 
     /// Rendering direction (orthogonal maps only)
@@ -752,6 +928,11 @@ impl Tiledmap {
         from_str(text)
     }
 
+    /// Serialize this map into Tiled's JSON format.
+    pub fn to_text(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| format!("{}", e))
+    }
+
     /// Load and deserialize a Tiled map file synchronously.
     pub fn from_file(file: &str) -> Result<Tiledmap, String> {
         Self::new(Path::new(file))
@@ -902,6 +1083,27 @@ impl Tiledmap {
         }
     }
 
+    /// Return the GlobalId of the tile drawn from the given sprite sheet at the
+    /// given source rectangle. If the Tilemap has not hydrated its tilesets,
+    /// this will always return None.
+    pub fn get_gid_by_frame(&self, sprite_sheet: &str, aabb: &AABB<u32>) -> Option<GlobalId> {
+        for item in self.tilesets.iter() {
+            let set = if let Some(set) = item.tileset() {
+                set
+            } else {
+                continue;
+            };
+            if set.image != sprite_sheet {
+                continue;
+            }
+            if let Some(LocalId(lid)) = set.local_id_of_aabb(aabb) {
+                let GlobalId(fgid) = item.firstgid;
+                return Some(GlobalId(fgid + lid));
+            }
+        }
+        None
+    }
+
     pub fn get_tile(&self, gid: &GlobalId) -> Option<&Tile> {
         let (firstgid, tileset) = self.get_tileset_by_gid(gid)?;
         tileset.tile(firstgid, gid)
@@ -3,11 +3,13 @@
 //! The engine itself is a struct with some type variables that determine what
 //! kind of rendering context and resources the engine will manage.
 use super::prelude::{
-    entity_local_origin, AnimationSystem, BackgroundColor, Color, DebugRenderingData, Dispatcher,
-    DispatcherBuilder, FPSCounter, FenceSystem, GamepadSystem, HasRenderingContext, Join,
-    MapEntity, MapRenderingData, Physics, PlayerSystem, RenderingContext, Resources, Screen,
-    ScreenSystem, SystemData, TiledmapSystem, TweenSystem, World, WorldExt, ZLevel, ZoneSystem,
-    AABB, V2,
+    entity_local_origin, AISystem, AnimationSystem, BackgroundColor, BehaviorSystem, Color,
+    DebugRenderingData, Dispatcher, DispatcherBuilder, Entities, FPSCounter, FenceSystem,
+    FixedTimestep, GamepadSystem, HasRenderingContext, Join, MapEntity, MapRenderingData,
+    NavMeshSystem, PathfindingSystem, Physics, PlatformerSystem, PlayerSystem, Position,
+    PreviousPosition, ReadStorage, RenderingContext, Resources, RouteSystem, Screen, ScreenSystem,
+    SystemData, TiledmapSystem, TweenSystem, Velocity, VisionSystem, World, WorldExt, WriteStorage,
+    ZLevel, ZoneSystem, AABB, V2,
 };
 use std::cmp::Ordering;
 
@@ -21,7 +23,8 @@ pub struct Engine<'a, 'b, Ctx, ImageResources> {
     pub rendering_context: Ctx,
     pub images: ImageResources,
 
-    dispatcher: Dispatcher<'a, 'b>,
+    simulation: Dispatcher<'a, 'b>,
+    frame: Dispatcher<'a, 'b>,
     debug_mode: bool,
 }
 
@@ -31,38 +34,79 @@ where
     Ctx: HasRenderingContext,
     ImageResources: Resources<<Ctx::Ctx as RenderingContext>::Image> + Default,
 {
+    /// Create an engine with extra systems that run once per rendered frame.
+    ///
+    /// Use `new_with_dispatchers` for systems that should run at the fixed
+    /// simulation rate along with physics.
     pub fn new_with<F>(
         base_url: &str,
         dispatcher_builder: DispatcherBuilder<'a, 'b>,
         new_ctx: F,
     ) -> Self
+    where
+        F: Fn() -> Ctx,
+    {
+        Self::new_with_dispatchers(
+            base_url,
+            DispatcherBuilder::new(),
+            dispatcher_builder,
+            new_ctx,
+        )
+    }
+
+    /// Create an engine with extra simulation systems and extra systems that
+    /// run once per rendered frame.
+    ///
+    /// Simulation systems run zero or more times each frame, in steps of
+    /// `FixedTimestep::step` seconds, and see that step as
+    /// `FPSCounter::last_delta`. Frame systems run once each frame after the
+    /// simulation and see the real frame delta.
+    pub fn new_with_dispatchers<F>(
+        base_url: &str,
+        simulation_builder: DispatcherBuilder<'a, 'b>,
+        frame_builder: DispatcherBuilder<'a, 'b>,
+        new_ctx: F,
+    ) -> Self
     where
         F: Fn() -> Ctx,
     {
         let mut world = World::new();
         world.insert(BackgroundColor(Color::rgb(0, 0, 0)));
+        world.insert(FixedTimestep::default());
+        world.register::<PreviousPosition>();
 
-        let mut dispatcher = dispatcher_builder
-            .with_thread_local(TiledmapSystem::new(base_url))
-            .with_thread_local(Physics::default())
-            .with_thread_local(ScreenSystem)
-            .with_thread_local(AnimationSystem)
+        let mut simulation = simulation_builder
             .with_thread_local(GamepadSystem::default())
             .with_thread_local(PlayerSystem)
+            .with_thread_local(AISystem::default())
+            .with_thread_local(BehaviorSystem::new(base_url))
+            .with_thread_local(RouteSystem)
+            .with_thread_local(PlatformerSystem)
+            .with_thread_local(Physics::default())
             .with_thread_local(TweenSystem)
             .with_thread_local(ZoneSystem)
+            .with_thread_local(VisionSystem)
             .with_thread_local(FenceSystem)
+            .with_thread_local(NavMeshSystem)
+            .with_thread_local(PathfindingSystem::default())
+            .build();
+        let mut frame = frame_builder
+            .with_thread_local(TiledmapSystem::new(base_url))
+            .with_thread_local(ScreenSystem)
+            .with_thread_local(AnimationSystem)
             //.with_thread_local(SoundSystem::new())
             //.with_thread_local(SpriteSystem)
             .build();
 
-        dispatcher.setup(&mut world);
+        simulation.setup(&mut world);
+        frame.setup(&mut world);
 
         // Maintain once so all our resources are created.
         world.maintain();
 
         Engine {
-            dispatcher,
+            simulation,
+            frame,
             world,
             base_url: base_url.into(),
             debug_mode: false,
@@ -116,12 +160,50 @@ where
         Self::new_with(base_url, DispatcherBuilder::new(), new_ctx)
     }
 
+    /// Advance the engine by one rendered frame, running as many fixed
+    /// simulation steps as have built up and then the frame systems.
     pub fn maintain(&mut self) {
-        self.world.write_resource::<FPSCounter>().next_frame();
-        self.dispatcher.dispatch(&self.world);
+        let dt = self.world.write_resource::<FPSCounter>().next_frame();
+        let (steps, step) = {
+            let mut fixed = self.world.write_resource::<FixedTimestep>();
+            (fixed.accumulate(dt), fixed.step())
+        };
+
+        self.world
+            .write_resource::<FPSCounter>()
+            .set_last_delta(step);
+        for _ in 0..steps {
+            self.remember_positions();
+            self.simulation.dispatch(&self.world);
+            self.world.maintain();
+        }
+
+        self.world.write_resource::<FPSCounter>().set_last_delta(dt);
+        self.frame.dispatch(&self.world);
         self.world.maintain();
     }
 
+    /// Store every moving entity's position before a simulation step. Anything
+    /// without a velocity is rendered at its current position.
+    fn remember_positions(&mut self) {
+        let (entities, positions, velocities, mut previous_positions): (
+            Entities,
+            ReadStorage<Position>,
+            ReadStorage<Velocity>,
+            WriteStorage<PreviousPosition>,
+        ) = self.world.system_data();
+        let stopped = (&entities, &previous_positions, !&velocities)
+            .join()
+            .map(|(ent, _, ())| ent)
+            .collect::<Vec<_>>();
+        for ent in stopped {
+            previous_positions.remove(ent);
+        }
+        for (ent, Position(position), _) in (&entities, &positions, &velocities).join() {
+            let _ = previous_positions.insert(ent, PreviousPosition(*position));
+        }
+    }
+
     /// Restart the simulation's time. This allows animations and other time-based
     /// components to operate correctly.
     pub fn restart_time(&mut self) {
@@ -136,15 +218,18 @@ where
 
         // Get all the on screen things to render.
         // Order the things by bottom to top, back to front.
+        let alpha = data.fixed_timestep.alpha();
         let mut ents: Vec<_> = (&data.entities, &data.positions, !&data.exiles)
             .join()
             .filter_map(|(ent, p, ())| {
+                // Render between the last two simulation steps
+                let p = p.interpolated(data.previous_positions.get(ent), alpha);
                 // Make sure we can see this thing (that its destination aabb intersects
                 // the screen)
                 let rendering = data.renderings.get(ent);
                 let (w, h) = rendering.map(|r| r.size()).unwrap_or((0, 0));
                 let aabb = AABB {
-                    top_left: p.0,
+                    top_left: p,
                     extents: V2::new(w as f32, h as f32),
                 };
                 if !(screen_aabb.collides_with(&aabb) || aabb.collides_with(&screen_aabb)) {
@@ -152,7 +237,7 @@ where
                 }
 
                 let offset: V2 = entity_local_origin(ent, &data.shapes, &data.offsets);
-                let pos = data.screen.from_map(&p.0);
+                let pos = data.screen.from_map(&p);
                 Some(MapEntity {
                     entity: ent,
                     position: pos,
@@ -4,7 +4,7 @@ use spade::BoundingRect;
 use super::{super::prelude::Shape, v2::V2};
 
 
-#[derive(Debug, Clone, PartialEq, Copy)]
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
 /// An axis aligned bounding box.
 pub struct AABB {
     /// The top left of the box.
@@ -354,4 +354,23 @@ impl AABB {
         );
         AABB::from_points(upper, lower)
     }
+
+
+    /// Grow the AABB by `margin` on every side.
+    pub fn expand(&self, margin: f32) -> AABB {
+        AABB {
+            top_left: self.top_left - V2::new(margin, margin),
+            extents: self.extents + V2::new(2.0 * margin, 2.0 * margin),
+        }
+    }
+
+
+    /// Whether the other AABB lies entirely inside this one. Touching edges
+    /// count as inside.
+    pub fn contains(&self, aabb: &AABB) -> bool {
+        aabb.left() >= self.left()
+            && aabb.right() <= self.right()
+            && aabb.top() >= self.top()
+            && aabb.bottom() <= self.bottom()
+    }
 }
@@ -32,15 +32,52 @@ impl SpatialObject for EntityBounds {
 }
 
 
+////////////////////////////////////////////////////////////////////////////////
+/// ## RaycastHit
+/// Something hit by a ray or shape cast.
+////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, PartialEq)]
+pub struct RaycastHit {
+    pub entity: Entity,
+
+    /// Where the ray hit. For a shape cast this is where the cast shape's
+    /// position is when it touches the thing it hit.
+    pub point: V2,
+
+    /// The normal of the surface that was hit, pointing back towards the
+    /// ray.
+    pub normal: V2,
+
+    /// How far along the ray the hit is.
+    pub distance: f32,
+}
+
+
 ////////////////////////////////////////////////////////////////////////////////
 /// ## The AABBTree structure
+/// A spatial index of entities' AABBs.
+///
+/// The rtree holds "fat" bounds, each entity's AABB grown by `margin`, while
+/// `index` holds the exact AABBs. Moving an entity within its fat bounds
+/// only updates the index, so things that move a little each frame rarely
+/// touch the rtree. Entities start out with exact bounds and only get fat
+/// ones once they move, so static barriers never grow.
 ////////////////////////////////////////////////////////////////////////////////
 pub struct AABBTree {
     pub index: HashMap<u32, AABB>,
     pub rtree: RTree<EntityBounds>,
+
+    /// How far fat bounds reach past the AABB they hold.
+    pub margin: f32,
+
+    fat: HashMap<u32, AABB>,
 }
 
 
+/// The default margin of fat bounds, in pixels.
+pub const AABB_TREE_MARGIN: f32 = 4.0;
+
+
 impl Default for AABBTree {
     fn default() -> AABBTree {
         AABBTree::new()
@@ -52,31 +89,48 @@ impl AABBTree {
     pub fn new() -> AABBTree {
         let rtree = RTree::new();
         let index = HashMap::new();
-        AABBTree { rtree, index }
+        AABBTree {
+            rtree,
+            index,
+            margin: AABB_TREE_MARGIN,
+            fat: HashMap::new(),
+        }
     }
 
 
     pub fn insert(&mut self, entity: Entity, aabb: AABB) {
         let id = entity.id();
-        if self.index.contains_key(&id) {
+        let bounds = match self.fat.get(&id) {
+            Some(fat) if fat.contains(&aabb) => {
+                // Still inside its fat bounds, the rtree can stay as it is
+                self.index.insert(id, aabb);
+                return;
+            }
+            // It moved out of its bounds, give it some room to move next time
+            Some(_) => aabb.expand(self.margin),
+            None => aabb,
+        };
+        if self.fat.contains_key(&id) {
             // We have to delete first
             self.remove(entity);
         }
         self.index.insert(id, aabb);
+        self.fat.insert(id, bounds);
         self.rtree.insert(EntityBounds {
             entity_id: id,
-            bounds: aabb.to_mbr(),
+            bounds: bounds.to_mbr(),
         });
     }
 
 
     pub fn remove(&mut self, entity: Entity) {
         let id = entity.id();
-        if let Some(aabb) = self.index.remove(&id) {
+        self.index.remove(&id);
+        if let Some(bounds) = self.fat.remove(&id) {
             let mut removals = 0;
             let eb = EntityBounds {
                 entity_id: id,
-                bounds: aabb.to_mbr(),
+                bounds: bounds.to_mbr(),
             };
             while self.rtree.remove(&eb) {
                 removals += 1;
@@ -88,6 +142,21 @@ impl AABBTree {
     }
 
 
+    /// The exact AABB of the given entity, if it is in the tree.
+    pub fn aabb(&self, entity: Entity) -> Option<AABB> {
+        self.index.get(&entity.id()).cloned()
+    }
+
+
+    /// The exact AABB of an rtree entry.
+    fn exact(&self, eb: &EntityBounds) -> AABB {
+        self.index
+            .get(&eb.entity_id)
+            .cloned()
+            .unwrap_or_else(|| AABB::from_mbr(&eb.bounds))
+    }
+
+
     /// Query for any aabbs intersecting the given aabb. Filter the results
     /// to *not* include aabbs of the given entity.
     ///
@@ -139,16 +208,19 @@ impl AABBTree {
         filter_entity: &Entity,
     ) -> Vec<(Entity, AABB)> {
         let aabb_center = aabb.center();
-        let mut collisions: Vec<&EntityBounds> = self
+        let mbr = aabb.to_mbr();
+        let mut collisions: Vec<(u32, AABB)> = self
             .rtree
-            .lookup_in_rectangle(&aabb.to_mbr())
+            .lookup_in_rectangle(&mbr)
             .into_iter()
             .filter(|eb| eb.entity_id != filter_entity.id())
+            .map(|eb| (eb.entity_id, self.exact(eb)))
+            .filter(|(_, exact)| exact.to_mbr().intersects(&mbr))
             .collect();
 
-        collisions.sort_by(|a, b| {
-            let ad = a.distance2(&aabb_center);
-            let bd = b.distance2(&aabb_center);
+        collisions.sort_by(|(_, a), (_, b)| {
+            let ad = a.to_mbr().distance2(&aabb_center);
+            let bd = b.to_mbr().distance2(&aabb_center);
             if ad < bd {
                 Ordering::Less
             } else if ad > bd {
@@ -160,7 +232,7 @@ impl AABBTree {
 
         collisions
             .into_iter()
-            .map(|eb| (entities.entity(eb.entity_id), AABB::from_mbr(&eb.bounds)))
+            .map(|(id, exact)| (entities.entity(id), exact))
             .collect()
     }
 
@@ -244,6 +316,299 @@ impl AABBTree {
             .collect()
     }
 
+    /// Sweep the entity's shape from `position` along `motion` and return the
+    /// barriers it would hit, in order of their time of impact.
+    /// Returns a vector of entity, time of impact as a fraction of the motion
+    /// and the normal of the surface hit.
+    ///
+    /// Barriers the entity is already intersecting are not included. Those
+    /// can be found with `query_intersecting_barriers`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn query_swept_barriers<S, P, B>(
+        &self,
+        entities: &Entities,
+        entity: &Entity,
+        position: V2,
+        motion: V2,
+        shapes: &S,
+        positions: &P,
+        barriers: &B,
+    ) -> Vec<(Entity, f32, V2)>
+    where
+        S: GetStorage<Shape>,
+        P: GetStorage<Position>,
+        B: GetStorage<Barrier>,
+    {
+        let shape = if let Some(shape) = shapes.get(*entity) {
+            shape
+        } else {
+            return vec![];
+        };
+        let aabb = shape.aabb().translate(&position);
+        let swept_aabb = AABB::union(&aabb, &aabb.translate(&motion));
+        let mut hits = self
+            .query(entities, &swept_aabb, entity)
+            .into_iter()
+            .filter_map(|(other_ent, _)| {
+                if !barriers.contains(other_ent) {
+                    return None;
+                }
+                let other_shape = shapes.get(other_ent)?;
+                let other_position = positions.get(other_ent)?.0;
+                shape
+                    .sweep(position, motion, other_shape, other_position)
+                    .map(|(toi, normal)| (other_ent, toi, normal))
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by(|(_, a, _), (_, b, _)| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        hits
+    }
+
+    /// The entities whose aabbs touch the line from `origin` to `end`.
+    fn query_segment(&self, entities: &Entities, origin: V2, end: V2) -> Vec<Entity> {
+        let aabb = AABB::from_points(
+            V2::new(origin.x.min(end.x), origin.y.min(end.y)),
+            V2::new(origin.x.max(end.x), origin.y.max(end.y)),
+        );
+        self.rtree
+            .lookup_in_rectangle(&aabb.to_mbr())
+            .into_iter()
+            .map(|eb| entities.entity(eb.entity_id))
+            .collect()
+    }
+
+    /// Cast a ray from `origin` in `direction` up to `max_distance` and return
+    /// everything it hits, closest first. Only entities for which `filter`
+    /// returns true are included.
+    ///
+    /// ```
+    /// use old_gods::prelude::*;
+    ///
+    /// let mut world = World::new();
+    /// world.register::<Position>();
+    /// world.register::<Shape>();
+    /// let mut tree = AABBTree::new();
+    /// let shape = Shape::box_with_size(10.0, 10.0);
+    /// let mut walls = vec![];
+    /// for x in [20.0, 40.0].iter() {
+    ///     let position = V2::new(*x, 0.0);
+    ///     let wall = world
+    ///         .create_entity()
+    ///         .with(Position(position))
+    ///         .with(shape.clone())
+    ///         .build();
+    ///     tree.insert(wall, shape.aabb().translate(&position));
+    ///     walls.push(wall);
+    /// }
+    ///
+    /// let (entities, shapes, positions): (Entities, ReadStorage<Shape>, ReadStorage<Position>) =
+    ///     world.system_data();
+    /// let hits = tree.raycast_all(
+    ///     &entities,
+    ///     V2::new(0.0, 5.0),
+    ///     V2::new(1.0, 0.0),
+    ///     100.0,
+    ///     &shapes,
+    ///     &positions,
+    ///     |_| true,
+    /// );
+    /// assert_eq!(hits.len(), 2);
+    /// assert_eq!(hits[0].entity, walls[0]);
+    /// assert_eq!(hits[0].point, V2::new(20.0, 5.0));
+    /// assert_eq!(hits[0].normal, V2::new(-1.0, 0.0));
+    ///
+    /// let hit = tree.raycast(
+    ///     &entities,
+    ///     V2::new(0.0, 5.0),
+    ///     V2::new(1.0, 0.0),
+    ///     100.0,
+    ///     &shapes,
+    ///     &positions,
+    ///     |ent| ent != walls[0],
+    /// );
+    /// assert_eq!(hit.map(|hit| hit.entity), Some(walls[1]));
+    ///
+    /// // A box is stopped short by its width
+    /// let hit = tree
+    ///     .shape_cast(
+    ///         &entities,
+    ///         &Shape::box_with_size(2.0, 2.0),
+    ///         V2::new(0.0, 4.0),
+    ///         V2::new(1.0, 0.0),
+    ///         100.0,
+    ///         &shapes,
+    ///         &positions,
+    ///         |_| true,
+    ///     )
+    ///     .unwrap();
+    /// assert_eq!(hit.entity, walls[0]);
+    /// assert_eq!(hit.point, V2::new(18.0, 4.0));
+    /// ```
+    #[allow(clippy::too_many_arguments)]
+    pub fn raycast_all<S, P, F>(
+        &self,
+        entities: &Entities,
+        origin: V2,
+        direction: V2,
+        max_distance: f32,
+        shapes: &S,
+        positions: &P,
+        filter: F,
+    ) -> Vec<RaycastHit>
+    where
+        S: GetStorage<Shape>,
+        P: GetStorage<Position>,
+        F: Fn(Entity) -> bool,
+    {
+        let direction = if let Some(direction) = direction.unitize() {
+            direction
+        } else {
+            return vec![];
+        };
+        let end = origin + direction.scalar_mul(max_distance);
+        let mut hits: Vec<RaycastHit> = self
+            .query_segment(entities, origin, end)
+            .into_iter()
+            .filter(|ent| filter(*ent))
+            .filter_map(|ent| {
+                let shape = shapes.get(ent)?;
+                let position = positions.get(ent)?.0;
+                let (distance, normal) =
+                    shape.raycast(position, origin, direction, max_distance)?;
+                Some(RaycastHit {
+                    entity: ent,
+                    point: origin + direction.scalar_mul(distance),
+                    normal,
+                    distance,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| {
+            a.distance
+                .partial_cmp(&b.distance)
+                .unwrap_or(Ordering::Equal)
+        });
+        hits
+    }
+
+    /// Like `raycast_all` but only returns the closest hit.
+    #[allow(clippy::too_many_arguments)]
+    pub fn raycast<S, P, F>(
+        &self,
+        entities: &Entities,
+        origin: V2,
+        direction: V2,
+        max_distance: f32,
+        shapes: &S,
+        positions: &P,
+        filter: F,
+    ) -> Option<RaycastHit>
+    where
+        S: GetStorage<Shape>,
+        P: GetStorage<Position>,
+        F: Fn(Entity) -> bool,
+    {
+        self.raycast_all(
+            entities,
+            origin,
+            direction,
+            max_distance,
+            shapes,
+            positions,
+            filter,
+        )
+        .into_iter()
+        .next()
+    }
+
+    /// Sweep `shape` from `origin` in `direction` up to `max_distance` and
+    /// return everything it hits, closest first. Only entities for which
+    /// `filter` returns true are included. Things the shape already overlaps
+    /// at the start are not hit.
+    #[allow(clippy::too_many_arguments)]
+    pub fn shape_cast_all<S, P, F>(
+        &self,
+        entities: &Entities,
+        shape: &Shape,
+        origin: V2,
+        direction: V2,
+        max_distance: f32,
+        shapes: &S,
+        positions: &P,
+        filter: F,
+    ) -> Vec<RaycastHit>
+    where
+        S: GetStorage<Shape>,
+        P: GetStorage<Position>,
+        F: Fn(Entity) -> bool,
+    {
+        let direction = if let Some(direction) = direction.unitize() {
+            direction
+        } else {
+            return vec![];
+        };
+        let motion = direction.scalar_mul(max_distance);
+        let aabb = shape.aabb().translate(&origin);
+        let swept_aabb = AABB::union(&aabb, &aabb.translate(&motion));
+        let mut hits: Vec<RaycastHit> = self
+            .rtree
+            .lookup_in_rectangle(&swept_aabb.to_mbr())
+            .into_iter()
+            .map(|eb| entities.entity(eb.entity_id))
+            .filter(|ent| filter(*ent))
+            .filter_map(|ent| {
+                let other_shape = shapes.get(ent)?;
+                let other_position = positions.get(ent)?.0;
+                let (toi, normal) = shape.sweep(origin, motion, other_shape, other_position)?;
+                let distance = toi * max_distance;
+                Some(RaycastHit {
+                    entity: ent,
+                    point: origin + direction.scalar_mul(distance),
+                    normal,
+                    distance,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| {
+            a.distance
+                .partial_cmp(&b.distance)
+                .unwrap_or(Ordering::Equal)
+        });
+        hits
+    }
+
+    /// Like `shape_cast_all` but only returns the closest hit.
+    #[allow(clippy::too_many_arguments)]
+    pub fn shape_cast<S, P, F>(
+        &self,
+        entities: &Entities,
+        shape: &Shape,
+        origin: V2,
+        direction: V2,
+        max_distance: f32,
+        shapes: &S,
+        positions: &P,
+        filter: F,
+    ) -> Option<RaycastHit>
+    where
+        S: GetStorage<Shape>,
+        P: GetStorage<Position>,
+        F: Fn(Entity) -> bool,
+    {
+        self.shape_cast_all(
+            entities,
+            shape,
+            origin,
+            direction,
+            max_distance,
+            shapes,
+            positions,
+            filter,
+        )
+        .into_iter()
+        .next()
+    }
+
     /// Query for the n closest things to the point. Filter the results to *not*
     /// include the given entity.
     ///
@@ -270,39 +635,180 @@ impl AABBTree {
         n: usize,
         filter_entity: &Entity,
     ) -> Vec<(Entity, AABB)> {
-        self.rtree
-            .nearest_n_neighbors(point, n + 1)
+        // The rtree orders things by their fat bounds, which are never
+        // further away than their exact AABBs. Keep widening the search until
+        // nothing left in the rtree could be closer than what we've found.
+        let wanted = n + 1;
+        let mut searched = wanted;
+        loop {
+            let candidates = self.rtree.nearest_n_neighbors(point, searched);
+            let mut nearest: Vec<(f32, &EntityBounds)> = candidates
+                .iter()
+                .map(|eb| (self.exact(eb).to_mbr().distance2(point), *eb))
+                .collect();
+            nearest.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            nearest.truncate(wanted);
+
+            let exhausted = candidates.len() < searched;
+            let settled = match (nearest.last(), candidates.last()) {
+                (Some((furthest, _)), Some(unsearched)) => {
+                    nearest.len() == wanted && *furthest <= unsearched.distance2(point)
+                }
+                _ => true,
+            };
+            if exhausted || settled {
+                return nearest
+                    .into_iter()
+                    .filter(|(_, eb)| eb.entity_id != filter_entity.id())
+                    .map(|(_, eb)| (entities.entity(eb.entity_id), self.exact(eb)))
+                    .collect();
+            }
+            searched *= 2;
+        }
+    }
+
+    /// Query for the n closest things within `max_distance` of the point that
+    /// pass the filter. Things that don't pass the filter don't count towards
+    /// the n. Only the tree around the point is searched.
+    ///
+    /// ```
+    /// use old_gods::geom::{AABBTree, AABB, V2};
+    /// use specs::prelude::*;
+    ///
+    /// let mut world = World::new();
+    ///
+    /// let mut tree = AABBTree::new();
+    /// let near = world.create_entity().build();
+    /// let far = world.create_entity().build();
+    /// let too_far = world.create_entity().build();
+    /// tree.insert(near, AABB::new(1.0, 0.0, 1.0, 1.0));
+    /// tree.insert(far, AABB::new(10.0, 0.0, 1.0, 1.0));
+    /// tree.insert(too_far, AABB::new(30.0, 0.0, 1.0, 1.0));
+    ///
+    /// let entities: Entities = world.system_data();
+    /// let nearest = tree.query_nearest_n_within(&entities, &V2::origin(), 2, 20.0, |ent| ent != near);
+    /// assert_eq!(nearest, vec![(far, AABB::new(10.0, 0.0, 1.0, 1.0))]);
+    /// ```
+    pub fn query_nearest_n_within<F>(
+        &self,
+        entities: &Entities,
+        point: &V2,
+        n: usize,
+        max_distance: f32,
+        filter: F,
+    ) -> Vec<(Entity, AABB)>
+    where
+        F: Fn(Entity) -> bool,
+    {
+        let area = AABB {
+            top_left: *point - V2::new(max_distance, max_distance),
+            extents: V2::new(max_distance * 2.0, max_distance * 2.0),
+        };
+        let max_distance2 = max_distance * max_distance;
+        let mut nearest: Vec<(f32, Entity, AABB)> = self
+            .rtree
+            .lookup_in_rectangle(&area.to_mbr())
             .into_iter()
-            .filter(|eb| eb.entity_id != filter_entity.id())
-            .map(|eb| (entities.entity(eb.entity_id), AABB::from_mbr(&eb.bounds)))
+            .map(|eb| {
+                let exact = self.exact(eb);
+                (exact.to_mbr().distance2(point), eb.entity_id, exact)
+            })
+            .filter(|(distance2, _, _)| *distance2 <= max_distance2)
+            .map(|(distance2, id, exact)| (distance2, entities.entity(id), exact))
+            .filter(|(_, ent, _)| filter(*ent))
+            .collect();
+        nearest.sort_by(|(a, _, _), (b, _, _)| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        nearest
+            .into_iter()
+            .take(n)
+            .map(|(_, ent, exact)| (ent, exact))
             .collect()
     }
 
+    /// Bring the tree up to date after the given shape and position events.
+    /// Each entity is updated once, no matter how many events it has.
+    /// Entities that `get_aabb` can't find an AABB for are removed.
     pub fn update_tree(
         &mut self,
         entities: &Entities,
         events: Vec<&ComponentEvent>,
         get_aabb: impl Fn(Entity) -> Option<(Entity, AABB)>,
     ) {
-        for event in events {
-            match event {
-                ComponentEvent::Inserted(id) => {
-                    let entity = entities.entity(*id);
-                    if let Some((entity, aabb)) = get_aabb(entity) {
-                        self.insert(entity, aabb);
-                    }
-                }
-                ComponentEvent::Modified(id) => {
-                    let entity = entities.entity(*id);
-                    if let Some((entity, aabb)) = get_aabb(entity) {
-                        self.insert(entity, aabb);
-                    }
-                }
-                ComponentEvent::Removed(id) => {
-                    let entity = entities.entity(*id);
-                    self.remove(entity);
-                }
+        let mut ids: Vec<u32> = events
+            .into_iter()
+            .map(|event| match event {
+                ComponentEvent::Inserted(id)
+                | ComponentEvent::Modified(id)
+                | ComponentEvent::Removed(id) => *id,
+            })
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        for id in ids.into_iter() {
+            let entity = entities.entity(id);
+            if let Some((entity, aabb)) = get_aabb(entity) {
+                self.insert(entity, aabb);
+            } else {
+                self.remove(entity);
             }
         }
     }
 }
+
+
+#[cfg(test)]
+mod aabb_tree_tests {
+    use super::*;
+
+    #[test]
+    fn small_moves_stay_in_fat_bounds() {
+        let mut world = World::new();
+        let mut tree = AABBTree::new();
+        let mover = world.create_entity().build();
+        let other = world.create_entity().build();
+        let far = world.create_entity().build();
+        tree.insert(mover, AABB::new(0.0, 0.0, 10.0, 10.0));
+        tree.insert(other, AABB::new(13.0, 0.0, 10.0, 10.0));
+        // Things start out exact
+        assert_eq!(
+            tree.rtree
+                .lookup_in_rectangle(&AABB::new(11.0, 0.0, 1.0, 1.0).to_mbr())
+                .len(),
+            0
+        );
+
+        // Moving out of its bounds makes it fat, moving within them doesn't
+        // touch the rtree
+        tree.insert(mover, AABB::new(1.0, 0.0, 10.0, 10.0));
+        let fat = tree
+            .rtree
+            .lookup_in_rectangle(&AABB::new(-2.0, 0.0, 1.0, 1.0).to_mbr())
+            .len();
+        assert_eq!(fat, 1);
+        tree.insert(mover, AABB::new(2.0, 0.0, 10.0, 10.0));
+        assert_eq!(tree.aabb(mover), Some(AABB::new(2.0, 0.0, 10.0, 10.0)));
+
+        // Queries still only see the exact aabbs
+        let entities = world.entities();
+        let found = tree.query(&entities, &AABB::new(-2.0, 0.0, 1.0, 1.0), &other);
+        assert!(found.is_empty(), "{:?}", found);
+        let found = tree.query(&entities, &AABB::new(12.0, 0.0, 0.5, 1.0), &other);
+        assert_eq!(found, vec![(mover, AABB::new(2.0, 0.0, 10.0, 10.0))]);
+
+        // The nearest things are found by their exact aabbs, even when a fat
+        // bounds is closer
+        tree.insert(mover, AABB::new(30.0, 0.0, 10.0, 10.0));
+        tree.insert(mover, AABB::new(33.0, 0.0, 10.0, 10.0));
+        let nearest = tree.query_nearest_n(&entities, &V2::new(25.0, 5.0), 0, &far);
+        assert_eq!(nearest, vec![(other, AABB::new(13.0, 0.0, 10.0, 10.0))]);
+
+        tree.remove(mover);
+        assert!(tree.aabb(mover).is_none());
+        assert_eq!(
+            tree.rtree
+                .lookup_in_rectangle(&AABB::new(20.0, 0.0, 40.0, 10.0).to_mbr())
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Convexity checks and convex decomposition of simple polygons.
+//!
+//! Collision detection with the separating axis theorem only works for convex
+//! shapes, so concave polygons are split into convex parts. The polygon is
+//! triangulated by ear clipping and then triangles are merged back together
+//! while the result stays convex (Hertel-Mehlhorn), which gives at most four
+//! times the optimal number of parts.
+use super::V2;
+
+
+/// Twice the signed area of the polygon.
+fn signed_area2(vertices: &[V2]) -> f32 {
+    (0..vertices.len())
+        .map(|i| vertices[i].cross(vertices[(i + 1) % vertices.len()]))
+        .sum()
+}
+
+
+/// The turn made at `b` when travelling from `a` to `c`, relative to the
+/// winding of the polygon. Positive for a convex corner.
+fn turn(winding: f32, a: V2, b: V2, c: V2) -> f32 {
+    (b - a).cross(c - b) * winding
+}
+
+
+fn is_convex_with(winding: f32, vertices: &[V2]) -> bool {
+    let n = vertices.len();
+    (0..n).all(|i| {
+        let a = vertices[(i + n - 1) % n];
+        let b = vertices[i];
+        let c = vertices[(i + 1) % n];
+        turn(winding, a, b, c) >= -f32::EPSILON
+    })
+}
+
+
+/// Whether the polygon is convex. Polygons with fewer than four vertices
+/// always are.
+pub fn is_convex(vertices: &[V2]) -> bool {
+    let winding = signed_area2(vertices).signum();
+    vertices.len() < 4 || is_convex_with(winding, vertices)
+}
+
+
+fn in_triangle(winding: f32, p: V2, a: V2, b: V2, c: V2) -> bool {
+    turn(winding, a, b, p) >= 0.0 && turn(winding, b, c, p) >= 0.0 && turn(winding, c, a, p) >= 0.0
+}
+
+
+/// Triangulate the polygon by ear clipping, returning triangles of vertex
+/// indices with the polygon's winding.
+fn triangulate(winding: f32, vertices: &[V2]) -> Vec<Vec<usize>> {
+    let mut remaining: Vec<usize> = (0..vertices.len()).collect();
+    let mut triangles = vec![];
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let ear = (0..n).find(|&i| {
+            let (ia, ib, ic) = (
+                remaining[(i + n - 1) % n],
+                remaining[i],
+                remaining[(i + 1) % n],
+            );
+            let (a, b, c) = (vertices[ia], vertices[ib], vertices[ic]);
+            turn(winding, a, b, c) > 0.0
+                && remaining
+                    .iter()
+                    .filter(|&&j| j != ia && j != ib && j != ic)
+                    .all(|&j| !in_triangle(winding, vertices[j], a, b, c))
+        });
+        // A simple polygon always has an ear, but rounding may hide it, in
+        // which case clip the first vertex and carry on.
+        let i = ear.unwrap_or(0);
+        triangles.push(vec![
+            remaining[(i + n - 1) % n],
+            remaining[i],
+            remaining[(i + 1) % n],
+        ]);
+        remaining.remove(i);
+    }
+    triangles.push(remaining);
+    triangles
+}
+
+
+/// Try to merge two polygons of vertex indices across a shared edge, keeping
+/// the result convex.
+fn merge(winding: f32, vertices: &[V2], p: &[usize], q: &[usize]) -> Option<Vec<usize>> {
+    let (np, nq) = (p.len(), q.len());
+    // Find an edge a -> b in p that runs b -> a in q
+    let (i, j) = (0..np).find_map(|i| {
+        let (a, b) = (p[i], p[(i + 1) % np]);
+        (0..nq)
+            .find(|&j| q[j] == b && q[(j + 1) % nq] == a)
+            .map(|j| (i, j))
+    })?;
+    // Walk p from b around to a, then q from after a around to before b
+    let mut merged: Vec<usize> = (1..=np).map(|k| p[(i + k) % np]).collect();
+    merged.extend((2..nq).map(|k| q[(j + k) % nq]));
+    let points: Vec<V2> = merged.iter().map(|&k| vertices[k]).collect();
+    if is_convex_with(winding, &points) {
+        Some(merged)
+    } else {
+        None
+    }
+}
+
+
+/// Split a simple polygon into convex polygons. A convex polygon is returned
+/// as is.
+pub fn decompose(vertices: &[V2]) -> Vec<Vec<V2>> {
+    let winding = signed_area2(vertices).signum();
+    if vertices.len() < 4 || is_convex_with(winding, vertices) {
+        return vec![vertices.to_vec()];
+    }
+
+    let mut parts = triangulate(winding, vertices);
+    'merging: loop {
+        for i in 0..parts.len() {
+            for j in i + 1..parts.len() {
+                if let Some(merged) = merge(winding, vertices, &parts[i], &parts[j]) {
+                    parts[i] = merged;
+                    parts.remove(j);
+                    continue 'merging;
+                }
+            }
+        }
+        break;
+    }
+
+    parts
+        .into_iter()
+        .map(|part| part.into_iter().map(|i| vertices[i]).collect())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod convex_tests {
+    use super::*;
+
+    fn area(parts: &[Vec<V2>]) -> f32 {
+        parts
+            .iter()
+            .map(|part| signed_area2(part).abs() / 2.0)
+            .sum()
+    }
+
+    #[test]
+    fn can_decompose_concave_polygons() {
+        // An L shape
+        let l = vec![
+            V2::new(0.0, 0.0),
+            V2::new(10.0, 0.0),
+            V2::new(10.0, 4.0),
+            V2::new(4.0, 4.0),
+            V2::new(4.0, 10.0),
+            V2::new(0.0, 10.0),
+        ];
+        assert!(!is_convex(&l));
+        let parts = decompose(&l);
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|part| is_convex(part)));
+        assert!((area(&parts) - 64.0).abs() < 0.001);
+
+        // The same shape wound the other way
+        let reversed: Vec<V2> = l.into_iter().rev().collect();
+        let parts = decompose(&reversed);
+        assert!(parts.iter().all(|part| is_convex(part)));
+        assert!((area(&parts) - 64.0).abs() < 0.001);
+
+        let square = vec![
+            V2::new(0.0, 0.0),
+            V2::new(1.0, 0.0),
+            V2::new(1.0, 1.0),
+            V2::new(0.0, 1.0),
+        ];
+        assert!(is_convex(&square));
+        assert_eq!(decompose(&square), vec![square]);
+    }
+}
@@ -1,7 +1,10 @@
 mod aabb;
 mod aabb_tree;
+mod convex;
 mod line;
+mod navmesh;
 mod shape;
+mod tile_grid;
 mod v2;
 
-pub use self::{aabb::*, aabb_tree::*, line::*, shape::*, v2::*};
+pub use self::{aabb::*, aabb_tree::*, line::*, navmesh::*, shape::*, tile_grid::*, v2::*};
@@ -0,0 +1,691 @@
+//! A navigation mesh for finding paths around barriers.
+//!
+//! The walkable area of a map is split into square chunks. Each chunk is
+//! triangulated on its own with a constrained Delaunay triangulation whose
+//! constraints are the outlines of the obstacles inside it, and triangles
+//! that overlap an obstacle are not walkable. Adding, moving or removing an
+//! obstacle only rebuilds the chunks it touches.
+//!
+//! Triangles are stitched together, within and across chunks, by the edges
+//! they share. Paths are found with A* over the triangles and then pulled
+//! tight around corners with a funnel.
+use spade::delaunay::{DelaunayWalkLocate, FloatCDT};
+use specs::prelude::*;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, BinaryHeap, HashMap},
+};
+
+use super::{Shape, AABB, V2};
+
+
+/// The default width and height of a navmesh chunk, in pixels.
+pub const NAV_MESH_CHUNK_SIZE: f32 = 256.0;
+
+
+/// Points are snapped to a grid this many times finer than a pixel when
+/// stitching triangles together.
+const NAV_MESH_PRECISION: f32 = 1000.0;
+
+
+/// Triangles overlapping an obstacle by less than this are still walkable.
+const NAV_MESH_OVERLAP: f32 = 0.01;
+
+
+type ChunkKey = (i32, i32);
+type PointKey = (i64, i64);
+type EdgeKey = (PointKey, PointKey);
+type Node = (ChunkKey, usize);
+
+
+fn point_key(p: V2) -> PointKey {
+    (
+        (p.x * NAV_MESH_PRECISION).round() as i64,
+        (p.y * NAV_MESH_PRECISION).round() as i64,
+    )
+}
+
+
+fn edge_key(a: V2, b: V2) -> EdgeKey {
+    let (a, b) = (point_key(a), point_key(b));
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+
+/// Twice the signed area of the triangle a, b, c.
+fn triarea2(a: V2, b: V2, c: V2) -> f32 {
+    let ab = b - a;
+    let ac = c - a;
+    ac.x * ab.y - ab.x * ac.y
+}
+
+
+/// Clip a polygon to an AABB.
+fn clip_polygon(vertices: &[V2], aabb: &AABB) -> Vec<V2> {
+    let planes: [(V2, f32); 4] = [
+        (V2::new(1.0, 0.0), aabb.left()),
+        (V2::new(-1.0, 0.0), -aabb.right()),
+        (V2::new(0.0, 1.0), aabb.top()),
+        (V2::new(0.0, -1.0), -aabb.bottom()),
+    ];
+    let mut output = vertices.to_vec();
+    for (normal, distance) in planes.iter() {
+        let input = std::mem::take(&mut output);
+        let inside = |p: V2| p.dot(*normal) >= *distance;
+        for (i, current) in input.iter().enumerate() {
+            let previous = input[(i + input.len() - 1) % input.len()];
+            let cross = || {
+                let t = (distance - previous.dot(*normal)) / (*current - previous).dot(*normal);
+                previous + (*current - previous).scalar_mul(t)
+            };
+            if inside(*current) {
+                if !inside(previous) {
+                    output.push(cross());
+                }
+                output.push(*current);
+            } else if inside(previous) {
+                output.push(cross());
+            }
+        }
+    }
+    output
+}
+
+
+/// One triangle of a navmesh.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavTriangle {
+    pub vertices: [V2; 3],
+    pub walkable: bool,
+}
+
+
+impl NavTriangle {
+    pub fn centroid(&self) -> V2 {
+        let [a, b, c] = self.vertices;
+        (a + b + c).scalar_mul(1.0 / 3.0)
+    }
+
+    /// Whether the point is inside the triangle or on its edges.
+    pub fn contains_point(&self, p: V2) -> bool {
+        let [a, b, c] = self.vertices;
+        let areas = [triarea2(a, b, p), triarea2(b, c, p), triarea2(c, a, p)];
+        let tolerance = -1e-4;
+        areas.iter().all(|area| *area >= tolerance) || areas.iter().all(|area| *area <= -tolerance)
+    }
+}
+
+
+/// A barrier's shape at its position, carved out of the navmesh.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavObstacle {
+    pub shape: Shape,
+    pub position: V2,
+}
+
+
+impl NavObstacle {
+    /// The obstacle's bounds, with positive extents.
+    fn aabb(&self) -> AABB {
+        let vertices = self.shape.translated(&self.position).vertices();
+        let min = vertices
+            .iter()
+            .fold(V2::new(f32::INFINITY, f32::INFINITY), |m, v| {
+                V2::new(m.x.min(v.x), m.y.min(v.y))
+            });
+        let max = vertices
+            .iter()
+            .fold(V2::new(f32::NEG_INFINITY, f32::NEG_INFINITY), |m, v| {
+                V2::new(m.x.max(v.x), m.y.max(v.y))
+            });
+        AABB::from_points(min, max)
+    }
+}
+
+
+/// An entry in the A* open set, ordered so the cheapest comes out of the heap
+/// first.
+struct Open {
+    cost: f32,
+    node: Node,
+}
+
+
+impl PartialEq for Open {
+    fn eq(&self, other: &Open) -> bool {
+        self.cost == other.cost
+    }
+}
+
+
+impl Eq for Open {}
+
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Open) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+impl Ord for Open {
+    fn cmp(&self, other: &Open) -> Ordering {
+        other
+            .cost
+            .partial_cmp(&self.cost)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+/// ## The NavMesh structure
+/// The walkable area of a map, for finding paths around obstacles.
+///
+/// Nothing is walkable until the navmesh has bounds. Changes to the bounds
+/// and obstacles take effect on the next `rebuild`.
+///
+/// ```
+/// use old_gods::prelude::*;
+///
+/// let mut world = World::new();
+/// let wall = world.create_entity().build();
+/// let mut navmesh = NavMesh::new();
+/// navmesh.set_bounds(AABB::new(0.0, 0.0, 100.0, 100.0));
+/// navmesh.insert_obstacle(wall, Shape::box_with_size(20.0, 80.0), V2::new(40.0, 0.0));
+/// navmesh.rebuild();
+///
+/// let path = navmesh
+///     .find_path(V2::new(10.0, 10.0), V2::new(90.0, 10.0))
+///     .unwrap();
+/// assert_eq!(path.last(), Some(&V2::new(90.0, 10.0)));
+/// // The path goes under the wall
+/// assert!(path.iter().any(|p| p.y >= 80.0));
+/// ```
+////////////////////////////////////////////////////////////////////////////////
+pub struct NavMesh {
+    /// The width and height of each chunk. Smaller chunks are quicker to
+    /// rebuild, larger ones are quicker to search.
+    pub chunk_size: f32,
+
+    bounds: Option<AABB>,
+    obstacles: HashMap<u32, NavObstacle>,
+    chunk_obstacles: HashMap<ChunkKey, BTreeSet<u32>>,
+    chunks: HashMap<ChunkKey, Vec<NavTriangle>>,
+    edges: HashMap<EdgeKey, Vec<Node>>,
+    dirty: BTreeSet<ChunkKey>,
+}
+
+
+impl Default for NavMesh {
+    fn default() -> NavMesh {
+        NavMesh::new()
+    }
+}
+
+
+impl NavMesh {
+    pub fn new() -> NavMesh {
+        NavMesh::with_chunk_size(NAV_MESH_CHUNK_SIZE)
+    }
+
+
+    pub fn with_chunk_size(chunk_size: f32) -> NavMesh {
+        NavMesh {
+            chunk_size,
+            bounds: None,
+            obstacles: HashMap::new(),
+            chunk_obstacles: HashMap::new(),
+            chunks: HashMap::new(),
+            edges: HashMap::new(),
+            dirty: BTreeSet::new(),
+        }
+    }
+
+
+    /// The walkable area, if any.
+    pub fn bounds(&self) -> Option<AABB> {
+        self.bounds
+    }
+
+
+    /// Set the walkable area, usually the whole map. The entire navmesh will
+    /// be rebuilt.
+    pub fn set_bounds(&mut self, bounds: AABB) {
+        self.bounds = Some(bounds);
+        self.chunks.clear();
+        self.edges.clear();
+        self.chunk_obstacles.clear();
+        let obstacles: Vec<(u32, AABB)> = self
+            .obstacles
+            .iter()
+            .map(|(id, obstacle)| (*id, obstacle.aabb()))
+            .collect();
+        for (id, aabb) in obstacles.into_iter() {
+            for key in self.chunks_overlapping(&aabb) {
+                self.chunk_obstacles.entry(key).or_default().insert(id);
+            }
+        }
+        self.dirty = self.chunks_overlapping(&bounds).into_iter().collect();
+    }
+
+
+    /// The obstacle of the given entity, if it has one.
+    pub fn obstacle(&self, entity: Entity) -> Option<&NavObstacle> {
+        self.obstacles.get(&entity.id())
+    }
+
+
+    /// Iterate over the entities that have obstacles.
+    pub fn obstacle_ids(&self) -> impl Iterator<Item = &u32> {
+        self.obstacles.keys()
+    }
+
+
+    /// Carve an entity's shape at the given position out of the navmesh,
+    /// replacing any obstacle it already had.
+    pub fn insert_obstacle(&mut self, entity: Entity, shape: Shape, position: V2) {
+        self.remove_obstacle(entity);
+        let id = entity.id();
+        let obstacle = NavObstacle { shape, position };
+        for key in self.chunks_overlapping(&obstacle.aabb()) {
+            self.chunk_obstacles.entry(key).or_default().insert(id);
+            self.dirty.insert(key);
+        }
+        self.obstacles.insert(id, obstacle);
+    }
+
+
+    pub fn remove_obstacle(&mut self, entity: Entity) {
+        let id = entity.id();
+        if let Some(obstacle) = self.obstacles.remove(&id) {
+            for key in self.chunks_overlapping(&obstacle.aabb()) {
+                if let Some(ids) = self.chunk_obstacles.get_mut(&key) {
+                    ids.remove(&id);
+                }
+                self.dirty.insert(key);
+            }
+        }
+    }
+
+
+    /// Whether any chunks have changed since the last rebuild.
+    pub fn needs_rebuild(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+
+    /// Rebuild the chunks that have changed.
+    pub fn rebuild(&mut self) {
+        let dirty = std::mem::take(&mut self.dirty);
+        for key in dirty.into_iter() {
+            self.rebuild_chunk(key);
+        }
+    }
+
+
+    /// Iterate over all the triangles in the navmesh.
+    pub fn triangles(&self) -> impl Iterator<Item = &NavTriangle> {
+        self.chunks.values().flatten()
+    }
+
+
+    /// The key of the chunk containing the given point.
+    fn chunk_key(&self, p: V2) -> ChunkKey {
+        let origin = self
+            .bounds
+            .map(|bounds| bounds.top_left)
+            .unwrap_or_else(V2::origin);
+        (
+            ((p.x - origin.x) / self.chunk_size).floor() as i32,
+            ((p.y - origin.y) / self.chunk_size).floor() as i32,
+        )
+    }
+
+
+    /// The area covered by the given chunk, if it's within the bounds.
+    fn chunk_aabb(&self, (x, y): ChunkKey) -> Option<AABB> {
+        let bounds = self.bounds?;
+        let top_left = bounds.top_left + V2::new(x as f32, y as f32).scalar_mul(self.chunk_size);
+        let lower_right = V2::new(
+            (top_left.x + self.chunk_size).min(bounds.right()),
+            (top_left.y + self.chunk_size).min(bounds.bottom()),
+        );
+        if x < 0 || y < 0 || lower_right.x <= top_left.x || lower_right.y <= top_left.y {
+            None
+        } else {
+            Some(AABB::from_points(top_left, lower_right))
+        }
+    }
+
+
+    /// The keys of all the chunks the AABB touches.
+    fn chunks_overlapping(&self, aabb: &AABB) -> Vec<ChunkKey> {
+        let (left, top) = self.chunk_key(aabb.top_left);
+        let (right, bottom) = self.chunk_key(aabb.upper());
+        (left..=right)
+            .flat_map(|x| (top..=bottom).map(move |y| (x, y)))
+            .collect()
+    }
+
+
+    fn rebuild_chunk(&mut self, key: ChunkKey) {
+        if let Some(triangles) = self.chunks.remove(&key) {
+            for triangle in triangles.iter() {
+                let [a, b, c] = triangle.vertices;
+                for (from, to) in [(a, b), (b, c), (c, a)].iter() {
+                    let edge = edge_key(*from, *to);
+                    if let Some(nodes) = self.edges.get_mut(&edge) {
+                        nodes.retain(|(chunk, _)| *chunk != key);
+                        if nodes.is_empty() {
+                            self.edges.remove(&edge);
+                        }
+                    }
+                }
+            }
+        }
+        let aabb = if let Some(aabb) = self.chunk_aabb(key) {
+            aabb
+        } else {
+            return;
+        };
+
+        let obstacles: Vec<Shape> = self
+            .chunk_obstacles
+            .get(&key)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.obstacles.get(id))
+            .map(|obstacle| obstacle.shape.translated(&obstacle.position))
+            .collect();
+
+        let mut cdt: FloatCDT<V2, DelaunayWalkLocate> = FloatCDT::with_walk_locate();
+        for corner in aabb.to_shape().vertices().into_iter() {
+            cdt.insert(corner);
+        }
+        for obstacle in obstacles.iter() {
+            let outline = clip_polygon(&obstacle.vertices(), &aabb);
+            let handles: Vec<usize> = outline.into_iter().map(|p| cdt.insert(p)).collect();
+            if handles.len() < 3 {
+                continue;
+            }
+            for (i, from) in handles.iter().enumerate() {
+                let to = handles[(i + 1) % handles.len()];
+                if *from != to && cdt.can_add_constraint(*from, to) {
+                    cdt.add_constraint(*from, to);
+                }
+            }
+        }
+
+        let triangles: Vec<NavTriangle> = cdt
+            .triangles()
+            .map(|face| {
+                let [a, b, c] = face.as_triangle();
+                let vertices = [*a, *b, *c];
+                let shape = Shape::Polygon {
+                    vertices: vertices.to_vec(),
+                };
+                let walkable = !obstacles.iter().any(|obstacle| {
+                    obstacle
+                        .mtv_apart(V2::origin(), &shape, V2::origin())
+                        .map(|mtv| mtv.magnitude() > NAV_MESH_OVERLAP)
+                        .unwrap_or(false)
+                });
+                NavTriangle { vertices, walkable }
+            })
+            .collect();
+        for (i, triangle) in triangles.iter().enumerate() {
+            let [a, b, c] = triangle.vertices;
+            for (from, to) in [(a, b), (b, c), (c, a)].iter() {
+                self.edges
+                    .entry(edge_key(*from, *to))
+                    .or_default()
+                    .push((key, i));
+            }
+        }
+        self.chunks.insert(key, triangles);
+    }
+
+
+    fn triangle(&self, (key, i): Node) -> Option<&NavTriangle> {
+        self.chunks.get(&key).and_then(|triangles| triangles.get(i))
+    }
+
+
+    /// Find the walkable triangles containing the given point. A point on an
+    /// edge or corner is in all the triangles that share it.
+    fn locate(&self, p: V2) -> Vec<Node> {
+        let (x, y) = self.chunk_key(p);
+        // Points on a chunk's edge may also be in its neighbors' triangles
+        let keys = [(x, y), (x - 1, y), (x, y - 1), (x - 1, y - 1)];
+        keys.iter()
+            .flat_map(|key| {
+                self.chunks
+                    .get(key)
+                    .into_iter()
+                    .flat_map(move |triangles| triangles.iter().enumerate())
+                    .filter(|(_, triangle)| triangle.walkable && triangle.contains_point(p))
+                    .map(move |(i, _)| (*key, i))
+            })
+            .collect()
+    }
+
+
+    /// The walkable triangles next to the given one, along with the edge
+    /// they share.
+    fn neighbors(&self, node: Node) -> Vec<(Node, (V2, V2))> {
+        let triangle = if let Some(triangle) = self.triangle(node) {
+            triangle
+        } else {
+            return vec![];
+        };
+        let [a, b, c] = triangle.vertices;
+        [(a, b), (b, c), (c, a)]
+            .iter()
+            .flat_map(|(from, to)| {
+                self.edges
+                    .get(&edge_key(*from, *to))
+                    .into_iter()
+                    .flatten()
+                    .filter(|other| **other != node)
+                    .filter(|other| self.triangle(**other).map(|t| t.walkable).unwrap_or(false))
+                    .map(move |other| (*other, (*from, *to)))
+            })
+            .collect()
+    }
+
+
+    /// Find a path from one point to another, returning the points to walk
+    /// through after `from`, ending with `to`. Returns None if either point
+    /// isn't walkable or there is no way between them.
+    pub fn find_path(&self, from: V2, to: V2) -> Option<Vec<V2>> {
+        let starts = self.locate(from);
+        let goals = self.locate(to);
+
+        // A* over the triangles, entering each through the middle of an edge
+        let mut open = BinaryHeap::new();
+        let mut costs: HashMap<Node, f32> = HashMap::new();
+        let mut entries: HashMap<Node, V2> = HashMap::new();
+        let mut came_from: HashMap<Node, (Node, (V2, V2))> = HashMap::new();
+        for start in starts.into_iter() {
+            costs.insert(start, 0.0);
+            entries.insert(start, from);
+            open.push(Open {
+                cost: from.distance_to(&to),
+                node: start,
+            });
+        }
+        let mut goal = None;
+        while let Some(Open { node, .. }) = open.pop() {
+            if goals.contains(&node) {
+                goal = Some(node);
+                break;
+            }
+            let cost = costs[&node];
+            let entry = entries[&node];
+            for (next, (a, b)) in self.neighbors(node).into_iter() {
+                let next_entry = (a + b).scalar_mul(0.5);
+                let next_cost = cost + entry.distance_to(&next_entry);
+                if costs.get(&next).map(|c| next_cost < *c).unwrap_or(true) {
+                    costs.insert(next, next_cost);
+                    entries.insert(next, next_entry);
+                    came_from.insert(next, (node, (a, b)));
+                    open.push(Open {
+                        cost: next_cost + next_entry.distance_to(&to),
+                        node: next,
+                    });
+                }
+            }
+        }
+        let goal = goal?;
+
+        // Walk back through the triangles, collecting the edges crossed as
+        // portals with their left and right sides
+        let mut portals = vec![(to, to)];
+        let mut node = goal;
+        while let Some((previous, (a, b))) = came_from.get(&node) {
+            let center = self.triangle(*previous)?.centroid();
+            if triarea2(center, *a, *b) > 0.0 {
+                portals.push((*a, *b));
+            } else {
+                portals.push((*b, *a));
+            }
+            node = *previous;
+        }
+        portals.push((from, from));
+        portals.reverse();
+        Some(NavMesh::pull_string(&portals))
+    }
+
+
+    /// Find the shortest path through a list of portals with the simple
+    /// stupid funnel algorithm. The first portal is the start and the last is
+    /// the goal. Returns the points after the start.
+    fn pull_string(portals: &[(V2, V2)]) -> Vec<V2> {
+        let same = |a: V2, b: V2| a.distance_to(&b) < 1e-6;
+        let mut path = vec![];
+        let mut apex = portals[0].0;
+        let (mut left, mut right) = portals[0];
+        let (mut left_index, mut right_index) = (0, 0);
+        let mut i = 1;
+        while i < portals.len() {
+            let (next_left, next_right) = portals[i];
+
+            if triarea2(apex, right, next_right) <= 0.0 {
+                if same(apex, right) || triarea2(apex, left, next_right) > 0.0 {
+                    // Tighten the funnel
+                    right = next_right;
+                    right_index = i;
+                } else {
+                    // The right side crossed over the left, so the left
+                    // corner is on the path
+                    path.push(left);
+                    apex = left;
+                    right = apex;
+                    right_index = left_index;
+                    i = left_index + 1;
+                    continue;
+                }
+            }
+
+            if triarea2(apex, left, next_left) >= 0.0 {
+                if same(apex, left) || triarea2(apex, right, next_left) < 0.0 {
+                    left = next_left;
+                    left_index = i;
+                } else {
+                    path.push(right);
+                    apex = right;
+                    left = apex;
+                    left_index = right_index;
+                    i = right_index + 1;
+                    continue;
+                }
+            }
+
+            i += 1;
+        }
+
+        let goal = portals[portals.len() - 1].0;
+        if path.last().map(|last| !same(*last, goal)).unwrap_or(true) {
+            path.push(goal);
+        }
+        path
+    }
+}
+
+
+#[cfg(test)]
+mod navmesh_tests {
+    use super::*;
+
+    fn wall_mesh(chunk_size: f32) -> (World, Entity, NavMesh) {
+        let mut world = World::new();
+        let wall = world.create_entity().build();
+        let mut navmesh = NavMesh::with_chunk_size(chunk_size);
+        navmesh.set_bounds(AABB::new(0.0, 0.0, 100.0, 100.0));
+        navmesh.insert_obstacle(wall, Shape::box_with_size(20.0, 70.0), V2::new(40.0, 10.0));
+        navmesh.rebuild();
+        (world, wall, navmesh)
+    }
+
+    /// Whether the segment passes through the wall at 40,10 - 60,80.
+    fn crosses_wall(a: V2, b: V2) -> bool {
+        (0..=100).any(|i| {
+            let p = a + (b - a).scalar_mul(i as f32 / 100.0);
+            p.x > 40.1 && p.x < 59.9 && p.y > 10.1 && p.y < 79.9
+        })
+    }
+
+    #[test]
+    fn paths_go_around_obstacles() {
+        for chunk_size in [256.0, 30.0].iter() {
+            let (_, _, navmesh) = wall_mesh(*chunk_size);
+            let from = V2::new(10.0, 50.0);
+            let to = V2::new(90.0, 50.0);
+            let path = navmesh.find_path(from, to).unwrap();
+            assert_eq!(path.last(), Some(&to));
+            let mut previous = from;
+            for p in path.iter() {
+                assert!(!crosses_wall(previous, *p), "{:?}", path);
+                previous = *p;
+            }
+            // The path is pulled tight around the wall's corners, it only
+            // bends once more when the goal sits on a chunk's edge
+            assert!(path.len() <= 4, "{:?}", path);
+            assert!(path.contains(&V2::new(40.0, 80.0)), "{:?}", path);
+            let length: f32 = path
+                .iter()
+                .scan(from, |previous, p| {
+                    let d = previous.distance_to(p);
+                    *previous = *p;
+                    Some(d)
+                })
+                .sum();
+            assert!(length < 125.0, "{} {:?}", length, path);
+
+            assert!(navmesh.find_path(from, V2::new(50.0, 50.0)).is_none());
+        }
+    }
+
+    #[test]
+    fn rebuilds_chunks_when_obstacles_change() {
+        let (mut world, wall, mut navmesh) = wall_mesh(30.0);
+        let from = V2::new(10.0, 50.0);
+        let to = V2::new(90.0, 50.0);
+
+        navmesh.remove_obstacle(wall);
+        assert!(navmesh.needs_rebuild());
+        navmesh.rebuild();
+        assert_eq!(navmesh.find_path(from, to), Some(vec![to]));
+
+        // Closing off the map leaves no way through
+        let block = world.create_entity().build();
+        navmesh.insert_obstacle(block, Shape::box_with_size(20.0, 100.0), V2::new(40.0, 0.0));
+        navmesh.rebuild();
+        assert!(navmesh.find_path(from, to).is_none());
+    }
+}
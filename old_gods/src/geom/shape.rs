@@ -1,8 +1,11 @@
 use specs::prelude::*;
 
-use super::super::prelude::{AABB, V2};
+use super::{
+    super::prelude::{AABB, V2},
+    convex,
+};
 
-// TODO: Real SAT for polygons, AABB and circles.
+// TODO: SAT for circles.
 // See http://www.metanetsoftware.com/2016/n-tutorial-a-collision-detection-and-response
 
 #[derive(Debug, Clone, PartialEq)]
@@ -15,8 +18,16 @@ pub enum Shape {
         upper: V2, // bottom right
     },
 
-    /// An assumed to be convex polygon
+    /// A convex polygon. Use `Shape::polygon` to create a polygon from
+    /// vertices that may be concave.
     Polygon { vertices: Vec<V2> },
+
+    /// A concave polygon along with the convex parts it is made of. The parts
+    /// are used for collision detection and the outline for everything else.
+    Concave {
+        vertices: Vec<V2>,
+        parts: Vec<Vec<V2>>,
+    },
 }
 
 
@@ -28,11 +39,35 @@ impl Shape {
         }
     }
 
+    /// Create a polygon shape, splitting it into convex parts if it is
+    /// concave.
+    pub fn polygon(vertices: Vec<V2>) -> Shape {
+        if convex::is_convex(&vertices) {
+            Shape::Polygon { vertices }
+        } else {
+            let parts = convex::decompose(&vertices);
+            Shape::Concave { vertices, parts }
+        }
+    }
+
+    /// The convex parts of this shape.
+    pub fn convex_parts(&self) -> Vec<Shape> {
+        match self {
+            Shape::Concave { parts, .. } => parts
+                .iter()
+                .map(|vertices| Shape::Polygon {
+                    vertices: vertices.clone(),
+                })
+                .collect(),
+            shape => vec![shape.clone()],
+        }
+    }
+
     /// The axis aligned box needed to contain the shape
     pub fn aabb(&self) -> AABB {
         match &self {
             Shape::Box { lower, upper } => AABB::from_points(*lower, *upper),
-            Shape::Polygon { vertices } => {
+            Shape::Polygon { vertices } | Shape::Concave { vertices, .. } => {
                 let mut left = std::f32::INFINITY;
                 let mut right = std::f32::NEG_INFINITY;
                 let mut top = std::f32::INFINITY;
@@ -77,6 +112,13 @@ impl Shape {
                 let vertices: Vec<V2> = vertices.into_iter().map(|v| v * *scale).collect();
                 Shape::Polygon { vertices }
             }
+            Shape::Concave { vertices, parts } => Shape::Concave {
+                vertices: vertices.into_iter().map(|v| v * *scale).collect(),
+                parts: parts
+                    .into_iter()
+                    .map(|part| part.into_iter().map(|v| v * *scale).collect())
+                    .collect(),
+            },
         }
     }
 
@@ -90,6 +132,13 @@ impl Shape {
             Shape::Polygon { vertices } => Shape::Polygon {
                 vertices: vertices.iter().map(|p| *p + *v).collect(),
             },
+            Shape::Concave { vertices, parts } => Shape::Concave {
+                vertices: vertices.iter().map(|p| *p + *v).collect(),
+                parts: parts
+                    .iter()
+                    .map(|part| part.iter().map(|p| *p + *v).collect())
+                    .collect(),
+            },
         }
     }
 
@@ -103,7 +152,7 @@ impl Shape {
                 *upper,
                 V2::new(lower.x, upper.y),
             ],
-            Shape::Polygon { vertices } => vertices.clone(),
+            Shape::Polygon { vertices } | Shape::Concave { vertices, .. } => vertices.clone(),
         }
     }
 
@@ -186,15 +235,102 @@ impl Shape {
     }
 
 
+    /// Find the minimum translation vector between shapes with more than one
+    /// convex part.
+    ///
+    /// Every separating axis of every intersecting pair of parts is tried as
+    /// a direction to push this shape in. The shortest push that leaves no
+    /// parts intersecting wins. If there is none the largest of the pairs'
+    /// vectors is used, which at least separates the deepest pair.
+    fn concave_mtv_apart(
+        &self,
+        this_position: V2,
+        other_shape: &Shape,
+        other_position: V2,
+    ) -> Option<V2> {
+        let parts = self.convex_parts();
+        let other_parts = other_shape.convex_parts();
+        let intersections = |position: V2| {
+            let mut pairs = vec![];
+            for part in parts.iter() {
+                for other_part in other_parts.iter() {
+                    if let Some(mtv) = part.mtv_apart(position, other_part, other_position) {
+                        // Ignore rounding errors
+                        if mtv.magnitude() > 1e-4 {
+                            pairs.push((part, other_part, mtv));
+                        }
+                    }
+                }
+            }
+            pairs
+        };
+
+        let intersecting = intersections(this_position);
+        let mut shortest: Option<V2> = None;
+        for (part, other_part, _) in intersecting.iter() {
+            let mut axes = part.potential_separating_axes();
+            axes.extend(other_part.potential_separating_axes());
+            for axis in axes.into_iter().flat_map(|a| vec![a, a.scalar_mul(-1.0)]) {
+                // How far we have to move along the axis to clear every pair
+                let distance = intersecting
+                    .iter()
+                    .map(|(part, other_part, _)| {
+                        let (my_start, _) = part.ranged_projection_on(this_position, axis);
+                        let (_, their_end) = other_part.ranged_projection_on(other_position, axis);
+                        their_end - my_start
+                    })
+                    .fold(0.0, f32::max);
+                let is_shorter = shortest
+                    .map(|push| distance < push.magnitude())
+                    .unwrap_or(true);
+                let push = axis.scalar_mul(distance);
+                if is_shorter && intersections(this_position + push).is_empty() {
+                    shortest = Some(push);
+                }
+            }
+        }
+
+        shortest.map(|push| push.scalar_mul(-1.0)).or_else(|| {
+            intersecting
+                .into_iter()
+                .map(|(_, _, mtv)| mtv)
+                .max_by(|a, b| {
+                    a.magnitude()
+                        .partial_cmp(&b.magnitude())
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+        })
+    }
+
+
     /// Returns the minimum translation vector needed to push an intersecting
     /// shape out of intersection. If the two are not intersecting it returns
     /// `None`. This should be called after the broadphase of detection.
+    ///
+    /// Subtracting the vector from this shape's position separates the two.
+    /// For concave shapes this is the shortest push that separates every pair
+    /// of intersecting convex parts, or the largest of the pairs' vectors if
+    /// no single push does.
+    ///
+    /// ```
+    /// use old_gods::geom::{Shape, V2};
+    ///
+    /// let a = Shape::box_with_size(10.0, 10.0);
+    /// let b = Shape::box_with_size(10.0, 10.0);
+    /// let mtv = a.mtv_apart(V2::new(8.0, 1.0), &b, V2::origin()).unwrap();
+    /// assert_eq!(mtv, V2::new(-2.0, 0.0));
+    /// ```
     pub fn mtv_apart(
         &self,
         this_position: V2,   // This shape's world location
         other_shape: &Shape, // The other shape
         other_position: V2,  // The other shape's world location
     ) -> Option<V2> {
+        let is_concave = |shape: &Shape| matches!(shape, Shape::Concave { .. });
+        if is_concave(self) || is_concave(other_shape) {
+            return self.concave_mtv_apart(this_position, other_shape, other_position);
+        }
+
         // Maintain the smallest axis overlap that we'll later use as the mtv
         let mut overlap: Option<(f32, V2)> = None;
 
@@ -210,8 +346,12 @@ impl Shape {
                 return None;
             }
 
-            let this_overlap = my_end - their_start;
-            let last_overlap = overlap.map(|(o, _)| o).unwrap_or(std::f32::INFINITY);
+            // We can either be pushed back along the axis or forward along it,
+            // whichever is shorter. A positive overlap pushes us back.
+            let back = my_end - their_start;
+            let forward = their_end - my_start;
+            let this_overlap = if back < forward { back } else { -forward };
+            let last_overlap = overlap.map(|(o, _)| o).unwrap_or(f32::INFINITY);
             if this_overlap.abs() < last_overlap.abs() {
                 overlap = Some((this_overlap, axis));
             }
@@ -219,6 +359,195 @@ impl Shape {
 
         overlap.map(|(o, v)| v.scalar_mul(o))
     }
+
+
+    /// Sweep this shape along `motion` and return the time of impact with the
+    /// other shape as a fraction of the motion, along with the normal of the
+    /// surface that was hit. The normal points away from the other shape.
+    ///
+    /// Returns `None` if the shapes don't meet during the motion, or if they
+    /// are already intersecting at the start.
+    ///
+    /// ```
+    /// use old_gods::geom::{Shape, V2};
+    ///
+    /// let bullet = Shape::box_with_size(2.0, 2.0);
+    /// let wall = Shape::Box {
+    ///     lower: V2::origin(),
+    ///     upper: V2::new(1.0, 100.0),
+    /// };
+    /// // Moving 100 units to the right in one step still hits the thin wall
+    /// let (toi, normal) = bullet
+    ///     .sweep(
+    ///         V2::new(-10.0, 50.0),
+    ///         V2::new(100.0, 0.0),
+    ///         &wall,
+    ///         V2::new(40.0, 0.0),
+    ///     )
+    ///     .unwrap();
+    /// assert_eq!(toi, 0.48);
+    /// assert_eq!(normal, V2::new(-1.0, 0.0));
+    /// ```
+    pub fn sweep(
+        &self,
+        this_position: V2,
+        motion: V2,
+        other_shape: &Shape,
+        other_position: V2,
+    ) -> Option<(f32, V2)> {
+        if let Shape::Concave { .. } = self {
+            return self
+                .convex_parts()
+                .iter()
+                .filter_map(|part| part.sweep(this_position, motion, other_shape, other_position))
+                .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        }
+        if let Shape::Concave { .. } = other_shape {
+            return other_shape
+                .convex_parts()
+                .iter()
+                .filter_map(|part| self.sweep(this_position, motion, part, other_position))
+                .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        }
+
+        // The latest time the shapes start overlapping on any axis and the
+        // earliest time they stop
+        let mut enter = f32::NEG_INFINITY;
+        let mut exit = f32::INFINITY;
+        let mut normal = None;
+
+        let mut axes: Vec<V2> = self.potential_separating_axes();
+        axes.extend(other_shape.potential_separating_axes());
+
+        for axis in axes {
+            let (my_start, my_end) = self.ranged_projection_on(this_position, axis);
+            let (their_start, their_end) = other_shape.ranged_projection_on(other_position, axis);
+            let speed = axis.dot(motion);
+            if speed.abs() < f32::EPSILON {
+                if my_end <= their_start || my_start >= their_end {
+                    // These will never overlap on this axis
+                    return None;
+                }
+                continue;
+            }
+
+            let t0 = (their_start - my_end) / speed;
+            let t1 = (their_end - my_start) / speed;
+            let (t_in, t_out) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+            if t_in > enter {
+                enter = t_in;
+                // The normal faces against the direction of travel
+                let facing = if speed > 0.0 {
+                    axis.scalar_mul(-1.0)
+                } else {
+                    axis
+                };
+                normal = Some(facing);
+            }
+            exit = exit.min(t_out);
+            if enter >= exit {
+                return None;
+            }
+        }
+
+        if !(0.0..=1.0).contains(&enter) {
+            return None;
+        }
+        normal.map(|n| (enter, n))
+    }
+
+
+    /// Cast a ray from `origin` along the unit vector `direction` and return
+    /// the distance at which it enters this shape, along with the normal of
+    /// the surface it hits. The normal points back out of the shape.
+    ///
+    /// A ray starting inside the shape hits it at a distance of zero, with a
+    /// normal opposite the direction.
+    ///
+    /// ```
+    /// use old_gods::geom::{Shape, V2};
+    ///
+    /// let wall = Shape::box_with_size(10.0, 10.0);
+    /// let (distance, normal) = wall
+    ///     .raycast(
+    ///         V2::new(20.0, 0.0),
+    ///         V2::new(0.0, 5.0),
+    ///         V2::new(1.0, 0.0),
+    ///         100.0,
+    ///     )
+    ///     .unwrap();
+    /// assert_eq!(distance, 20.0);
+    /// assert_eq!(normal, V2::new(-1.0, 0.0));
+    ///
+    /// // Too short to reach it
+    /// assert!(wall
+    ///     .raycast(
+    ///         V2::new(20.0, 0.0),
+    ///         V2::new(0.0, 5.0),
+    ///         V2::new(1.0, 0.0),
+    ///         10.0
+    ///     )
+    ///     .is_none());
+    /// ```
+    pub fn raycast(
+        &self,
+        position: V2,
+        origin: V2,
+        direction: V2,
+        max_distance: f32,
+    ) -> Option<(f32, V2)> {
+        if let Shape::Concave { .. } = self {
+            return self
+                .convex_parts()
+                .iter()
+                .filter_map(|part| part.raycast(position, origin, direction, max_distance))
+                .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        }
+
+        let vertices: Vec<V2> = self.vertices().into_iter().map(|v| v + position).collect();
+        let n = vertices.len();
+        let center = vertices
+            .iter()
+            .fold(V2::origin(), |sum, v| sum + *v)
+            .scalar_mul(1.0 / n as f32);
+        // Clip the ray against each edge in turn
+        let mut enter = 0.0;
+        let mut exit = max_distance;
+        let mut normal = direction.scalar_mul(-1.0);
+        for i in 0..n {
+            let (a, b) = (vertices[i], vertices[(i + 1) % n]);
+            let edge = b - a;
+            let mut outward = match V2::new(edge.y, -edge.x).unitize() {
+                Some(outward) => outward,
+                None => continue,
+            };
+            if outward.dot(center - a) > 0.0 {
+                outward = outward.scalar_mul(-1.0);
+            }
+            let speed = outward.dot(direction);
+            let distance = outward.dot(a - origin);
+            if speed.abs() < f32::EPSILON {
+                if distance < 0.0 {
+                    // Parallel to and outside of this edge
+                    return None;
+                }
+                continue;
+            }
+            let t = distance / speed;
+            if speed < 0.0 {
+                if t > enter {
+                    enter = t;
+                    normal = outward;
+                }
+            } else {
+                exit = exit.min(t);
+            }
+            if enter > exit {
+                return None;
+            }
+        }
+        Some((enter, normal))
+    }
 }
 
 
@@ -229,3 +558,95 @@ impl Shape {
 impl Component for Shape {
     type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
 }
+
+
+#[cfg(test)]
+mod shape_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Vertices around a circle at the given angles and radii.
+    fn around(center: (f32, f32), angles: Vec<f32>, radii: Vec<f32>) -> Vec<V2> {
+        let mut angles = angles;
+        angles.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        angles.dedup_by(|a, b| (*a - *b).abs() < 0.05);
+        angles
+            .into_iter()
+            .zip(radii.into_iter().cycle())
+            .map(|(a, r)| V2::new(center.0 + r * a.cos(), center.1 + r * a.sin()))
+            .collect()
+    }
+
+    fn convex_shape() -> impl Strategy<Value = Shape> {
+        prop_oneof![
+            (1.0f32..20.0, 1.0f32..20.0).prop_map(|(w, h)| Shape::box_with_size(w, h)),
+            (
+                prop::collection::vec(0.0f32..std::f32::consts::PI * 2.0, 3..8),
+                1.0f32..10.0
+            )
+                .prop_filter_map("degenerate polygon", |(angles, r)| {
+                    let vertices = around((0.0, 0.0), angles, vec![r]);
+                    if vertices.len() >= 3 {
+                        Some(Shape::Polygon { vertices })
+                    } else {
+                        None
+                    }
+                }),
+        ]
+    }
+
+    fn concave_shape() -> impl Strategy<Value = Shape> {
+        (4usize..10, prop::collection::vec(2.0f32..10.0, 10)).prop_map(|(n, radii)| {
+            // A star shaped polygon is always simple
+            let step = std::f32::consts::PI * 2.0 / n as f32;
+            let angles = (0..n).map(|i| i as f32 * step).collect();
+            Shape::polygon(around((0.0, 0.0), angles, radii))
+        })
+    }
+
+    fn position() -> impl Strategy<Value = V2> {
+        (-15.0f32..15.0, -15.0f32..15.0).prop_map(|(x, y)| V2::new(x, y))
+    }
+
+    fn is_separated(a: &Shape, pa: V2, b: &Shape, pb: V2) -> bool {
+        a.mtv_apart(pa, b, pb)
+            .map(|mtv| mtv.magnitude() < 1e-3)
+            .unwrap_or(true)
+    }
+
+    proptest! {
+        #[test]
+        fn mtv_separates_convex_shapes(
+            a in convex_shape(),
+            pa in position(),
+            b in convex_shape(),
+            pb in position(),
+        ) {
+            if let Some(mtv) = a.mtv_apart(pa, &b, pb) {
+                prop_assert!(is_separated(&a, pa - mtv, &b, pb), "mtv {:?}", mtv);
+            }
+        }
+
+        #[test]
+        fn mtv_separates_concave_shapes(
+            a in concave_shape(),
+            pa in position(),
+            b in convex_shape(),
+            pb in position(),
+        ) {
+            if let Some(mtv) = a.mtv_apart(pa, &b, pb) {
+                prop_assert!(is_separated(&a, pa - mtv, &b, pb), "mtv {:?}", mtv);
+            }
+            if let Some(mtv) = b.mtv_apart(pb, &a, pa) {
+                prop_assert!(is_separated(&b, pb - mtv, &a, pa), "mtv {:?}", mtv);
+            }
+        }
+
+        #[test]
+        fn concave_parts_are_convex(shape in concave_shape()) {
+            for part in shape.convex_parts() {
+                prop_assert!(convex::is_convex(&part.vertices()));
+            }
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//! A grid of tiles for finding paths on tile maps.
+//!
+//! Each cell of the grid is either blocked or walkable with a movement cost.
+//! Paths are found with A* from cell to cell, moving either in the four
+//! cardinal directions or in all eight. Diagonal moves never cut the corner
+//! of a blocked cell.
+use log::warn;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+};
+
+use super::{AABB, V2};
+
+
+type Cell = (u32, u32);
+
+
+/// The directions paths may move in from one cell to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridMovement {
+    /// North, east, south and west.
+    FourWay,
+
+    /// The cardinal directions and the diagonals between them.
+    EightWay,
+}
+
+
+impl GridMovement {
+    fn steps(&self) -> &'static [(i32, i32)] {
+        match self {
+            GridMovement::FourWay => &[(0, -1), (1, 0), (0, 1), (-1, 0)],
+            GridMovement::EightWay => &[
+                (0, -1),
+                (1, 0),
+                (0, 1),
+                (-1, 0),
+                (1, -1),
+                (1, 1),
+                (-1, 1),
+                (-1, -1),
+            ],
+        }
+    }
+}
+
+
+/// An entry in the A* open set, ordered so the cheapest comes out of the heap
+/// first.
+struct Open {
+    cost: f32,
+    cell: Cell,
+}
+
+
+impl PartialEq for Open {
+    fn eq(&self, other: &Open) -> bool {
+        self.cost == other.cost
+    }
+}
+
+
+impl Eq for Open {}
+
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Open) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+impl Ord for Open {
+    fn cmp(&self, other: &Open) -> Ordering {
+        other
+            .cost
+            .partial_cmp(&self.cost)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+/// ## The TileGrid structure
+/// The walkable tiles of a tile map and what it costs to walk across them.
+///
+/// Crossing a cell costs the distance travelled times the cell's cost, so a
+/// cell with a cost of 2.0 takes twice as long to cross as an ordinary one.
+///
+/// ```
+/// use old_gods::prelude::*;
+///
+/// let mut grid = TileGrid::new(5, 5, 16.0, 16.0);
+/// for y in 0..4 {
+///     grid.block(2, y);
+/// }
+///
+/// let path = grid
+///     .find_path(V2::new(8.0, 8.0), V2::new(72.0, 8.0), GridMovement::FourWay)
+///     .unwrap();
+/// assert_eq!(path.last(), Some(&V2::new(72.0, 8.0)));
+/// // The path goes under the wall
+/// assert!(path.contains(&V2::new(40.0, 72.0)));
+/// ```
+////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileGrid {
+    pub tile_width: f32,
+    pub tile_height: f32,
+
+    width: u32,
+    height: u32,
+    costs: Vec<Option<f32>>,
+    min_cost: f32,
+}
+
+
+impl Default for TileGrid {
+    fn default() -> TileGrid {
+        TileGrid::new(0, 0, 0.0, 0.0)
+    }
+}
+
+
+impl TileGrid {
+    /// A grid of `width` by `height` tiles, all of them walkable at a cost of
+    /// 1.0.
+    pub fn new(width: u32, height: u32, tile_width: f32, tile_height: f32) -> TileGrid {
+        TileGrid {
+            tile_width,
+            tile_height,
+            width,
+            height,
+            costs: vec![Some(1.0); (width * height) as usize],
+            min_cost: 1.0,
+        }
+    }
+
+
+    /// The name of the tile property that holds a tile's movement cost.
+    pub fn tiled_cost_property() -> String {
+        "move_cost".to_string()
+    }
+
+
+    /// The width and height of the grid, in tiles.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.costs.is_empty()
+    }
+
+
+    fn index(&self, (x, y): Cell) -> Option<usize> {
+        if x < self.width && y < self.height {
+            Some((y * self.width + x) as usize)
+        } else {
+            None
+        }
+    }
+
+
+    /// The cost of crossing the tile at x, y. Returns None if the tile is
+    /// blocked or off the grid.
+    pub fn cost(&self, x: u32, y: u32) -> Option<f32> {
+        self.index((x, y)).and_then(|i| self.costs[i])
+    }
+
+
+    pub fn is_walkable(&self, x: u32, y: u32) -> bool {
+        self.cost(x, y).is_some()
+    }
+
+
+    /// Set the cost of crossing the tile at x, y. None blocks the tile.
+    /// Costs must be positive, anything else is ignored.
+    pub fn set_cost(&mut self, x: u32, y: u32, cost: Option<f32>) {
+        if let Some(i) = self.index((x, y)) {
+            if let Some(cost) = cost {
+                if cost.is_nan() || cost <= 0.0 {
+                    warn!("ignoring movement cost {} for tile {},{}", cost, x, y);
+                    return;
+                }
+                self.min_cost = self.min_cost.min(cost);
+            }
+            self.costs[i] = cost;
+        }
+    }
+
+
+    pub fn block(&mut self, x: u32, y: u32) {
+        self.set_cost(x, y, None);
+    }
+
+
+    /// Block every tile the AABB overlaps.
+    pub fn block_aabb(&mut self, aabb: &AABB) {
+        if self.tile_width <= 0.0 || self.tile_height <= 0.0 {
+            return;
+        }
+        // Only touching the edge of a tile doesn't block it
+        let first = |lower: f32, size: f32| (lower / size + 1e-3).floor().max(0.0) as u32;
+        let last = |upper: f32, size: f32| (upper / size - 1e-3).ceil().max(0.0) as u32;
+        let (left, right) = (aabb.left().min(aabb.right()), aabb.left().max(aabb.right()));
+        let (top, bottom) = (aabb.top().min(aabb.bottom()), aabb.top().max(aabb.bottom()));
+        for y in first(top, self.tile_height)..last(bottom, self.tile_height).min(self.height) {
+            for x in first(left, self.tile_width)..last(right, self.tile_width).min(self.width) {
+                self.block(x, y);
+            }
+        }
+    }
+
+
+    /// The tile at a point on the map.
+    pub fn cell_at(&self, p: V2) -> Option<(u32, u32)> {
+        if p.x < 0.0 || p.y < 0.0 || self.tile_width <= 0.0 || self.tile_height <= 0.0 {
+            return None;
+        }
+        let cell = (
+            (p.x / self.tile_width) as u32,
+            (p.y / self.tile_height) as u32,
+        );
+        self.index(cell).map(|_| cell)
+    }
+
+
+    /// The center of the tile at x, y.
+    pub fn cell_center(&self, x: u32, y: u32) -> V2 {
+        V2::new(
+            (x as f32 + 0.5) * self.tile_width,
+            (y as f32 + 0.5) * self.tile_height,
+        )
+    }
+
+
+    /// The walkable cells next to the given cell and the cost of stepping into
+    /// each.
+    fn neighbors(&self, (x, y): Cell, movement: GridMovement) -> Vec<(Cell, f32)> {
+        let walkable = |dx: i32, dy: i32| {
+            let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+            nx >= 0 && ny >= 0 && self.is_walkable(nx as u32, ny as u32)
+        };
+        movement
+            .steps()
+            .iter()
+            .filter(|(dx, dy)| walkable(*dx, *dy))
+            // Diagonals can't squeeze between two blocked tiles or cut a corner
+            .filter(|(dx, dy)| *dx == 0 || *dy == 0 || (walkable(*dx, 0) && walkable(0, *dy)))
+            .map(|(dx, dy)| {
+                let next = ((x as i32 + dx) as u32, (y as i32 + dy) as u32);
+                let distance = V2::new(*dx as f32 * self.tile_width, *dy as f32 * self.tile_height)
+                    .magnitude();
+                let cost = self.cost(next.0, next.1).unwrap_or(1.0);
+                (next, distance * cost)
+            })
+            .collect()
+    }
+
+
+    /// An estimate of the cost between two cells that is never too high.
+    fn heuristic(&self, (ax, ay): Cell, (bx, by): Cell, movement: GridMovement) -> f32 {
+        let dx = (ax as f32 - bx as f32).abs() * self.tile_width;
+        let dy = (ay as f32 - by as f32).abs() * self.tile_height;
+        let distance = match movement {
+            GridMovement::FourWay => dx + dy,
+            GridMovement::EightWay => V2::new(dx, dy).magnitude(),
+        };
+        distance * self.min_cost
+    }
+
+
+    /// Find the cheapest path from one point to another. The path is the
+    /// centers of the tiles stepped through after the first, ending at `to`.
+    /// Returns None if either point is on a blocked tile or off the grid, or
+    /// if there is no way through.
+    pub fn find_path(&self, from: V2, to: V2, movement: GridMovement) -> Option<Vec<V2>> {
+        let start = self.cell_at(from)?;
+        let goal = self.cell_at(to)?;
+        if !self.is_walkable(start.0, start.1) || !self.is_walkable(goal.0, goal.1) {
+            return None;
+        }
+
+        let mut open = BinaryHeap::new();
+        let mut costs: HashMap<Cell, f32> = HashMap::new();
+        let mut came_from: HashMap<Cell, Cell> = HashMap::new();
+        costs.insert(start, 0.0);
+        open.push(Open {
+            cost: self.heuristic(start, goal, movement),
+            cell: start,
+        });
+        let mut found = false;
+        while let Some(Open { cell, .. }) = open.pop() {
+            if cell == goal {
+                found = true;
+                break;
+            }
+            let cost = costs[&cell];
+            for (next, step_cost) in self.neighbors(cell, movement).into_iter() {
+                let next_cost = cost + step_cost;
+                if costs.get(&next).map(|c| next_cost < *c).unwrap_or(true) {
+                    costs.insert(next, next_cost);
+                    came_from.insert(next, cell);
+                    open.push(Open {
+                        cost: next_cost + self.heuristic(next, goal, movement),
+                        cell: next,
+                    });
+                }
+            }
+        }
+        if !found {
+            return None;
+        }
+
+        let mut path = vec![to];
+        let mut cell = goal;
+        while let Some(previous) = came_from.get(&cell) {
+            if *previous != start {
+                path.push(self.cell_center(previous.0, previous.1));
+            }
+            cell = *previous;
+        }
+        path.reverse();
+        Some(path)
+    }
+}
+
+
+#[cfg(test)]
+mod tile_grid_tests {
+    use super::*;
+
+    /// A 5x5 grid with a wall down the middle that leaves a gap at the
+    /// bottom.
+    fn walled_grid() -> TileGrid {
+        let mut grid = TileGrid::new(5, 5, 10.0, 10.0);
+        for y in 0..4 {
+            grid.block(2, y);
+        }
+        grid
+    }
+
+    #[test]
+    fn paths_go_around_blocked_tiles() {
+        let grid = walled_grid();
+        let from = V2::new(5.0, 5.0);
+        let to = V2::new(45.0, 5.0);
+
+        let four = grid.find_path(from, to, GridMovement::FourWay).unwrap();
+        assert_eq!(four.len(), 12, "{:?}", four);
+        assert_eq!(four.last(), Some(&to));
+        for pair in four.windows(2) {
+            assert!((pair[0].distance_to(&pair[1]) - 10.0).abs() < 1e-4);
+        }
+
+        let eight = grid.find_path(from, to, GridMovement::EightWay).unwrap();
+        assert!(eight.len() < four.len(), "{:?}", eight);
+        // The wall's bottom corner can't be cut
+        assert!(eight.contains(&V2::new(25.0, 45.0)), "{:?}", eight);
+
+        assert!(grid
+            .find_path(from, V2::new(25.0, 5.0), GridMovement::FourWay)
+            .is_none());
+        assert!(grid
+            .find_path(from, V2::new(55.0, 5.0), GridMovement::FourWay)
+            .is_none());
+        assert_eq!(
+            grid.find_path(from, V2::new(7.0, 2.0), GridMovement::FourWay),
+            Some(vec![V2::new(7.0, 2.0)])
+        );
+    }
+
+    #[test]
+    fn paths_avoid_costly_tiles() {
+        let mut grid = TileGrid::new(3, 3, 10.0, 10.0);
+        let from = V2::new(5.0, 15.0);
+        let to = V2::new(25.0, 15.0);
+        assert_eq!(
+            grid.find_path(from, to, GridMovement::FourWay),
+            Some(vec![V2::new(15.0, 15.0), to])
+        );
+
+        grid.set_cost(1, 1, Some(5.0));
+        let path = grid.find_path(from, to, GridMovement::FourWay).unwrap();
+        assert_eq!(path.len(), 4, "{:?}", path);
+        assert!(!path.contains(&V2::new(15.0, 15.0)));
+
+        // Costs that would break the search are ignored
+        grid.set_cost(1, 1, Some(0.0));
+        grid.set_cost(1, 1, Some(-1.0));
+        assert_eq!(grid.cost(1, 1), Some(5.0));
+    }
+
+    #[test]
+    fn aabbs_block_the_tiles_they_overlap() {
+        let mut grid = TileGrid::new(4, 4, 10.0, 10.0);
+        grid.block_aabb(&AABB::new(5.0, 10.0, 10.0, 5.0));
+        let blocked: Vec<(u32, u32)> = (0..4)
+            .flat_map(|y| (0..4).map(move |x| (x, y)))
+            .filter(|(x, y)| !grid.is_walkable(*x, *y))
+            .collect();
+        assert_eq!(blocked, vec![(0, 1), (1, 1)]);
+    }
+}
@@ -1,4 +1,4 @@
-use spade::PointN;
+use spade::{PointN, TwoDimensional};
 //use sdl2::rect::Point;
 use std::{fmt::Debug, ops::*};
 
@@ -174,6 +174,9 @@ impl PointN for V2 {
 }
 
 
+impl TwoDimensional for V2 {}
+
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct KeyVal<K, V> {
     pub key: K,
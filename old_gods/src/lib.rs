@@ -18,8 +18,13 @@ pub mod geom;
 pub mod image;
 pub mod parser;
 pub mod prelude;
+pub mod procgen;
+pub mod recording;
 pub mod rendering;
 pub mod resources;
+#[cfg(feature = "sdl2")]
+pub mod sdl;
+pub mod software;
 pub mod sound;
 pub mod systems;
 pub mod time;
@@ -1,3 +1,5 @@
+#[cfg(feature = "sdl2")]
+pub use super::sdl::*;
 pub use super::{
     color::*,
     components::{tiled::*, *},
@@ -5,19 +7,28 @@ pub use super::{
     geom::{AABB, *},
     image::*,
     parser::*,
+    recording::*,
     rendering::*,
     resources::*,
+    software::*,
     sound::*,
     systems::{
+        ai::*,
         animation::{Frame, *},
+        behavior::*,
         fence::*,
         gamepad::*,
+        navmesh::*,
+        pathfinding::*,
         physics::*,
         player::*,
+        route::*,
         screen::*,
         sound::*,
+        steering::*,
         tiled::*,
         tween::*,
+        vision::*,
         zone::*,
     },
     time::*,
@@ -0,0 +1,136 @@
+//! Binary space partitioning.
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{Level, Room};
+
+
+#[derive(Debug, Clone)]
+pub struct BspParams {
+    /// Width of the level in tiles.
+    pub width: u32,
+
+    /// Height of the level in tiles.
+    pub height: u32,
+
+    /// Partitions are not split below this width or height, in tiles.
+    pub min_leaf_size: u32,
+
+    /// The minimum width and height of a room, in tiles.
+    pub min_room_size: u32,
+
+    /// The maximum number of times to split the level.
+    pub max_depth: u32,
+}
+
+
+impl Default for BspParams {
+    fn default() -> Self {
+        BspParams {
+            width: 64,
+            height: 48,
+            min_leaf_size: 10,
+            min_room_size: 4,
+            max_depth: 5,
+        }
+    }
+}
+
+
+/// Recursively split the area, place a room in each leaf and connect the
+/// leaves back up the tree. Returns the rooms in the area.
+fn split(
+    rng: &mut ChaCha8Rng,
+    params: &BspParams,
+    level: &mut Level,
+    area: Room,
+    depth: u32,
+) -> Vec<Room> {
+    let min = params.min_leaf_size.max(params.min_room_size + 2);
+    let can_split_x = area.width >= min * 2;
+    let can_split_y = area.height >= min * 2;
+
+    if depth >= params.max_depth || !(can_split_x || can_split_y) {
+        // This is a leaf, place a room inside it with a wall around it
+        if area.width < 3 || area.height < 3 {
+            return vec![];
+        }
+        let min_size = params.min_room_size.max(1);
+        let max_w = area.width - 2;
+        let max_h = area.height - 2;
+        let width = rng.gen_range(min_size.min(max_w), max_w + 1);
+        let height = rng.gen_range(min_size.min(max_h), max_h + 1);
+        let room = Room {
+            x: area.x + rng.gen_range(1, (area.width - width).max(2)),
+            y: area.y + rng.gen_range(1, (area.height - height).max(2)),
+            width,
+            height,
+        };
+        level.carve_room(&room);
+        return vec![room];
+    }
+
+    // Split across the longer side when both are possible
+    let split_x = if can_split_x && can_split_y {
+        if area.width == area.height {
+            rng.gen::<bool>()
+        } else {
+            area.width > area.height
+        }
+    } else {
+        can_split_x
+    };
+    let (a, b) = if split_x {
+        let w = rng.gen_range(min, area.width - min + 1);
+        (
+            Room { width: w, ..area },
+            Room {
+                x: area.x + w,
+                width: area.width - w,
+                ..area
+            },
+        )
+    } else {
+        let h = rng.gen_range(min, area.height - min + 1);
+        (
+            Room { height: h, ..area },
+            Room {
+                y: area.y + h,
+                height: area.height - h,
+                ..area
+            },
+        )
+    };
+
+    let mut rooms = split(rng, params, level, a, depth + 1);
+    let rooms_b = split(rng, params, level, b, depth + 1);
+    // Connect the two halves through a random room on each side
+    if !rooms.is_empty() && !rooms_b.is_empty() {
+        let from = rooms[rng.gen_range(0, rooms.len())].center();
+        let to = rooms_b[rng.gen_range(0, rooms_b.len())].center();
+        level.carve_corridor(rng, from, to);
+    }
+    rooms.extend(rooms_b);
+    rooms
+}
+
+
+/// Partition the level into a tree of areas, place a room in each leaf and
+/// connect sibling areas with corridors.
+///
+/// A spawn point is placed in the center of every room. Areas too small to
+/// fit a room with a wall around it are left as wall, so levels less than
+/// three tiles wide or high have no rooms at all.
+pub fn bsp(seed: u64, params: &BspParams) -> Level {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut level = Level::new(params.width, params.height);
+    let area = Room {
+        x: 0,
+        y: 0,
+        width: params.width,
+        height: params.height,
+    };
+    level.rooms = split(&mut rng, params, &mut level, area, 0);
+    level.spawns = level.rooms.iter().map(|room| room.center()).collect();
+    level
+}
@@ -0,0 +1,105 @@
+//! Cellular automata caves.
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{Cell, Level};
+
+
+#[derive(Debug, Clone)]
+pub struct CaveParams {
+    /// Width of the level in tiles.
+    pub width: u32,
+
+    /// Height of the level in tiles.
+    pub height: u32,
+
+    /// The chance that a cell starts out as a wall.
+    pub wall_chance: f32,
+
+    /// How many times to smooth the noise.
+    pub iterations: u32,
+
+    /// A cell becomes a wall when at least this many of its eight neighbors
+    /// are walls.
+    pub birth_limit: u32,
+
+    /// A wall stays a wall when at least this many of its eight neighbors are
+    /// walls.
+    pub survival_limit: u32,
+
+    /// How many spawn points to scatter through the cave.
+    pub spawns: u32,
+}
+
+
+impl Default for CaveParams {
+    fn default() -> Self {
+        CaveParams {
+            width: 64,
+            height: 48,
+            wall_chance: 0.45,
+            iterations: 5,
+            birth_limit: 5,
+            survival_limit: 4,
+            spawns: 4,
+        }
+    }
+}
+
+
+fn wall_neighbors(level: &Level, x: i32, y: i32) -> u32 {
+    let mut n = 0;
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            if (dx, dy) != (0, 0) && level.get(x + dx, y + dy) == Cell::Wall {
+                n += 1;
+            }
+        }
+    }
+    n
+}
+
+
+/// Fill the level with random noise and smooth it into caves. Only the largest
+/// cave is kept, the rest are filled in.
+///
+/// Spawn points are scattered randomly over the cave's floor.
+pub fn caves(seed: u64, params: &CaveParams) -> Level {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut level = Level::new(params.width, params.height);
+    for y in 1..params.height.saturating_sub(1) {
+        for x in 1..params.width.saturating_sub(1) {
+            if !rng.gen_bool(params.wall_chance.clamp(0.0, 1.0) as f64) {
+                level.set(x, y, Cell::Floor);
+            }
+        }
+    }
+
+    for _ in 0..params.iterations {
+        let mut next = level.clone();
+        for y in 1..params.height.saturating_sub(1) {
+            for x in 1..params.width.saturating_sub(1) {
+                let n = wall_neighbors(&level, x as i32, y as i32);
+                let is_wall = match level.get(x as i32, y as i32) {
+                    Cell::Wall => n >= params.survival_limit,
+                    Cell::Floor => n >= params.birth_limit,
+                };
+                next.set(x, y, if is_wall { Cell::Wall } else { Cell::Floor });
+            }
+        }
+        level = next;
+    }
+
+    let mut regions = level.regions().into_iter();
+    let cave = regions.next().unwrap_or_default();
+    for (x, y) in regions.flatten() {
+        level.set(x, y, Cell::Wall);
+    }
+
+    if !cave.is_empty() {
+        level.spawns = (0..params.spawns)
+            .map(|_| cave[rng.gen_range(0, cave.len())])
+            .collect();
+    }
+    level
+}
@@ -0,0 +1,399 @@
+//! Procedural level generation.
+//!
+//! Each generator takes a seed and some parameters and produces a `Level`, a
+//! grid of wall and floor cells along with any rooms and spawn points. A Level
+//! can then be turned into a `Tiledmap` using an existing tileset and inserted
+//! into the ECS with `insert_map`, just like a map made in Tiled.
+//!
+//! Generators use a seeded `ChaCha8Rng`, so the same seed and parameters always
+//! produce the same level, on every platform.
+use log::trace;
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use std::{
+    cmp::Reverse,
+    collections::{HashSet, VecDeque},
+};
+
+use super::components::tiled::{
+    GlobalTileIndex, LayerBuilder, LocalId, ObjectBuilder, Tiledmap, TiledmapBuilder, Tileset,
+};
+
+mod bsp;
+mod caves;
+mod rooms;
+
+pub use self::{bsp::*, caves::*, rooms::*};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cell {
+    Wall,
+    Floor,
+}
+
+
+/// A rectangular room, in tiles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Room {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+
+impl Room {
+    pub fn center(&self) -> (u32, u32) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+
+    /// Whether this room overlaps the other room, or comes within `margin`
+    /// tiles of it.
+    pub fn intersects(&self, other: &Room, margin: u32) -> bool {
+        self.x < other.x + other.width + margin
+            && other.x < self.x + self.width + margin
+            && self.y < other.y + other.height + margin
+            && other.y < self.y + self.height + margin
+    }
+}
+
+
+/// A generated level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+    /// Width in tiles.
+    pub width: u32,
+
+    /// Height in tiles.
+    pub height: u32,
+
+    /// The level's cells in row-major order.
+    pub cells: Vec<Cell>,
+
+    /// Any rooms in the level. These become zones.
+    pub rooms: Vec<Room>,
+
+    /// Tile coordinates of spawn points. These become "spawn" objects.
+    pub spawns: Vec<(u32, u32)>,
+}
+
+
+impl Level {
+    /// A level filled with walls.
+    pub fn new(width: u32, height: u32) -> Level {
+        Level {
+            width,
+            height,
+            cells: vec![Cell::Wall; (width * height) as usize],
+            rooms: vec![],
+            spawns: vec![],
+        }
+    }
+
+    /// Return the cell at the given tile coordinates. Anything outside the
+    /// level is a wall.
+    pub fn get(&self, x: i32, y: i32) -> Cell {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            Cell::Wall
+        } else {
+            self.cells[(y as u32 * self.width + x as u32) as usize]
+        }
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, cell: Cell) {
+        if x < self.width && y < self.height {
+            self.cells[(y * self.width + x) as usize] = cell;
+        }
+    }
+
+    /// Carve the room out of the level.
+    pub fn carve_room(&mut self, room: &Room) {
+        for y in room.y..room.y + room.height {
+            for x in room.x..room.x + room.width {
+                self.set(x, y, Cell::Floor);
+            }
+        }
+    }
+
+    /// Carve an L shaped corridor between two points, randomly choosing
+    /// whether to go horizontally or vertically first.
+    pub fn carve_corridor(&mut self, rng: &mut ChaCha8Rng, from: (u32, u32), to: (u32, u32)) {
+        let (x0, y0) = from;
+        let (x1, y1) = to;
+        let corner = if rng.gen::<bool>() {
+            (x1, y0)
+        } else {
+            (x0, y1)
+        };
+        for &((ax, ay), (bx, by)) in [((x0, y0), corner), (corner, (x1, y1))].iter() {
+            for x in ax.min(bx)..=ax.max(bx) {
+                for y in ay.min(by)..=ay.max(by) {
+                    self.set(x, y, Cell::Floor);
+                }
+            }
+        }
+    }
+
+    /// Return the 4-connected regions of floor cells, largest first.
+    pub fn regions(&self) -> Vec<Vec<(u32, u32)>> {
+        let mut seen = HashSet::new();
+        let mut regions = vec![];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.get(x as i32, y as i32) != Cell::Floor || seen.contains(&(x, y)) {
+                    continue;
+                }
+                let mut region = vec![];
+                let mut queue = VecDeque::new();
+                seen.insert((x, y));
+                queue.push_back((x, y));
+                while let Some((cx, cy)) = queue.pop_front() {
+                    region.push((cx, cy));
+                    let (ix, iy) = (cx as i32, cy as i32);
+                    for &(nx, ny) in [(ix - 1, iy), (ix + 1, iy), (ix, iy - 1), (ix, iy + 1)].iter()
+                    {
+                        if self.get(nx, ny) == Cell::Floor && seen.insert((nx as u32, ny as u32)) {
+                            queue.push_back((nx as u32, ny as u32));
+                        }
+                    }
+                }
+                regions.push(region);
+            }
+        }
+        regions.sort_by_key(|region| Reverse(region.len()));
+        regions
+    }
+
+    /// Whether the wall at the given coordinates touches a floor cell, in
+    /// which case it needs a barrier.
+    fn is_edge_wall(&self, x: u32, y: u32) -> bool {
+        let (x, y) = (x as i32, y as i32);
+        self.get(x, y) == Cell::Wall
+            && (-1..=1).any(|dy| (-1..=1).any(|dx| self.get(x + dx, y + dy) == Cell::Floor))
+    }
+
+    /// Convert this level into a Tiled map using the given tileset.
+    ///
+    /// The map has a "tiles" tile layer and an "objects" object layer. Walls
+    /// that touch the floor are covered by barriers, merged into horizontal
+    /// runs. Each room becomes a zone and each spawn point becomes a point
+    /// object with type "spawn".
+    pub fn to_tiledmap(&self, tileset: &Tileset, floor: LocalId, wall: LocalId) -> Tiledmap {
+        let (tw, th) = (tileset.tilewidth, tileset.tileheight);
+        let builder = TiledmapBuilder::new(self.width, self.height, tw, th);
+        let firstgid = builder.next_firstgid().0;
+        let (floor_gid, wall_gid) = (firstgid + floor.0, firstgid + wall.0);
+
+        let mut tiles = LayerBuilder::tiles("tiles", self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let gid = match self.get(x as i32, y as i32) {
+                    Cell::Floor => floor_gid,
+                    Cell::Wall => wall_gid,
+                };
+                tiles = tiles.tile(x, y, GlobalTileIndex::from_bits(gid));
+            }
+        }
+
+        let mut objects = LayerBuilder::objects("objects");
+        for y in 0..self.height {
+            let mut x = 0;
+            while x < self.width {
+                if !self.is_edge_wall(x, y) {
+                    x += 1;
+                    continue;
+                }
+                let start = x;
+                while x < self.width && self.is_edge_wall(x, y) {
+                    x += 1;
+                }
+                objects = objects.object(
+                    ObjectBuilder::rect(
+                        (start * tw) as f32,
+                        (y * th) as f32,
+                        ((x - start) * tw) as f32,
+                        th as f32,
+                    )
+                    .type_is("barrier")
+                    .build(),
+                );
+            }
+        }
+        for (i, room) in self.rooms.iter().enumerate() {
+            objects = objects.object(
+                ObjectBuilder::rect(
+                    (room.x * tw) as f32,
+                    (room.y * th) as f32,
+                    (room.width * tw) as f32,
+                    (room.height * th) as f32,
+                )
+                .name(&format!("room {}", i))
+                .type_is("zone")
+                .build(),
+            );
+        }
+        for (i, (x, y)) in self.spawns.iter().enumerate() {
+            objects = objects.object(
+                ObjectBuilder::point((x * tw + tw / 2) as f32, (y * th + th / 2) as f32)
+                    .name(&format!("spawn {}", i))
+                    .type_is("spawn")
+                    .build(),
+            );
+        }
+
+        trace!(
+            "generated a {}x{} level with {} rooms",
+            self.width,
+            self.height,
+            self.rooms.len()
+        );
+        builder
+            .tileset(tileset.clone())
+            .layer(tiles.build())
+            .layer(objects.build())
+            .build()
+    }
+}
+
+
+#[cfg(test)]
+mod procgen_tests {
+    use super::{
+        super::{
+            components::tiled::TilesetBuilder,
+            systems::{
+                physics::Barrier,
+                tiled::{insert_map, InsertMapData},
+            },
+        },
+        *,
+    };
+    use specs::{Join, ReadStorage, SystemData, World, WorldExt};
+
+    fn check(generate: impl Fn(u64) -> Level) {
+        let level = generate(666);
+        assert_eq!(level, generate(666), "level is not reproducible");
+        assert_ne!(level.cells, generate(667).cells, "seed has no effect");
+        assert_eq!(level.regions().len(), 1, "level is not connected");
+        assert!(!level.spawns.is_empty(), "level has no spawns");
+
+        let tileset = TilesetBuilder::new("dungeon", "dungeon.png", 32, 16, 16, 16).build();
+        let map = level.to_tiledmap(&tileset, LocalId(0), LocalId(1));
+        let objects = map.layers[1].objects();
+        assert!(objects.iter().any(|obj| obj.type_is == "barrier"));
+        assert_eq!(
+            objects.iter().filter(|obj| obj.type_is == "spawn").count(),
+            level.spawns.len()
+        );
+
+        let mut world = World::new();
+        InsertMapData::setup(&mut world);
+        insert_map(&map, &mut world.system_data());
+        let barriers = world.system_data::<ReadStorage<Barrier>>().join().count();
+        assert_eq!(
+            barriers,
+            objects
+                .iter()
+                .filter(|obj| obj.type_is == "barrier")
+                .count()
+        );
+    }
+
+    /// An FNV-1a hash of the level's cells, stable across platforms.
+    fn cells_hash(level: &Level) -> u64 {
+        level
+            .cells
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, cell| {
+                let bit = match cell {
+                    Cell::Wall => 0,
+                    Cell::Floor => 1,
+                };
+                (hash ^ bit).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    #[test]
+    fn levels_are_pinned_to_their_seed() {
+        let hashes = [
+            cells_hash(&rooms_and_corridors(666, &RoomsParams::default())),
+            cells_hash(&caves(666, &CaveParams::default())),
+            cells_hash(&bsp(666, &BspParams::default())),
+        ];
+        // If these change then existing seeds produce different levels
+        assert_eq!(
+            hashes,
+            [
+                10792628607886690604,
+                11209741146555246224,
+                4194228584861959993
+            ]
+        );
+    }
+
+    #[test]
+    fn rooms_and_corridors_are_reproducible() {
+        check(|seed| rooms_and_corridors(seed, &RoomsParams::default()));
+    }
+
+    #[test]
+    fn caves_are_reproducible() {
+        check(|seed| caves(seed, &CaveParams::default()));
+    }
+
+    #[test]
+    fn bsp_is_reproducible() {
+        check(|seed| bsp(seed, &BspParams::default()));
+    }
+
+    #[test]
+    fn bsp_leaves_tiny_levels_as_wall() {
+        for size in 0..3 {
+            let params = BspParams {
+                width: size,
+                height: size,
+                min_leaf_size: 0,
+                min_room_size: 0,
+                ..BspParams::default()
+            };
+            let level = bsp(666, &params);
+            assert_eq!(level.cells.len(), (size * size) as usize);
+            assert!(level.cells.iter().all(|cell| *cell == Cell::Wall));
+            assert!(level.rooms.is_empty());
+            assert!(level.spawns.is_empty());
+        }
+
+        // Leaves too small for a room are skipped when connecting the rest
+        let level = bsp(
+            666,
+            &BspParams {
+                width: 16,
+                height: 16,
+                min_leaf_size: 0,
+                min_room_size: 0,
+                max_depth: 8,
+            },
+        );
+        assert!(!level.rooms.is_empty());
+        assert_eq!(level.regions().len(), 1);
+
+        let level = bsp(
+            666,
+            &BspParams {
+                width: 3,
+                height: 3,
+                ..BspParams::default()
+            },
+        );
+        assert_eq!(
+            level.rooms,
+            vec![Room {
+                x: 1,
+                y: 1,
+                width: 1,
+                height: 1
+            }]
+        );
+    }
+}
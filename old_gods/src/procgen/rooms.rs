@@ -0,0 +1,83 @@
+//! Rooms connected by corridors.
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{Level, Room};
+
+
+#[derive(Debug, Clone)]
+pub struct RoomsParams {
+    /// Width of the level in tiles.
+    pub width: u32,
+
+    /// Height of the level in tiles.
+    pub height: u32,
+
+    /// The maximum number of rooms to place.
+    pub max_rooms: u32,
+
+    /// The minimum width and height of a room, in tiles.
+    pub min_room_size: u32,
+
+    /// The maximum width and height of a room, in tiles.
+    pub max_room_size: u32,
+
+    /// How many times to try placing a room before giving up.
+    pub attempts: u32,
+}
+
+
+impl Default for RoomsParams {
+    fn default() -> Self {
+        RoomsParams {
+            width: 64,
+            height: 48,
+            max_rooms: 12,
+            min_room_size: 4,
+            max_room_size: 10,
+            attempts: 200,
+        }
+    }
+}
+
+
+/// Scatter non-overlapping rooms over the level and connect each room to the
+/// one placed before it with an L shaped corridor.
+///
+/// A spawn point is placed in the center of every room.
+pub fn rooms_and_corridors(seed: u64, params: &RoomsParams) -> Level {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut level = Level::new(params.width, params.height);
+    let min_size = params.min_room_size.max(1);
+    let max_size = params.max_room_size.max(min_size);
+
+    for _ in 0..params.attempts {
+        if level.rooms.len() as u32 >= params.max_rooms {
+            break;
+        }
+        let width = rng.gen_range(min_size, max_size + 1);
+        let height = rng.gen_range(min_size, max_size + 1);
+        // Leave a border of wall around the level
+        if width + 2 > params.width || height + 2 > params.height {
+            continue;
+        }
+        let room = Room {
+            x: rng.gen_range(1, params.width - width),
+            y: rng.gen_range(1, params.height - height),
+            width,
+            height,
+        };
+        if level.rooms.iter().any(|other| room.intersects(other, 1)) {
+            continue;
+        }
+        level.carve_room(&room);
+        if let Some(prev) = level.rooms.last() {
+            let from = prev.center();
+            level.carve_corridor(&mut rng, from, room.center());
+        }
+        level.rooms.push(room);
+    }
+
+    level.spawns = level.rooms.iter().map(|room| room.center()).collect();
+    level
+}
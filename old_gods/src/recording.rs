@@ -0,0 +1,414 @@
+//! A rendering context that records draw calls instead of drawing.
+//!
+//! `RecordingContext` keeps every call made to it as a `DrawCommand`, which
+//! makes it possible to test `render_map`, `render_ui` and the debug overlays
+//! without a browser. Images are never loaded, they are only named by their
+//! paths, and text is measured as if each character were half as wide as the
+//! font is tall, so recordings are the same on every machine.
+//!
+//! A frame's commands can be checked against a snapshot file with
+//! `assert_snapshot`. Set `UPDATE_SNAPSHOTS` in the environment to write the
+//! snapshots out again after changing the way things are drawn.
+use std::{env, fs};
+
+use super::prelude::{
+    Color, DefaultRenderingContext, Engine, FontDetails, HasRenderingContext, LoadStatus,
+    LoadableResources, RenderingContext, Resources, SharedResource, AABB, V2,
+};
+
+
+/// The environment variable that makes `assert_snapshot` write snapshots
+/// instead of checking them.
+pub const UPDATE_SNAPSHOTS_VAR: &str = "UPDATE_SNAPSHOTS";
+
+
+/// One call made to a `RecordingContext`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum DrawCommand {
+    Clear,
+    SetContextSize {
+        width: u32,
+        height: u32,
+    },
+    SetFillColor {
+        color: Color,
+    },
+    SetGlobalAlpha {
+        alpha: f64,
+    },
+    FillRect {
+        aabb: AABB,
+    },
+    SetFont {
+        font: FontDetails,
+    },
+    FillText {
+        text: String,
+        point: V2,
+    },
+    SetStrokeColor {
+        color: Color,
+    },
+    StrokeLines {
+        lines: Vec<V2>,
+    },
+    StrokeRect {
+        aabb: AABB,
+    },
+    DrawImage {
+        image: String,
+        src: AABB,
+        destination: AABB,
+    },
+    /// Another recording drawn into this one.
+    DrawContext {
+        commands: Vec<DrawCommand>,
+        destination: AABB,
+    },
+}
+
+
+/// An image that is only known by its path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedImage(pub String);
+
+
+/// Records draw calls.
+pub struct RecordingContext {
+    pub commands: Vec<DrawCommand>,
+    size: (u32, u32),
+    alpha: f64,
+}
+
+
+impl RecordingContext {
+    pub fn new(width: u32, height: u32) -> RecordingContext {
+        RecordingContext {
+            commands: vec![],
+            size: (width, height),
+            alpha: 1.0,
+        }
+    }
+
+    /// Return the commands recorded so far and start a new recording.
+    pub fn take_commands(&mut self) -> Vec<DrawCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
+    fn record(&mut self, command: DrawCommand) {
+        self.commands.push(command);
+    }
+}
+
+
+impl RenderingContext for RecordingContext {
+    type Image = RecordedImage;
+    type Font = FontDetails;
+
+    fn context_size(&mut self) -> Result<(u32, u32), String> {
+        Ok(self.size)
+    }
+
+    fn set_context_size(&mut self, (width, height): (u32, u32)) -> Result<(), String> {
+        self.size = (width, height);
+        self.record(DrawCommand::SetContextSize { width, height });
+        Ok(())
+    }
+
+    /// Clearing throws away everything recorded before, the same way it
+    /// throws away everything drawn before.
+    fn clear(&mut self) -> Result<(), String> {
+        self.commands = vec![DrawCommand::Clear];
+        Ok(())
+    }
+
+    fn set_fill_color(&mut self, color: &Color) {
+        self.record(DrawCommand::SetFillColor { color: *color });
+    }
+
+    fn global_alpha(&mut self) -> f64 {
+        self.alpha
+    }
+
+    fn set_global_alpha(&mut self, alpha: f64) {
+        self.alpha = alpha;
+        self.record(DrawCommand::SetGlobalAlpha { alpha });
+    }
+
+    fn fill_rect(&mut self, aabb: &AABB) {
+        self.record(DrawCommand::FillRect { aabb: *aabb });
+    }
+
+    fn set_font(&mut self, font: &Self::Font) {
+        self.record(DrawCommand::SetFont { font: font.clone() });
+    }
+
+    fn fill_text(&mut self, text: &str, point: &V2) -> Result<(), String> {
+        self.record(DrawCommand::FillText {
+            text: text.to_string(),
+            point: *point,
+        });
+        Ok(())
+    }
+
+    fn size_of_text(&mut self, font: &Self::Font, text: &str) -> Result<(f32, f32), String> {
+        let size = font.size as f32;
+        let width = text
+            .lines()
+            .map(|line| line.chars().count() as f32 * size / 2.0)
+            .fold(0.0, f32::max);
+        Ok((width, size * text.lines().count() as f32))
+    }
+
+    fn set_stroke_color(&mut self, color: &Color) {
+        self.record(DrawCommand::SetStrokeColor { color: *color });
+    }
+
+    fn stroke_lines(&mut self, lines: &[V2]) {
+        self.record(DrawCommand::StrokeLines {
+            lines: lines.to_vec(),
+        });
+    }
+
+    fn stroke_rect(&mut self, aabb: &AABB) {
+        self.record(DrawCommand::StrokeRect { aabb: *aabb });
+    }
+
+    fn draw_image(
+        &mut self,
+        img: &Self::Image,
+        src: &AABB,
+        destination: &AABB,
+    ) -> Result<(), String> {
+        self.record(DrawCommand::DrawImage {
+            image: img.0.clone(),
+            src: *src,
+            destination: *destination,
+        });
+        Ok(())
+    }
+
+    fn draw_context(&mut self, context: &Self, destination: &AABB) -> Result<(), String> {
+        self.record(DrawCommand::DrawContext {
+            commands: context.commands.clone(),
+            destination: *destination,
+        });
+        Ok(())
+    }
+
+    fn font_details_to_font(&mut self, font_details: &FontDetails) -> Self::Font {
+        font_details.clone()
+    }
+}
+
+
+impl HasRenderingContext for DefaultRenderingContext<RecordingContext> {
+    type Ctx = RecordingContext;
+
+    fn get_rendering_context(&mut self) -> &mut RecordingContext {
+        &mut self.context
+    }
+}
+
+
+/// Has every image it is asked for, without reading anything.
+#[derive(Default)]
+pub struct RecordingImageResources(pub LoadableResources<RecordedImage>);
+
+
+impl Resources<RecordedImage> for RecordingImageResources {
+    fn status_of(&self, s: &str) -> LoadStatus {
+        self.0.status_of(s)
+    }
+
+    fn load(&mut self, path: &str) {
+        let rsrc = SharedResource::default();
+        rsrc.set_status_and_resource((LoadStatus::Complete, Some(RecordedImage(path.to_string()))));
+        self.0.resources.insert(path.to_string(), rsrc);
+    }
+
+    fn take(&mut self, s: &str) -> Option<SharedResource<RecordedImage>> {
+        self.0.take(s)
+    }
+
+    fn put(&mut self, path: &str, shared: SharedResource<RecordedImage>) {
+        self.0.put(path, shared)
+    }
+}
+
+
+impl<'a, 'b> Engine<'a, 'b, DefaultRenderingContext<RecordingContext>, RecordingImageResources> {
+    /// Render a frame and return the commands it was drawn with.
+    pub fn render_commands(&mut self) -> Result<Vec<DrawCommand>, String> {
+        self.rendering_context.context.take_commands();
+        self.render()?;
+        Ok(self.rendering_context.context.take_commands())
+    }
+}
+
+
+/// Write commands out as a snapshot, one JSON field to a line so that
+/// snapshots diff well.
+pub fn snapshot(commands: &[DrawCommand]) -> Result<String, String> {
+    serde_json::to_string_pretty(commands)
+        .map(|json| json + "\n")
+        .map_err(|e| format!("can't write snapshot: {}", e))
+}
+
+
+/// Read commands back from a snapshot.
+pub fn commands_from_snapshot(snapshot: &str) -> Result<Vec<DrawCommand>, String> {
+    serde_json::from_str(snapshot).map_err(|e| format!("can't read snapshot: {}", e))
+}
+
+
+/// How much unchanged text to show around a difference.
+const SNAPSHOT_DIFF_CONTEXT: usize = 3;
+
+
+/// A description of where two snapshots differ, if they do. Lines only in
+/// the expected snapshot start with "-" and lines only in the actual one
+/// start with "+".
+pub fn diff_snapshots(expected: &str, actual: &str) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let prefix = expected
+        .iter()
+        .zip(actual.iter())
+        .take_while(|(e, a)| e == a)
+        .count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(e, a)| e == a)
+        .count();
+    let start = prefix.saturating_sub(SNAPSHOT_DIFF_CONTEXT);
+    let mut diff = vec![format!("@@ line {} @@", prefix + 1)];
+    diff.extend(expected[start..prefix].iter().map(|l| format!(" {}", l)));
+    diff.extend(
+        expected[prefix..expected.len() - suffix]
+            .iter()
+            .map(|l| format!("-{}", l)),
+    );
+    diff.extend(
+        actual[prefix..actual.len() - suffix]
+            .iter()
+            .map(|l| format!("+{}", l)),
+    );
+    let end = (actual.len() - suffix + SNAPSHOT_DIFF_CONTEXT).min(actual.len());
+    diff.extend(
+        actual[actual.len() - suffix..end]
+            .iter()
+            .map(|l| format!(" {}", l)),
+    );
+    Some(diff.join("\n"))
+}
+
+
+/// Check commands against the snapshot file at `path`, writing the file if
+/// it doesn't exist yet or `UPDATE_SNAPSHOTS` is set.
+///
+/// ## Panics
+/// When the commands don't match the snapshot.
+pub fn assert_snapshot(path: &str, commands: &[DrawCommand]) {
+    let actual = snapshot(commands).unwrap();
+    let expected = match fs::read_to_string(path) {
+        Ok(expected) if env::var_os(UPDATE_SNAPSHOTS_VAR).is_none() => expected,
+        _ => {
+            fs::write(path, &actual)
+                .unwrap_or_else(|e| panic!("can't write snapshot '{}': {}", path, e));
+            return;
+        }
+    };
+    if let Some(diff) = diff_snapshots(&expected, &actual) {
+        panic!(
+            "rendering doesn't match snapshot '{}', set {} to update it:\n{}",
+            path, UPDATE_SNAPSHOTS_VAR, diff
+        );
+    }
+}
+
+
+#[cfg(test)]
+mod recording_tests {
+    use super::{
+        super::{
+            components::tiled::AABB as TiledAABB,
+            prelude::{
+                BackgroundColor, Builder, Position, Rendering, RenderingToggles, Text,
+                TextureFrame, WorldExt,
+            },
+        },
+        *,
+    };
+    use std::collections::HashSet;
+
+    #[test]
+    fn diffs_show_what_changed() {
+        assert_eq!(diff_snapshots("a\nb\nc", "a\nb\nc"), None);
+        assert_eq!(
+            diff_snapshots("a\nb\nc\nd", "a\nB\nc\nd").unwrap(),
+            "@@ line 2 @@\n a\n-b\n+B\n c\n d"
+        );
+    }
+
+    #[test]
+    fn frames_match_their_snapshot() {
+        let mut engine: Engine<DefaultRenderingContext<RecordingContext>, RecordingImageResources> =
+            Engine::new("", || DefaultRenderingContext {
+                context: RecordingContext::new(64, 48),
+            });
+        engine.set_map_viewport_size(32, 24);
+        engine.world.insert(BackgroundColor(Color::rgb(10, 20, 30)));
+        engine
+            .world
+            .create_entity()
+            .with(Position(V2::new(4.0, 4.0)))
+            .with(Rendering::from_frame(TextureFrame {
+                sprite_sheet: "images/uf_heroes.png".to_string(),
+                source_aabb: TiledAABB {
+                    x: 16,
+                    y: 0,
+                    w: 16,
+                    h: 16,
+                },
+                size: (16, 16),
+                is_flipped_horizontally: false,
+                is_flipped_vertically: false,
+                is_flipped_diagonally: false,
+            }))
+            .build();
+        engine
+            .world
+            .create_entity()
+            .with(Position(V2::new(20.0, 12.0)))
+            .with(Rendering::from_text(Text {
+                text: "hi".to_string(),
+                font: FontDetails {
+                    path: "fonts/PressStart2P-Regular.ttf".to_string(),
+                    size: 8,
+                },
+                color: Color::rgb(255, 255, 255),
+                size: (16, 8),
+            }))
+            .build();
+        engine.set_debug_mode(true);
+        engine.world.insert(
+            vec![RenderingToggles::Positions]
+                .into_iter()
+                .collect::<HashSet<_>>(),
+        );
+
+        // The first frame starts loading the sprite sheet
+        engine.render_commands().unwrap();
+        let commands = engine.render_commands().unwrap();
+        let json = snapshot(&commands).unwrap();
+        assert_eq!(commands_from_snapshot(&json), Ok(commands.clone()));
+        assert_snapshot("test_data/snapshots/sprite_and_text.json", &commands);
+    }
+}
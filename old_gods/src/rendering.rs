@@ -9,6 +9,8 @@ pub struct MapRenderingData<'s> {
     pub screen: Read<'s, Screen>,
     pub entities: Entities<'s>,
     pub positions: ReadStorage<'s, Position>,
+    pub previous_positions: ReadStorage<'s, PreviousPosition>,
+    pub fixed_timestep: Read<'s, FixedTimestep>,
     pub offsets: ReadStorage<'s, OriginOffset>,
     pub renderings: ReadStorage<'s, Rendering>,
     pub z_levels: ReadStorage<'s, ZLevel>,
@@ -45,6 +47,7 @@ pub struct DebugRenderingData<'s> {
     pub fences: ReadStorage<'s, Fence>,
     pub shapes: ReadStorage<'s, Shape>,
     pub step_fences: ReadStorage<'s, StepFence>,
+    pub visions: ReadStorage<'s, Vision>,
     pub z_levels: ReadStorage<'s, ZLevel>,
 }
 
@@ -505,7 +508,10 @@ where
             } else {
                 Color::rgba(255, 255, 0, alpha)
             };
-            let aabb = AABB::from_mbr(&mbr);
+            let aabb = data
+                .aabb_tree
+                .aabb(entity)
+                .unwrap_or_else(|| AABB::from_mbr(mbr));
             let aabb = AABB::from_points(
                 from_viewport(data.screen.from_map(&aabb.top_left)),
                 from_viewport(data.screen.from_map(&aabb.upper())),
@@ -555,6 +561,38 @@ where
     }
 
 
+    fn draw_vision(
+        &mut self,
+        data: &DebugRenderingData,
+        map_ent: &MapEntity,
+        viewport_to_context: impl Fn(V2) -> V2,
+    ) {
+        let vision = if let Some(vision) = data.visions.get(map_ent.entity) {
+            vision
+        } else {
+            return;
+        };
+        if vision.polygon.is_empty() {
+            return;
+        }
+        // Red when something is seen, yellow otherwise
+        let color = if vision.seen.is_empty() {
+            Color::rgba(255, 255, 0, 128)
+        } else {
+            Color::rgba(255, 0, 0, 192)
+        };
+        self.get_rendering_context().set_stroke_color(&color);
+        let eye = map_ent.position + map_ent.offset;
+        let mut lines: Vec<V2> = vision
+            .polygon
+            .iter()
+            .map(|v| viewport_to_context(eye + *v))
+            .collect();
+        lines.push(lines[0]);
+        self.get_rendering_context().stroke_lines(&lines);
+    }
+
+
     fn draw_fence(
         &mut self,
         data: &DebugRenderingData,
@@ -906,6 +944,10 @@ where
             self.draw_zone(data, map_ent, &viewport_to_context)?;
         }
 
+        if toggles.contains(&RenderingToggles::Vision) {
+            self.draw_vision(data, map_ent, &viewport_to_context);
+        }
+
         Ok(())
     }
 
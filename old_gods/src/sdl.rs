@@ -0,0 +1,544 @@
+//! The SDL2 backend, built with the "sdl2" feature.
+//!
+//! `Sdl2Backend` opens a window and owns the canvas everything is drawn on.
+//! Each `Sdl2Context` it makes draws into its own target texture, so the map
+//! and the window can be rendered separately and composited like they are on
+//! the web. Call `Engine::render_and_present` to draw a frame and show it.
+//!
+//! Images and fonts are loaded from disk, with image paths as file names and
+//! font paths as TrueType file names. Each context opens a font the first
+//! time it draws or measures text with it and keeps it open after that.
+//! Input comes from `Sdl2GamepadSystem` and sound from `Sdl2SoundSystem`, both
+//! of which should be added to the engine's dispatchers.
+//!
+//! For CI, `Sdl2Backend::new_headless` uses SDL's dummy video and audio
+//! drivers and its software renderer, so no display or sound card is needed.
+use log::warn;
+use sdl2::{
+    image::{InitFlag, LoadSurface, Sdl2ImageContext},
+    pixels::{Color as SdlColor, PixelFormatEnum},
+    rect::{Point, Rect},
+    render::{BlendMode, Texture, TextureCreator, WindowCanvas},
+    surface::Surface,
+    ttf::{Font, Sdl2TtfContext},
+    video::WindowContext,
+    EventPump, Sdl,
+};
+use std::{cell::RefCell, collections::HashMap, env, rc::Rc, sync::Mutex};
+
+use super::prelude::{
+    Color, DefaultRenderingContext, Engine, FontDetails, HasRenderingContext, LoadStatus,
+    LoadableResources, RenderingContext, Resources, SharedResource, AABB, V2,
+};
+
+
+/// An SDL window and the canvas that draws on it.
+pub struct Sdl2Backend {
+    pub sdl: Sdl,
+    pub canvas: Rc<RefCell<WindowCanvas>>,
+    pub texture_creator: Rc<TextureCreator<WindowContext>>,
+    ttf: &'static Sdl2TtfContext,
+    _image: Sdl2ImageContext,
+}
+
+
+/// The ttf context shared by every backend.
+///
+/// It is leaked so that opened fonts can be kept for the life of the process,
+/// and since TTF can only be initialized once it is made on first use.
+fn ttf_context() -> Result<&'static Sdl2TtfContext, String> {
+    static TTF: Mutex<Option<&'static Sdl2TtfContext>> = Mutex::new(None);
+    let mut ttf = TTF.lock().map_err(|e| e.to_string())?;
+    if let Some(ttf) = *ttf {
+        return Ok(ttf);
+    }
+    let context: &'static Sdl2TtfContext =
+        Box::leak(Box::new(sdl2::ttf::init().map_err(|e| e.to_string())?));
+    *ttf = Some(context);
+    Ok(context)
+}
+
+
+impl Sdl2Backend {
+    /// Open a window with a hardware accelerated canvas.
+    pub fn new(title: &str, width: u32, height: u32) -> Result<Sdl2Backend, String> {
+        Sdl2Backend::build(title, width, height, false)
+    }
+
+    /// Open a window on SDL's dummy video driver with a software canvas, and
+    /// use the dummy audio driver.
+    pub fn new_headless(width: u32, height: u32) -> Result<Sdl2Backend, String> {
+        env::set_var("SDL_VIDEODRIVER", "dummy");
+        env::set_var("SDL_AUDIODRIVER", "dummy");
+        Sdl2Backend::build("old_gods", width, height, true)
+    }
+
+    fn build(title: &str, width: u32, height: u32, software: bool) -> Result<Sdl2Backend, String> {
+        let sdl = sdl2::init()?;
+        let video = sdl.video()?;
+        sdl.audio()?;
+        let window = video
+            .window(title, width, height)
+            .position_centered()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let builder = window.into_canvas();
+        let builder = if software {
+            builder.software()
+        } else {
+            builder.accelerated()
+        };
+        let mut canvas = builder.build().map_err(|e| e.to_string())?;
+        canvas.set_blend_mode(BlendMode::Blend);
+        let texture_creator = canvas.texture_creator();
+        let image = sdl2::image::init(InitFlag::PNG)?;
+        let ttf = ttf_context()?;
+        Ok(Sdl2Backend {
+            sdl,
+            canvas: Rc::new(RefCell::new(canvas)),
+            texture_creator: Rc::new(texture_creator),
+            ttf,
+            _image: image,
+        })
+    }
+
+    /// A new context the size of the window.
+    pub fn new_context(&self) -> Result<Sdl2Context, String> {
+        let size = self.canvas.borrow().output_size()?;
+        let mut context = Sdl2Context {
+            canvas: self.canvas.clone(),
+            texture_creator: self.texture_creator.clone(),
+            ttf: self.ttf,
+            target: None,
+            size,
+            fill_color: Color::rgb(0, 0, 0),
+            stroke_color: Color::rgb(0, 0, 0),
+            alpha: 1.0,
+            font: None,
+            fonts: HashMap::new(),
+            textures: HashMap::new(),
+        };
+        context.create_target(size)?;
+        Ok(context)
+    }
+
+    pub fn event_pump(&self) -> Result<EventPump, String> {
+        self.sdl.event_pump()
+    }
+}
+
+
+/// An image loaded from disk, turned into a texture the first time a context
+/// draws it.
+#[derive(Clone)]
+pub struct Sdl2Image {
+    pub path: String,
+    pub surface: Rc<Surface<'static>>,
+}
+
+
+/// Loads images from disk, using their keys as file paths.
+#[derive(Default)]
+pub struct Sdl2ImageResources(pub LoadableResources<Sdl2Image>);
+
+
+impl Resources<Sdl2Image> for Sdl2ImageResources {
+    fn status_of(&self, s: &str) -> LoadStatus {
+        self.0.status_of(s)
+    }
+
+    fn load(&mut self, path: &str) {
+        let rsrc = SharedResource::default();
+        match Surface::from_file(path) {
+            Ok(surface) => rsrc.set_status_and_resource((
+                LoadStatus::Complete,
+                Some(Sdl2Image {
+                    path: path.to_string(),
+                    surface: Rc::new(surface),
+                }),
+            )),
+            Err(msg) => rsrc.set_status_and_resource((
+                LoadStatus::Error(format!("can't read '{}': {}", path, msg)),
+                None,
+            )),
+        }
+        self.0.resources.insert(path.to_string(), rsrc);
+    }
+
+    fn take(&mut self, s: &str) -> Option<SharedResource<Sdl2Image>> {
+        self.0.take(s)
+    }
+
+    fn put(&mut self, path: &str, shared: SharedResource<Sdl2Image>) {
+        self.0.put(path, shared)
+    }
+}
+
+
+fn to_rect(aabb: &AABB) -> Rect {
+    let (x0, x1) = (aabb.top_left.x, aabb.top_left.x + aabb.extents.x);
+    let (y0, y1) = (aabb.top_left.y, aabb.top_left.y + aabb.extents.y);
+    Rect::new(
+        x0.min(x1).round() as i32,
+        y0.min(y1).round() as i32,
+        (x1 - x0).abs().round() as u32,
+        (y1 - y0).abs().round() as u32,
+    )
+}
+
+
+fn to_point(v: &V2) -> Point {
+    Point::new(v.x.round() as i32, v.y.round() as i32)
+}
+
+
+/// Draws into a target texture on the backend's canvas.
+pub struct Sdl2Context {
+    canvas: Rc<RefCell<WindowCanvas>>,
+    texture_creator: Rc<TextureCreator<WindowContext>>,
+    ttf: &'static Sdl2TtfContext,
+    target: Option<Texture>,
+    size: (u32, u32),
+    fill_color: Color,
+    stroke_color: Color,
+    alpha: f64,
+    font: Option<FontDetails>,
+    fonts: HashMap<FontDetails, Option<Font<'static, 'static>>>,
+    textures: HashMap<String, Texture>,
+}
+
+
+impl Sdl2Context {
+    fn create_target(&mut self, (w, h): (u32, u32)) -> Result<(), String> {
+        let mut target = self
+            .texture_creator
+            .create_texture_target(PixelFormatEnum::RGBA8888, w.max(1), h.max(1))
+            .map_err(|e| e.to_string())?;
+        target.set_blend_mode(BlendMode::Blend);
+        if let Some(old) = self.target.replace(target) {
+            // Textures are only freed by hand with "unsafe_textures"
+            unsafe { old.destroy() };
+        }
+        self.size = (w, h);
+        Ok(())
+    }
+
+    fn sdl_color(&self, color: &Color) -> SdlColor {
+        let a = (color.a as f64 * self.alpha).round() as u8;
+        SdlColor::RGBA(color.r, color.g, color.b, a)
+    }
+
+    /// Draw on the context's target texture.
+    fn draw<F>(&mut self, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut WindowCanvas, &mut HashMap<String, Texture>) -> Result<(), String>,
+    {
+        let target = self.target.as_mut().ok_or("context has no target")?;
+        let textures = &mut self.textures;
+        let mut result = Ok(());
+        self.canvas
+            .borrow_mut()
+            .with_texture_canvas(target, |canvas| result = f(canvas, textures))
+            .map_err(|e| e.to_string())?;
+        result
+    }
+
+    /// The opened font, or `None` if it can't be loaded.
+    ///
+    /// Fonts that can't be loaded are only warned about once.
+    fn font(&mut self, details: &FontDetails) -> Option<&Font<'static, 'static>> {
+        let ttf = self.ttf;
+        self.fonts
+            .entry(details.clone())
+            .or_insert_with(|| match ttf.load_font(&details.path, details.size) {
+                Ok(font) => Some(font),
+                Err(msg) => {
+                    warn!("can't load font '{}': {}", details.path, msg);
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    /// Copy the context to the window and show it.
+    pub fn present(&mut self) -> Result<(), String> {
+        let target = self.target.as_ref().ok_or("context has no target")?;
+        let mut canvas = self.canvas.borrow_mut();
+        canvas.set_draw_color(SdlColor::RGB(0, 0, 0));
+        canvas.clear();
+        canvas.copy(target, None, None)?;
+        canvas.present();
+        Ok(())
+    }
+
+    /// Read the context's pixels, four RGBA bytes to a pixel, row by row.
+    pub fn read_pixels(&mut self) -> Result<Vec<u8>, String> {
+        let mut pixels = vec![];
+        self.draw(|canvas, _| {
+            pixels = canvas.read_pixels(None, PixelFormatEnum::RGBA32)?;
+            Ok(())
+        })?;
+        Ok(pixels)
+    }
+}
+
+
+impl Drop for Sdl2Context {
+    fn drop(&mut self) {
+        // Textures are only freed by hand with "unsafe_textures"
+        for (_, texture) in self.textures.drain() {
+            unsafe { texture.destroy() };
+        }
+        if let Some(target) = self.target.take() {
+            unsafe { target.destroy() };
+        }
+    }
+}
+
+
+impl RenderingContext for Sdl2Context {
+    type Image = Sdl2Image;
+    type Font = FontDetails;
+
+    fn context_size(&mut self) -> Result<(u32, u32), String> {
+        Ok(self.size)
+    }
+
+    fn set_context_size(&mut self, size: (u32, u32)) -> Result<(), String> {
+        if size != self.size {
+            self.create_target(size)?;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), String> {
+        self.draw(|canvas, _| {
+            canvas.set_draw_color(SdlColor::RGBA(0, 0, 0, 0));
+            canvas.clear();
+            Ok(())
+        })
+    }
+
+    fn set_fill_color(&mut self, color: &Color) {
+        self.fill_color = *color;
+    }
+
+    fn global_alpha(&mut self) -> f64 {
+        self.alpha
+    }
+
+    fn set_global_alpha(&mut self, alpha: f64) {
+        self.alpha = alpha;
+    }
+
+    fn fill_rect(&mut self, aabb: &AABB) {
+        let color = self.sdl_color(&self.fill_color);
+        let result = self.draw(|canvas, _| {
+            canvas.set_draw_color(color);
+            canvas.fill_rect(to_rect(aabb))
+        });
+        if let Err(msg) = result {
+            warn!("can't fill rect: {}", msg);
+        }
+    }
+
+    fn set_font(&mut self, font: &Self::Font) {
+        self.font = Some(font.clone());
+    }
+
+    /// Fills text with its baseline at `point`, like a canvas does.
+    fn fill_text(&mut self, text: &str, point: &V2) -> Result<(), String> {
+        let details = self.font.clone().ok_or("no font has been set")?;
+        let color = self.sdl_color(&self.fill_color);
+        let font = match self.font(&details) {
+            Some(font) => font,
+            None => return Ok(()),
+        };
+        let surfaces = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(ndx, line)| {
+                let surface = font
+                    .render(line)
+                    .blended(color)
+                    .map_err(|e| e.to_string())?;
+                Ok((ndx, font.ascent(), surface))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let texture_creator = self.texture_creator.clone();
+        self.draw(|canvas, _| {
+            for (ndx, ascent, surface) in surfaces {
+                let texture = texture_creator
+                    .create_texture_from_surface(&surface)
+                    .map_err(|e| e.to_string())?;
+                let top = point.y.round() as i32 - ascent + (ndx as i32 * details.size as i32);
+                let dest = Rect::new(
+                    point.x.round() as i32,
+                    top,
+                    surface.width(),
+                    surface.height(),
+                );
+                let result = canvas.copy(&texture, None, dest);
+                unsafe { texture.destroy() };
+                result?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Text in fonts that can't be loaded is measured as if each character
+    /// were half as wide as the font is tall.
+    fn size_of_text(&mut self, font: &Self::Font, text: &str) -> Result<(f32, f32), String> {
+        let size = font.size as f32;
+        let height = size * text.lines().count() as f32;
+        let widths = match self.font(font) {
+            Some(loaded) => text
+                .lines()
+                .map(|line| Ok(loaded.size_of(line).map_err(|e| e.to_string())?.0 as f32))
+                .collect::<Result<Vec<_>, String>>()?,
+            None => text
+                .lines()
+                .map(|line| line.chars().count() as f32 * size / 2.0)
+                .collect(),
+        };
+        let width = widths.into_iter().fold(0.0, f32::max);
+        Ok((width, height))
+    }
+
+    fn set_stroke_color(&mut self, color: &Color) {
+        self.stroke_color = *color;
+    }
+
+    fn stroke_lines(&mut self, lines: &[V2]) {
+        let color = self.sdl_color(&self.stroke_color);
+        let points: Vec<Point> = lines.iter().map(to_point).collect();
+        let result = self.draw(|canvas, _| {
+            canvas.set_draw_color(color);
+            canvas.draw_lines(points.as_slice())
+        });
+        if let Err(msg) = result {
+            warn!("can't stroke lines: {}", msg);
+        }
+    }
+
+    fn stroke_rect(&mut self, aabb: &AABB) {
+        let color = self.sdl_color(&self.stroke_color);
+        let result = self.draw(|canvas, _| {
+            canvas.set_draw_color(color);
+            canvas.draw_rect(to_rect(aabb))
+        });
+        if let Err(msg) = result {
+            warn!("can't stroke rect: {}", msg);
+        }
+    }
+
+    fn draw_image(
+        &mut self,
+        img: &Self::Image,
+        src: &AABB,
+        destination: &AABB,
+    ) -> Result<(), String> {
+        let alpha = (self.alpha * 255.0).round() as u8;
+        let texture_creator = self.texture_creator.clone();
+        self.draw(|canvas, textures| {
+            if !textures.contains_key(&img.path) {
+                let mut texture = texture_creator
+                    .create_texture_from_surface(img.surface.as_ref())
+                    .map_err(|e| e.to_string())?;
+                texture.set_blend_mode(BlendMode::Blend);
+                textures.insert(img.path.clone(), texture);
+            }
+            let texture = textures.get_mut(&img.path).ok_or("texture went missing")?;
+            texture.set_alpha_mod(alpha);
+            canvas.copy(texture, to_rect(src), to_rect(destination))
+        })
+    }
+
+    fn draw_context(&mut self, context: &Self, destination: &AABB) -> Result<(), String> {
+        let source = context.target.as_ref().ok_or("context has no target")?;
+        let target = self.target.as_mut().ok_or("context has no target")?;
+        let mut result = Ok(());
+        self.canvas
+            .borrow_mut()
+            .with_texture_canvas(target, |canvas| {
+                result = canvas.copy(source, None, to_rect(destination));
+            })
+            .map_err(|e| e.to_string())?;
+        result
+    }
+
+    fn font_details_to_font(&mut self, font_details: &FontDetails) -> Self::Font {
+        font_details.clone()
+    }
+}
+
+
+impl HasRenderingContext for DefaultRenderingContext<Sdl2Context> {
+    type Ctx = Sdl2Context;
+
+    fn get_rendering_context(&mut self) -> &mut Sdl2Context {
+        &mut self.context
+    }
+}
+
+
+impl<'a, 'b> Engine<'a, 'b, DefaultRenderingContext<Sdl2Context>, Sdl2ImageResources> {
+    /// Render a frame and show it in the window.
+    pub fn render_and_present(&mut self) -> Result<(), String> {
+        self.render()?;
+        self.rendering_context.context.present()
+    }
+}
+
+
+#[cfg(test)]
+mod sdl_tests {
+    use super::*;
+
+    /// Only one SDL context can be alive at a time, so tests take turns.
+    static SDL: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn renders_headless() {
+        let _sdl = SDL.lock().unwrap_or_else(|e| e.into_inner());
+        let backend = Sdl2Backend::new_headless(8, 8).unwrap();
+        let mut engine: Engine<DefaultRenderingContext<Sdl2Context>, Sdl2ImageResources> =
+            Engine::new("", || DefaultRenderingContext {
+                context: backend.new_context().unwrap(),
+            });
+        engine.set_map_viewport_size(8, 8);
+        engine
+            .world
+            .insert(super::super::prelude::BackgroundColor(Color::rgb(
+                0, 128, 255,
+            )));
+        engine.render_and_present().unwrap();
+        let pixels = engine.rendering_context.context.read_pixels().unwrap();
+        let ndx = (4 * 8 + 4) * 4;
+        assert_eq!(&pixels[ndx..ndx + 4], &[0, 128, 255, 255]);
+    }
+
+    #[test]
+    fn draws_text_with_a_font() {
+        let _sdl = SDL.lock().unwrap_or_else(|e| e.into_inner());
+        let backend = Sdl2Backend::new_headless(32, 32).unwrap();
+        let mut context = backend.new_context().unwrap();
+        let font = FontDetails {
+            path: "test_data/fonts/DejaVuSansMono.ttf".to_string(),
+            size: 16,
+        };
+        let (width, height) = context.size_of_text(&font, "Hi").unwrap();
+        assert!(width > 0.0);
+        assert_eq!(height, 16.0);
+
+        context.clear().unwrap();
+        context.set_font(&font);
+        context.set_fill_color(&Color::rgb(255, 255, 255));
+        context.fill_text("Hi", &V2::new(2.0, 20.0)).unwrap();
+        assert_eq!(context.fonts.len(), 1);
+        let pixels = context.read_pixels().unwrap();
+        let lit = pixels.chunks(4).filter(|pixel| pixel[3] > 0).count();
+        assert!(lit > 0, "no text was drawn");
+    }
+}
@@ -0,0 +1,522 @@
+//! A rendering backend that draws into memory on the CPU.
+//!
+//! `SoftwareContext` implements `RenderingContext` without a browser, so
+//! frames can be rendered in tests or on a server and saved as PNGs. Images
+//! are loaded from PNG files on disk by `SoftwareImageResources` and fonts are
+//! loaded from TrueType files, using the font's path as the file name. Text in
+//! a font that can't be loaded is drawn in the fallback font, if one has been
+//! set, and otherwise only measured.
+//!
+//! ```
+//! use old_gods::prelude::*;
+//!
+//! let mut engine: Engine<DefaultRenderingContext<SoftwareContext>, SoftwareImageResources> =
+//!     Engine::new("", || DefaultRenderingContext {
+//!         context: SoftwareContext::new(4, 4),
+//!     });
+//! engine.set_map_viewport_size(4, 4);
+//! engine
+//!     .world
+//!     .insert(BackgroundColor(Color::rgb(0, 128, 255)));
+//! let frame = engine.render_image().unwrap();
+//! assert_eq!(frame.pixel(2, 2), Some(Color::rgb(0, 128, 255)));
+//! ```
+use rusttype::{point, Font, Scale};
+use std::{collections::HashMap, fs, sync::Arc};
+
+use super::prelude::{
+    Color, DefaultRenderingContext, Engine, FontDetails, HasRenderingContext, LoadStatus,
+    LoadableResources, RenderingContext, Resources, SharedResource, AABB, V2,
+};
+
+
+/// An RGBA image in memory, four bytes to a pixel, row by row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoftwareImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+
+impl SoftwareImage {
+    /// A transparent image.
+    pub fn new(width: u32, height: u32) -> SoftwareImage {
+        SoftwareImage {
+            width,
+            height,
+            pixels: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    pub fn from_png_bytes(bytes: &[u8]) -> Result<SoftwareImage, String> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder
+            .read_info()
+            .map_err(|e| format!("can't read png: {}", e))?;
+        let mut buffer = vec![0; info.buffer_size()];
+        reader
+            .next_frame(&mut buffer)
+            .map_err(|e| format!("can't decode png: {}", e))?;
+        let pixels = match info.color_type {
+            png::ColorType::RGBA => buffer,
+            png::ColorType::RGB => buffer
+                .chunks(3)
+                .flat_map(|rgb| vec![rgb[0], rgb[1], rgb[2], 255])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => buffer
+                .chunks(2)
+                .flat_map(|ga| vec![ga[0], ga[0], ga[0], ga[1]])
+                .collect(),
+            png::ColorType::Grayscale => {
+                buffer.iter().flat_map(|g| vec![*g, *g, *g, 255]).collect()
+            }
+            png::ColorType::Indexed => {
+                return Err("can't read an indexed png that wasn't expanded".to_string())
+            }
+        };
+        Ok(SoftwareImage {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+
+    pub fn from_png_file(path: &str) -> Result<SoftwareImage, String> {
+        let bytes = fs::read(path).map_err(|e| format!("can't read '{}': {}", path, e))?;
+        SoftwareImage::from_png_bytes(&bytes).map_err(|e| format!("'{}': {}", path, e))
+    }
+
+    pub fn to_png_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, self.width, self.height);
+            encoder.set_color(png::ColorType::RGBA);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder
+                .write_header()
+                .map_err(|e| format!("can't write png header: {}", e))?;
+            writer
+                .write_image_data(&self.pixels)
+                .map_err(|e| format!("can't write png: {}", e))?;
+        }
+        Ok(bytes)
+    }
+
+    pub fn save_png(&self, path: &str) -> Result<(), String> {
+        let bytes = self.to_png_bytes()?;
+        fs::write(path, bytes).map_err(|e| format!("can't write '{}': {}", path, e))
+    }
+
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            None
+        } else {
+            Some((y as usize * self.width as usize + x as usize) * 4)
+        }
+    }
+
+    /// The color of a pixel, if it is inside the image.
+    pub fn pixel(&self, x: u32, y: u32) -> Option<Color> {
+        let i = self.index(x as i32, y as i32)?;
+        let p = &self.pixels[i..i + 4];
+        Some(Color::rgba(p[0], p[1], p[2], p[3]))
+    }
+
+    /// Draw a color over a pixel, scaling its alpha by `coverage`. Pixels
+    /// outside the image are ignored.
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: &Color, coverage: f32) {
+        let i = match self.index(x, y) {
+            Some(i) => i,
+            None => return,
+        };
+        let src_a = color.a as f32 / 255.0 * coverage.clamp(0.0, 1.0);
+        if src_a <= 0.0 {
+            return;
+        }
+        let dst = &mut self.pixels[i..i + 4];
+        let dst_a = dst[3] as f32 / 255.0;
+        let out_a = src_a + dst_a * (1.0 - src_a);
+        let mix = |src: u8, dst: u8| -> u8 {
+            let c = (src as f32 * src_a + dst as f32 * dst_a * (1.0 - src_a)) / out_a;
+            c.round().clamp(0.0, 255.0) as u8
+        };
+        dst[0] = mix(color.r, dst[0]);
+        dst[1] = mix(color.g, dst[1]);
+        dst[2] = mix(color.b, dst[2]);
+        dst[3] = (out_a * 255.0).round() as u8;
+    }
+}
+
+
+/// The whole pixels covered by an AABB, as left, top, right and bottom.
+fn pixel_bounds(aabb: &AABB) -> (i32, i32, i32, i32) {
+    let (x0, x1) = (aabb.top_left.x, aabb.top_left.x + aabb.extents.x);
+    let (y0, y1) = (aabb.top_left.y, aabb.top_left.y + aabb.extents.y);
+    (
+        x0.min(x1).round() as i32,
+        y0.min(y1).round() as i32,
+        x0.max(x1).round() as i32,
+        y0.max(y1).round() as i32,
+    )
+}
+
+
+/// A font loaded for the software context.
+#[derive(Clone)]
+pub struct SoftwareFont {
+    pub details: FontDetails,
+    font: Option<Arc<Font<'static>>>,
+}
+
+
+impl SoftwareFont {
+    /// Whether the font's glyphs were loaded.
+    pub fn is_loaded(&self) -> bool {
+        self.font.is_some()
+    }
+}
+
+
+/// Draws into a SoftwareImage.
+pub struct SoftwareContext {
+    pub image: SoftwareImage,
+    fill_color: Color,
+    stroke_color: Color,
+    alpha: f64,
+    font: Option<SoftwareFont>,
+    fonts: HashMap<String, Option<Arc<Font<'static>>>>,
+    fallback_font: Option<Arc<Font<'static>>>,
+}
+
+
+impl SoftwareContext {
+    pub fn new(width: u32, height: u32) -> SoftwareContext {
+        SoftwareContext {
+            image: SoftwareImage::new(width, height),
+            fill_color: Color::rgb(0, 0, 0),
+            stroke_color: Color::rgb(0, 0, 0),
+            alpha: 1.0,
+            font: None,
+            fonts: HashMap::new(),
+            fallback_font: None,
+        }
+    }
+
+    /// Draw text in fonts that can't be loaded with the font in the given
+    /// TrueType file.
+    pub fn set_fallback_font_file(&mut self, path: &str) -> Result<(), String> {
+        let bytes = fs::read(path).map_err(|e| format!("can't read '{}': {}", path, e))?;
+        let font = Font::try_from_vec(bytes).ok_or(format!("'{}' is not a font", path))?;
+        self.fallback_font = Some(Arc::new(font));
+        Ok(())
+    }
+
+    fn plot(&mut self, x: i32, y: i32, color: &Color) {
+        let coverage = self.alpha as f32;
+        self.image.blend_pixel(x, y, color, coverage);
+    }
+
+    fn stroke_line(&mut self, from: V2, to: V2) {
+        let color = self.stroke_color;
+        let steps = (to.x - from.x).abs().max((to.y - from.y).abs()).ceil() as i32;
+        if steps == 0 {
+            self.plot(from.x.floor() as i32, from.y.floor() as i32, &color);
+            return;
+        }
+        // Leave the last pixel for the next line to plot
+        for step in 0..steps {
+            let t = step as f32 / steps as f32;
+            let p = from + (to - from).scalar_mul(t);
+            self.plot(p.x.floor() as i32, p.y.floor() as i32, &color);
+        }
+    }
+}
+
+
+impl RenderingContext for SoftwareContext {
+    type Image = SoftwareImage;
+    type Font = SoftwareFont;
+
+    fn context_size(&mut self) -> Result<(u32, u32), String> {
+        Ok((self.image.width, self.image.height))
+    }
+
+    fn set_context_size(&mut self, (w, h): (u32, u32)) -> Result<(), String> {
+        if (w, h) != (self.image.width, self.image.height) {
+            self.image = SoftwareImage::new(w, h);
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), String> {
+        self.image.pixels.iter_mut().for_each(|p| *p = 0);
+        Ok(())
+    }
+
+    fn set_fill_color(&mut self, color: &Color) {
+        self.fill_color = *color;
+    }
+
+    fn global_alpha(&mut self) -> f64 {
+        self.alpha
+    }
+
+    fn set_global_alpha(&mut self, alpha: f64) {
+        self.alpha = alpha;
+    }
+
+    fn fill_rect(&mut self, aabb: &AABB) {
+        let color = self.fill_color;
+        let (left, top, right, bottom) = pixel_bounds(aabb);
+        for y in top.max(0)..bottom.min(self.image.height as i32) {
+            for x in left.max(0)..right.min(self.image.width as i32) {
+                self.plot(x, y, &color);
+            }
+        }
+    }
+
+    fn set_font(&mut self, font: &Self::Font) {
+        self.font = Some(font.clone());
+    }
+
+    /// Fills text with its baseline at `point`, like a canvas does.
+    fn fill_text(&mut self, text: &str, point_at: &V2) -> Result<(), String> {
+        let (font, size) = match &self.font {
+            Some(SoftwareFont {
+                font: Some(font),
+                details,
+            }) => (font.clone(), details.size),
+            _ => return Ok(()),
+        };
+        let color = self.fill_color;
+        let alpha = self.alpha as f32;
+        let scale = Scale::uniform(size as f32);
+        let line_height = size as f32;
+        for (line_ndx, line) in text.lines().enumerate() {
+            let origin = point(point_at.x, point_at.y + line_ndx as f32 * line_height);
+            for glyph in font.layout(line, scale, origin) {
+                if let Some(bounds) = glyph.pixel_bounding_box() {
+                    let image = &mut self.image;
+                    glyph.draw(|x, y, coverage| {
+                        image.blend_pixel(
+                            bounds.min.x + x as i32,
+                            bounds.min.y + y as i32,
+                            &color,
+                            coverage * alpha,
+                        );
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Text in fonts that aren't loaded is measured as if each character
+    /// were half as wide as the font is tall.
+    fn size_of_text(&mut self, font: &Self::Font, text: &str) -> Result<(f32, f32), String> {
+        let size = font.details.size as f32;
+        let height = size * text.lines().count() as f32;
+        let width = text
+            .lines()
+            .map(|line| match &font.font {
+                Some(glyphs) => glyphs
+                    .layout(line, Scale::uniform(size), point(0.0, 0.0))
+                    .last()
+                    .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+                    .unwrap_or(0.0),
+                None => line.chars().count() as f32 * size / 2.0,
+            })
+            .fold(0.0, f32::max);
+        Ok((width, height))
+    }
+
+    fn set_stroke_color(&mut self, color: &Color) {
+        self.stroke_color = *color;
+    }
+
+    fn stroke_lines(&mut self, lines: &[V2]) {
+        for pair in lines.windows(2) {
+            self.stroke_line(pair[0], pair[1]);
+        }
+        if let Some(last) = lines.last() {
+            let color = self.stroke_color;
+            self.plot(last.x.floor() as i32, last.y.floor() as i32, &color);
+        }
+    }
+
+    fn stroke_rect(&mut self, aabb: &AABB) {
+        let (left, top, right, bottom) = pixel_bounds(aabb);
+        let (left, top) = (left as f32, top as f32);
+        let (right, bottom) = ((right - 1) as f32, (bottom - 1) as f32);
+        self.stroke_lines(&[
+            V2::new(left, top),
+            V2::new(right, top),
+            V2::new(right, bottom),
+            V2::new(left, bottom),
+            V2::new(left, top),
+        ]);
+    }
+
+    fn draw_image(
+        &mut self,
+        img: &Self::Image,
+        src: &AABB,
+        destination: &AABB,
+    ) -> Result<(), String> {
+        let (left, top, right, bottom) = pixel_bounds(destination);
+        if right <= left || bottom <= top {
+            return Ok(());
+        }
+        let scale_x = src.extents.x / (right - left) as f32;
+        let scale_y = src.extents.y / (bottom - top) as f32;
+        for y in top.max(0)..bottom.min(self.image.height as i32) {
+            let src_y = (src.top_left.y + (y - top) as f32 * scale_y).floor();
+            for x in left.max(0)..right.min(self.image.width as i32) {
+                let src_x = (src.top_left.x + (x - left) as f32 * scale_x).floor();
+                if src_x < 0.0 || src_y < 0.0 {
+                    continue;
+                }
+                if let Some(color) = img.pixel(src_x as u32, src_y as u32) {
+                    self.plot(x, y, &color);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn draw_context(&mut self, context: &Self, destination: &AABB) -> Result<(), String> {
+        let src = AABB::new(
+            0.0,
+            0.0,
+            context.image.width as f32,
+            context.image.height as f32,
+        );
+        self.draw_image(&context.image, &src, destination)
+    }
+
+    fn font_details_to_font(&mut self, font_details: &FontDetails) -> Self::Font {
+        let font = self
+            .fonts
+            .entry(font_details.path.clone())
+            .or_insert_with(|| {
+                fs::read(&font_details.path)
+                    .ok()
+                    .and_then(Font::try_from_vec)
+                    .map(Arc::new)
+            })
+            .clone()
+            .or_else(|| self.fallback_font.clone());
+        SoftwareFont {
+            details: font_details.clone(),
+            font,
+        }
+    }
+}
+
+
+impl HasRenderingContext for DefaultRenderingContext<SoftwareContext> {
+    type Ctx = SoftwareContext;
+
+    fn get_rendering_context(&mut self) -> &mut SoftwareContext {
+        &mut self.context
+    }
+}
+
+
+/// Loads PNG images from disk, using their keys as file paths.
+#[derive(Default)]
+pub struct SoftwareImageResources(pub LoadableResources<SoftwareImage>);
+
+
+impl Resources<SoftwareImage> for SoftwareImageResources {
+    fn status_of(&self, s: &str) -> LoadStatus {
+        self.0.status_of(s)
+    }
+
+    fn load(&mut self, path: &str) {
+        let rsrc = SharedResource::default();
+        match SoftwareImage::from_png_file(path) {
+            Ok(img) => rsrc.set_status_and_resource((LoadStatus::Complete, Some(img))),
+            Err(msg) => rsrc.set_status_and_resource((LoadStatus::Error(msg), None)),
+        }
+        self.0.resources.insert(path.to_string(), rsrc);
+    }
+
+    fn take(&mut self, s: &str) -> Option<SharedResource<SoftwareImage>> {
+        self.0.take(s)
+    }
+
+    fn put(&mut self, path: &str, shared: SharedResource<SoftwareImage>) {
+        self.0.put(path, shared)
+    }
+}
+
+
+impl<'a, 'b> Engine<'a, 'b, DefaultRenderingContext<SoftwareContext>, SoftwareImageResources> {
+    /// Render a frame and return it.
+    pub fn render_image(&mut self) -> Result<&SoftwareImage, String> {
+        self.render()?;
+        Ok(&self.rendering_context.context.image)
+    }
+}
+
+
+#[cfg(test)]
+mod software_tests {
+    use super::*;
+
+    #[test]
+    fn fills_strokes_and_blends() {
+        let mut ctx = SoftwareContext::new(8, 8);
+        ctx.set_fill_color(&Color::rgb(255, 0, 0));
+        ctx.fill_rect(&AABB::new(2.0, 2.0, 4.0, 4.0));
+        assert_eq!(ctx.image.pixel(1, 1), Some(Color::rgba(0, 0, 0, 0)));
+        assert_eq!(ctx.image.pixel(2, 2), Some(Color::rgb(255, 0, 0)));
+        assert_eq!(ctx.image.pixel(5, 5), Some(Color::rgb(255, 0, 0)));
+        assert_eq!(ctx.image.pixel(6, 6), Some(Color::rgba(0, 0, 0, 0)));
+
+        // Half of blue over red
+        ctx.set_global_alpha(0.5);
+        ctx.set_fill_color(&Color::rgb(0, 0, 255));
+        ctx.fill_rect(&AABB::new(0.0, 0.0, 8.0, 8.0));
+        assert_eq!(ctx.image.pixel(3, 3), Some(Color::rgb(128, 0, 128)));
+        assert_eq!(ctx.image.pixel(0, 0), Some(Color::rgba(0, 0, 255, 128)));
+
+        ctx.clear().unwrap();
+        ctx.set_global_alpha(1.0);
+        ctx.set_stroke_color(&Color::rgb(0, 255, 0));
+        ctx.stroke_lines(&[V2::new(0.0, 0.0), V2::new(7.0, 7.0)]);
+        for i in 0..8 {
+            assert_eq!(ctx.image.pixel(i, i), Some(Color::rgb(0, 255, 0)));
+        }
+        assert_eq!(ctx.image.pixel(1, 0), Some(Color::rgba(0, 0, 0, 0)));
+    }
+
+    #[test]
+    fn images_scale_and_round_trip_through_png() {
+        let mut img = SoftwareImage::new(2, 1);
+        img.blend_pixel(0, 0, &Color::rgb(255, 255, 0), 1.0);
+        img.blend_pixel(1, 0, &Color::rgb(0, 255, 255), 1.0);
+        let png = img.to_png_bytes().unwrap();
+        assert_eq!(SoftwareImage::from_png_bytes(&png), Ok(img.clone()));
+
+        let mut ctx = SoftwareContext::new(4, 2);
+        ctx.draw_image(
+            &img,
+            &AABB::new(0.0, 0.0, 2.0, 1.0),
+            &AABB::new(0.0, 0.0, 4.0, 2.0),
+        )
+        .unwrap();
+        assert_eq!(ctx.image.pixel(1, 1), Some(Color::rgb(255, 255, 0)));
+        assert_eq!(ctx.image.pixel(2, 0), Some(Color::rgb(0, 255, 255)));
+
+        let font = ctx.font_details_to_font(&FontDetails {
+            path: "no such font".to_string(),
+            size: 10,
+        });
+        assert!(!font.is_loaded());
+        assert_eq!(ctx.size_of_text(&font, "four"), Ok((20.0, 10.0)));
+    }
+}
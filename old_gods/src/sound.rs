@@ -104,3 +104,70 @@ impl Resources<HtmlAudioElement> for SoundBlaster {
         self.resources.put(path, sound)
     }
 }
+
+
+/// A sound loaded into the SDL mixer.
+#[cfg(feature = "sdl2")]
+#[derive(Clone)]
+pub struct Sdl2Sound(pub std::rc::Rc<sdl2::mixer::Chunk>);
+
+
+/// Loads sounds from disk into the SDL mixer and plays them.
+#[cfg(feature = "sdl2")]
+pub struct Sdl2SoundBlaster {
+    resources: LoadableResources<Sdl2Sound>,
+}
+
+
+#[cfg(feature = "sdl2")]
+impl Sdl2SoundBlaster {
+    /// Open the audio device. The audio subsystem must already be
+    /// initialized.
+    pub fn new() -> Result<Self, String> {
+        use sdl2::mixer::{DEFAULT_CHANNELS, DEFAULT_FORMAT, DEFAULT_FREQUENCY};
+        sdl2::mixer::open_audio(DEFAULT_FREQUENCY, DEFAULT_FORMAT, DEFAULT_CHANNELS, 1024)?;
+        Ok(Sdl2SoundBlaster {
+            resources: LoadableResources::new(),
+        })
+    }
+}
+
+
+#[cfg(feature = "sdl2")]
+impl Drop for Sdl2SoundBlaster {
+    fn drop(&mut self) {
+        sdl2::mixer::close_audio();
+    }
+}
+
+
+#[cfg(feature = "sdl2")]
+impl Resources<Sdl2Sound> for Sdl2SoundBlaster {
+    fn status_of(&self, s: &str) -> LoadStatus {
+        self.resources.status_of(s)
+    }
+
+    fn load(&mut self, path: &str) {
+        trace!("loading sound: {}", path);
+        let rsrc = SharedResource::default();
+        match sdl2::mixer::Chunk::from_file(path) {
+            Ok(chunk) => rsrc.set_status_and_resource((
+                LoadStatus::Complete,
+                Some(Sdl2Sound(std::rc::Rc::new(chunk))),
+            )),
+            Err(msg) => rsrc.set_status_and_resource((
+                LoadStatus::Error(format!("failed loading {}: {}", path, msg)),
+                None,
+            )),
+        }
+        self.resources.resources.insert(path.to_string(), rsrc);
+    }
+
+    fn take(&mut self, s: &str) -> Option<SharedResource<Sdl2Sound>> {
+        self.resources.take(s)
+    }
+
+    fn put(&mut self, path: &str, sound: SharedResource<Sdl2Sound>) {
+        self.resources.put(path, sound)
+    }
+}
@@ -1,15 +1,22 @@
 //pub mod action;
+pub mod ai;
 pub mod animation;
+pub mod behavior;
 //pub mod effect;
 pub mod fence;
 pub mod gamepad;
 //pub mod map_loader;
 //pub mod message;
+pub mod navmesh;
+pub mod pathfinding;
 pub mod physics;
 pub mod player;
+pub mod route;
 pub mod screen;
 pub mod sound;
+pub mod steering;
 //pub mod sprite;
 pub mod tiled;
 pub mod tween;
+pub mod vision;
 pub mod zone;
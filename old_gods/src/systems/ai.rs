@@ -0,0 +1,451 @@
+//! Moves non player characters.
+//!
+//! Characters with `control: npc` get an `Npc` driven by their `AI`. Like
+//! players, NPCs move at their `MaxSpeed` by setting their `Velocity`, or by
+//! running if they are platformers, and physics takes it from there.
+//!
+//! NPCs following a target on a map with a TileGrid ask for paths around the
+//! map's barriers with a `PathRequest`, otherwise they head straight for it.
+//!
+//! NPCs with a `Steering` slow down as they arrive and steer around each other
+//! and the barriers in their way.
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use specs::prelude::*;
+use std::collections::HashMap;
+
+use super::super::prelude::{
+    arrive, Exile, FPSCounter, Gravity, GridMovement, MapGravity, MaxSpeed, Name, PathRequest,
+    PathStatus, Platformer, Position, RouteData, RouteMode, RouteProgress, SteeringData, TileGrid,
+    Velocity, AI, V2,
+};
+
+
+/// How close an NPC has to get to a spot to have arrived.
+const NPC_ARRIVAL_DISTANCE: f32 = 2.0;
+
+
+/// The shortest and longest time a wandering NPC waits between walks.
+const NPC_WANDER_WAIT: (f32, f32) = (1.0, 3.0);
+
+
+/// How fast NPCs without a MaxSpeed move.
+pub const NPC_MAX_SPEED: f32 = 100.0;
+
+
+/// The velocity that takes something at `position` to `destination` at up to
+/// `max_speed`, slowing down to land right on it.
+pub fn steer_towards(position: V2, destination: V2, max_speed: f32, dt: f32) -> V2 {
+    let to = destination - position;
+    let speed = if dt > 0.0 {
+        max_speed.min(to.magnitude() / dt)
+    } else {
+        max_speed
+    };
+    to.unitize()
+        .map(|dir| dir.scalar_mul(speed))
+        .unwrap_or_else(V2::origin)
+}
+
+
+/// Move a character at the given velocity, or run along it if the character
+/// is a platformer. Platformers run at the part of the velocity that is across
+/// their gravity.
+pub fn set_movement(
+    ent: Entity,
+    velocity: V2,
+    gravities: &ReadStorage<Gravity>,
+    map_gravity: &MapGravity,
+    platformers: &mut WriteStorage<Platformer>,
+    velocities: &mut WriteStorage<Velocity>,
+) {
+    if let Some(platformer) = platformers.get_mut(ent) {
+        platformer.run = Gravity::down_of(ent, gravities, map_gravity)
+            .map(|down| velocity.dot(Platformer::across(down)))
+            .unwrap_or(0.0);
+        platformer.jump = false;
+    } else if let Some(Velocity(v)) = velocities.get_mut(ent) {
+        *v = velocity;
+    }
+}
+
+
+/// A character controlled by an AI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Npc {
+    pub ai: AI,
+
+    home: Option<V2>,
+    destination: Option<V2>,
+    waiting: f32,
+    patrol: RouteProgress,
+    chasing: Option<(u32, u32)>,
+}
+
+
+impl Npc {
+    pub fn new(ai: AI) -> Npc {
+        let mode = match &ai {
+            AI::Patrol { mode, .. } => *mode,
+            _ => RouteMode::PingPong,
+        };
+        Npc {
+            ai,
+            home: None,
+            destination: None,
+            waiting: 0.0,
+            patrol: RouteProgress::new(mode),
+            chasing: None,
+        }
+    }
+
+
+    /// Pick a new spot to walk to once the last one is reached and the NPC
+    /// has waited there.
+    fn wander(&mut self, position: V2, radius: f32, dt: f32, rng: &mut ChaCha8Rng) -> Option<V2> {
+        let home = *self.home.get_or_insert(position);
+        if let Some(destination) = self.destination {
+            if position.distance_to(&destination) > NPC_ARRIVAL_DISTANCE {
+                return Some(destination);
+            }
+            self.destination = None;
+            self.waiting = rng.gen_range(NPC_WANDER_WAIT.0, NPC_WANDER_WAIT.1);
+        }
+        self.waiting -= dt;
+        if self.waiting <= 0.0 {
+            let angle = rng.gen_range(0.0, std::f32::consts::PI * 2.0);
+            let distance = rng.gen_range(0.0, radius.max(0.0) + f32::EPSILON);
+            self.destination = Some(home + V2::new(angle.cos(), angle.sin()).scalar_mul(distance));
+        }
+        self.destination
+    }
+}
+
+
+impl Component for Npc {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Turns NPCs' AIs into movement. Runs after the `PlayerSystem` and before
+/// physics.
+pub struct AISystem {
+    rng: ChaCha8Rng,
+}
+
+
+impl AISystem {
+    pub fn new(seed: u64) -> AISystem {
+        AISystem {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+}
+
+
+impl Default for AISystem {
+    fn default() -> AISystem {
+        AISystem::new(0)
+    }
+}
+
+
+#[derive(SystemData)]
+pub struct AISystemData<'a> {
+    entities: Entities<'a>,
+    exiles: ReadStorage<'a, Exile>,
+    fps: Read<'a, FPSCounter>,
+    gravities: ReadStorage<'a, Gravity>,
+    map_gravity: Read<'a, MapGravity>,
+    max_speeds: ReadStorage<'a, MaxSpeed>,
+    names: ReadStorage<'a, Name>,
+    npcs: WriteStorage<'a, Npc>,
+    path_requests: WriteStorage<'a, PathRequest>,
+    platformers: WriteStorage<'a, Platformer>,
+    positions: ReadStorage<'a, Position>,
+    routes: RouteData<'a>,
+    steering: SteeringData<'a>,
+    tile_grid: Read<'a, TileGrid>,
+    velocities: WriteStorage<'a, Velocity>,
+}
+
+
+impl<'a> AISystemData<'a> {
+    /// Where to go next on the way to a target, asking for a path around the
+    /// map's barriers if there is a tile grid.
+    fn chase(&mut self, ent: Entity, position: V2, target: V2) -> Option<V2> {
+        if self.tile_grid.is_empty() {
+            return Some(target);
+        }
+        let npc = self.npcs.get_mut(ent)?;
+        let target_cell = self.tile_grid.cell_at(target);
+        if !self.path_requests.contains(ent) || target_cell != npc.chasing {
+            npc.chasing = target_cell;
+            let _ = self
+                .path_requests
+                .insert(ent, PathRequest::new(target, GridMovement::EightWay));
+            return Some(target);
+        }
+        match self
+            .path_requests
+            .get_mut(ent)
+            .map(|request| &mut request.status)
+        {
+            Some(PathStatus::Found(path)) => {
+                while path.len() > 1 && position.distance_to(&path[0]) <= NPC_ARRIVAL_DISTANCE {
+                    path.remove(0);
+                }
+                path.first().cloned()
+            }
+            Some(PathStatus::NotFound) => None,
+            _ => Some(target),
+        }
+    }
+}
+
+
+impl<'a> System<'a> for AISystem {
+    type SystemData = AISystemData<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        let dt = data.fps.last_delta();
+        let named: HashMap<String, Entity> = (&data.entities, &data.names)
+            .join()
+            .map(|(ent, Name(name))| (name.clone(), ent))
+            .collect();
+        let npcs: Vec<(Entity, V2)> = (&data.entities, &data.npcs, &data.positions, !&data.exiles)
+            .join()
+            .map(|(ent, _, Position(p), ())| (ent, *p))
+            .collect();
+
+        for (ent, position) in npcs.into_iter() {
+            let ai = match data.npcs.get(ent) {
+                Some(npc) => npc.ai.clone(),
+                None => continue,
+            };
+            let target_position = match &ai {
+                AI::Follow { target, .. } | AI::Flee { target, .. } => named
+                    .get(target)
+                    .and_then(|target| data.positions.get(*target))
+                    .map(|Position(p)| *p),
+                _ => None,
+            };
+            let destination = match ai {
+                AI::Idle => None,
+                AI::Wander { radius } => data
+                    .npcs
+                    .get_mut(ent)
+                    .and_then(|npc| npc.wander(position, radius, dt, &mut self.rng)),
+                AI::Patrol { path, .. } => {
+                    let route = data.routes.route_on_map(&path);
+                    match (data.npcs.get_mut(ent), route) {
+                        (Some(npc), Some(route)) => npc.patrol.next(position, &route, dt),
+                        _ => None,
+                    }
+                }
+                AI::Follow { distance, .. } => match target_position {
+                    Some(target) if position.distance_to(&target) > distance => {
+                        data.chase(ent, position, target)
+                    }
+                    _ => None,
+                },
+                AI::Flee { distance, .. } => match target_position {
+                    Some(target) if position.distance_to(&target) < distance => {
+                        // Anywhere away from the target will do
+                        (position - target)
+                            .unitize()
+                            .map(|away| position + away.scalar_mul(distance))
+                    }
+                    _ => None,
+                },
+            };
+
+            let max_speed = data
+                .max_speeds
+                .get(ent)
+                .map(|s| s.0)
+                .unwrap_or(NPC_MAX_SPEED);
+            let velocity = match data.steering.steerings.get(ent) {
+                Some(steering) => {
+                    let desired = destination
+                        .map(|destination| {
+                            arrive(
+                                position,
+                                destination,
+                                max_speed,
+                                steering.slowing_distance,
+                                dt,
+                            )
+                        })
+                        .unwrap_or_else(V2::origin);
+                    data.steering
+                        .steer(ent, steering, position, desired, max_speed)
+                }
+                None => destination
+                    .map(|destination| steer_towards(position, destination, max_speed, dt))
+                    .unwrap_or_else(V2::origin),
+            };
+            set_movement(
+                ent,
+                velocity,
+                &data.gravities,
+                &data.map_gravity,
+                &mut data.platformers,
+                &mut data.velocities,
+            );
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod ai_tests {
+    use super::{
+        super::super::prelude::{Fence, RouteRef},
+        *,
+    };
+
+    fn world_with_npc(ai: AI, position: V2) -> (World, AISystem, Entity) {
+        let mut world = World::new();
+        let mut system = AISystem::default();
+        System::setup(&mut system, &mut world);
+        world
+            .write_resource::<FPSCounter>()
+            .set_last_delta(1.0 / 60.0);
+        let npc = world
+            .create_entity()
+            .with(Npc::new(ai))
+            .with(Position(position))
+            .with(Velocity(V2::origin()))
+            .with(MaxSpeed(60.0))
+            .build();
+        (world, system, npc)
+    }
+
+    /// Run the AI, then move the npc by its velocity.
+    fn step(world: &mut World, system: &mut AISystem, npc: Entity) -> V2 {
+        system.run_now(world);
+        let Velocity(v) = *world.read_storage::<Velocity>().get(npc).unwrap();
+        let mut positions = world.write_storage::<Position>();
+        let Position(p) = positions.get_mut(npc).unwrap();
+        *p += v.scalar_mul(1.0 / 60.0);
+        v
+    }
+
+    #[test]
+    fn npcs_follow_and_flee() {
+        let target = |world: &mut World, name: &str, p: V2| {
+            world
+                .create_entity()
+                .with(Name(name.to_string()))
+                .with(Position(p))
+                .build()
+        };
+        let (mut world, mut system, npc) = world_with_npc(
+            AI::Follow {
+                target: "hero".to_string(),
+                distance: 10.0,
+            },
+            V2::origin(),
+        );
+        target(&mut world, "hero", V2::new(100.0, 0.0));
+        assert_eq!(step(&mut world, &mut system, npc), V2::new(60.0, 0.0));
+        for _ in 0..120 {
+            step(&mut world, &mut system, npc);
+        }
+        let Position(p) = *world.read_storage::<Position>().get(npc).unwrap();
+        assert!((p.x - 90.0).abs() < 1.0, "{:?}", p);
+
+        let (mut world, mut system, npc) = world_with_npc(
+            AI::Flee {
+                target: "hero".to_string(),
+                distance: 50.0,
+            },
+            V2::origin(),
+        );
+        target(&mut world, "hero", V2::new(0.0, 20.0));
+        assert_eq!(step(&mut world, &mut system, npc), V2::new(0.0, -60.0));
+        for _ in 0..120 {
+            step(&mut world, &mut system, npc);
+        }
+        assert_eq!(step(&mut world, &mut system, npc), V2::origin());
+    }
+
+    #[test]
+    fn npcs_patrol_back_and_forth() {
+        let (mut world, mut system, npc) = world_with_npc(
+            AI::Patrol {
+                path: RouteRef::Name("beat".to_string()),
+                mode: RouteMode::PingPong,
+            },
+            V2::new(10.0, 10.0),
+        );
+        world
+            .create_entity()
+            .with(Name("beat".to_string()))
+            .with(Position(V2::new(10.0, 10.0)))
+            .with(Fence::new(vec![V2::origin(), V2::new(30.0, 0.0)]))
+            .build();
+
+        let mut xs = vec![];
+        for _ in 0..240 {
+            step(&mut world, &mut system, npc);
+            xs.push(world.read_storage::<Position>().get(npc).unwrap().0.x);
+        }
+        let max = xs.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        assert!((max - 40.0).abs() <= NPC_ARRIVAL_DISTANCE, "{}", max);
+        // It turned back and got home again
+        assert!(xs[60..]
+            .iter()
+            .any(|x| (x - 10.0).abs() <= NPC_ARRIVAL_DISTANCE));
+    }
+
+    #[test]
+    fn npcs_wander_near_home() {
+        let home = V2::new(50.0, 50.0);
+        let (mut world, mut system, npc) = world_with_npc(AI::Wander { radius: 20.0 }, home);
+        let mut moved = false;
+        for _ in 0..600 {
+            moved |= step(&mut world, &mut system, npc) != V2::origin();
+            let Position(p) = *world.read_storage::<Position>().get(npc).unwrap();
+            assert!(p.distance_to(&home) <= 20.1, "{:?}", p);
+        }
+        assert!(moved);
+    }
+
+    #[test]
+    fn platformers_run_across_their_gravity() {
+        let run_of =
+            |world: &World, npc: Entity| world.read_storage::<Platformer>().get(npc).unwrap().run;
+        let (mut world, mut system, npc) = world_with_npc(
+            AI::Follow {
+                target: "hero".to_string(),
+                distance: 10.0,
+            },
+            V2::origin(),
+        );
+        world
+            .create_entity()
+            .with(Name("hero".to_string()))
+            .with(Position(V2::new(0.0, -100.0)))
+            .build();
+        world
+            .write_storage::<Platformer>()
+            .insert(npc, Platformer::default())
+            .unwrap();
+
+        // Gravity pulls east, so heading north is running along the ground
+        world
+            .write_storage::<Gravity>()
+            .insert(npc, Gravity(V2::new(600.0, 0.0)))
+            .unwrap();
+        system.run_now(&world);
+        assert_eq!(run_of(&world, npc), 60.0);
+
+        // With the map's gravity pulling south, north is straight up
+        world.write_storage::<Gravity>().remove(npc);
+        world.insert(MapGravity(V2::new(0.0, 600.0)));
+        system.run_now(&world);
+        assert_eq!(run_of(&world, npc), 0.0);
+    }
+}
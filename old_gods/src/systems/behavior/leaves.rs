@@ -0,0 +1,364 @@
+//! The leaves every behavior tree can use.
+use serde_json::Value;
+use std::collections::HashMap;
+
+use super::{
+    super::super::prelude::{set_movement, steer_towards, Position, NPC_MAX_SPEED, V2},
+    BehaviorData, BehaviorLeaf, BehaviorStatus, LeafContext,
+};
+
+
+/// How close a mover has to get to where it's going to have arrived.
+const MOVE_TO_ARRIVAL_DISTANCE: f32 = 2.0;
+
+
+fn string_param(params: &HashMap<String, Value>, name: &str) -> Result<String, String> {
+    params
+        .get(name)
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or_else(|| format!("'{}' must be a string", name))
+}
+
+
+fn float_param(params: &HashMap<String, Value>, name: &str) -> Result<f32, String> {
+    params
+        .get(name)
+        .and_then(|v| v.as_f64())
+        .map(|f| f as f32)
+        .ok_or_else(|| format!("'{}' must be a number", name))
+}
+
+
+/// Where a mover is going.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MoveTarget {
+    Point(V2),
+    Named(String),
+}
+
+
+/// Walks to a point or a named entity at the entity's MaxSpeed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveTo {
+    pub target: MoveTarget,
+}
+
+
+impl MoveTo {
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<MoveTo, String> {
+        let target = if params.contains_key("target") {
+            MoveTarget::Named(string_param(params, "target")?)
+        } else {
+            MoveTarget::Point(V2::new(
+                float_param(params, "x")?,
+                float_param(params, "y")?,
+            ))
+        };
+        Ok(MoveTo { target })
+    }
+}
+
+
+impl BehaviorLeaf for MoveTo {
+    fn tick(&self, context: &mut LeafContext, data: &mut BehaviorData) -> BehaviorStatus {
+        let ent = context.entity;
+        let destination = match &self.target {
+            MoveTarget::Point(p) => Some(*p),
+            MoveTarget::Named(name) => data
+                .named(name)
+                .and_then(|target| data.positions.get(target))
+                .map(|Position(p)| *p),
+        };
+        let (position, destination) = match (data.positions.get(ent), destination) {
+            (Some(Position(position)), Some(destination)) => (*position, destination),
+            _ => return BehaviorStatus::Failure,
+        };
+
+        if position.distance_to(&destination) <= MOVE_TO_ARRIVAL_DISTANCE {
+            set_movement(
+                ent,
+                V2::origin(),
+                &data.gravities,
+                &data.map_gravity,
+                &mut data.platformers,
+                &mut data.velocities,
+            );
+            return BehaviorStatus::Success;
+        }
+        let max_speed = data
+            .max_speeds
+            .get(ent)
+            .map(|s| s.0)
+            .unwrap_or(NPC_MAX_SPEED);
+        let velocity = steer_towards(position, destination, max_speed, context.dt);
+        set_movement(
+            ent,
+            velocity,
+            &data.gravities,
+            &data.map_gravity,
+            &mut data.platformers,
+            &mut data.velocities,
+        );
+        BehaviorStatus::Running
+    }
+}
+
+
+/// Runs for a number of seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wait {
+    pub seconds: f32,
+}
+
+
+impl Wait {
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<Wait, String> {
+        Ok(Wait {
+            seconds: float_param(params, "seconds")?,
+        })
+    }
+}
+
+
+impl BehaviorLeaf for Wait {
+    fn tick(&self, context: &mut LeafContext, _: &mut BehaviorData) -> BehaviorStatus {
+        if context.elapsed + context.dt >= self.seconds {
+            BehaviorStatus::Success
+        } else {
+            BehaviorStatus::Running
+        }
+    }
+}
+
+
+/// Plays the entity's Animation from the start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayAnimation {
+    /// Whether to loop the animation, succeeding as soon as it starts.
+    pub repeat: bool,
+}
+
+
+impl PlayAnimation {
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<PlayAnimation, String> {
+        Ok(PlayAnimation {
+            repeat: params
+                .get("repeat")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+        })
+    }
+}
+
+
+impl BehaviorLeaf for PlayAnimation {
+    fn tick(&self, context: &mut LeafContext, data: &mut BehaviorData) -> BehaviorStatus {
+        let animation = match data.animations.get_mut(context.entity) {
+            Some(animation) => animation,
+            None => return BehaviorStatus::Failure,
+        };
+        if context.elapsed == 0.0 {
+            animation.seek_to(0);
+            animation.current_frame_progress = 0.0;
+            animation.should_repeat = self.repeat;
+            animation.play();
+        }
+        if self.repeat || !animation.is_playing {
+            BehaviorStatus::Success
+        } else {
+            BehaviorStatus::Running
+        }
+    }
+}
+
+
+/// Switches the entity's Sprite to a keyframe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframe {
+    pub name: String,
+}
+
+
+impl Keyframe {
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<Keyframe, String> {
+        Ok(Keyframe {
+            name: string_param(params, "name")?,
+        })
+    }
+}
+
+
+impl BehaviorLeaf for Keyframe {
+    fn tick(&self, context: &mut LeafContext, data: &mut BehaviorData) -> BehaviorStatus {
+        match data.sprites.get_mut(context.entity) {
+            Some(sprite) => {
+                sprite.keyframe = Some(self.name.clone());
+                BehaviorStatus::Success
+            }
+            None => BehaviorStatus::Failure,
+        }
+    }
+}
+
+
+/// Succeeds if the entity is inside the named Zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InZone {
+    pub zone: String,
+}
+
+
+impl InZone {
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<InZone, String> {
+        Ok(InZone {
+            zone: string_param(params, "zone")?,
+        })
+    }
+}
+
+
+impl BehaviorLeaf for InZone {
+    fn tick(&self, context: &mut LeafContext, data: &mut BehaviorData) -> BehaviorStatus {
+        let inside = data
+            .named(&self.zone)
+            .and_then(|zone| data.zones.get(zone))
+            .map(|zone| zone.inside.contains(&context.entity))
+            .unwrap_or(false);
+        if inside {
+            BehaviorStatus::Success
+        } else {
+            BehaviorStatus::Failure
+        }
+    }
+}
+
+
+/// Sets a value on the blackboard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetValue {
+    pub key: String,
+    pub value: Value,
+}
+
+
+impl SetValue {
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<SetValue, String> {
+        Ok(SetValue {
+            key: string_param(params, "key")?,
+            value: params.get("value").cloned().unwrap_or(Value::Null),
+        })
+    }
+}
+
+
+impl BehaviorLeaf for SetValue {
+    fn tick(&self, context: &mut LeafContext, _: &mut BehaviorData) -> BehaviorStatus {
+        context
+            .blackboard
+            .insert(self.key.clone(), self.value.clone());
+        BehaviorStatus::Success
+    }
+}
+
+
+/// Succeeds if a value on the blackboard is the given value. Missing values
+/// are null.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckValue {
+    pub key: String,
+    pub value: Value,
+}
+
+
+impl CheckValue {
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<CheckValue, String> {
+        Ok(CheckValue {
+            key: string_param(params, "key")?,
+            value: params.get("value").cloned().unwrap_or(Value::Null),
+        })
+    }
+}
+
+
+impl BehaviorLeaf for CheckValue {
+    fn tick(&self, context: &mut LeafContext, _: &mut BehaviorData) -> BehaviorStatus {
+        let value = context.blackboard.get(&self.key).unwrap_or(&Value::Null);
+        if *value == self.value {
+            BehaviorStatus::Success
+        } else {
+            BehaviorStatus::Failure
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod leaves_tests {
+    use super::{
+        super::{
+            super::super::prelude::{Name, Velocity, Zone},
+            Behavior, BehaviorLeaves, BehaviorSpec, BehaviorTree,
+        },
+        *,
+    };
+    use specs::prelude::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn guards_walk_to_the_gate_and_wait_there() {
+        let mut world = World::new();
+        BehaviorData::setup(&mut world);
+        let guard = world
+            .create_entity()
+            .with(Position(V2::origin()))
+            .with(Velocity(V2::origin()))
+            .build();
+        world
+            .create_entity()
+            .with(Name("gate".to_string()))
+            .with(Zone {
+                inside: vec![guard],
+            })
+            .build();
+        let spec = BehaviorSpec::from_text(
+            r#"
+sequence
+  move_to x=25 y=0
+  in_zone zone=gate
+  wait seconds=0.5
+"#,
+        )
+        .unwrap();
+        let tree = BehaviorTree::new(&spec, &BehaviorLeaves::default()).unwrap();
+        let mut behavior = Behavior::new(Arc::new(tree));
+
+        let dt = 0.125;
+        let mut statuses = vec![];
+        for _ in 0..8 {
+            statuses.push(behavior.tick(guard, dt, &mut world.system_data()));
+            let Velocity(v) = *world.read_storage::<Velocity>().get(guard).unwrap();
+            let mut positions = world.write_storage::<Position>();
+            let Position(p) = positions.get_mut(guard).unwrap();
+            *p += v.scalar_mul(dt);
+        }
+        // Two steps to get there, then four to wait out half a second
+        let finished = statuses
+            .iter()
+            .position(|s| *s == BehaviorStatus::Success)
+            .unwrap();
+        assert_eq!(finished, 5, "{:?}", statuses);
+        let Position(p) = *world.read_storage::<Position>().get(guard).unwrap();
+        assert!(p.distance_to(&V2::new(25.0, 0.0)) <= MOVE_TO_ARRIVAL_DISTANCE);
+
+        // Outside the gate the sequence fails once it arrives
+        world.write_storage::<Zone>().clear();
+        let mut behavior = Behavior::new(Arc::new(
+            BehaviorTree::new(&spec, &BehaviorLeaves::default()).unwrap(),
+        ));
+        assert_eq!(
+            behavior.tick(guard, dt, &mut world.system_data()),
+            BehaviorStatus::Failure
+        );
+    }
+}
@@ -0,0 +1,684 @@
+//! Behavior trees for scripting characters from data.
+//!
+//! An object with a "behavior" property names a JSON or text file holding a
+//! behavior tree. Once the file is loaded the entity gets a `Behavior` that
+//! ticks the tree every simulation step.
+//!
+//! Trees are made of:
+//! * `sequence` - ticks its children in order until one fails
+//! * `selector` - ticks its children in order until one succeeds
+//! * `invert` - swaps its child's success and failure
+//! * `succeed` - succeeds when its child finishes, either way
+//! * `repeat` - runs its child `times` times, or forever without `times`
+//! * `until_fail` - runs its child until it fails
+//! * leaves - which do the actual work, see `BehaviorLeaves`
+//!
+//! Each entity's `Behavior` has a blackboard of values its leaves can share.
+use log::{trace, warn};
+use serde_json::Value;
+use specs::prelude::*;
+use std::{collections::HashMap, sync::Arc};
+use wasm_bindgen_futures::spawn_local;
+
+use super::super::{
+    fetch,
+    prelude::{
+        Animation, Exile, FPSCounter, Gravity, LoadStatus, LoadableResources, MapGravity, MaxSpeed,
+        Name, Platformer, Position, Resources, SharedResource, Sprite, Velocity, Zone,
+    },
+    resources,
+};
+
+mod leaves;
+mod parse;
+pub use self::{leaves::*, parse::*};
+
+
+/// The result of ticking a node of a behavior tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BehaviorStatus {
+    Success,
+    Failure,
+    Running,
+}
+
+
+/// What a leaf knows about the entity it's ticking for.
+pub struct LeafContext<'t> {
+    pub entity: Entity,
+
+    /// Seconds since the leaf started running.
+    pub elapsed: f32,
+
+    /// Seconds since the last tick.
+    pub dt: f32,
+
+    /// The entity's behavior's blackboard.
+    pub blackboard: &'t mut HashMap<String, Value>,
+}
+
+
+/// A leaf of a behavior tree. Leaves are made from their parameters by a
+/// factory registered in `BehaviorLeaves`, then ticked once per step for as
+/// long as they are running.
+pub trait BehaviorLeaf: Send + Sync {
+    fn tick(&self, context: &mut LeafContext, data: &mut BehaviorData) -> BehaviorStatus;
+}
+
+
+/// Makes a leaf from its parameters.
+pub type LeafFactory =
+    Box<dyn Fn(&HashMap<String, Value>) -> Result<Box<dyn BehaviorLeaf>, String> + Send + Sync>;
+
+
+/// The leaves behavior trees can be built from, by name.
+///
+/// The defaults are:
+/// * `move_to` - walks to `x` and `y` or to the entity named `target`
+/// * `wait` - runs for `seconds`
+/// * `animation` - plays the entity's animation from the start, running
+///   until it ends, or succeeding right away if `repeat` is true
+/// * `keyframe` - switches the entity's sprite to the keyframe `name`
+/// * `in_zone` - succeeds if the entity is inside the zone named `zone`
+/// * `set` - sets the blackboard's `key` to `value`
+/// * `check` - succeeds if the blackboard's `key` is `value`
+///
+/// Games can register their own.
+pub struct BehaviorLeaves {
+    factories: HashMap<String, LeafFactory>,
+}
+
+
+impl Default for BehaviorLeaves {
+    fn default() -> BehaviorLeaves {
+        let mut leaves = BehaviorLeaves {
+            factories: HashMap::new(),
+        };
+        leaves.register("move_to", MoveTo::from_params);
+        leaves.register("wait", Wait::from_params);
+        leaves.register("animation", PlayAnimation::from_params);
+        leaves.register("keyframe", Keyframe::from_params);
+        leaves.register("in_zone", InZone::from_params);
+        leaves.register("set", SetValue::from_params);
+        leaves.register("check", CheckValue::from_params);
+        leaves
+    }
+}
+
+
+impl BehaviorLeaves {
+    /// Register a leaf, replacing any other with the same name.
+    pub fn register<F, L>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&HashMap<String, Value>) -> Result<L, String> + Send + Sync + 'static,
+        L: BehaviorLeaf + 'static,
+    {
+        self.factories.insert(
+            name.to_string(),
+            Box::new(move |params| {
+                factory(params).map(|leaf| Box::new(leaf) as Box<dyn BehaviorLeaf>)
+            }),
+        );
+    }
+
+
+    pub fn make(
+        &self,
+        name: &str,
+        params: &HashMap<String, Value>,
+    ) -> Result<Box<dyn BehaviorLeaf>, String> {
+        let factory = self
+            .factories
+            .get(name)
+            .ok_or_else(|| format!("unknown behavior node '{}'", name))?;
+        factory(params).map_err(|e| format!("bad '{}' leaf: {}", name, e))
+    }
+}
+
+
+enum Node {
+    Sequence(Vec<usize>),
+    Selector(Vec<usize>),
+    Invert(usize),
+    Succeed(usize),
+    Repeat(usize, Option<u32>),
+    UntilFail(usize),
+    Leaf(Box<dyn BehaviorLeaf>),
+}
+
+
+/// A behavior tree, ready to be ticked by any number of entities' `Behavior`s.
+pub struct BehaviorTree {
+    /// The nodes in depth first order, the root first.
+    nodes: Vec<Node>,
+}
+
+
+impl BehaviorTree {
+    pub fn new(spec: &BehaviorSpec, leaves: &BehaviorLeaves) -> Result<BehaviorTree, String> {
+        let mut tree = BehaviorTree { nodes: vec![] };
+        tree.add(spec, leaves)?;
+        Ok(tree)
+    }
+
+
+    /// Add a node and its children, returning the node's index.
+    fn add(&mut self, spec: &BehaviorSpec, leaves: &BehaviorLeaves) -> Result<usize, String> {
+        let index = self.nodes.len();
+        // Hold the node's place until its children are added
+        self.nodes.push(Node::Sequence(vec![]));
+        let mut children = vec![];
+        for child in spec.children.iter() {
+            children.push(self.add(child, leaves)?);
+        }
+        let only_child = || match children.as_slice() {
+            [child] => Ok(*child),
+            _ => Err(format!("'{}' must have exactly one child", spec.kind)),
+        };
+        self.nodes[index] = match spec.kind.as_str() {
+            "sequence" => Node::Sequence(children.clone()),
+            "selector" => Node::Selector(children.clone()),
+            "invert" => Node::Invert(only_child()?),
+            "succeed" => Node::Succeed(only_child()?),
+            "repeat" => {
+                let times = match spec.params.get("times") {
+                    Some(times) => Some(
+                        times
+                            .as_u64()
+                            .ok_or("'repeat' times must be a positive integer")?
+                            as u32,
+                    ),
+                    None => None,
+                };
+                Node::Repeat(only_child()?, times)
+            }
+            "until_fail" => Node::UntilFail(only_child()?),
+            kind => {
+                if !children.is_empty() {
+                    return Err(format!("leaf '{}' can't have children", kind));
+                }
+                Node::Leaf(leaves.make(kind, &spec.params)?)
+            }
+        };
+        Ok(index)
+    }
+
+
+    /// How many nodes are in the tree.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+
+/// What a node remembers between ticks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct NodeMemory {
+    /// The tick the node was last left running on.
+    last_tick: u64,
+    child: usize,
+    count: u32,
+    elapsed: f32,
+}
+
+
+impl Default for NodeMemory {
+    fn default() -> NodeMemory {
+        NodeMemory {
+            last_tick: u64::MAX,
+            child: 0,
+            count: 0,
+            elapsed: 0.0,
+        }
+    }
+}
+
+
+/// An entity's run through a behavior tree.
+pub struct Behavior {
+    /// The file the tree was loaded from, if any.
+    pub file: Option<String>,
+
+    /// Values shared between the tree's leaves.
+    pub blackboard: HashMap<String, Value>,
+
+    tree: Arc<BehaviorTree>,
+    memory: Vec<NodeMemory>,
+    ticks: u64,
+}
+
+
+impl Behavior {
+    pub fn new(tree: Arc<BehaviorTree>) -> Behavior {
+        Behavior {
+            file: None,
+            blackboard: HashMap::new(),
+            memory: vec![NodeMemory::default(); tree.len()],
+            tree,
+            ticks: 0,
+        }
+    }
+
+
+    /// Tick the tree from its root. A tree that finishes starts again from
+    /// the top on the next tick.
+    pub fn tick(&mut self, entity: Entity, dt: f32, data: &mut BehaviorData) -> BehaviorStatus {
+        self.ticks += 1;
+        if self.tree.is_empty() {
+            return BehaviorStatus::Failure;
+        }
+        let tree = self.tree.clone();
+        self.tick_node(&tree, 0, entity, dt, data)
+    }
+
+
+    fn tick_node(
+        &mut self,
+        tree: &BehaviorTree,
+        index: usize,
+        entity: Entity,
+        dt: f32,
+        data: &mut BehaviorData,
+    ) -> BehaviorStatus {
+        // A node that wasn't left running last tick starts over
+        let mut memory = self.memory[index];
+        if memory.last_tick.wrapping_add(1) != self.ticks {
+            memory = NodeMemory::default();
+        }
+
+        let status = match &tree.nodes[index] {
+            Node::Sequence(children) | Node::Selector(children) => {
+                let is_sequence = matches!(tree.nodes[index], Node::Sequence(_));
+                // Sequences carry on through successes, selectors through
+                // failures
+                let keep_going = if is_sequence {
+                    BehaviorStatus::Success
+                } else {
+                    BehaviorStatus::Failure
+                };
+                let mut status = keep_going;
+                while memory.child < children.len() {
+                    status = self.tick_node(tree, children[memory.child], entity, dt, data);
+                    if status == keep_going {
+                        memory.child += 1;
+                    } else {
+                        break;
+                    }
+                }
+                status
+            }
+            Node::Invert(child) => match self.tick_node(tree, *child, entity, dt, data) {
+                BehaviorStatus::Success => BehaviorStatus::Failure,
+                BehaviorStatus::Failure => BehaviorStatus::Success,
+                BehaviorStatus::Running => BehaviorStatus::Running,
+            },
+            Node::Succeed(child) => match self.tick_node(tree, *child, entity, dt, data) {
+                BehaviorStatus::Running => BehaviorStatus::Running,
+                _ => BehaviorStatus::Success,
+            },
+            Node::Repeat(child, times) => match self.tick_node(tree, *child, entity, dt, data) {
+                BehaviorStatus::Running => BehaviorStatus::Running,
+                _ => {
+                    // Carry on next tick, so a repeat never spins in place
+                    memory.count += 1;
+                    if times.map(|times| memory.count >= times).unwrap_or(false) {
+                        BehaviorStatus::Success
+                    } else {
+                        BehaviorStatus::Running
+                    }
+                }
+            },
+            Node::UntilFail(child) => match self.tick_node(tree, *child, entity, dt, data) {
+                BehaviorStatus::Failure => BehaviorStatus::Success,
+                _ => BehaviorStatus::Running,
+            },
+            Node::Leaf(leaf) => {
+                if memory.last_tick != u64::MAX {
+                    memory.elapsed += dt;
+                }
+                let mut context = LeafContext {
+                    entity,
+                    elapsed: memory.elapsed,
+                    dt,
+                    blackboard: &mut self.blackboard,
+                };
+                leaf.tick(&mut context, data)
+            }
+        };
+
+        self.memory[index] = if status == BehaviorStatus::Running {
+            NodeMemory {
+                last_tick: self.ticks,
+                ..memory
+            }
+        } else {
+            NodeMemory::default()
+        };
+        status
+    }
+}
+
+
+impl Component for Behavior {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Asks for the behavior tree in `file` to be loaded and given to the entity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadBehavior {
+    pub file: String,
+}
+
+
+impl LoadBehavior {
+    pub fn tiled_property() -> String {
+        "behavior".to_string()
+    }
+}
+
+
+impl Component for LoadBehavior {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Loads the text of behavior tree files.
+pub struct BehaviorResources {
+    base_url: String,
+    loads: LoadableResources<String>,
+}
+
+
+async fn load_behavior_wasm(url: String, shared: SharedResource<String>) {
+    match fetch::from_url(&url).await {
+        Ok(text) => {
+            shared.set_status_and_resource((LoadStatus::Complete, Some(text)));
+        }
+        Err(err) => {
+            shared.set_status(LoadStatus::Error(err));
+        }
+    }
+}
+
+
+impl BehaviorResources {
+    pub fn new(base_url: &str) -> Self {
+        BehaviorResources {
+            base_url: base_url.to_string(),
+            loads: LoadableResources::new(),
+        }
+    }
+}
+
+
+impl Resources<String> for BehaviorResources {
+    fn status_of(&self, key: &str) -> LoadStatus {
+        self.loads.status_of(key)
+    }
+
+    fn load(&mut self, path: &str) {
+        trace!("loading behavior '{}'", path);
+        let shared = SharedResource::default();
+        shared.set_status(LoadStatus::Started);
+        self.loads
+            .resources
+            .insert(path.to_string(), shared.clone());
+        let url = format!("{}/{}", self.base_url, path);
+
+        spawn_local(async move { load_behavior_wasm(url, shared).await });
+    }
+
+    fn take(&mut self, path: &str) -> Option<SharedResource<String>> {
+        self.loads.take(path)
+    }
+
+    fn put(&mut self, path: &str, text: SharedResource<String>) {
+        self.loads.put(path, text)
+    }
+}
+
+
+/// Everything a behavior tree's leaves can reach. Anything else can be done
+/// through `lazy`.
+#[derive(SystemData)]
+pub struct BehaviorData<'a> {
+    pub animations: WriteStorage<'a, Animation>,
+    pub entities: Entities<'a>,
+    pub gravities: ReadStorage<'a, Gravity>,
+    pub lazy: Read<'a, LazyUpdate>,
+    pub map_gravity: Read<'a, MapGravity>,
+    pub max_speeds: ReadStorage<'a, MaxSpeed>,
+    pub names: ReadStorage<'a, Name>,
+    pub platformers: WriteStorage<'a, Platformer>,
+    pub positions: ReadStorage<'a, Position>,
+    pub sprites: WriteStorage<'a, Sprite>,
+    pub velocities: WriteStorage<'a, Velocity>,
+    pub zones: ReadStorage<'a, Zone>,
+}
+
+
+impl<'a> BehaviorData<'a> {
+    /// The entity with the given name.
+    pub fn named(&self, name: &str) -> Option<Entity> {
+        (&self.entities, &self.names)
+            .join()
+            .find(|(_, Name(n))| n == name)
+            .map(|(ent, _)| ent)
+    }
+}
+
+
+/// Loads behavior trees and ticks every entity's `Behavior`. Runs after the
+/// AISystem, so behaviors have the last word on where NPCs go.
+pub struct BehaviorSystem {
+    resources: BehaviorResources,
+    trees: HashMap<String, Arc<BehaviorTree>>,
+}
+
+
+impl BehaviorSystem {
+    pub fn new(base_url: &str) -> Self {
+        BehaviorSystem {
+            resources: BehaviorResources::new(base_url),
+            trees: HashMap::new(),
+        }
+    }
+}
+
+
+impl<'a> System<'a> for BehaviorSystem {
+    type SystemData = (
+        Read<'a, FPSCounter>,
+        Read<'a, BehaviorLeaves>,
+        WriteStorage<'a, Behavior>,
+        ReadStorage<'a, Exile>,
+        WriteStorage<'a, LoadBehavior>,
+        BehaviorData<'a>,
+    );
+
+    fn run(&mut self, (fps, leaves, mut behaviors, exiles, mut loads, mut data): Self::SystemData) {
+        // Give entities their trees once they're loaded, building each tree
+        // once
+        let mut loaded = vec![];
+        for (ent, LoadBehavior { file }) in (&data.entities, &loads).join() {
+            if !self.trees.contains_key(file) {
+                let res = resources::when_loaded(&mut self.resources, file, |text| {
+                    BehaviorSpec::from_file_contents(file, text)
+                        .and_then(|spec| BehaviorTree::new(&spec, &leaves))
+                });
+                match res {
+                    Ok(None) => continue,
+                    Ok(Some(Ok(tree))) => {
+                        self.trees.insert(file.clone(), Arc::new(tree));
+                    }
+                    Ok(Some(Err(msg))) | Err(msg) => {
+                        warn!("could not load behavior '{}': {}", file, msg);
+                        loaded.push((ent, None));
+                        continue;
+                    }
+                }
+            }
+            loaded.push((ent, Some(file.clone())));
+        }
+        for (ent, file) in loaded.into_iter() {
+            loads.remove(ent);
+            if let Some(tree) = file.as_ref().and_then(|file| self.trees.get(file)) {
+                let mut behavior = Behavior::new(tree.clone());
+                behavior.file = file;
+                let _ = behaviors.insert(ent, behavior);
+            }
+        }
+
+        let dt = fps.last_delta();
+        let ticking: Vec<Entity> = (&data.entities, &behaviors, !&exiles)
+            .join()
+            .map(|(ent, _, ())| ent)
+            .collect();
+        for ent in ticking.into_iter() {
+            if let Some(behavior) = behaviors.get_mut(ent) {
+                behavior.tick(ent, dt, &mut data);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod behavior_tests {
+    use super::*;
+
+    /// Counts its ticks on the blackboard and then finishes with its status.
+    struct Count {
+        key: String,
+        ticks: u64,
+        status: BehaviorStatus,
+    }
+
+    impl BehaviorLeaf for Count {
+        fn tick(&self, context: &mut LeafContext, _: &mut BehaviorData) -> BehaviorStatus {
+            let count = context
+                .blackboard
+                .get(&self.key)
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0);
+            context
+                .blackboard
+                .insert(self.key.clone(), Value::from(count + 1));
+            if context.elapsed >= self.ticks as f32 - 1.5 {
+                self.status
+            } else {
+                BehaviorStatus::Running
+            }
+        }
+    }
+
+    fn count(params: &HashMap<String, Value>) -> Result<Count, String> {
+        let status = match params.get("status").and_then(|v| v.as_str()) {
+            Some("failure") => BehaviorStatus::Failure,
+            _ => BehaviorStatus::Success,
+        };
+        Ok(Count {
+            key: params
+                .get("key")
+                .and_then(|v| v.as_str())
+                .ok_or("no key")?
+                .to_string(),
+            ticks: params.get("ticks").and_then(|v| v.as_u64()).unwrap_or(1),
+            status,
+        })
+    }
+
+    fn behavior(world: &mut World, text: &str) -> Behavior {
+        BehaviorData::setup(world);
+        let mut leaves = BehaviorLeaves::default();
+        leaves.register("count", count);
+        let spec = BehaviorSpec::from_text(text).unwrap();
+        Behavior::new(Arc::new(BehaviorTree::new(&spec, &leaves).unwrap()))
+    }
+
+    fn counted(behavior: &Behavior, key: &str) -> u64 {
+        behavior
+            .blackboard
+            .get(key)
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn composites_and_decorators() {
+        let mut world = World::new();
+        let mut tree = behavior(
+            &mut world,
+            r#"
+selector
+  sequence
+    count key=a ticks=2
+    count key=b status=failure
+    count key=never
+  invert
+    count key=c status=failure
+  count key=never
+"#,
+        );
+        let ent = world.create_entity().build();
+        let tick = |tree: &mut Behavior| tree.tick(ent, 1.0, &mut world.system_data());
+
+        // "a" runs for two ticks, then "b" fails the sequence and "c" is
+        // inverted into a success
+        assert_eq!(tick(&mut tree), BehaviorStatus::Running);
+        assert_eq!(tick(&mut tree), BehaviorStatus::Success);
+        assert_eq!(
+            (
+                counted(&tree, "a"),
+                counted(&tree, "b"),
+                counted(&tree, "c")
+            ),
+            (2, 1, 1)
+        );
+        assert_eq!(counted(&tree, "never"), 0);
+        // It starts over from the top
+        tick(&mut tree);
+        assert_eq!(counted(&tree, "a"), 3);
+    }
+
+    #[test]
+    fn repeats_finish_on_later_ticks() {
+        let mut world = World::new();
+        let mut tree = behavior(
+            &mut world,
+            r#"
+sequence
+  repeat times=3
+    count key=a
+  until_fail
+    sequence
+      check key=a value=3
+      count key=b
+      set key=a value=0
+"#,
+        );
+        let ent = world.create_entity().build();
+        let mut statuses = vec![];
+        for _ in 0..5 {
+            statuses.push(tree.tick(ent, 1.0, &mut world.system_data()));
+        }
+        use BehaviorStatus::*;
+        // "a" is counted to three, then "b" is counted once before the check
+        // fails and the tree starts over
+        assert_eq!(statuses, vec![Running, Running, Running, Success, Running]);
+        assert_eq!((counted(&tree, "a"), counted(&tree, "b")), (1, 1));
+
+        let leaves = BehaviorLeaves::default();
+        let build =
+            |text: &str| BehaviorTree::new(&BehaviorSpec::from_text(text).unwrap(), &leaves);
+        assert!(build("dance").is_err());
+        assert!(build("invert").is_err());
+        assert!(build("wait seconds=1\n  wait seconds=1").is_err());
+    }
+}
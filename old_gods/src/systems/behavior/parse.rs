@@ -0,0 +1,246 @@
+//! Reading behavior trees out of JSON and text.
+//!
+//! In JSON every node is an object with a "type", its children in "children"
+//! or its only child in "child", and any other keys as its parameters:
+//!
+//! ```json
+//! {
+//!   "type": "sequence",
+//!   "children": [
+//!     { "type": "move_to", "x": 32, "y": 64 },
+//!     { "type": "wait", "seconds": 2 }
+//!   ]
+//! }
+//! ```
+//!
+//! The text format is the same tree, one node per line with its parameters as
+//! `key=value` pairs and its children indented below it. Values are JSON, and
+//! anything that isn't JSON is a string. Lines starting with `#` are comments.
+//!
+//! ```text
+//! sequence
+//!   move_to x=32 y=64
+//!   wait seconds=2
+//! ```
+use serde_json::Value;
+use std::collections::HashMap;
+
+
+/// One node of a behavior tree as it was written, before its leaves have been
+/// looked up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BehaviorSpec {
+    pub kind: String,
+    pub params: HashMap<String, Value>,
+    pub children: Vec<BehaviorSpec>,
+}
+
+
+impl BehaviorSpec {
+    pub fn new(kind: &str) -> BehaviorSpec {
+        BehaviorSpec {
+            kind: kind.to_string(),
+            params: HashMap::new(),
+            children: vec![],
+        }
+    }
+
+
+    pub fn param<V: Into<Value>>(mut self, name: &str, value: V) -> BehaviorSpec {
+        self.params.insert(name.to_string(), value.into());
+        self
+    }
+
+
+    pub fn child(mut self, child: BehaviorSpec) -> BehaviorSpec {
+        self.children.push(child);
+        self
+    }
+
+
+    /// Read a tree from a file's contents, as JSON if the file ends in
+    /// ".json" and as text otherwise.
+    pub fn from_file_contents(file: &str, contents: &str) -> Result<BehaviorSpec, String> {
+        if file.ends_with(".json") {
+            let value: Value = serde_json::from_str(contents)
+                .map_err(|e| format!("could not parse '{}': {}", file, e))?;
+            BehaviorSpec::from_json(&value)
+        } else {
+            BehaviorSpec::from_text(contents)
+        }
+    }
+
+
+    pub fn from_json(value: &Value) -> Result<BehaviorSpec, String> {
+        let object = value
+            .as_object()
+            .ok_or_else(|| format!("a behavior node must be an object, not {}", value))?;
+        let kind = object
+            .get("type")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| format!("behavior node {} is missing its 'type'", value))?;
+        let mut spec = BehaviorSpec::new(kind);
+        for (key, value) in object.iter() {
+            match key.as_str() {
+                "type" => {}
+                "child" => {
+                    spec.children.push(BehaviorSpec::from_json(value)?);
+                }
+                "children" => {
+                    let children = value
+                        .as_array()
+                        .ok_or_else(|| format!("'{}' children must be an array", kind))?;
+                    for child in children.iter() {
+                        spec.children.push(BehaviorSpec::from_json(child)?);
+                    }
+                }
+                _ => {
+                    spec.params.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        Ok(spec)
+    }
+
+
+    pub fn from_text(text: &str) -> Result<BehaviorSpec, String> {
+        // The nodes still open and how far each was indented
+        let mut stack: Vec<(usize, BehaviorSpec)> = vec![];
+        let mut roots = vec![];
+        for (line_ndx, line) in text.lines().enumerate() {
+            let content = line.trim();
+            if content.is_empty() || content.starts_with('#') {
+                continue;
+            }
+            let indent = line.len() - line.trim_start().len();
+            let spec = parse_line(content).map_err(|e| format!("line {}: {}", line_ndx + 1, e))?;
+            while stack.last().map(|(i, _)| *i >= indent).unwrap_or(false) {
+                let (_, done) = stack.pop().unwrap();
+                close(&mut stack, &mut roots, done);
+            }
+            stack.push((indent, spec));
+        }
+        while let Some((_, done)) = stack.pop() {
+            close(&mut stack, &mut roots, done);
+        }
+        match roots.len() {
+            1 => Ok(roots.pop().unwrap()),
+            0 => Err("a behavior tree needs a node".to_string()),
+            n => Err(format!("a behavior tree has one root, found {}", n)),
+        }
+    }
+}
+
+
+/// Add a finished node to its parent, or to the roots if it has none.
+fn close(stack: &mut [(usize, BehaviorSpec)], roots: &mut Vec<BehaviorSpec>, done: BehaviorSpec) {
+    match stack.last_mut() {
+        Some((_, parent)) => parent.children.push(done),
+        None => roots.push(done),
+    }
+}
+
+
+/// Split a line into words, keeping quoted strings whole.
+fn words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in line.chars() {
+        if quoted {
+            word.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                quoted = false;
+            }
+        } else if c.is_whitespace() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+        } else {
+            if c == '"' {
+                quoted = true;
+            }
+            word.push(c);
+        }
+    }
+    if quoted {
+        return Err(format!("unterminated string in '{}'", line));
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+
+fn parse_line(line: &str) -> Result<BehaviorSpec, String> {
+    let words = words(line)?;
+    let mut spec = BehaviorSpec::new(&words[0]);
+    for word in words[1..].iter() {
+        let mut split = word.splitn(2, '=');
+        let key = split.next().unwrap_or("");
+        let value = split
+            .next()
+            .ok_or_else(|| format!("'{}' is not a key=value parameter", word))?;
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+        spec.params.insert(key.to_string(), value);
+    }
+    Ok(spec)
+}
+
+
+#[cfg(test)]
+mod parse_tests {
+    use super::*;
+
+    #[test]
+    fn text_and_json_read_the_same() {
+        let text = r#"
+# Guard the gate
+selector
+  sequence
+    in_zone zone="the gate"
+    keyframe name=alert
+  repeat times=2
+    wait seconds=0.5
+"#;
+        let json = r#"{
+          "type": "selector",
+          "children": [
+            { "type": "sequence", "children": [
+              { "type": "in_zone", "zone": "the gate" },
+              { "type": "keyframe", "name": "alert" }
+            ]},
+            { "type": "repeat", "times": 2, "child": { "type": "wait", "seconds": 0.5 } }
+          ]
+        }"#;
+        let expected = BehaviorSpec::new("selector")
+            .child(
+                BehaviorSpec::new("sequence")
+                    .child(BehaviorSpec::new("in_zone").param("zone", "the gate"))
+                    .child(BehaviorSpec::new("keyframe").param("name", "alert")),
+            )
+            .child(
+                BehaviorSpec::new("repeat")
+                    .param("times", 2)
+                    .child(BehaviorSpec::new("wait").param("seconds", 0.5)),
+            );
+        assert_eq!(
+            BehaviorSpec::from_file_contents("guard.txt", text),
+            Ok(expected.clone())
+        );
+        assert_eq!(
+            BehaviorSpec::from_file_contents("guard.json", json),
+            Ok(expected)
+        );
+
+        assert!(BehaviorSpec::from_text("wait\nwait").is_err());
+        assert!(BehaviorSpec::from_text("wait seconds").is_err());
+        assert!(BehaviorSpec::from_text("in_zone zone=\"gate").is_err());
+    }
+}
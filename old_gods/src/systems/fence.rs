@@ -107,7 +107,10 @@ pub fn run_fence(
                 continue;
             }
             // Add this thing so we can check it next frame
-            let entity_center = AABB::from_mbr(bounds).center();
+            let entity_center = aabb_tree
+                .aabb(entity)
+                .unwrap_or_else(|| AABB::from_mbr(bounds))
+                .center();
             fence.watching.insert(entity, entity_center);
             // Continue on to the next entity if we already know this one crossed
             if fence.crossed.contains_key(&entity) {
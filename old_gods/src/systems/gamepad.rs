@@ -18,17 +18,20 @@
 //!
 //! NOTE: All of this is tuned to my preference and should probably be made
 //! configurable.
-use js_sys::Reflect;
-use log::trace;
-use specs::prelude::{System, SystemData, World, WorldExt, Write};
+#[cfg(feature = "sdl2")]
+use sdl2::{
+    controller::{Axis, Button, GameController},
+    GameControllerSubsystem, Sdl,
+};
+use specs::prelude::{System, SystemData, World, Write};
 use std::{
     cell::{Cell, RefCell},
     collections::HashMap,
     rc::Rc,
     sync::{Arc, Mutex},
 };
-use wasm_bindgen::{closure::Closure, JsCast, JsValue};
-use web_sys::{window, Gamepad, GamepadButton};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{Gamepad, GamepadButton};
 
 use super::super::{geom::V2, time::Millis};
 
@@ -343,12 +346,18 @@ impl PlayerController {
         )
     }
 
-    fn step_gamepad(&mut self, gamepad: &Gamepad) {
+    /// Move on to the next frame, given the stick's axes, if the controller
+    /// has them, and whether each of its buttons are held.
+    fn step(
+        &mut self,
+        x_axis: Option<f32>,
+        y_axis: Option<f32>,
+        buttons: &[(ControllerEventName, bool)],
+    ) {
         self.last_frame = std::mem::replace(&mut self.this_frame, ControllerState::new());
 
         let now = Millis::now();
-        let axes = gamepad.axes();
-        if let Some(x) = axes.get(0).as_f64().map(|x| x as f32) {
+        if let Some(x) = x_axis {
             self.this_frame.x_axis = x;
             if x > ANALOG_DEADZONE {
                 self.this_frame.right = true;
@@ -388,7 +397,7 @@ impl PlayerController {
             }
         }
 
-        if let Some(y) = axes.get(1).as_f64().map(|y| y as f32) {
+        if let Some(y) = y_axis {
             self.this_frame.y_axis = y;
             if y > ANALOG_DEADZONE {
                 self.this_frame.down = true;
@@ -425,6 +434,39 @@ impl PlayerController {
             }
         }
 
+        for (event, pressed) in buttons {
+            let pressed = *pressed;
+            match event {
+                ControllerEventName::A => {
+                    self.this_frame.a = pressed;
+                }
+                ControllerEventName::B => {
+                    self.this_frame.b = pressed;
+                }
+                ControllerEventName::X => {
+                    self.this_frame.x = pressed;
+                }
+                ControllerEventName::Y => {
+                    self.this_frame.y = pressed;
+                }
+                ControllerEventName::Back => {
+                    self.this_frame.back = pressed;
+                }
+                ControllerEventName::Start => {
+                    self.this_frame.start = pressed;
+                }
+                _ => {}
+            }
+        }
+
+        self.debouncing.set(false);
+    }
+
+    fn step_gamepad(&mut self, gamepad: &Gamepad) {
+        let axes = gamepad.axes();
+        let x_axis = axes.get(0).as_f64().map(|x| x as f32);
+        let y_axis = axes.get(1).as_f64().map(|y| y as f32);
+
         let ndx_to_event = |ndx: u32| -> Option<ControllerEventName> {
             match ndx {
                 0 => Some(ControllerEventName::A),
@@ -437,48 +479,21 @@ impl PlayerController {
             }
         };
 
-        //let mut msgs = vec![];
         let buttons = gamepad.buttons();
+        let mut pressed = vec![];
         for btn_ndx in 0..buttons.length() {
             let val: JsValue = buttons.get(btn_ndx);
             match val.dyn_into::<GamepadButton>() {
                 Ok(button) => {
-                    let pressed = button.pressed();
-                    let _value = button.value();
                     if let Some(event) = ndx_to_event(btn_ndx) {
-                        match event {
-                            ControllerEventName::A => {
-                                self.this_frame.a = pressed;
-                            }
-                            ControllerEventName::B => {
-                                self.this_frame.b = pressed;
-                            }
-                            ControllerEventName::X => {
-                                self.this_frame.x = pressed;
-                            }
-                            ControllerEventName::Y => {
-                                self.this_frame.y = pressed;
-                            }
-                            ControllerEventName::Back => {
-                                self.this_frame.back = pressed;
-                            }
-                            ControllerEventName::Start => {
-                                self.this_frame.start = pressed;
-                            }
-                            _ => {}
-                        }
-                    } else {
-                        //msgs.push(format!("button {} {}", btn_ndx, pressed));
+                        pressed.push((event, button.pressed()));
                     }
                 }
                 Err(err) => panic!("TODO: Support GamepadButton on other browsers: {:#?}", err),
             }
         }
 
-        self.debouncing.set(false);
-        //if msgs.len() > 0 {
-        //  trace!("{}", msgs.join("\n"));
-        //}
+        self.step(x_axis, y_axis, &pressed);
     }
 }
 
@@ -554,10 +569,8 @@ impl PlayerControllers {
 }
 
 
+/// Steps player controllers with the browser's gamepads.
 pub struct GamepadSystem {
-    //pub event_pump: EventPump,
-    //pub controller_system: GameControllerSubsystem,
-    //sdl_controllers: Vec<GameController>,
     web_controllers: Rc<RefCell<HashMap<u32, Gamepad>>>,
 }
 
@@ -570,11 +583,17 @@ impl Default for GamepadSystem {
     }
 }
 
-impl<'a> System<'a> for GamepadSystem {
-    type SystemData = Write<'a, PlayerControllers>;
 
-    fn setup(&mut self, world: &mut World) {
-        Self::SystemData::setup(world);
+impl GamepadSystem {
+    /// Add and remove player controllers as the browser connects and
+    /// disconnects gamepads.
+    #[cfg(target_arch = "wasm32")]
+    fn listen_for_gamepads(&self, world: &mut World) {
+        use js_sys::Reflect;
+        use log::trace;
+        use specs::prelude::WorldExt;
+        use wasm_bindgen::closure::Closure;
+        use web_sys::window;
 
         {
             let pc = world.write_resource::<PlayerControllers>();
@@ -659,6 +678,18 @@ impl<'a> System<'a> for GamepadSystem {
             cb.forget();
         }
     }
+}
+
+
+impl<'a> System<'a> for GamepadSystem {
+    type SystemData = Write<'a, PlayerControllers>;
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+
+        #[cfg(target_arch = "wasm32")]
+        self.listen_for_gamepads(world);
+    }
 
     fn run(&mut self, ui: Self::SystemData) {
         //// Debug printing
@@ -675,20 +706,19 @@ impl<'a> System<'a> for GamepadSystem {
         if let Ok(mut controllers) = ui.controllers.try_lock() {
             for (ndx, player_controller) in controllers.iter_mut() {
                 let gamepads = self.web_controllers.borrow();
+                // Controllers without a gamepad are stepped by other systems
                 if let Some(gamepad) = gamepads.get(&ndx) {
                     player_controller.step_gamepad(gamepad);
 
-                //let ctrl = player_controller;
-                //debug_btn("left", ctrl.left());
-                //debug_btn("right", ctrl.right());
-                //debug_btn("up", ctrl.up());
-                //debug_btn("down", ctrl.down());
-                //debug_btn("a", ctrl.a());
-                //debug_btn("b", ctrl.b());
-                //debug_btn("x", ctrl.x());
-                //debug_btn("y", ctrl.y());
-                } else {
-                    panic!("no gamepad at index: {}", ndx);
+                    //let ctrl = player_controller;
+                    //debug_btn("left", ctrl.left());
+                    //debug_btn("right", ctrl.right());
+                    //debug_btn("up", ctrl.up());
+                    //debug_btn("down", ctrl.down());
+                    //debug_btn("a", ctrl.a());
+                    //debug_btn("b", ctrl.b());
+                    //debug_btn("x", ctrl.x());
+                    //debug_btn("y", ctrl.y());
                 }
             }
         } else {
@@ -696,3 +726,116 @@ impl<'a> System<'a> for GamepadSystem {
         }
     }
 }
+
+
+/// Steps player controllers with SDL game controllers.
+///
+/// Controllers are opened as they are plugged in and dropped when they are
+/// unplugged. SDL only updates them when its events are pumped, so the event
+/// pump has to be polled every frame.
+///
+/// SDL's device indices shift as controllers come and go, so open controllers
+/// are tracked by their instance id. Each is given the lowest free player
+/// slot when it is opened and keeps it until it is unplugged.
+#[cfg(feature = "sdl2")]
+pub struct Sdl2GamepadSystem {
+    subsystem: GameControllerSubsystem,
+    controllers: HashMap<i32, (u32, GameController)>,
+}
+
+
+#[cfg(feature = "sdl2")]
+impl Sdl2GamepadSystem {
+    pub fn new(sdl: &Sdl) -> Result<Sdl2GamepadSystem, String> {
+        Ok(Sdl2GamepadSystem {
+            subsystem: sdl.game_controller()?,
+            controllers: HashMap::new(),
+        })
+    }
+
+    /// Open newly attached controllers and drop detached ones, returning the
+    /// player slots of those dropped.
+    fn update_attached(&mut self) -> Vec<u32> {
+        let detached: Vec<i32> = self
+            .controllers
+            .iter()
+            .filter(|(_, (_, controller))| !controller.attached())
+            .map(|(id, _)| *id)
+            .collect();
+        let players = detached
+            .iter()
+            .filter_map(|id| self.controllers.remove(id))
+            .map(|(player, _)| player)
+            .collect();
+
+        let num_joysticks = self.subsystem.num_joysticks().unwrap_or(0);
+        let indices: Vec<u32> = (0..num_joysticks)
+            .filter(|ndx| self.subsystem.is_game_controller(*ndx))
+            .collect();
+        if indices.len() <= self.controllers.len() {
+            return players;
+        }
+        // There's no way to tell which device indices are already open
+        // without opening them, which hands back the same controller
+        for ndx in indices {
+            let controller = match self.subsystem.open(ndx) {
+                Ok(controller) => controller,
+                Err(err) => {
+                    log::warn!("could not open controller {}: {}", ndx, err);
+                    continue;
+                }
+            };
+            let id = controller.instance_id();
+            if self.controllers.contains_key(&id) {
+                continue;
+            }
+            let mut player = 0;
+            while self.controllers.values().any(|(taken, _)| *taken == player) {
+                player += 1;
+            }
+            log::trace!(
+                "Controller {} connected as player {}: {}",
+                id,
+                player,
+                controller.name()
+            );
+            self.controllers.insert(id, (player, controller));
+        }
+        players
+    }
+}
+
+
+#[cfg(feature = "sdl2")]
+impl<'a> System<'a> for Sdl2GamepadSystem {
+    type SystemData = Write<'a, PlayerControllers>;
+
+    fn run(&mut self, ui: Self::SystemData) {
+        let detached = self.update_attached();
+        if let Ok(mut player_controllers) = ui.controllers.try_lock() {
+            for player in detached {
+                player_controllers.remove(&player);
+            }
+            for (player, controller) in self.controllers.values() {
+                let buttons = [
+                    (ControllerEventName::A, controller.button(Button::A)),
+                    (ControllerEventName::B, controller.button(Button::B)),
+                    (ControllerEventName::X, controller.button(Button::X)),
+                    (ControllerEventName::Y, controller.button(Button::Y)),
+                    (ControllerEventName::Back, controller.button(Button::Back)),
+                    (ControllerEventName::Start, controller.button(Button::Start)),
+                ];
+                player_controllers
+                    .entry(*player)
+                    .or_insert_with(PlayerController::default)
+                    .step(
+                        Some(scale_i16(controller.axis(Axis::LeftX))),
+                        Some(scale_i16(controller.axis(Axis::LeftY))),
+                        &buttons,
+                    );
+            }
+        } else {
+            panic!("no lock on controllers!");
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Keeps the NavMesh resource in sync with the barriers on the map.
+//!
+//! Every barrier that doesn't move is an obstacle in the navmesh. Barriers
+//! that move, are sensors or have been exiled are not. When an obstacle is
+//! added, moved, reshaped or removed only the chunks of the navmesh around it
+//! are rebuilt.
+use specs::prelude::*;
+use std::collections::HashSet;
+
+use super::super::prelude::{Barrier, Exile, NavMesh, Position, Sensor, Shape, Velocity};
+
+
+pub struct NavMeshSystem;
+
+
+#[derive(SystemData)]
+pub struct NavMeshSystemData<'a> {
+    barriers: ReadStorage<'a, Barrier>,
+    entities: Entities<'a>,
+    exiles: ReadStorage<'a, Exile>,
+    nav_mesh: Write<'a, NavMesh>,
+    positions: ReadStorage<'a, Position>,
+    sensors: ReadStorage<'a, Sensor>,
+    shapes: ReadStorage<'a, Shape>,
+    velocities: ReadStorage<'a, Velocity>,
+}
+
+
+impl<'a> System<'a> for NavMeshSystem {
+    type SystemData = NavMeshSystemData<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        if data.nav_mesh.bounds().is_none() {
+            return;
+        }
+
+        // Only clone the obstacles that have changed
+        let mut ids = HashSet::new();
+        let mut changed = vec![];
+        for (ent, _, shape, Position(position), (), (), ()) in (
+            &data.entities,
+            &data.barriers,
+            &data.shapes,
+            &data.positions,
+            !&data.exiles,
+            !&data.sensors,
+            !&data.velocities,
+        )
+            .join()
+        {
+            ids.insert(ent.id());
+            let is_current = data
+                .nav_mesh
+                .obstacle(ent)
+                .map(|obstacle| obstacle.shape == *shape && obstacle.position == *position)
+                .unwrap_or(false);
+            if !is_current {
+                changed.push((ent, shape.clone(), *position));
+            }
+        }
+
+        let removed: Vec<Entity> = data
+            .nav_mesh
+            .obstacle_ids()
+            .filter(|id| !ids.contains(id))
+            .map(|id| data.entities.entity(*id))
+            .collect();
+        for ent in removed.into_iter() {
+            data.nav_mesh.remove_obstacle(ent);
+        }
+        for (ent, shape, position) in changed.into_iter() {
+            data.nav_mesh.insert_obstacle(ent, shape, position);
+        }
+
+        if data.nav_mesh.needs_rebuild() {
+            data.nav_mesh.rebuild();
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Answers requests for paths across the TileGrid.
+//!
+//! An entity that wants to get somewhere is given a `PathRequest`. The
+//! `PathfindingSystem` searches the grid for a handful of pending requests
+//! each frame, so a crowd asking at once doesn't stall a single frame, and
+//! leaves the answer in the request for whatever is steering the entity.
+use specs::prelude::*;
+
+use super::super::prelude::{Exile, GridMovement, Position, TileGrid, V2};
+
+
+/// How many paths the PathfindingSystem searches for each frame, by default.
+pub const PATH_SEARCHES_PER_FRAME: usize = 8;
+
+
+/// The answer to a PathRequest.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathStatus {
+    /// The request hasn't been answered yet.
+    Pending,
+
+    /// The waypoints to walk through, ending at the request's destination.
+    Found(Vec<V2>),
+
+    /// There is no way there.
+    NotFound,
+}
+
+
+/// A request for a path from the entity's position to somewhere else on the
+/// tile grid. Replace the request to ask again, eg. when the destination
+/// moves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathRequest {
+    pub to: V2,
+    pub movement: GridMovement,
+    pub status: PathStatus,
+}
+
+
+impl PathRequest {
+    pub fn new(to: V2, movement: GridMovement) -> PathRequest {
+        PathRequest {
+            to,
+            movement,
+            status: PathStatus::Pending,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == PathStatus::Pending
+    }
+
+    /// The path found, if any.
+    pub fn path(&self) -> Option<&Vec<V2>> {
+        match &self.status {
+            PathStatus::Found(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+
+impl Component for PathRequest {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Searches the TileGrid for pending PathRequests, a few each frame.
+pub struct PathfindingSystem {
+    /// How many requests to answer each frame.
+    pub searches_per_frame: usize,
+
+    /// The id of the entity to start answering from next frame, so every
+    /// request gets its turn.
+    next_id: u32,
+}
+
+
+impl Default for PathfindingSystem {
+    fn default() -> PathfindingSystem {
+        PathfindingSystem {
+            searches_per_frame: PATH_SEARCHES_PER_FRAME,
+            next_id: 0,
+        }
+    }
+}
+
+
+#[derive(SystemData)]
+pub struct PathfindingSystemData<'a> {
+    entities: Entities<'a>,
+    exiles: ReadStorage<'a, Exile>,
+    path_requests: WriteStorage<'a, PathRequest>,
+    positions: ReadStorage<'a, Position>,
+    tile_grid: Read<'a, TileGrid>,
+}
+
+
+impl<'a> System<'a> for PathfindingSystem {
+    type SystemData = PathfindingSystemData<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        let mut pending: Vec<Entity> = (&data.entities, &data.path_requests, !&data.exiles)
+            .join()
+            .filter(|(_, request, ())| request.is_pending())
+            .map(|(ent, _, ())| ent)
+            .collect();
+        // Start where the last frame left off and wrap around
+        let next_id = self.next_id;
+        pending.sort_by_key(|ent| (ent.id() < next_id, ent.id()));
+        pending.truncate(self.searches_per_frame);
+        if let Some(last) = pending.last() {
+            self.next_id = last.id() + 1;
+        }
+
+        for ent in pending.into_iter() {
+            let from = match data.positions.get(ent) {
+                Some(Position(from)) => *from,
+                None => continue,
+            };
+            if let Some(request) = data.path_requests.get_mut(ent) {
+                request.status = data
+                    .tile_grid
+                    .find_path(from, request.to, request.movement)
+                    .map(PathStatus::Found)
+                    .unwrap_or(PathStatus::NotFound);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod pathfinding_tests {
+    use super::{
+        super::super::prelude::{
+            insert_map, InsertMapData, LayerBuilder, ObjectBuilder, TiledmapBuilder, TilesetBuilder,
+        },
+        *,
+    };
+
+    #[test]
+    fn maps_build_the_grid_from_their_tiles() {
+        let tileset = TilesetBuilder::new("tiles", "tiles.png", 64, 16, 16, 16)
+            .tile_object(
+                1,
+                ObjectBuilder::rect(4.0, 4.0, 8.0, 8.0)
+                    .type_is("barrier")
+                    .build(),
+            )
+            .tile_property(2, &TileGrid::tiled_cost_property(), 3.0)
+            .tile_property(3, &TileGrid::tiled_cost_property(), 0.5)
+            .build();
+        let map = TiledmapBuilder::new(4, 1, 16, 16)
+            .tileset(tileset)
+            .layer(
+                LayerBuilder::tiles("ground", 4, 1)
+                    .tile_bits(&[1, 2, 3, 4])
+                    .build(),
+            )
+            .build();
+        let mut world = World::new();
+        InsertMapData::setup(&mut world);
+        insert_map(&map, &mut world.system_data());
+
+        let grid = world.read_resource::<TileGrid>();
+        assert_eq!(grid.dimensions(), (4, 1));
+        assert_eq!(grid.cost(0, 0), Some(1.0));
+        assert_eq!(grid.cost(1, 0), None);
+        assert_eq!(grid.cost(2, 0), Some(3.0));
+        assert_eq!(grid.cost(3, 0), Some(0.5));
+    }
+
+    #[test]
+    fn answers_a_few_requests_each_frame() {
+        let mut world = World::new();
+        let mut system = PathfindingSystem {
+            searches_per_frame: 2,
+            ..PathfindingSystem::default()
+        };
+        System::setup(&mut system, &mut world);
+        let mut grid = TileGrid::new(4, 4, 10.0, 10.0);
+        grid.block(3, 3);
+        world.insert(grid);
+
+        let to = V2::new(35.0, 5.0);
+        let walled_in = V2::new(35.0, 35.0);
+        let requesters: Vec<Entity> = [to, to, walled_in]
+            .iter()
+            .map(|to| {
+                world
+                    .create_entity()
+                    .with(Position(V2::new(5.0, 5.0)))
+                    .with(PathRequest::new(*to, GridMovement::FourWay))
+                    .build()
+            })
+            .collect();
+        let status = |world: &World, ent: Entity| {
+            world
+                .read_storage::<PathRequest>()
+                .get(ent)
+                .unwrap()
+                .status
+                .clone()
+        };
+
+        system.run_now(&world);
+        let found = PathStatus::Found(vec![V2::new(15.0, 5.0), V2::new(25.0, 5.0), to]);
+        assert_eq!(status(&world, requesters[0]), found);
+        assert_eq!(status(&world, requesters[1]), found);
+        assert_eq!(status(&world, requesters[2]), PathStatus::Pending);
+
+        system.run_now(&world);
+        assert_eq!(status(&world, requesters[2]), PathStatus::NotFound);
+    }
+}
@@ -1,11 +1,20 @@
 /// Manages:
 /// * maintaining the cardinal direction an object is/was last moving in
+/// * accelerating bodies by their forces, friction and drag
+/// * resolving collisions, trading momentum between dynamic bodies
+/// * sending events when things start and stop colliding
+/// * letting things through one way barriers and sensors
+/// * moving and sliding kinematic controllers
+/// * pulling bodies down with gravity and letting platformers run and jump
+use shrev::EventChannel;
 use specs::prelude::*;
+use std::collections::BTreeMap;
 
 use super::super::prelude::{AABBTree, Cardinal, Exile, FPSCounter, Shape, ZLevel, AABB, V2};
 
+mod platformer;
+pub use self::platformer::*;
 
-// TODO: Mass and acceleration for physical bodies.
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Position(pub V2);
@@ -16,6 +25,29 @@ impl Component for Position {
 }
 
 
+impl Position {
+    /// The position to render at, `alpha` of the way from the previous
+    /// position to this one.
+    pub fn interpolated(&self, previous: Option<&PreviousPosition>, alpha: f32) -> V2 {
+        match previous {
+            Some(PreviousPosition(previous)) => *previous + (self.0 - *previous).scalar_mul(alpha),
+            None => self.0,
+        }
+    }
+}
+
+
+/// Where an entity was before the latest simulation step. The engine keeps
+/// this up to date so rendering can interpolate between steps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviousPosition(pub V2);
+
+
+impl Component for PreviousPosition {
+    type Storage = VecStorage<Self>;
+}
+
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Velocity(pub V2);
 
@@ -41,9 +73,411 @@ impl Component for Barrier {
 }
 
 
+/// Makes a barrier solid from one side only.
+///
+/// Things on the given side of the barrier are blocked by it and pushed back
+/// out that way, while things coming from any other side pass through. A
+/// ledge that can be hopped down from the north but not climbed from the
+/// south is `OneWay(Cardinal::South)`.
+///
+/// In Tiled this is the "one_way" string property of a barrier, one of
+/// "north", "east", "south" or "west".
+#[derive(Debug, Clone, PartialEq)]
+pub struct OneWay(pub Cardinal);
+
+
+impl OneWay {
+    pub fn tiled_property() -> String {
+        "one_way".to_string()
+    }
+}
+
+
+impl Component for OneWay {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// How far inside a one way barrier a thing may have been last frame and
+/// still be blocked by it.
+const ONE_WAY_TOLERANCE: f32 = 0.5;
+
+
+/// Marks a barrier as a sensor. Sensors detect overlaps, which are sent as
+/// `CollisionEvent`s, but never block or push anything.
+///
+/// In Tiled a sensor is an object with the type "sensor", which is also a
+/// zone, or any object with the boolean property "sensor" set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sensor;
+
+
+impl Sensor {
+    pub fn tiled_type() -> String {
+        "sensor".to_string()
+    }
+}
+
+
+impl Component for Sensor {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Decides which barriers collide with each other.
+///
+/// Two barriers collide when each one's category shares a bit with the
+/// other's mask. If either of them also requires the same zlevel then they
+/// must both have equal ZLevels. Barriers without a filter use the default,
+/// which collides with everything on the same zlevel.
+///
+/// In Tiled the filter is set with the integer properties
+/// "collision_category" and "collision_mask" and the boolean property
+/// "collision_same_zlevel".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollisionFilter {
+    /// The categories this barrier belongs to.
+    pub category: u32,
+
+    /// The categories this barrier collides with.
+    pub mask: u32,
+
+    /// Whether this barrier only collides with barriers on its own zlevel.
+    pub same_zlevel: bool,
+}
+
+
+impl Default for CollisionFilter {
+    fn default() -> CollisionFilter {
+        CollisionFilter {
+            category: 1,
+            mask: u32::MAX,
+            same_zlevel: true,
+        }
+    }
+}
+
+
+impl CollisionFilter {
+    pub fn tiled_category_property() -> String {
+        "collision_category".to_string()
+    }
+
+    pub fn tiled_mask_property() -> String {
+        "collision_mask".to_string()
+    }
+
+    pub fn tiled_same_zlevel_property() -> String {
+        "collision_same_zlevel".to_string()
+    }
+
+    /// Whether the categories and masks of the two filters allow them to
+    /// collide. This does not take zlevels into account.
+    pub fn accepts(&self, other: &CollisionFilter) -> bool {
+        self.category & other.mask != 0 && other.category & self.mask != 0
+    }
+}
+
+
+impl Component for CollisionFilter {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// The mass of a dynamic body.
+///
+/// Bodies with a mass and a velocity are dynamic - they respond to forces and
+/// are pushed around by collisions. Moving bodies without a mass are
+/// kinematic, they only move by having their velocity set and act as if their
+/// mass were infinite. Barriers without a velocity are static.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mass(pub f32);
+
+
+impl Mass {
+    pub fn tiled_property() -> String {
+        "mass".to_string()
+    }
+
+    /// One over the mass, or zero for a body that cannot be moved.
+    pub fn inverse(&self) -> f32 {
+        if self.0 > 0.0 {
+            1.0 / self.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Change the velocity of a body with this mass by an instantaneous
+    /// impulse, eg. to knock it back.
+    pub fn apply_impulse(&self, velocity: &mut Velocity, impulse: V2) {
+        velocity.0 += impulse.scalar_mul(self.inverse());
+    }
+}
+
+
+impl Component for Mass {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// A constant acceleration, regardless of mass.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acceleration(pub V2);
+
+
+impl Component for Acceleration {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// A force acting on a dynamic body. Forces are cleared after every physics
+/// step, so a force must be inserted each frame it should act.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Force(pub V2);
+
+
+impl Component for Force {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Slows a moving body down by a constant amount, in units per second per
+/// second, until it stops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Friction(pub f32);
+
+
+impl Friction {
+    pub fn tiled_property() -> String {
+        "friction".to_string()
+    }
+}
+
+
+impl Component for Friction {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Slows a moving body down in proportion to its speed. This is the fraction
+/// of its velocity the body loses each second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drag(pub f32);
+
+
+impl Drag {
+    pub fn tiled_property() -> String {
+        "drag".to_string()
+    }
+}
+
+
+impl Component for Drag {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// How bouncy a body is, from 0.0 where colliding bodies stop dead to 1.0
+/// where they bounce apart without losing any speed. When two bodies collide
+/// the bouncier of the two is used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Restitution(pub f32);
+
+
+impl Restitution {
+    pub fn tiled_property() -> String {
+        "restitution".to_string()
+    }
+}
+
+
+impl Component for Restitution {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// A contact between a moving barrier and another barrier.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collision {
+    /// The moving thing.
+    pub entity: Entity,
+
+    /// The thing it ran into.
+    pub other: Entity,
+
+    /// The minimum translation vector that pushed `entity` out of `other`,
+    /// measured before the collision was resolved. Subtract it from the
+    /// position of `entity` to separate the two.
+    pub mtv: V2,
+
+    /// The unit contact normal, pointing from `other` towards `entity`.
+    pub normal: V2,
+}
+
+
+impl Collision {
+    pub fn new(entity: Entity, other: Entity, mtv: V2) -> Collision {
+        Collision {
+            entity,
+            other,
+            mtv,
+            normal: mtv.scalar_mul(-1.0).unitize().unwrap_or_else(V2::origin),
+        }
+    }
+
+    /// Whether this collision is between the given entity and anything else.
+    pub fn involves(&self, ent: Entity) -> bool {
+        self.entity == ent || self.other == ent
+    }
+
+    /// The pair of entities in a stable order, regardless of which one was
+    /// moving.
+    fn key(&self) -> (Entity, Entity) {
+        if self.entity < self.other {
+            (self.entity, self.other)
+        } else {
+            (self.other, self.entity)
+        }
+    }
+}
+
+
+/// Sent by `Physics` through an `EventChannel<CollisionEvent>` resource
+/// whenever two barriers that can collide start or stop touching.
+///
+/// Two barriers can collide when their `CollisionFilter`s accept each other
+/// and neither is exiled. If either filter requires the same zlevel (the
+/// default does) then both barriers must have equal ZLevels, and a barrier
+/// without a ZLevel never matches.
+///
+/// Things only collide while at least one of them is moving, and a thing
+/// pushing against a wall stays in contact for as long as it keeps pushing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CollisionEvent {
+    /// The two things collided this frame and did not the frame before.
+    Started(Collision),
+
+    /// The two things are no longer colliding. The collision is the last
+    /// one seen between them, and either entity may have been deleted.
+    Ended(Collision),
+}
+
+
+/// Opts an entity into continuous collision detection.
+///
+/// Normally a body moves by its whole velocity each frame and any overlap
+/// with barriers is resolved afterwards, which lets fast bodies pass right
+/// through thin barriers. A body with continuous collision sweeps its shape
+/// along its motion and stops or slides at the first barrier it would hit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContinuousCollision {
+    /// Stop at the point of impact.
+    Stop,
+
+    /// Slide along the surface that was hit for the rest of the motion.
+    Slide,
+}
+
+
+impl Component for ContinuousCollision {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Moves a kinematic body with "move and slide" instead of moving it by its
+/// whole velocity and pushing it out of whatever it lands in.
+///
+/// The body moves in small steps. After each step it is pushed out of the
+/// barriers it overlaps one at a time, deepest first, so it slides along
+/// walls and around corners without jittering. When it runs into something
+/// head on it tries stepping aside by up to `step_height` to get past small
+/// ledges and corners. Dynamic bodies are not obstacles, they are pushed
+/// around as usual.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KinematicController {
+    /// The furthest the body will step aside to get past an obstacle.
+    pub step_height: f32,
+
+    /// Which way is up, if any. With an up direction the body only steps up
+    /// over ledges, without one it steps to either side.
+    pub up: Option<V2>,
+
+    /// The most obstacles the body is pushed out of in each step.
+    pub max_slides: usize,
+
+    /// What the body hit during its last move.
+    pub hits: Vec<Collision>,
+}
+
+
+impl Default for KinematicController {
+    fn default() -> KinematicController {
+        KinematicController {
+            step_height: 0.0,
+            up: None,
+            max_slides: 4,
+            hits: vec![],
+        }
+    }
+}
+
+
+impl KinematicController {
+    pub fn tiled_step_height_property() -> String {
+        "step_height".to_string()
+    }
+
+    /// Whether the last move hit anything.
+    pub fn is_blocked(&self) -> bool {
+        !self.hits.is_empty()
+    }
+}
+
+
+impl Component for KinematicController {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Overlaps smaller than this are rounding errors, not collisions.
+const KINEMATIC_SKIN: f32 = 1e-4;
+
+
+/// How far a swept body is kept from the surface it hits.
+const CONTINUOUS_COLLISION_SKIN: f32 = 0.01;
+
+
+/// The most surfaces a sliding body can hit in one frame.
+const CONTINUOUS_COLLISION_MAX_SWEEPS: usize = 4;
+
+
+/// Find the translation vector that pushes a shape that moved from `previous`
+/// to `position` out of a one way barrier on its solid `side`, if the shape
+/// came from that side.
+fn one_way_mtv_between(
+    shape: &Shape,
+    previous: V2,
+    position: V2,
+    barrier_shape: &Shape,
+    barrier_position: V2,
+    side: &Cardinal,
+) -> Option<V2> {
+    let normal = side.as_v2();
+    let (_, barrier_end) = barrier_shape.ranged_projection_on(barrier_position, normal);
+    let (previous_start, _) = shape.ranged_projection_on(previous, normal);
+    if previous_start < barrier_end - ONE_WAY_TOLERANCE {
+        return None;
+    }
+    let (start, _) = shape.ranged_projection_on(position, normal);
+    Some(normal.scalar_mul(start - barrier_end))
+}
+
+
 pub struct Physics {
     pub shape_reader: Option<ReaderId<ComponentEvent>>,
     pub position_reader: Option<ReaderId<ComponentEvent>>,
+    contacts: BTreeMap<(Entity, Entity), Collision>,
 }
 
 
@@ -52,33 +486,128 @@ impl Default for Physics {
         Physics {
             shape_reader: None,
             position_reader: None,
+            contacts: BTreeMap::new(),
         }
     }
 }
 
 
+impl Physics {
+    /// Compare this frame's collisions to the last frame's and send events
+    /// for the contacts that started and ended.
+    fn send_collision_events(
+        &mut self,
+        collisions: Vec<Collision>,
+        channel: &mut EventChannel<CollisionEvent>,
+    ) {
+        let mut contacts = BTreeMap::new();
+        for collision in collisions.into_iter() {
+            contacts.entry(collision.key()).or_insert(collision);
+        }
+        let mut events = vec![];
+        for (key, collision) in self.contacts.iter() {
+            if !contacts.contains_key(key) {
+                events.push(CollisionEvent::Ended(collision.clone()));
+            }
+        }
+        for (key, collision) in contacts.iter() {
+            if !self.contacts.contains_key(key) {
+                events.push(CollisionEvent::Started(collision.clone()));
+            }
+        }
+        self.contacts = contacts;
+        channel.iter_write(events);
+    }
+}
+
+
 #[derive(SystemData)]
 pub struct PhysicsSystemData<'a> {
     aabb_tree: Write<'a, AABBTree>,
+    accelerations: ReadStorage<'a, Acceleration>,
     barriers: ReadStorage<'a, Barrier>,
     cardinals: WriteStorage<'a, Cardinal>,
+    collision_filters: ReadStorage<'a, CollisionFilter>,
+    collision_events: Write<'a, EventChannel<CollisionEvent>>,
+    continuous_collisions: ReadStorage<'a, ContinuousCollision>,
+    drags: ReadStorage<'a, Drag>,
     entities: Entities<'a>,
     exiles: ReadStorage<'a, Exile>,
+    forces: WriteStorage<'a, Force>,
     fps: Read<'a, FPSCounter>,
+    frictions: ReadStorage<'a, Friction>,
+    gravities: ReadStorage<'a, Gravity>,
+    kinematic_controllers: WriteStorage<'a, KinematicController>,
+    map_gravity: Read<'a, MapGravity>,
+    masses: ReadStorage<'a, Mass>,
+    one_ways: ReadStorage<'a, OneWay>,
+    platformers: ReadStorage<'a, Platformer>,
     positions: WriteStorage<'a, Position>,
+    previous_positions: ReadStorage<'a, PreviousPosition>,
+    restitutions: ReadStorage<'a, Restitution>,
+    sensors: ReadStorage<'a, Sensor>,
     shapes: ReadStorage<'a, Shape>,
-    velocities: ReadStorage<'a, Velocity>,
+    velocities: WriteStorage<'a, Velocity>,
     zlevels: ReadStorage<'a, ZLevel>,
 }
 
 
 impl<'a> PhysicsSystemData<'a> {
+    /// Change the velocities of things by their accelerations, gravity,
+    /// forces, friction and drag over `dt` seconds, then clear all forces.
+    pub fn accelerate_things(&mut self, dt: f32) {
+        for (ent, vel, ()) in (&self.entities, &mut self.velocities, !&self.exiles).join() {
+            let mut acceleration = self
+                .accelerations
+                .get(ent)
+                .map(|Acceleration(a)| *a)
+                .unwrap_or_else(V2::origin);
+            // Dynamic bodies and platformers feel the map's gravity unless
+            // they have their own
+            acceleration += match self.gravities.get(ent) {
+                Some(Gravity(gravity)) => *gravity,
+                None if self.masses.contains(ent) || self.platformers.contains(ent) => {
+                    self.map_gravity.0
+                }
+                None => V2::origin(),
+            };
+            if let (Some(mass), Some(Force(force))) = (self.masses.get(ent), self.forces.get(ent)) {
+                acceleration += force.scalar_mul(mass.inverse());
+            }
+            vel.0 += acceleration.scalar_mul(dt);
+
+            if let Some(Friction(friction)) = self.frictions.get(ent) {
+                let speed = vel.0.magnitude();
+                if speed > 0.0 {
+                    vel.0 = vel.0.scalar_mul((speed - friction * dt).max(0.0) / speed);
+                }
+            }
+            if let Some(Drag(drag)) = self.drags.get(ent) {
+                vel.0 = vel.0.scalar_mul((1.0 - drag * dt).max(0.0));
+            }
+        }
+        self.forces.clear();
+    }
+
     /// Move all the things that can move.
     pub fn move_things(&mut self) {
         let dt = self.fps.last_delta();
-        for (ent, vel, ()) in (&self.entities, &self.velocities, !&self.exiles).join() {
-            let v = vel.0;
-            let dxy = v.scalar_mul(dt);
+        self.accelerate_things(dt);
+        let moving: Vec<(Entity, V2)> = (&self.entities, &self.velocities, !&self.exiles)
+            .join()
+            .map(|(ent, Velocity(v), ())| (ent, *v))
+            .collect();
+        for (ent, v) in moving.into_iter() {
+            let mut dxy = v.scalar_mul(dt);
+            if let Some(controller) = self.kinematic_controllers.get(ent) {
+                let (motion, hits) = self.move_and_slide(ent, dxy, controller);
+                dxy = motion;
+                if let Some(controller) = self.kinematic_controllers.get_mut(ent) {
+                    controller.hits = hits;
+                }
+            } else if let Some(continuous) = self.continuous_collisions.get(ent) {
+                dxy = self.swept_motion(ent, dxy, *continuous);
+            }
             if dxy.magnitude() > 0.0 {
                 let pos = self
                     .positions
@@ -95,67 +624,448 @@ impl<'a> PhysicsSystemData<'a> {
         }
     }
 
-    /// For each entity that has a position, barrier, shape, zlevel and velocity -
-    /// find any collisions and deal with them.
-    /// Only adjust the positions of entities that have a velocity, that way tiles
-    /// with overlapping borders will not be moved around.
-    pub fn collide_things(&mut self) {
-        for (ent, _, _, shape, &ZLevel(z), ()) in (
-            &self.entities,
-            &self.velocities,
-            &self.barriers,
-            &self.shapes,
-            &self.zlevels,
-            !&self.exiles,
-        )
-            .join()
-        {
-            let may_pos = self.positions.get(ent);
-            if may_pos.is_none() {
-                continue;
-            }
-            let pos = may_pos.expect("Impossible").0;
-            // Query all collisions with this entity's shape.
-            // Find the new position using the minimum translation vector that pushes
-            // it out of intersection.
-            //
-            // If the resulting position is different from the previous, update the
-            // position.
-            let new_position = self
+    /// Whether the other thing blocks a kinematic body. Dynamic bodies don't,
+    /// they get pushed instead.
+    fn is_kinematic_obstacle(&self, ent: Entity, other_ent: Entity) -> bool {
+        self.barriers.contains(other_ent)
+            && !self.sensors.contains(other_ent)
+            && !(self.masses.contains(other_ent) && self.velocities.contains(other_ent))
+            && self.can_collide(ent, other_ent)
+    }
+
+    /// Find the obstacles a kinematic body overlaps at `position`.
+    fn kinematic_overlaps(&self, ent: Entity, shape: &Shape, position: V2) -> Vec<Collision> {
+        self.aabb_tree
+            .query(&self.entities, &shape.aabb().translate(&position), &ent)
+            .into_iter()
+            .filter_map(|(other_ent, _)| {
+                if !self.is_kinematic_obstacle(ent, other_ent) {
+                    return None;
+                }
+                let other_shape = self.shapes.get(other_ent)?;
+                let Position(other_position) = self.positions.get(other_ent)?;
+                let mtv = shape.mtv_apart(position, other_shape, *other_position)?;
+                if mtv.magnitude() < KINEMATIC_SKIN {
+                    return None;
+                }
+                if let Some(OneWay(side)) = self.one_ways.get(other_ent) {
+                    // Only stand on a one way barrier, never get pulled into it
+                    if mtv.dot(side.as_v2()) >= 0.0 || mtv.magnitude() > ONE_WAY_TOLERANCE {
+                        return None;
+                    }
+                }
+                Some(Collision::new(ent, other_ent, mtv))
+            })
+            .collect()
+    }
+
+    /// Push a kinematic body out of what it overlaps at `position`, deepest
+    /// first, recording what it was pushed out of.
+    fn depenetrate(
+        &self,
+        ent: Entity,
+        shape: &Shape,
+        mut position: V2,
+        max_slides: usize,
+        hits: &mut Vec<Collision>,
+    ) -> V2 {
+        for _ in 0..max_slides {
+            let deepest = self
+                .kinematic_overlaps(ent, shape, position)
+                .into_iter()
+                .max_by(|a, b| {
+                    a.mtv
+                        .magnitude()
+                        .partial_cmp(&b.mtv.magnitude())
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+            if let Some(hit) = deepest {
+                position -= hit.mtv;
+                if !hits.iter().any(|seen| seen.other == hit.other) {
+                    hits.push(hit);
+                }
+            } else {
+                break;
+            }
+        }
+        position
+    }
+
+    /// Sweep a kinematic body from `position` along `motion` and return the
+    /// first obstacle it would hit, with the time of impact and normal.
+    fn kinematic_sweep(&self, ent: Entity, position: V2, motion: V2) -> Option<(Entity, f32, V2)> {
+        self.aabb_tree
+            .query_swept_barriers(
+                &self.entities,
+                &ent,
+                position,
+                motion,
+                &self.shapes,
+                &self.positions,
+                &self.barriers,
+            )
+            .into_iter()
+            .find(|(other_ent, _, normal)| {
+                let passes_one_way = self
+                    .one_ways
+                    .get(*other_ent)
+                    .map(|OneWay(side)| normal.dot(side.as_v2()) <= 0.0)
+                    .unwrap_or(false);
+                self.is_kinematic_obstacle(ent, *other_ent) && !passes_one_way
+            })
+    }
+
+    /// Move a kinematic body by `motion`, sliding along and stepping over
+    /// what it hits. Returns how far it actually moved and what it hit.
+    ///
+    /// The body sweeps along its motion until it hits something, then slides
+    /// along that surface for the rest of the motion. Any overlaps left over
+    /// are pushed out using the minimum translation vector.
+    fn move_and_slide(
+        &self,
+        ent: Entity,
+        motion: V2,
+        controller: &KinematicController,
+    ) -> (V2, Vec<Collision>) {
+        let (shape, start) = match (self.shapes.get(ent), self.positions.get(ent)) {
+            (Some(shape), Some(Position(start))) => (shape, *start),
+            _ => return (motion, vec![]),
+        };
+        let mut hits: Vec<Collision> = vec![];
+        let mut position = self.depenetrate(ent, shape, start, controller.max_slides, &mut hits);
+        let mut remaining = motion;
+
+        for _ in 0..controller.max_slides {
+            let direction = if let Some(direction) = remaining.unitize() {
+                direction
+            } else {
+                break;
+            };
+            let (other_ent, toi, normal) =
+                if let Some(hit) = self.kinematic_sweep(ent, position, remaining) {
+                    hit
+                } else {
+                    position += remaining;
+                    break;
+                };
+
+            // Running into something head on, try stepping past it
+            if normal.dot(direction) < -0.7 && controller.step_height > 0.0 {
+                let sides = match controller.up {
+                    Some(up) => vec![up],
+                    None => vec![direction.normal(), direction.normal().scalar_mul(-1.0)],
+                };
+                let stepped = (1..=4)
+                    .flat_map(|i| {
+                        let height = controller.step_height * i as f32 / 4.0;
+                        sides
+                            .iter()
+                            .map(move |side| position + side.scalar_mul(height))
+                    })
+                    .find(|stepped| {
+                        self.kinematic_overlaps(ent, shape, *stepped).is_empty()
+                            && self
+                                .kinematic_sweep(ent, *stepped, remaining)
+                                .map(|(_, stepped_toi, _)| stepped_toi > toi)
+                                .unwrap_or(true)
+                    });
+                if let Some(stepped) = stepped {
+                    position = stepped;
+                    continue;
+                }
+            }
+
+            let rest = remaining.scalar_mul(1.0 - toi);
+            let into = rest.dot(normal);
+            position += remaining.scalar_mul(toi) + normal.scalar_mul(KINEMATIC_SKIN);
+            if !hits.iter().any(|seen| seen.other == other_ent) {
+                hits.push(Collision::new(ent, other_ent, normal.scalar_mul(into)));
+            }
+            remaining = rest - normal.scalar_mul(into);
+        }
+
+        let position = self.depenetrate(ent, shape, position, controller.max_slides, &mut hits);
+        (position - start, hits)
+    }
+
+    /// Sweep the entity's shape along `motion` and return how far it can
+    /// actually move before hitting a barrier on its zlevel.
+    fn swept_motion(&self, ent: Entity, motion: V2, continuous: ContinuousCollision) -> V2 {
+        let start = if let Some(Position(start)) = self.positions.get(ent) {
+            *start
+        } else {
+            return motion;
+        };
+        let mut pos = start;
+        let mut remaining = motion;
+        for _ in 0..CONTINUOUS_COLLISION_MAX_SWEEPS {
+            if remaining.magnitude() <= 0.0 {
+                break;
+            }
+            let hit = self
                 .aabb_tree
-                .query_intersecting_barriers(
+                .query_swept_barriers(
                     &self.entities,
                     &ent,
+                    pos,
+                    remaining,
                     &self.shapes,
                     &self.positions,
                     &self.barriers,
                 )
                 .into_iter()
-                .fold(pos, |new_pos, (other_ent, _, mtv)| {
-                    let other_z = self.zlevels.get(other_ent);
-                    let should_include =
-                        // The other thing must have a zlevel
-                        other_z.is_some()
-                        // The two things must be on the same zlevel.
-                        && (z - other_z.unwrap().0).abs() < f32::EPSILON
-                        // The other thing must not be exiled.
-                        && !self.exiles.contains(other_ent);
-                    if !should_include {
-                        return new_pos;
+                .find(|(other_ent, _, normal)| {
+                    let passes_one_way = self
+                        .one_ways
+                        .get(*other_ent)
+                        .map(|OneWay(side)| normal.dot(side.as_v2()) <= 0.0)
+                        .unwrap_or(false);
+                    self.can_collide(ent, *other_ent)
+                        && !self.sensors.contains(*other_ent)
+                        && !passes_one_way
+                });
+            if let Some((_, toi, normal)) = hit {
+                pos += remaining.scalar_mul(toi) + normal.scalar_mul(CONTINUOUS_COLLISION_SKIN);
+                remaining = match continuous {
+                    ContinuousCollision::Stop => V2::origin(),
+                    ContinuousCollision::Slide => {
+                        let rest = remaining.scalar_mul(1.0 - toi);
+                        rest - normal.scalar_mul(rest.dot(normal))
                     }
+                };
+            } else {
+                pos += remaining;
+                remaining = V2::origin();
+            }
+        }
+        pos - start
+    }
 
-                    new_pos - mtv
-                });
+    /// One over the mass of the entity, or zero if it is not dynamic.
+    fn inverse_mass(&self, ent: Entity) -> f32 {
+        if self.velocities.contains(ent) {
+            self.masses.get(ent).map(Mass::inverse).unwrap_or(0.0)
+        } else {
+            0.0
+        }
+    }
 
-            if pos != new_position {
-                let pos = self.positions.get_mut(ent).expect("Impossible");
-                pos.0 = new_position;
-                // TODO: Check if this is necessary
+    /// Move the entity by `delta`, keeping the aabb tree up to date.
+    fn nudge(&mut self, ent: Entity, delta: V2) {
+        if let Some(pos) = self.positions.get_mut(ent) {
+            pos.0 += delta;
+            let new_position = pos.0;
+            if let Some(shape) = self.shapes.get(ent) {
                 self.aabb_tree
                     .insert(ent, shape.aabb().translate(&new_position));
             }
         }
     }
+
+    /// Whether the two barriers are allowed to collide by their filters and
+    /// zlevels. Exiled things never collide.
+    fn can_collide(&self, ent: Entity, other_ent: Entity) -> bool {
+        let filter = self.collision_filters.get(ent).cloned().unwrap_or_default();
+        let other_filter = self
+            .collision_filters
+            .get(other_ent)
+            .cloned()
+            .unwrap_or_default();
+        let same_zlevel = match (self.zlevels.get(ent), self.zlevels.get(other_ent)) {
+            (Some(ZLevel(z)), Some(ZLevel(other_z))) => (z - other_z).abs() < f32::EPSILON,
+            _ => false,
+        };
+        filter.accepts(&other_filter)
+            && (same_zlevel || !(filter.same_zlevel || other_filter.same_zlevel))
+            && !self.exiles.contains(ent)
+            && !self.exiles.contains(other_ent)
+    }
+
+    /// Find the translation vector that pushes `body` out of a one way barrier
+    /// on its solid `side`, if the body came from that side.
+    ///
+    /// The body's previous position is taken from its `PreviousPosition`, or
+    /// found from its velocity if it doesn't have one.
+    fn one_way_mtv(&self, body: Entity, barrier: Entity, side: &Cardinal) -> Option<V2> {
+        let (shape, Position(position)) = (self.shapes.get(body)?, self.positions.get(body)?);
+        let (barrier_shape, Position(barrier_position)) =
+            (self.shapes.get(barrier)?, self.positions.get(barrier)?);
+        let velocity = self
+            .velocities
+            .get(body)
+            .map(|Velocity(v)| *v)
+            .unwrap_or_else(V2::origin);
+        let previous = self
+            .previous_positions
+            .get(body)
+            .map(|PreviousPosition(p)| *p)
+            .unwrap_or_else(|| *position - velocity.scalar_mul(self.fps.last_delta()));
+
+        one_way_mtv_between(
+            shape,
+            previous,
+            *position,
+            barrier_shape,
+            *barrier_position,
+            side,
+        )
+    }
+
+    /// Whether either of the two things is a sensor.
+    fn is_sensing(&self, ent: Entity, other_ent: Entity) -> bool {
+        self.sensors.contains(ent) || self.sensors.contains(other_ent)
+    }
+
+    /// Find the barriers that the entity intersects and can collide with,
+    /// along with the minimum translation vector that pushes it out of each.
+    ///
+    /// Intersections with one way barriers are only included when they
+    /// should block, and their vectors push straight out of the solid side.
+    fn intersections(&self, ent: Entity) -> Vec<(Entity, V2)> {
+        self.aabb_tree
+            .query_intersecting_barriers(
+                &self.entities,
+                &ent,
+                &self.shapes,
+                &self.positions,
+                &self.barriers,
+            )
+            .into_iter()
+            .filter(|(other_ent, _, _)| self.can_collide(ent, *other_ent))
+            .filter_map(|(other_ent, _, mtv)| {
+                if let Some(OneWay(side)) = self.one_ways.get(other_ent) {
+                    self.one_way_mtv(ent, other_ent, side)
+                        .map(|mtv| (other_ent, mtv))
+                } else if let Some(OneWay(side)) = self.one_ways.get(ent) {
+                    self.one_way_mtv(other_ent, ent, side)
+                        .map(|mtv| (other_ent, mtv.scalar_mul(-1.0)))
+                } else {
+                    Some((other_ent, mtv))
+                }
+            })
+            .collect()
+    }
+
+    /// Push two intersecting things apart in proportion to their inverse
+    /// masses and exchange an impulse between them if they are moving
+    /// together. Returns false if neither thing can be moved.
+    fn resolve(&mut self, ent: Entity, other_ent: Entity, mtv: V2) -> bool {
+        let inv_mass = self.inverse_mass(ent);
+        let other_inv_mass = self.inverse_mass(other_ent);
+        let total = inv_mass + other_inv_mass;
+        if total <= 0.0 {
+            return false;
+        }
+        self.nudge(ent, mtv.scalar_mul(-inv_mass / total));
+        self.nudge(other_ent, mtv.scalar_mul(other_inv_mass / total));
+
+        // The collision normal points from the other thing to this one
+        let normal = if let Some(normal) = mtv.scalar_mul(-1.0).unitize() {
+            normal
+        } else {
+            return true;
+        };
+        let velocity = |ent| {
+            self.velocities
+                .get(ent)
+                .map(|Velocity(v)| *v)
+                .unwrap_or_else(V2::origin)
+        };
+        let closing_speed = (velocity(ent) - velocity(other_ent)).dot(normal);
+        if closing_speed >= 0.0 {
+            // They are already moving apart
+            return true;
+        }
+        let restitution = |ent| {
+            self.restitutions
+                .get(ent)
+                .map(|Restitution(e)| *e)
+                .unwrap_or(0.0)
+        };
+        let e = restitution(ent).max(restitution(other_ent));
+        let impulse = normal.scalar_mul(-(1.0 + e) * closing_speed / total);
+        if let Some(Velocity(v)) = self.velocities.get_mut(ent) {
+            *v += impulse.scalar_mul(inv_mass);
+        }
+        if let Some(Velocity(v)) = self.velocities.get_mut(other_ent) {
+            *v -= impulse.scalar_mul(other_inv_mass);
+        }
+        true
+    }
+
+    /// For each entity that has a position, barrier, shape and velocity -
+    /// find any collisions allowed by their filters and deal with them.
+    /// Only adjust the positions of entities that have a velocity, that way tiles
+    /// with overlapping borders will not be moved around.
+    ///
+    /// Collisions are resolved in three passes:
+    /// 1. dynamic things push each other apart and are pushed by kinematic
+    ///    things
+    /// 2. all moving things are pushed out of static barriers
+    /// 3. kinematic things are pushed out of anything still in their way, so a
+    ///    crate stuck against a wall blocks the player pushing it
+    ///
+    /// Collisions with sensors are found but not resolved.
+    ///
+    /// Returns every collision found, before it was resolved.
+    pub fn collide_things(&mut self) -> Vec<Collision> {
+        let mut collisions = vec![];
+        let moving: Vec<Entity> = (
+            &self.entities,
+            &self.velocities,
+            &self.barriers,
+            &self.shapes,
+            &self.positions,
+            !&self.exiles,
+        )
+            .join()
+            .map(|(ent, _, _, _, _, ())| ent)
+            .collect();
+
+        for &ent in moving.iter() {
+            if self.masses.contains(ent) {
+                for (other_ent, mtv) in self.intersections(ent) {
+                    if self.velocities.contains(other_ent) {
+                        collisions.push(Collision::new(ent, other_ent, mtv));
+                        if !self.is_sensing(ent, other_ent) {
+                            self.resolve(ent, other_ent, mtv);
+                        }
+                    }
+                }
+            }
+        }
+
+        for &ent in moving.iter() {
+            for (other_ent, mtv) in self.intersections(ent) {
+                if self.velocities.contains(other_ent) {
+                    continue;
+                }
+                collisions.push(Collision::new(ent, other_ent, mtv));
+                if self.is_sensing(ent, other_ent) {
+                    continue;
+                }
+                if !self.resolve(ent, other_ent, mtv) {
+                    // A kinematic thing hitting a static one
+                    self.nudge(ent, mtv.scalar_mul(-1.0));
+                }
+            }
+        }
+
+        for &ent in moving.iter() {
+            if !self.masses.contains(ent) {
+                for (other_ent, mtv) in self.intersections(ent) {
+                    if self.velocities.contains(other_ent) {
+                        collisions.push(Collision::new(ent, other_ent, mtv));
+                        if !self.is_sensing(ent, other_ent) {
+                            self.nudge(ent, mtv.scalar_mul(-1.0));
+                        }
+                    }
+                }
+            }
+        }
+
+        collisions
+    }
 }
 
 
@@ -173,7 +1083,8 @@ impl<'a> System<'a> for Physics {
 
     fn run(&mut self, mut data: PhysicsSystemData) {
         data.move_things();
-        data.collide_things();
+        let collisions = data.collide_things();
+        self.send_collision_events(collisions, &mut data.collision_events);
 
         // Maintain our aabb_tree with new positions and shapes
         let shape_reader = self
@@ -205,3 +1116,292 @@ impl<'a> System<'a> for Physics {
         );
     }
 }
+
+
+#[cfg(test)]
+mod physics_tests {
+    use super::*;
+
+    #[test]
+    fn continuous_collision_stops_and_slides() {
+        let mut world = World::new();
+        PhysicsSystemData::setup(&mut world);
+
+        let wall_shape = Shape::Box {
+            lower: V2::origin(),
+            upper: V2::new(1.0, 100.0),
+        };
+        let wall_position = V2::new(40.0, 0.0);
+        let wall = world
+            .create_entity()
+            .with(Barrier)
+            .with(wall_shape.clone())
+            .with(Position(wall_position))
+            .with(ZLevel(0.0))
+            .build();
+        let bullet = world
+            .create_entity()
+            .with(Shape::box_with_size(2.0, 2.0))
+            .with(Position(V2::new(0.0, 50.0)))
+            .with(ZLevel(0.0))
+            .build();
+        world
+            .write_resource::<AABBTree>()
+            .insert(wall, wall_shape.aabb().translate(&wall_position));
+
+        let data: PhysicsSystemData = world.system_data();
+        let motion = data.swept_motion(bullet, V2::new(100.0, 10.0), ContinuousCollision::Stop);
+        assert!(motion.x < 38.0 && motion.x > 37.9, "{:?}", motion);
+        assert!(motion.y < 4.0, "{:?}", motion);
+
+        let motion = data.swept_motion(bullet, V2::new(100.0, 10.0), ContinuousCollision::Slide);
+        assert!(motion.x < 38.0, "{:?}", motion);
+        assert!((motion.y - 10.0).abs() < 0.1, "{:?}", motion);
+    }
+
+    /// Create a box barrier and add it to the aabb tree.
+    fn body(world: &mut World, x: f32, velocity: Option<f32>, mass: Option<f32>) -> Entity {
+        let shape = Shape::box_with_size(10.0, 10.0);
+        let position = V2::new(x, 0.0);
+        let mut builder = world
+            .create_entity()
+            .with(Barrier)
+            .with(shape.clone())
+            .with(Position(position))
+            .with(ZLevel(0.0));
+        if let Some(v) = velocity {
+            builder = builder.with(Velocity(V2::new(v, 0.0)));
+        }
+        if let Some(m) = mass {
+            builder = builder.with(Mass(m));
+        }
+        let ent = builder.build();
+        world
+            .write_resource::<AABBTree>()
+            .insert(ent, shape.aabb().translate(&position));
+        ent
+    }
+
+    fn x_of<T: Component + Clone>(world: &World, ent: Entity, f: impl Fn(T) -> V2) -> f32 {
+        f(world.read_storage::<T>().get(ent).cloned().unwrap()).x
+    }
+
+    #[test]
+    fn forces_and_friction_change_velocity() {
+        let mut world = World::new();
+        PhysicsSystemData::setup(&mut world);
+        let ent = body(&mut world, 0.0, Some(0.0), Some(2.0));
+        world
+            .write_storage::<Force>()
+            .insert(ent, Force(V2::new(20.0, 0.0)))
+            .unwrap();
+        world
+            .write_storage::<Friction>()
+            .insert(ent, Friction(5.0))
+            .unwrap();
+
+        let mut data: PhysicsSystemData = world.system_data();
+        data.accelerate_things(1.0);
+        drop(data);
+        assert_eq!(x_of(&world, ent, |Velocity(v)| v), 5.0);
+        assert!(world.read_storage::<Force>().get(ent).is_none());
+
+        let mut data: PhysicsSystemData = world.system_data();
+        data.accelerate_things(2.0);
+        drop(data);
+        assert_eq!(x_of(&world, ent, |Velocity(v)| v), 0.0);
+    }
+
+    #[test]
+    fn dynamic_bodies_trade_momentum() {
+        let mut world = World::new();
+        PhysicsSystemData::setup(&mut world);
+        let a = body(&mut world, 0.0, Some(10.0), Some(1.0));
+        let b = body(&mut world, 8.0, Some(-10.0), Some(1.0));
+        world
+            .write_storage::<Restitution>()
+            .insert(a, Restitution(1.0))
+            .unwrap();
+
+        let mut data: PhysicsSystemData = world.system_data();
+        data.collide_things();
+        drop(data);
+        // Pushed apart equally and bounced back
+        assert_eq!(x_of(&world, a, |Position(p)| p), -1.0);
+        assert_eq!(x_of(&world, b, |Position(p)| p), 9.0);
+        assert_eq!(x_of(&world, a, |Velocity(v)| v), -10.0);
+        assert_eq!(x_of(&world, b, |Velocity(v)| v), 10.0);
+    }
+
+    #[test]
+    fn kinematic_bodies_push_dynamic_ones() {
+        let mut world = World::new();
+        PhysicsSystemData::setup(&mut world);
+        let player = body(&mut world, 0.0, Some(10.0), None);
+        let crate_ = body(&mut world, 8.0, Some(0.0), Some(1.0));
+
+        let mut data: PhysicsSystemData = world.system_data();
+        data.collide_things();
+        drop(data);
+        // The crate is shoved along and the player keeps its place
+        assert_eq!(x_of(&world, player, |Position(p)| p), 0.0);
+        assert_eq!(x_of(&world, crate_, |Position(p)| p), 10.0);
+        assert_eq!(x_of(&world, crate_, |Velocity(v)| v), 10.0);
+
+        // Against a wall the crate stays put and blocks the player
+        let _wall = body(&mut world, 20.0, None, None);
+        for &(ent, x) in [(player, 2.0), (crate_, 11.0)].iter() {
+            world
+                .write_storage::<Position>()
+                .insert(ent, Position(V2::new(x, 0.0)))
+                .unwrap();
+            world
+                .write_resource::<AABBTree>()
+                .insert(ent, AABB::new(x, 0.0, 10.0, 10.0));
+        }
+        let mut data: PhysicsSystemData = world.system_data();
+        data.collide_things();
+        drop(data);
+        assert_eq!(x_of(&world, crate_, |Position(p)| p), 10.0);
+        assert_eq!(x_of(&world, player, |Position(p)| p), 0.0);
+    }
+
+    #[test]
+    fn sends_collision_events() {
+        let mut world = World::new();
+        let mut physics = Physics::default();
+        System::setup(&mut physics, &mut world);
+        let mut reader = world
+            .fetch_mut::<EventChannel<CollisionEvent>>()
+            .register_reader();
+        let player = body(&mut world, 0.0, Some(0.0), None);
+        let wall = body(&mut world, 8.0, None, None);
+
+        physics.run_now(&world);
+        world.maintain();
+        let events: Vec<CollisionEvent> = world
+            .fetch::<EventChannel<CollisionEvent>>()
+            .read(&mut reader)
+            .cloned()
+            .collect();
+        assert_eq!(
+            events,
+            vec![CollisionEvent::Started(Collision {
+                entity: player,
+                other: wall,
+                mtv: V2::new(2.0, 0.0),
+                normal: V2::new(-1.0, 0.0),
+            })]
+        );
+
+        // Still touching, but no longer overlapping
+        physics.run_now(&world);
+        let events: Vec<CollisionEvent> = world
+            .fetch::<EventChannel<CollisionEvent>>()
+            .read(&mut reader)
+            .cloned()
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], CollisionEvent::Ended(c) if c.involves(wall)));
+    }
+
+    #[test]
+    fn collision_filters_replace_zlevels() {
+        let mut world = World::new();
+        PhysicsSystemData::setup(&mut world);
+        let ghost = body(&mut world, 0.0, Some(0.0), None);
+        let wall = body(&mut world, 8.0, None, None);
+        let floating = body(&mut world, 8.0, None, None);
+        world
+            .write_storage::<ZLevel>()
+            .insert(floating, ZLevel(1.0))
+            .unwrap();
+        let can_collide = |world: &World, a, b| {
+            let data: PhysicsSystemData = world.system_data();
+            data.can_collide(a, b)
+        };
+        assert!(can_collide(&world, ghost, wall));
+        assert!(!can_collide(&world, ghost, floating));
+
+        // Ghosts don't collide with the default category
+        let mut filters = world.write_storage::<CollisionFilter>();
+        let ghost_filter = CollisionFilter {
+            category: 2,
+            mask: 2,
+            same_zlevel: false,
+        };
+        filters.insert(ghost, ghost_filter).unwrap();
+        drop(filters);
+        assert!(!can_collide(&world, ghost, wall));
+        assert!(!can_collide(&world, wall, ghost));
+
+        // Filters that don't care about zlevels collide across them
+        let mut filters = world.write_storage::<CollisionFilter>();
+        filters.insert(floating, ghost_filter).unwrap();
+        drop(filters);
+        assert!(can_collide(&world, ghost, floating));
+    }
+
+    #[test]
+    fn one_way_barriers_and_sensors() {
+        let mut world = World::new();
+        PhysicsSystemData::setup(&mut world);
+        let ledge = body(&mut world, 0.0, None, None);
+        world
+            .write_storage::<OneWay>()
+            .insert(ledge, OneWay(Cardinal::East))
+            .unwrap();
+        let sensor = body(&mut world, 100.0, None, None);
+        world
+            .write_storage::<Sensor>()
+            .insert(sensor, Sensor)
+            .unwrap();
+        // Coming from the west, through the ledge
+        let hopper = body(&mut world, -2.0, Some(0.0), None);
+        // Coming from the east, stopped by the ledge
+        let climber = body(&mut world, 9.8, Some(0.0), None);
+        let sensed = body(&mut world, 95.0, Some(0.0), None);
+
+        let mut data: PhysicsSystemData = world.system_data();
+        let collisions = data.collide_things();
+        drop(data);
+        assert_eq!(x_of(&world, hopper, |Position(p)| p), -2.0);
+        assert!((x_of(&world, climber, |Position(p)| p) - 10.0).abs() < 0.001);
+        assert_eq!(x_of(&world, sensed, |Position(p)| p), 95.0);
+        assert!(collisions
+            .iter()
+            .any(|c| c.involves(sensor) && c.involves(sensed)));
+        assert!(!collisions.iter().any(|c| c.involves(hopper)));
+    }
+
+    #[test]
+    fn kinematic_controllers_slide_and_step() {
+        let mut world = World::new();
+        PhysicsSystemData::setup(&mut world);
+        let _wall = body(&mut world, 20.0, None, None);
+        let player = body(&mut world, 0.0, Some(0.0), None);
+        world
+            .write_storage::<Position>()
+            .insert(player, Position(V2::new(0.0, -8.0)))
+            .unwrap();
+        let controller = KinematicController::default();
+
+        // Sliding along the wall's face keeps the motion along it
+        let data: PhysicsSystemData = world.system_data();
+        let (motion, hits) = data.move_and_slide(player, V2::new(30.0, 4.0), &controller);
+        assert!((motion.x - 10.0).abs() < 0.001, "{:?}", motion);
+        assert!((motion.y - 4.0).abs() < 0.001, "{:?}", motion);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].normal, V2::new(-1.0, 0.0));
+
+        // Clipping the wall's corner steps around it
+        let controller = KinematicController {
+            step_height: 4.0,
+            ..KinematicController::default()
+        };
+        let (motion, hits) = data.move_and_slide(player, V2::new(30.0, 0.0), &controller);
+        assert!(hits.is_empty(), "{:?}", hits);
+        assert!(motion.x > 29.0, "{:?}", motion);
+        assert!(motion.y <= -2.0, "{:?}", motion);
+    }
+}
@@ -0,0 +1,430 @@
+//! Gravity and side scrolling movement.
+//!
+//! Maps are top-down unless they have gravity. A map's gravity comes from its
+//! `gravity_x` and `gravity_y` Tiled properties and pulls on every dynamic
+//! body and platformer. Objects with the same properties get their own
+//! `Gravity`, which replaces the map's.
+//!
+//! Platformers are kinematic bodies that run along the ground and jump. They
+//! stand on the same barriers a top-down map uses, and one way barriers
+//! solid on the side facing away from gravity make platforms that can be
+//! jumped up through.
+use serde_json::Value;
+use specs::prelude::*;
+use std::collections::HashMap;
+
+use super::{
+    super::super::prelude::{FPSCounter, Property, V2},
+    Exile, KinematicController, Velocity,
+};
+
+
+/// An entity's own gravity, replacing the map's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gravity(pub V2);
+
+
+impl Gravity {
+    pub fn tiled_x_property() -> String {
+        "gravity_x".to_string()
+    }
+
+    pub fn tiled_y_property() -> String {
+        "gravity_y".to_string()
+    }
+
+    /// Read a gravity out of an object's properties, removing them. Returns
+    /// None if neither component is set.
+    pub fn from_properties(properties: &mut HashMap<String, Value>) -> Option<Gravity> {
+        let mut take = |name: String| {
+            properties
+                .remove(&name)
+                .and_then(|v| v.as_f64())
+                .map(|f| f as f32)
+        };
+        let x = take(Gravity::tiled_x_property());
+        let y = take(Gravity::tiled_y_property());
+        if x.is_none() && y.is_none() {
+            None
+        } else {
+            Some(Gravity(V2::new(x.unwrap_or(0.0), y.unwrap_or(0.0))))
+        }
+    }
+
+    /// Read a gravity out of a map's properties.
+    pub fn from_map_properties(properties: &[Property]) -> Option<Gravity> {
+        let mut properties: HashMap<String, Value> = properties
+            .iter()
+            .map(|p| (p.name.clone(), p.value.clone()))
+            .collect();
+        Gravity::from_properties(&mut properties)
+    }
+
+    /// The unit direction the entity is pulled in by its own gravity, or else
+    /// the map's. `None` if nothing pulls on it.
+    pub fn down_of(
+        ent: Entity,
+        gravities: &ReadStorage<Gravity>,
+        map_gravity: &MapGravity,
+    ) -> Option<V2> {
+        gravities
+            .get(ent)
+            .map(|Gravity(gravity)| *gravity)
+            .unwrap_or(map_gravity.0)
+            .unitize()
+    }
+}
+
+
+impl Component for Gravity {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// The gravity of the current map, pulling on dynamic bodies and platformers
+/// that don't have their own. Zero on top-down maps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MapGravity(pub V2);
+
+
+impl Default for MapGravity {
+    fn default() -> MapGravity {
+        MapGravity(V2::origin())
+    }
+}
+
+
+/// How steeply a surface has to face against gravity to be stood on.
+const GROUND_SLOPE: f32 = 0.7;
+
+
+/// Lets a kinematic body run and jump under gravity.
+///
+/// Whatever controls the body sets `run` and `jump` each frame and the
+/// `PlatformerSystem` turns them into a velocity. Letting go of jump while
+/// still rising cuts the jump short, and a jump can still be started for
+/// `coyote_time` seconds after running off a ledge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Platformer {
+    /// The speed a jump starts with.
+    pub jump_speed: f32,
+
+    /// How much of its upward speed a jump keeps when jump is let go early.
+    pub jump_cut: f32,
+
+    /// How long after leaving the ground a jump can still be started.
+    pub coyote_time: f32,
+
+    /// The speed to run at, along the ground. Positive runs to the right of
+    /// gravity, eg. east when gravity is south.
+    pub run: f32,
+
+    /// Whether jump is held.
+    pub jump: bool,
+
+    /// Whether the body was standing on something after its last move.
+    pub grounded: bool,
+
+    /// Seconds since the body was last grounded.
+    pub airborne_time: f32,
+
+    jumping: bool,
+    jump_was_held: bool,
+}
+
+
+impl Default for Platformer {
+    fn default() -> Platformer {
+        Platformer::new(300.0)
+    }
+}
+
+
+impl Platformer {
+    /// The direction a positive `run` goes in, given the unit direction of
+    /// gravity.
+    pub fn across(down: V2) -> V2 {
+        down.normal().scalar_mul(-1.0)
+    }
+
+    pub fn new(jump_speed: f32) -> Platformer {
+        Platformer {
+            jump_speed,
+            jump_cut: 0.5,
+            coyote_time: 0.1,
+            run: 0.0,
+            jump: false,
+            grounded: false,
+            airborne_time: 0.0,
+            jumping: false,
+            jump_was_held: false,
+        }
+    }
+
+    pub fn tiled_jump_speed_property() -> String {
+        "jump_speed".to_string()
+    }
+
+    pub fn tiled_jump_cut_property() -> String {
+        "jump_cut".to_string()
+    }
+
+    pub fn tiled_coyote_time_property() -> String {
+        "coyote_time".to_string()
+    }
+
+    /// Whether a jump pressed now would leave the ground.
+    pub fn can_jump(&self) -> bool {
+        !self.jumping && (self.grounded || self.airborne_time <= self.coyote_time)
+    }
+
+    /// Update the body's state after its last move and find its new velocity.
+    /// `down` is the unit direction of gravity.
+    fn step(&mut self, velocity: V2, down: V2, controller: &KinematicController, dt: f32) -> V2 {
+        let across = Platformer::across(down);
+        let mut fall = velocity.dot(down);
+
+        self.grounded = controller
+            .hits
+            .iter()
+            .any(|hit| hit.normal.dot(down) < -GROUND_SLOPE);
+        let bumped_head = controller
+            .hits
+            .iter()
+            .any(|hit| hit.normal.dot(down) > GROUND_SLOPE);
+        if self.grounded {
+            self.airborne_time = 0.0;
+            self.jumping = false;
+            fall = fall.min(0.0);
+        } else {
+            self.airborne_time += dt;
+        }
+        if bumped_head {
+            fall = fall.max(0.0);
+        }
+
+        let pressed = self.jump && !self.jump_was_held;
+        self.jump_was_held = self.jump;
+        if pressed && self.can_jump() {
+            fall = -self.jump_speed;
+            self.jumping = true;
+        } else if self.jumping && !self.jump && fall < 0.0 {
+            // Only cut the jump once, after that it falls as usual
+            fall *= self.jump_cut;
+            self.jump_was_held = false;
+            self.jumping = false;
+            self.airborne_time = f32::INFINITY;
+        }
+
+        across.scalar_mul(self.run) + down.scalar_mul(fall)
+    }
+}
+
+
+impl Component for Platformer {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Turns platformers' running and jumping into velocities. Runs before
+/// `Physics`, which then pulls them down by gravity and moves them.
+pub struct PlatformerSystem;
+
+
+#[derive(SystemData)]
+pub struct PlatformerSystemData<'a> {
+    entities: Entities<'a>,
+    exiles: ReadStorage<'a, Exile>,
+    fps: Read<'a, FPSCounter>,
+    gravities: ReadStorage<'a, Gravity>,
+    kinematic_controllers: WriteStorage<'a, KinematicController>,
+    map_gravity: Read<'a, MapGravity>,
+    platformers: WriteStorage<'a, Platformer>,
+    velocities: WriteStorage<'a, Velocity>,
+}
+
+
+impl<'a> System<'a> for PlatformerSystem {
+    type SystemData = PlatformerSystemData<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        let dt = data.fps.last_delta();
+        for (ent, platformer, controller, velocity, ()) in (
+            &data.entities,
+            &mut data.platformers,
+            &mut data.kinematic_controllers,
+            &mut data.velocities,
+            !&data.exiles,
+        )
+            .join()
+        {
+            let down = if let Some(down) = Gravity::down_of(ent, &data.gravities, &data.map_gravity)
+            {
+                down
+            } else {
+                // Without gravity there is no ground to run along
+                continue;
+            };
+            controller.up = Some(down.scalar_mul(-1.0));
+            velocity.0 = platformer.step(velocity.0, down, controller, dt);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod platformer_tests {
+    use super::{
+        super::{
+            super::super::prelude::{AABBTree, Barrier, Cardinal, Shape, ZLevel},
+            OneWay, Physics, Position,
+        },
+        *,
+    };
+
+    fn step(world: &mut World, physics: &mut Physics) {
+        PlatformerSystem.run_now(world);
+        physics.run_now(world);
+        world.maintain();
+    }
+
+    fn barrier(world: &mut World, x: f32, y: f32, w: f32, h: f32) -> Entity {
+        let shape = Shape::box_with_size(w, h);
+        let ent = world
+            .create_entity()
+            .with(Barrier)
+            .with(shape.clone())
+            .with(Position(V2::new(x, y)))
+            .with(ZLevel(0.0))
+            .build();
+        world
+            .write_resource::<AABBTree>()
+            .insert(ent, shape.aabb().translate(&V2::new(x, y)));
+        ent
+    }
+
+    fn y_of(world: &World, ent: Entity) -> f32 {
+        world.read_storage::<Position>().get(ent).unwrap().0.y
+    }
+
+    #[test]
+    fn jumps_through_one_way_platforms_and_lands_on_them() {
+        let mut world = World::new();
+        let mut physics = Physics::default();
+        System::setup(&mut physics, &mut world);
+        PlatformerSystemData::setup(&mut world);
+        world.insert(MapGravity(V2::new(0.0, 600.0)));
+        world
+            .write_resource::<FPSCounter>()
+            .set_last_delta(1.0 / 60.0);
+
+        let _floor = barrier(&mut world, -100.0, 100.0, 200.0, 10.0);
+        let platform = barrier(&mut world, -100.0, 60.0, 200.0, 2.0);
+        world
+            .write_storage::<OneWay>()
+            .insert(platform, OneWay(Cardinal::North))
+            .unwrap();
+        let hero = barrier(&mut world, 0.0, 80.0, 10.0, 10.0);
+        world
+            .write_storage::<Velocity>()
+            .insert(hero, Velocity(V2::origin()))
+            .unwrap();
+        world
+            .write_storage::<KinematicController>()
+            .insert(hero, KinematicController::default())
+            .unwrap();
+        world
+            .write_storage::<Platformer>()
+            .insert(hero, Platformer::default())
+            .unwrap();
+
+        // Falls onto the floor and stands there
+        for _ in 0..60 {
+            step(&mut world, &mut physics);
+        }
+        assert!(
+            (y_of(&world, hero) - 90.0).abs() < 0.01,
+            "{}",
+            y_of(&world, hero)
+        );
+        assert!(
+            world
+                .read_storage::<Platformer>()
+                .get(hero)
+                .unwrap()
+                .grounded
+        );
+
+        // Jumps up through the platform and lands on top of it
+        world
+            .write_storage::<Platformer>()
+            .get_mut(hero)
+            .unwrap()
+            .jump = true;
+        let mut highest = f32::INFINITY;
+        for _ in 0..120 {
+            step(&mut world, &mut physics);
+            highest = highest.min(y_of(&world, hero));
+        }
+        assert!(highest < 50.0, "{}", highest);
+        assert!(
+            (y_of(&world, hero) - 50.0).abs() < 0.01,
+            "{}",
+            y_of(&world, hero)
+        );
+        assert!(
+            world
+                .read_storage::<Platformer>()
+                .get(hero)
+                .unwrap()
+                .grounded
+        );
+    }
+
+    #[test]
+    fn letting_go_cuts_jumps_short() {
+        let controller = KinematicController::default();
+        let down = V2::new(0.0, 1.0);
+        let mut held = Platformer {
+            grounded: true,
+            ..Platformer::default()
+        };
+        let mut released = held.clone();
+        held.jump = true;
+        released.jump = true;
+        let v = held.step(V2::origin(), down, &controller, 0.1);
+        assert_eq!(v, V2::new(0.0, -300.0));
+        released.step(V2::origin(), down, &controller, 0.1);
+
+        released.jump = false;
+        assert_eq!(held.step(v, down, &controller, 0.1), v);
+        assert_eq!(
+            released.step(v, down, &controller, 0.1),
+            V2::new(0.0, -150.0)
+        );
+        // And it can't jump again until it lands
+        released.jump = true;
+        assert!(!released.can_jump());
+    }
+
+    #[test]
+    fn coyote_time_allows_late_jumps() {
+        let controller = KinematicController::default();
+        let down = V2::new(0.0, 1.0);
+        let mut platformer = Platformer {
+            airborne_time: 0.05,
+            jump: true,
+            ..Platformer::default()
+        };
+        let v = platformer.step(V2::new(0.0, 20.0), down, &controller, 0.02);
+        assert_eq!(v.y, -300.0);
+
+        let mut platformer = Platformer {
+            airborne_time: 0.2,
+            jump: true,
+            ..Platformer::default()
+        };
+        let v = platformer.step(V2::new(0.0, 20.0), down, &controller, 0.02);
+        assert_eq!(v.y, 20.0);
+    }
+}
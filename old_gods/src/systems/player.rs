@@ -1,9 +1,15 @@
 /// Manages:
+/// * turning character objects into players and NPCs
 /// * moving players based on their controllers' axes
+/// * giving characters kinematic controllers so they slide along walls
+/// * running and jumping players that are platformers
 use log::{trace, warn};
 use specs::prelude::*;
 
-use super::super::prelude::{Exile, MaxSpeed, Object, Player, PlayerControllers, Velocity, V2};
+use super::super::prelude::{
+    Exile, KinematicController, MaxSpeed, Npc, Object, Platformer, Player, PlayerControllers,
+    Steering, Velocity, AI, V2,
+};
 
 
 /// Players the movement and actions taken by characters.
@@ -16,8 +22,12 @@ pub struct PlayerSystemData<'a> {
     player_controllers: Read<'a, PlayerControllers>,
     players: WriteStorage<'a, Player>,
     exiles: ReadStorage<'a, Exile>,
-    max_speeds: ReadStorage<'a, MaxSpeed>,
+    kinematic_controllers: WriteStorage<'a, KinematicController>,
+    max_speeds: WriteStorage<'a, MaxSpeed>,
+    npcs: WriteStorage<'a, Npc>,
     objects: WriteStorage<'a, Object>,
+    platformers: WriteStorage<'a, Platformer>,
+    steerings: WriteStorage<'a, Steering>,
     velocities: WriteStorage<'a, Velocity>,
 }
 
@@ -50,9 +60,18 @@ impl<'a> System<'a> for PlayerSystem {
                         let _ = data.players.insert(ent, Player(ndx as u32));
                     }
 
-                    Some("npc") => {
-                        panic!("TODO: NPC support");
-                    }
+                    Some("npc") => match AI::from_properties(&properties) {
+                        Ok(ai) => {
+                            let _ = data.npcs.insert(ent, Npc::new(ai));
+                            let _ = data
+                                .steerings
+                                .insert(ent, Steering::from_properties(&properties));
+                        }
+                        Err(msg) => {
+                            warn!("npc {:?} is idle: {}", obj.name, msg);
+                            let _ = data.npcs.insert(ent, Npc::new(AI::Idle));
+                        }
+                    },
 
                     None => {
                         panic!("character object must have a 'control' property");
@@ -64,6 +83,35 @@ impl<'a> System<'a> for PlayerSystem {
                 }
 
                 let _ = data.velocities.insert(ent, Velocity(V2::origin()));
+                if let Some(max_speed) = properties
+                    .get(&MaxSpeed::tiled_key())
+                    .and_then(|v| v.as_f64())
+                {
+                    let _ = data.max_speeds.insert(ent, MaxSpeed(max_speed as f32));
+                }
+                let step_height = properties
+                    .get(&KinematicController::tiled_step_height_property())
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or(0.0) as f32;
+                let _ = data.kinematic_controllers.insert(
+                    ent,
+                    KinematicController {
+                        step_height,
+                        ..KinematicController::default()
+                    },
+                );
+                // Characters with a jump speed run and jump under gravity
+                let float = |name: String| properties.get(&name).and_then(|v| v.as_f64());
+                if let Some(jump_speed) = float(Platformer::tiled_jump_speed_property()) {
+                    let mut platformer = Platformer::new(jump_speed as f32);
+                    if let Some(jump_cut) = float(Platformer::tiled_jump_cut_property()) {
+                        platformer.jump_cut = jump_cut as f32;
+                    }
+                    if let Some(coyote_time) = float(Platformer::tiled_coyote_time_property()) {
+                        platformer.coyote_time = coyote_time as f32;
+                    }
+                    let _ = data.platformers.insert(ent, platformer);
+                }
                 deletes.push(ent);
             }
         }
@@ -84,6 +132,15 @@ impl<'a> System<'a> for PlayerSystem {
 
             let max_speed: MaxSpeed = data.max_speeds.get(ent).cloned().unwrap_or(MaxSpeed(100.0));
 
+            if let Some(platformer) = data.platformers.get_mut(ent) {
+                // Platformers only run along the ground, gravity does the rest
+                data.player_controllers.with_map_ctrl_at(player.0, |ctrl| {
+                    platformer.run = ctrl.analog_rate().x.clamp(-1.0, 1.0) * max_speed.0;
+                    platformer.jump = ctrl.a().is_on();
+                });
+                continue;
+            }
+
             // Get the player's controller on the map
             data.player_controllers.with_map_ctrl_at(player.0, |ctrl| {
                 // Update the velocity of the toon based on the
@@ -0,0 +1,360 @@
+//! Moves things along routes.
+//!
+//! Any object with a "route" property naming a "path" object, or giving its
+//! object id, follows that route with a `RouteFollower`. A "route_mode"
+//! property of "loop", "ping_pong" or "once" picks what happens at the end,
+//! ping pong by default. Followers move at their `MaxSpeed` like NPCs do,
+//! which makes routes good for moving platforms and ambient critters.
+//!
+//! NPCs patrol routes with their AI instead, so they are left alone here.
+use serde_json::Value;
+use specs::prelude::*;
+use std::collections::HashMap;
+
+use super::super::prelude::{
+    route_from_values, set_movement, steer_towards, Exile, FPSCounter, Fence, Gravity, MapGravity,
+    MaxSpeed, Name, Npc, Object, ObjectId, Platformer, Position, Route, RouteMode, RouteRef,
+    Velocity, NPC_MAX_SPEED, V2,
+};
+
+
+/// How close a follower has to get to a waypoint to have reached it.
+pub const ROUTE_ARRIVAL_DISTANCE: f32 = 2.0;
+
+
+/// How far along a route something is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteProgress {
+    pub mode: RouteMode,
+    waypoint: usize,
+    reversed: bool,
+    waiting: f32,
+    finished: bool,
+}
+
+
+impl RouteProgress {
+    pub fn new(mode: RouteMode) -> RouteProgress {
+        RouteProgress {
+            mode,
+            waypoint: 0,
+            reversed: false,
+            waiting: 0.0,
+            finished: false,
+        }
+    }
+
+    /// The index of the waypoint being headed for.
+    pub fn waypoint(&self) -> usize {
+        self.waypoint
+    }
+
+    /// Whether a route followed once has been walked to its end.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The waypoint after the given one, if there is one.
+    fn after(&mut self, waypoint: usize, len: usize) -> Option<usize> {
+        match self.mode {
+            RouteMode::Loop => Some((waypoint + 1) % len),
+            RouteMode::Once => Some(waypoint + 1).filter(|next| *next < len),
+            RouteMode::PingPong => {
+                if len < 2 {
+                    return Some(waypoint);
+                }
+                if waypoint == len - 1 {
+                    self.reversed = true;
+                } else if waypoint == 0 {
+                    self.reversed = false;
+                }
+                let next = if self.reversed {
+                    waypoint - 1
+                } else {
+                    waypoint + 1
+                };
+                Some(next)
+            }
+        }
+    }
+
+    /// Where to head for next along a route on the map, if anywhere. Waits
+    /// at each waypoint reached before moving on.
+    pub fn next(&mut self, position: V2, route: &Route, dt: f32) -> Option<V2> {
+        if self.finished || route.points.is_empty() {
+            return None;
+        }
+        if self.waiting > 0.0 {
+            self.waiting -= dt;
+            return None;
+        }
+        let waypoint = self.waypoint.min(route.points.len() - 1);
+        if position.distance_to(&route.points[waypoint]) > ROUTE_ARRIVAL_DISTANCE {
+            return Some(route.points[waypoint]);
+        }
+        self.waiting = route.wait_at(waypoint);
+        match self.after(waypoint, route.points.len()) {
+            Some(next) => self.waypoint = next,
+            None => self.finished = true,
+        }
+        if self.waiting > 0.0 || self.finished {
+            None
+        } else {
+            Some(route.points[self.waypoint])
+        }
+    }
+}
+
+
+/// Follows a route.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteFollower {
+    pub route: RouteRef,
+    pub progress: RouteProgress,
+}
+
+
+impl RouteFollower {
+    pub fn new(route: RouteRef, mode: RouteMode) -> RouteFollower {
+        RouteFollower {
+            route,
+            progress: RouteProgress::new(mode),
+        }
+    }
+
+    pub fn tiled_key() -> String {
+        "route".to_string()
+    }
+
+    /// Read and remove a follower from an object's properties.
+    pub fn from_properties(
+        properties: &mut HashMap<String, Value>,
+    ) -> Option<Result<RouteFollower, String>> {
+        let route = properties.remove(&RouteFollower::tiled_key())?;
+        let mode = properties.remove(&RouteMode::tiled_key());
+        Some(
+            route_from_values(&route, mode.as_ref())
+                .map(|(route, mode)| RouteFollower::new(route, mode)),
+        )
+    }
+}
+
+
+impl Component for RouteFollower {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Everything needed to find a route on the map.
+#[derive(SystemData)]
+pub struct RouteData<'a> {
+    pub entities: Entities<'a>,
+    pub fences: ReadStorage<'a, Fence>,
+    pub names: ReadStorage<'a, Name>,
+    pub object_ids: ReadStorage<'a, ObjectId>,
+    pub objects: ReadStorage<'a, Object>,
+    pub positions: ReadStorage<'a, Position>,
+    pub routes: ReadStorage<'a, Route>,
+}
+
+
+impl<'a> RouteData<'a> {
+    /// The route object referred to.
+    pub fn find(&self, route: &RouteRef) -> Option<Entity> {
+        match route {
+            RouteRef::Name(name) => (&self.entities, &self.names)
+                .join()
+                .find(|(_, Name(n))| n == name)
+                .map(|(ent, _)| ent),
+            RouteRef::Id(id) => (&self.entities, &self.object_ids)
+                .join()
+                .find(|(_, ObjectId(i))| i == id)
+                .map(|(ent, _)| ent),
+        }
+    }
+
+    /// The route referred to, placed on the map. Fences and other polylines
+    /// can be walked as routes without any waits.
+    pub fn route_on_map(&self, route: &RouteRef) -> Option<Route> {
+        let ent = self.find(route)?;
+        let route = if let Some(route) = self.routes.get(ent) {
+            route.clone()
+        } else if let Some(fence) = self.fences.get(ent) {
+            Route::new(fence.points.clone())
+        } else {
+            let polyline = self.objects.get(ent)?.polyline.as_ref()?;
+            Route::new(polyline.iter().map(|p| V2::new(p.x, p.y)).collect())
+        };
+        let origin = self
+            .positions
+            .get(ent)
+            .map(|Position(p)| *p)
+            .unwrap_or_else(V2::origin);
+        Some(route.translated(origin))
+    }
+}
+
+
+pub struct RouteSystem;
+
+
+#[derive(SystemData)]
+pub struct RouteSystemData<'a> {
+    exiles: ReadStorage<'a, Exile>,
+    followers: WriteStorage<'a, RouteFollower>,
+    fps: Read<'a, FPSCounter>,
+    gravities: ReadStorage<'a, Gravity>,
+    map_gravity: Read<'a, MapGravity>,
+    max_speeds: ReadStorage<'a, MaxSpeed>,
+    npcs: ReadStorage<'a, Npc>,
+    platformers: WriteStorage<'a, Platformer>,
+    routes: RouteData<'a>,
+    velocities: WriteStorage<'a, Velocity>,
+}
+
+
+impl<'a> System<'a> for RouteSystem {
+    type SystemData = RouteSystemData<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        let dt = data.fps.last_delta();
+        let routes = &data.routes;
+        for (ent, follower, Position(position), (), ()) in (
+            &routes.entities,
+            &mut data.followers,
+            &routes.positions,
+            !&data.exiles,
+            !&data.npcs,
+        )
+            .join()
+        {
+            let destination = routes
+                .route_on_map(&follower.route)
+                .and_then(|route| follower.progress.next(*position, &route, dt));
+            let max_speed = data
+                .max_speeds
+                .get(ent)
+                .map(|s| s.0)
+                .unwrap_or(NPC_MAX_SPEED);
+            let velocity = destination
+                .map(|destination| steer_towards(*position, destination, max_speed, dt))
+                .unwrap_or_else(V2::origin);
+            set_movement(
+                ent,
+                velocity,
+                &data.gravities,
+                &data.map_gravity,
+                &mut data.platformers,
+                &mut data.velocities,
+            );
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod route_tests {
+    use super::*;
+
+    fn walk(progress: &mut RouteProgress, route: &Route, dt: f32, steps: usize) -> Vec<V2> {
+        // Teleport to wherever it's headed, noting where it stood each step
+        let mut position = route.points[0];
+        let mut trail = vec![];
+        for _ in 0..steps {
+            if let Some(next) = progress.next(position, route, dt) {
+                position = next;
+            }
+            trail.push(position);
+        }
+        trail
+    }
+
+    #[test]
+    fn routes_loop_ping_pong_and_end() {
+        let (a, b, c) = (V2::new(0.0, 0.0), V2::new(10.0, 0.0), V2::new(10.0, 10.0));
+        let route = Route::new(vec![a, b, c]);
+
+        let mut looping = RouteProgress::new(RouteMode::Loop);
+        assert_eq!(walk(&mut looping, &route, 1.0, 5), vec![b, c, a, b, c]);
+
+        let mut ping_pong = RouteProgress::new(RouteMode::PingPong);
+        assert_eq!(walk(&mut ping_pong, &route, 1.0, 5), vec![b, c, b, a, b]);
+
+        let mut once = RouteProgress::new(RouteMode::Once);
+        assert_eq!(walk(&mut once, &route, 1.0, 5), vec![b, c, c, c, c]);
+        assert!(once.is_finished());
+    }
+
+    #[test]
+    fn followers_wait_at_waypoints() {
+        let mut properties: HashMap<String, Value> = HashMap::new();
+        properties.insert(Route::tiled_wait_at_property(1), Value::from(2.0));
+        let (a, b) = (V2::new(0.0, 0.0), V2::new(10.0, 0.0));
+        let route = Route::from_properties(vec![a, b], &mut properties);
+        assert!(properties.is_empty());
+        assert_eq!(route.waits, vec![0.0, 2.0]);
+        assert_eq!(
+            route
+                .wait_properties()
+                .get(&Route::tiled_wait_at_property(1)),
+            Some(&Value::from(2.0))
+        );
+
+        let mut progress = RouteProgress::new(RouteMode::Loop);
+        let trail = walk(&mut progress, &route, 1.0, 5);
+        // It stands at b for two seconds before heading back to a
+        assert_eq!(trail, vec![b, b, b, b, a]);
+    }
+
+    #[test]
+    fn followers_find_their_route_by_name_or_id() {
+        let mut world = World::new();
+        System::setup(&mut RouteSystem, &mut world);
+        world
+            .write_resource::<FPSCounter>()
+            .set_last_delta(1.0 / 60.0);
+        world
+            .create_entity()
+            .with(Name("beat".to_string()))
+            .with(ObjectId(7))
+            .with(Position(V2::new(10.0, 10.0)))
+            .with(Route::new(vec![V2::origin(), V2::new(30.0, 0.0)]))
+            .build();
+        let mut properties: HashMap<String, Value> = HashMap::new();
+        properties.insert(RouteFollower::tiled_key(), Value::from(7));
+        properties.insert(RouteMode::tiled_key(), Value::from("once"));
+        let follower = RouteFollower::from_properties(&mut properties)
+            .unwrap()
+            .unwrap();
+        assert_eq!(follower.route, RouteRef::Id(7));
+        let critter = world
+            .create_entity()
+            .with(follower)
+            .with(Position(V2::new(10.0, 10.0)))
+            .with(Velocity(V2::origin()))
+            .with(MaxSpeed(60.0))
+            .build();
+
+        for _ in 0..60 {
+            RouteSystem.run_now(&world);
+            let Velocity(v) = *world.read_storage::<Velocity>().get(critter).unwrap();
+            let mut positions = world.write_storage::<Position>();
+            let Position(p) = positions.get_mut(critter).unwrap();
+            *p += v.scalar_mul(1.0 / 60.0);
+        }
+        let Position(p) = *world.read_storage::<Position>().get(critter).unwrap();
+        assert!(p.distance_to(&V2::new(40.0, 10.0)) <= ROUTE_ARRIVAL_DISTANCE);
+        RouteSystem.run_now(&world);
+        assert_eq!(
+            world.read_storage::<Velocity>().get(critter),
+            Some(&Velocity(V2::origin()))
+        );
+
+        let named = RouteRef::Name("beat".to_string());
+        let data: RouteData = world.system_data();
+        assert_eq!(
+            data.route_on_map(&named).unwrap().points[1],
+            V2::new(40.0, 10.0)
+        );
+    }
+}
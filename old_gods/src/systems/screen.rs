@@ -1,7 +1,13 @@
 /// The screen system keeps the players within view of the screen.
 use specs::prelude::*;
 
-use super::super::components::{Exile, OriginOffset, Player, Position, AABB, V2};
+use super::{
+    super::{
+        components::{Exile, OriginOffset, Player, Position, AABB, V2},
+        time::FixedTimestep,
+    },
+    physics::PreviousPosition,
+};
 use std::f32::{INFINITY, NEG_INFINITY};
 
 /// TODO: Rename to Viewport
@@ -112,8 +118,10 @@ pub struct ScreenSystem;
 pub struct ScreenSystemData<'a> {
     entities: Entities<'a>,
     exiles: ReadStorage<'a, Exile>,
+    fixed_timestep: Read<'a, FixedTimestep>,
     players: ReadStorage<'a, Player>,
     positions: ReadStorage<'a, Position>,
+    previous_positions: ReadStorage<'a, PreviousPosition>,
     offsets: ReadStorage<'a, OriginOffset>,
     screen: Write<'a, Screen>,
 }
@@ -133,7 +141,10 @@ impl<'a> System<'a> for ScreenSystem {
         let mut top = INFINITY;
         let mut bottom = NEG_INFINITY;
 
-        for (entity, _player, Position(pos), ()) in (
+        // Follow players where they are drawn, between the last two
+        // simulation steps, so they don't jitter against the map
+        let alpha = data.fixed_timestep.alpha();
+        for (entity, _player, position, ()) in (
             &data.entities,
             &data.players,
             &data.positions,
@@ -150,7 +161,7 @@ impl<'a> System<'a> for ScreenSystem {
                 .map(|o| o.0)
                 .unwrap_or_else(V2::origin);
 
-            let p = *pos + offset;
+            let p = position.interpolated(data.previous_positions.get(entity), alpha) + offset;
 
             if p.x < left {
                 left = p.x;
@@ -175,3 +186,61 @@ impl<'a> System<'a> for ScreenSystem {
         data.screen.viewport.top_left += distance;
     }
 }
+
+
+#[cfg(test)]
+mod screen_tests {
+    use super::*;
+
+    #[test]
+    fn followed_players_stay_still_on_screen() {
+        let mut world = World::new();
+        ScreenSystemData::setup(&mut world);
+        world.insert(FixedTimestep::new(60.0));
+        let player = world
+            .create_entity()
+            .with(Player(0))
+            .with(Position(V2::origin()))
+            .build();
+
+        // Frames come slower than simulation steps, so some frames run one
+        // step and some run two
+        let mut on_screen = vec![];
+        for _ in 0..60 {
+            let (steps, step) = {
+                let mut fixed = world.write_resource::<FixedTimestep>();
+                (fixed.accumulate(1.0 / 45.0), fixed.step())
+            };
+            for _ in 0..steps {
+                let mut positions = world.write_storage::<Position>();
+                let position = positions.get(player).unwrap().0;
+                world
+                    .write_storage::<PreviousPosition>()
+                    .insert(player, PreviousPosition(position))
+                    .unwrap();
+                positions
+                    .insert(player, Position(position + V2::new(120.0 * step, 0.0)))
+                    .unwrap();
+            }
+            ScreenSystem.run_now(&world);
+
+            let data: ScreenSystemData = world.system_data();
+            let drawn_at = data.positions.get(player).unwrap().interpolated(
+                data.previous_positions.get(player),
+                data.fixed_timestep.alpha(),
+            );
+            on_screen.push(data.screen.from_map(&drawn_at));
+        }
+
+        // Once the player has pushed the screen along it stays put on screen
+        let settled = on_screen[30];
+        for pos in on_screen[30..].iter() {
+            assert!(
+                pos.distance_to(&settled) < 0.01,
+                "{:?} != {:?}",
+                pos,
+                settled
+            );
+        }
+    }
+}
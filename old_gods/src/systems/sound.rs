@@ -1,14 +1,14 @@
+#[cfg(feature = "sdl2")]
+use log::warn;
+#[cfg(feature = "sdl2")]
+use sdl2::mixer::{Channel, Group, MAX_VOLUME};
 /// The SoundSystem handles playing music and sound.
 use specs::prelude::*;
-//use sdl2::mixer::{
-//  AUDIO_S16LSB,
-//  DEFAULT_CHANNELS,
-//  Chunk,
-//  Channel,
-//  Group
-//};
-//use sdl2::mixer::InitFlag;
+#[cfg(feature = "sdl2")]
+use std::collections::HashMap;
 
+#[cfg(feature = "sdl2")]
+use super::super::prelude::{when_loaded, Sdl2Sound, Sdl2SoundBlaster};
 use super::super::prelude::{
     Exile, OriginOffset, Player, Position, Screen, SoundBlaster, JSON, V2,
 };
@@ -44,28 +44,6 @@ pub struct SoundSystem {
 }
 
 
-impl SoundSystem {
-    // /// The channel group used for sound effects.
-    // fn fx_group(&self) -> Group {
-    //     Group(-1)
-    // }
-
-    // fn next_fx_channel(&self) -> Channel {
-    //     let channel = self.fx_group().find_available();
-    //     if let Some(channel) = channel {
-    //         channel
-    //     } else {
-    //         // Increase the number of channels
-    //         let num_channels = self.fx_group().count();
-    //         sdl2::mixer::allocate_channels(num_channels + 1);
-    //         self.fx_group()
-    //             .find_available()
-    //             .expect("No sound fx channels are available")
-    //     }
-    // }
-}
-
-
 impl Default for SoundSystem {
     fn default() -> SoundSystem {
         SoundSystem {
@@ -88,18 +66,18 @@ pub struct SoundSystemData<'a> {
 }
 
 
-impl<'a> System<'a> for SoundSystem {
-    type SystemData = SoundSystemData<'a>;
-
-    fn run(&mut self, mut data: SoundSystemData) {
+impl<'a> SoundSystemData<'a> {
+    /// Where sounds are heard from and the greatest distance they can be
+    /// heard at.
+    fn listener(&self) -> (V2, f32) {
         // Find the greatest distance a player could see
-        let max_distance = data.screen.get_size().scalar_mul(0.3).magnitude();
+        let max_distance = self.screen.get_size().scalar_mul(0.3).magnitude();
         // Find the zeroeth player
-        let player_pos: V2 = (&data.entities, &data.players, &data.positions)
+        let player_pos: V2 = (&self.entities, &self.players, &self.positions)
             .join()
             .filter_map(|(e, c, p)| {
                 if c.0 == 0 {
-                    data.offsets
+                    self.offsets
                         .get(e)
                         .map(|&OriginOffset(o)| p.0 + o)
                         .or(Some(p.0))
@@ -110,7 +88,38 @@ impl<'a> System<'a> for SoundSystem {
             .collect::<Vec<_>>()
             .first()
             .cloned()
-            .unwrap_or_else(|| data.screen.get_focus());
+            .unwrap_or_else(|| self.screen.get_focus());
+        (player_pos, max_distance)
+    }
+}
+
+
+/// How a sound at `position` is heard from `listener`, as its distance out of
+/// 255, the angle it comes from in degrees and whether it can be heard at all.
+pub fn hearing(listener: V2, position: V2, volume: f32, max_distance: f32) -> (u8, i16, bool) {
+    // Find the player's proximity to the sound
+    let proximity = listener.distance_to(&position);
+    // adjust for the max distance of seeing things and the volume
+    // the volume effectively lowers the distance at which things can be
+    // heard
+    let percent = proximity / (max_distance * volume);
+    // Scale out of 255
+    let distance = (255.0 * percent) as u8;
+
+    // Get the angle as well
+    let v = listener - position;
+    let a = v.angle_degrees();
+    let angle = (270 + a) % 360;
+
+    (distance, angle, percent < 1.0)
+}
+
+
+impl<'a> System<'a> for SoundSystem {
+    type SystemData = SoundSystemData<'a>;
+
+    fn run(&mut self, mut data: SoundSystemData) {
+        let (player_pos, max_distance) = data.listener();
 
         // Run through all the sounds that need to be triggered
         for (_ent, sound, &Position(p), ()) in (
@@ -123,23 +132,8 @@ impl<'a> System<'a> for SoundSystem {
         {
             // TODO: Only check the sounds that are within a certain range of the
             // player position
-            let (_distance, _angle, _can_hear_sound) = {
-                // Find the player's proximity to the sound
-                let proximity = player_pos.distance_to(&p);
-                // adjust for the max distance of seeing things and the volume
-                // the volume effectively lowers the distance at which things can be
-                // heard
-                let percent = proximity / (max_distance * sound.volume);
-                // Scale out of 255
-                let distance = (255.0 * percent) as u8;
-
-                // Get the angle as well
-                let v = player_pos - p;
-                let a = v.angle_degrees();
-                let angle = (270 + a) % 360;
-
-                (distance, angle, percent < 1.0)
-            };
+            let (_distance, _angle, _can_hear_sound) =
+                hearing(player_pos, p, sound.volume, max_distance);
 
             //if can_hear_sound {
             //} else {
@@ -147,3 +141,112 @@ impl<'a> System<'a> for SoundSystem {
         }
     }
 }
+
+
+/// Plays sounds through the SDL mixer, positioned around the zeroeth player.
+///
+/// Sounds that autoplay loop for as long as they can be heard.
+#[cfg(feature = "sdl2")]
+pub struct Sdl2SoundSystem {
+    pub blaster: Sdl2SoundBlaster,
+    channels: HashMap<Entity, Channel>,
+}
+
+
+#[cfg(feature = "sdl2")]
+impl Sdl2SoundSystem {
+    pub fn new() -> Result<Sdl2SoundSystem, String> {
+        Ok(Sdl2SoundSystem {
+            blaster: Sdl2SoundBlaster::new()?,
+            channels: HashMap::new(),
+        })
+    }
+
+    /// The channel group used for sound effects.
+    fn fx_group(&self) -> Group {
+        Group(-1)
+    }
+
+    fn next_fx_channel(&self) -> Channel {
+        let channel = self.fx_group().find_available();
+        if let Some(channel) = channel {
+            channel
+        } else {
+            // Increase the number of channels
+            let num_channels = self.fx_group().count();
+            sdl2::mixer::allocate_channels(num_channels + 1);
+            self.fx_group()
+                .find_available()
+                .expect("No sound fx channels are available")
+        }
+    }
+
+    /// Start looping a sound once it has loaded.
+    fn play(&mut self, sound: &Sound) -> Result<Option<Channel>, String> {
+        let channel = self.next_fx_channel();
+        let played = when_loaded(&mut self.blaster, &sound.file, |Sdl2Sound(chunk)| {
+            channel.play(chunk, -1)
+        })?;
+        played.transpose()
+    }
+}
+
+
+#[cfg(feature = "sdl2")]
+impl<'a> System<'a> for Sdl2SoundSystem {
+    type SystemData = SoundSystemData<'a>;
+
+    fn run(&mut self, data: SoundSystemData) {
+        let (player_pos, max_distance) = data.listener();
+
+        // Stop the sounds that are gone
+        self.channels.retain(|ent, channel| {
+            let keep = data.sounds.contains(*ent) && !data.exiles.contains(*ent);
+            if !keep {
+                channel.halt();
+            }
+            keep
+        });
+
+        for (ent, sound, &Position(p), ()) in
+            (&data.entities, &data.sounds, &data.positions, !&data.exiles).join()
+        {
+            let (distance, angle, can_hear_sound) =
+                hearing(player_pos, p, sound.volume, max_distance);
+            let playing = self
+                .channels
+                .get(&ent)
+                .cloned()
+                .filter(|channel| channel.is_playing());
+            let channel = match (can_hear_sound, playing) {
+                (true, Some(channel)) => channel,
+                (true, None) if sound.autoplay => match self.play(sound) {
+                    Ok(Some(channel)) => {
+                        self.channels.insert(ent, channel);
+                        channel
+                    }
+                    Ok(None) => continue,
+                    Err(msg) => {
+                        warn!("{}", msg);
+                        continue;
+                    }
+                },
+                (false, Some(channel)) => {
+                    channel.halt();
+                    self.channels.remove(&ent);
+                    continue;
+                }
+                _ => continue,
+            };
+            let (angle, distance) = if sound.on_map {
+                (angle, distance)
+            } else {
+                (0, 0)
+            };
+            channel.set_volume((sound.volume * MAX_VOLUME as f32) as i32);
+            if let Err(msg) = channel.set_position(angle, distance) {
+                warn!("{}", msg);
+            }
+        }
+    }
+}
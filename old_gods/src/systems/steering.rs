@@ -0,0 +1,293 @@
+//! Steering for crowds.
+//!
+//! Characters heading somewhere seek it at full speed or arrive at it by
+//! slowing down on the way in. Characters with a `Steering` also keep clear
+//! of their nearest steering neighbours and swerve away from barriers ahead
+//! of them, which is blended into the velocity they were going to move at.
+//!
+//! Neighbours are found in the `AABBTree`, so only characters with a `Shape`
+//! are kept clear of.
+use serde_json::Value;
+use specs::prelude::*;
+use std::collections::HashMap;
+
+use super::super::prelude::{steer_towards, AABBTree, Barrier, Exile, Position, Sensor, Shape, V2};
+
+
+/// The velocity that takes something at `position` straight to `target` at
+/// `max_speed`.
+pub fn seek(position: V2, target: V2, max_speed: f32) -> V2 {
+    (target - position)
+        .unitize()
+        .map(|dir| dir.scalar_mul(max_speed))
+        .unwrap_or_else(V2::origin)
+}
+
+
+/// The velocity that takes something at `position` to `target`, slowing down
+/// once it is within `slowing_distance` of it.
+pub fn arrive(position: V2, target: V2, max_speed: f32, slowing_distance: f32, dt: f32) -> V2 {
+    let distance = position.distance_to(&target);
+    let speed = if distance < slowing_distance {
+        max_speed * distance / slowing_distance
+    } else {
+        max_speed
+    };
+    steer_towards(position, target, speed, dt)
+}
+
+
+/// A push away from the neighbours closer than `distance`, stronger the
+/// closer they are. Each neighbour pushes with a strength of up to 1.
+pub fn separation(position: V2, neighbors: &[V2], distance: f32) -> V2 {
+    neighbors
+        .iter()
+        .filter_map(|neighbor| {
+            let away = position - *neighbor;
+            let closeness = 1.0 - away.magnitude() / distance;
+            if closeness <= 0.0 {
+                return None;
+            }
+            away.unitize().map(|away| away.scalar_mul(closeness))
+        })
+        .fold(V2::origin(), |sum, push| sum + push)
+}
+
+
+/// Shorten a vector to at most `max` long.
+fn truncate(v: V2, max: f32) -> V2 {
+    if v.magnitude() > max {
+        v.unitize()
+            .map(|dir| dir.scalar_mul(max))
+            .unwrap_or_else(V2::origin)
+    } else {
+        v
+    }
+}
+
+
+/// How a character steers through a crowd.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Steering {
+    /// How close others can get before the character moves away.
+    pub separation: f32,
+
+    /// How far ahead the character looks for barriers to swerve around.
+    pub look_ahead: f32,
+
+    /// How far from its destination the character starts slowing down.
+    pub slowing_distance: f32,
+
+    /// How many of the nearest others within `separation` the character keeps
+    /// clear of.
+    pub neighbors: usize,
+}
+
+
+impl Default for Steering {
+    fn default() -> Steering {
+        Steering {
+            separation: 24.0,
+            look_ahead: 32.0,
+            slowing_distance: 16.0,
+            neighbors: 6,
+        }
+    }
+}
+
+
+impl Steering {
+    pub fn tiled_separation_property() -> String {
+        "separation".to_string()
+    }
+
+    pub fn tiled_look_ahead_property() -> String {
+        "look_ahead".to_string()
+    }
+
+    pub fn tiled_slowing_distance_property() -> String {
+        "slowing_distance".to_string()
+    }
+
+    /// Read steering from a character's properties, using the defaults for
+    /// anything missing.
+    pub fn from_properties(properties: &HashMap<String, Value>) -> Steering {
+        let get = |name: String, default: f32| {
+            properties
+                .get(&name)
+                .and_then(|v| v.as_f64())
+                .map(|f| f as f32)
+                .unwrap_or(default)
+        };
+        let default = Steering::default();
+        Steering {
+            separation: get(Steering::tiled_separation_property(), default.separation),
+            look_ahead: get(Steering::tiled_look_ahead_property(), default.look_ahead),
+            slowing_distance: get(
+                Steering::tiled_slowing_distance_property(),
+                default.slowing_distance,
+            ),
+            ..default
+        }
+    }
+}
+
+
+impl Component for Steering {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Everything needed to steer through a crowd.
+#[derive(SystemData)]
+pub struct SteeringData<'a> {
+    pub aabb_tree: Read<'a, AABBTree>,
+    pub barriers: ReadStorage<'a, Barrier>,
+    pub entities: Entities<'a>,
+    pub exiles: ReadStorage<'a, Exile>,
+    pub positions: ReadStorage<'a, Position>,
+    pub sensors: ReadStorage<'a, Sensor>,
+    pub shapes: ReadStorage<'a, Shape>,
+    pub steerings: ReadStorage<'a, Steering>,
+}
+
+
+impl<'a> SteeringData<'a> {
+    /// Whether the entity is a barrier to swerve around rather than someone
+    /// to keep clear of.
+    fn is_obstacle(&self, ent: Entity) -> bool {
+        self.barriers.contains(ent)
+            && !self.sensors.contains(ent)
+            && !self.exiles.contains(ent)
+            && !self.steerings.contains(ent)
+    }
+
+    /// A push away from a barrier ahead of something at `center` moving
+    /// along `velocity`, stronger the closer it is, of up to 1.
+    fn avoidance(&self, ent: Entity, center: V2, velocity: V2, look_ahead: f32) -> V2 {
+        self.aabb_tree
+            .raycast(
+                &self.entities,
+                center,
+                velocity,
+                look_ahead,
+                &self.shapes,
+                &self.positions,
+                |other| other != ent && self.is_obstacle(other),
+            )
+            .map(|hit| hit.normal.scalar_mul(1.0 - hit.distance / look_ahead))
+            .unwrap_or_else(V2::origin)
+    }
+
+    /// Blend keeping clear of neighbours and swerving around barriers into
+    /// the velocity `ent` wants to move at.
+    pub fn steer(
+        &self,
+        ent: Entity,
+        steering: &Steering,
+        position: V2,
+        desired: V2,
+        max_speed: f32,
+    ) -> V2 {
+        let center = self
+            .aabb_tree
+            .aabb(ent)
+            .map(|aabb| aabb.center())
+            .unwrap_or(position);
+        let neighbors: Vec<V2> = self
+            .aabb_tree
+            .query_nearest_n_within(
+                &self.entities,
+                &center,
+                steering.neighbors,
+                steering.separation,
+                |other| {
+                    other != ent && self.steerings.contains(other) && !self.exiles.contains(other)
+                },
+            )
+            .into_iter()
+            .map(|(_, aabb)| aabb.center())
+            .collect();
+        let push = separation(center, &neighbors, steering.separation);
+        let swerve = if desired.magnitude() > 0.0 {
+            self.avoidance(ent, center, desired, steering.look_ahead)
+        } else {
+            V2::origin()
+        };
+        truncate(desired + (push + swerve).scalar_mul(max_speed), max_speed)
+    }
+}
+
+
+#[cfg(test)]
+mod steering_tests {
+    use super::{super::super::prelude::AABB, *};
+
+    fn body(world: &mut World, position: V2, steering: Option<Steering>) -> Entity {
+        let aabb = AABB::new(position.x, position.y, 10.0, 10.0);
+        let mut builder = world
+            .create_entity()
+            .with(Position(position))
+            .with(Shape::box_with_size(10.0, 10.0))
+            .with(Barrier);
+        if let Some(steering) = steering {
+            builder = builder.with(steering);
+        }
+        let ent = builder.build();
+        world.write_resource::<AABBTree>().insert(ent, aabb);
+        ent
+    }
+
+    #[test]
+    fn seek_and_arrive() {
+        let target = V2::new(100.0, 0.0);
+        assert_eq!(seek(V2::origin(), target, 50.0), V2::new(50.0, 0.0));
+        assert_eq!(
+            arrive(V2::origin(), target, 50.0, 20.0, 1.0 / 60.0),
+            V2::new(50.0, 0.0)
+        );
+        assert_eq!(
+            arrive(V2::new(90.0, 0.0), target, 50.0, 20.0, 1.0 / 60.0),
+            V2::new(25.0, 0.0)
+        );
+        assert_eq!(arrive(target, target, 50.0, 20.0, 1.0 / 60.0), V2::origin());
+    }
+
+    #[test]
+    fn crowds_keep_apart_and_swerve_around_walls() {
+        let mut world = World::new();
+        SteeringData::setup(&mut world);
+        let steering = Steering::default();
+        let a = body(&mut world, V2::origin(), Some(steering.clone()));
+        let _b = body(&mut world, V2::new(12.0, 0.0), Some(steering.clone()));
+        let wall = body(&mut world, V2::new(0.0, 30.0), None);
+
+        let data: SteeringData = world.system_data();
+        // Standing still, a moves away from b
+        let v = data.steer(a, &steering, V2::origin(), V2::origin(), 60.0);
+        assert!(v.x < 0.0 && v.y.abs() < 0.001, "{:?}", v);
+
+        // Heading for the wall, a slows down and backs off from it
+        let v = data.steer(a, &steering, V2::origin(), V2::new(0.0, 60.0), 60.0);
+        assert!(v.y < 60.0 && v.magnitude() <= 60.0 + 0.001, "{:?}", v);
+        assert!(!data.steerings.contains(wall));
+    }
+
+    #[test]
+    fn walls_do_not_crowd_out_neighbors() {
+        let mut world = World::new();
+        SteeringData::setup(&mut world);
+        let steering = Steering {
+            neighbors: 1,
+            ..Steering::default()
+        };
+        let a = body(&mut world, V2::origin(), Some(steering.clone()));
+        let _b = body(&mut world, V2::new(0.0, 12.0), Some(steering.clone()));
+        // A wall right next to a, closer than b
+        let _wall = body(&mut world, V2::new(10.0, 0.0), None);
+
+        let data: SteeringData = world.system_data();
+        let v = data.steer(a, &steering, V2::origin(), V2::origin(), 60.0);
+        assert!(v.y < 0.0 && v.x.abs() < 0.001, "{:?}", v);
+    }
+}
@@ -0,0 +1,726 @@
+//! Exporting the ECS back into a Tiled map.
+//!
+//! This is the inverse of `insert_map`. Tile entities are written back into
+//! the tile layer cells they came from and every other entity with a
+//! `Position` becomes a Tiled object. Tilesets are taken from a template map,
+//! usually the map the world was loaded from, so the tile gids written out
+//! match the original tilesets.
+use super::{
+    super::super::prelude::{
+        Animation, Barrier, Behavior, Cardinal, CollisionFilter, Drag, Entities, Entity, Exile,
+        Fence, Friction, GlobalId, GlobalTileIndex, Gravity, Join, Layer, LayerData,
+        LayerLayerData, LoadBehavior, Mass, Name, Object, ObjectLayerData, ObjectRenderingToggles,
+        OneWay, Point, Position, Property, ReadStorage, Rendering, ResourceId, Restitution, Route,
+        RouteFollower, RouteMode, Sensor, Shape, StepFence, SystemData, TileLayerData, Tiledmap,
+        Vision, World, ZLevel, Zone, JSON, V2,
+    },
+    ObjectId, TileCell,
+};
+use log::warn;
+use serde_json::Value;
+use std::collections::HashMap;
+
+
+#[derive(SystemData)]
+pub struct ExportMapData<'s> {
+    animations: ReadStorage<'s, Animation>,
+    barriers: ReadStorage<'s, Barrier>,
+    behaviors: ReadStorage<'s, Behavior>,
+    cardinals: ReadStorage<'s, Cardinal>,
+    collision_filters: ReadStorage<'s, CollisionFilter>,
+    drags: ReadStorage<'s, Drag>,
+    entities: Entities<'s>,
+    exiles: ReadStorage<'s, Exile>,
+    fences: ReadStorage<'s, Fence>,
+    frictions: ReadStorage<'s, Friction>,
+    gravities: ReadStorage<'s, Gravity>,
+    jsons: ReadStorage<'s, JSON>,
+    load_behaviors: ReadStorage<'s, LoadBehavior>,
+    masses: ReadStorage<'s, Mass>,
+    names: ReadStorage<'s, Name>,
+    objects: ReadStorage<'s, Object>,
+    object_ids: ReadStorage<'s, ObjectId>,
+    object_toggles: ReadStorage<'s, ObjectRenderingToggles>,
+    one_ways: ReadStorage<'s, OneWay>,
+    positions: ReadStorage<'s, Position>,
+    renderings: ReadStorage<'s, Rendering>,
+    restitutions: ReadStorage<'s, Restitution>,
+    route_followers: ReadStorage<'s, RouteFollower>,
+    routes: ReadStorage<'s, Route>,
+    sensors: ReadStorage<'s, Sensor>,
+    shapes: ReadStorage<'s, Shape>,
+    step_fences: ReadStorage<'s, StepFence>,
+    tile_cells: ReadStorage<'s, TileCell>,
+    visions: ReadStorage<'s, Vision>,
+    zlevels: ReadStorage<'s, ZLevel>,
+    zones: ReadStorage<'s, Zone>,
+}
+
+
+/// Find the tile index that would produce the given rendering or animation.
+///
+/// Animated tiles are matched by the first frame of their animation.
+fn tile_index(
+    map: &Tiledmap,
+    rendering: Option<&Rendering>,
+    animation: Option<&Animation>,
+) -> Option<GlobalTileIndex> {
+    let frame = animation
+        .and_then(|anime| anime.frames.first())
+        .map(|frame| &frame.rendering)
+        .or(rendering)?
+        .as_frame()?;
+    let gid = map.get_gid_by_frame(&frame.sprite_sheet, &frame.source_aabb);
+    if gid.is_none() {
+        warn!(
+            "could not find a tile for {:?} in sprite sheet '{}'",
+            frame.source_aabb, frame.sprite_sheet
+        );
+    }
+    let mut gid = gid?;
+
+    if animation.is_some() {
+        let (firstgid, tileset) = map.get_tileset_by_gid(&gid)?;
+        let lid = firstgid.convert_to_local(&gid);
+        let animated = tileset.tiles.iter().find(|tile| {
+            tile.animation
+                .as_ref()
+                .and_then(|frames| frames.first())
+                .map(|frame| frame.tileid == lid)
+                .unwrap_or(false)
+        });
+        if let Some(tile) = animated {
+            gid = GlobalId(firstgid.0 + tile.id.0);
+        }
+    }
+
+    Some(GlobalTileIndex {
+        id: gid,
+        is_flipped_horizontally: frame.is_flipped_horizontally,
+        is_flipped_vertically: frame.is_flipped_vertically,
+        is_flipped_diagonally: frame.is_flipped_diagonally,
+    })
+}
+
+
+fn points(vs: &[V2]) -> Option<Vec<Point<f32>>> {
+    Some(vs.iter().map(|v| Point { x: v.x, y: v.y }).collect())
+}
+
+
+/// Write one entity out as a Tiled object.
+fn export_object(
+    map: &Tiledmap,
+    data: &ExportMapData,
+    ent: Entity,
+    id: u32,
+    position: &Position,
+) -> Object {
+    let mut obj = data.objects.get(ent).cloned().unwrap_or(Object {
+        id,
+        width: 0.0,
+        height: 0.0,
+        name: String::new(),
+        type_is: String::new(),
+        properties: vec![],
+        visible: true,
+        x: 0.0,
+        y: 0.0,
+        rotation: 0.0,
+        gid: None,
+        ellipse: false,
+        point: false,
+        polygon: None,
+        polyline: None,
+        text: HashMap::new(),
+    });
+    obj.id = id;
+    obj.name = data
+        .names
+        .get(ent)
+        .map(|Name(name)| name.clone())
+        .unwrap_or_default();
+    obj.visible = data.exiles.get(ent).is_none();
+    obj.x = position.0.x;
+    obj.y = position.0.y;
+
+    let mut properties: HashMap<String, Value> = data
+        .jsons
+        .get(ent)
+        .map(|JSON(props)| props.clone())
+        .unwrap_or_default();
+    let body = [
+        (
+            Mass::tiled_property(),
+            data.masses.get(ent).map(|Mass(m)| *m),
+        ),
+        (
+            Friction::tiled_property(),
+            data.frictions.get(ent).map(|Friction(f)| *f),
+        ),
+        (
+            Drag::tiled_property(),
+            data.drags.get(ent).map(|Drag(d)| *d),
+        ),
+        (
+            Restitution::tiled_property(),
+            data.restitutions.get(ent).map(|Restitution(e)| *e),
+        ),
+    ];
+    for (name, value) in body.iter() {
+        if let Some(value) = value {
+            properties.insert(name.clone(), Value::from(*value as f64));
+        }
+    }
+    if let Some(Gravity(gravity)) = data.gravities.get(ent) {
+        properties.insert(Gravity::tiled_x_property(), Value::from(gravity.x as f64));
+        properties.insert(Gravity::tiled_y_property(), Value::from(gravity.y as f64));
+    }
+    if let Some(OneWay(side)) = data.one_ways.get(ent) {
+        properties.insert(OneWay::tiled_property(), Value::from(side.as_str()));
+    }
+    let behavior_file = data
+        .load_behaviors
+        .get(ent)
+        .map(|LoadBehavior { file }| file)
+        .or_else(|| data.behaviors.get(ent).and_then(|b| b.file.as_ref()));
+    if let Some(file) = behavior_file {
+        properties.insert(LoadBehavior::tiled_property(), Value::from(file.as_str()));
+    }
+    if let Some(vision) = data.visions.get(ent) {
+        properties.insert(
+            Vision::tiled_range_property(),
+            Value::from(vision.range as f64),
+        );
+        properties.insert(Vision::tiled_fov_property(), Value::from(vision.fov as f64));
+        if let Some(facing) = data.cardinals.get(ent) {
+            properties.insert(
+                Vision::tiled_facing_property(),
+                Value::from(facing.as_str()),
+            );
+        }
+    }
+    if let Some(follower) = data.route_followers.get(ent) {
+        properties.insert(RouteFollower::tiled_key(), follower.route.to_value());
+        properties.insert(
+            RouteMode::tiled_key(),
+            Value::from(follower.progress.mode.as_str()),
+        );
+    }
+    if data.sensors.get(ent).is_some() && data.zones.get(ent).is_none() {
+        properties.insert(Sensor::tiled_type(), Value::Bool(true));
+    }
+    if let Some(filter) = data.collision_filters.get(ent) {
+        properties.insert(
+            CollisionFilter::tiled_category_property(),
+            Value::from(filter.category as i32),
+        );
+        properties.insert(
+            CollisionFilter::tiled_mask_property(),
+            Value::from(filter.mask as i32),
+        );
+        properties.insert(
+            CollisionFilter::tiled_same_zlevel_property(),
+            Value::Bool(filter.same_zlevel),
+        );
+    }
+
+    let gid = tile_index(map, data.renderings.get(ent), data.animations.get(ent));
+    if let Some(gid) = gid {
+        // Tile objects are positioned by their bottom left corner.
+        let (w, h) = match data.shapes.get(ent) {
+            Some(Shape::Box { lower, upper })
+                if data.barriers.get(ent).is_none() && lower.x == 0.0 && lower.y == 0.0 =>
+            {
+                (upper.x, upper.y)
+            }
+            _ => {
+                let (w, h) = data.renderings.get(ent).map(|r| r.size()).unwrap_or((0, 0));
+                (w as f32, h as f32)
+            }
+        };
+        obj.width = w;
+        obj.height = h;
+        obj.y += h;
+        obj.gid = Some(gid);
+    } else if let Some(step_fence) = data.step_fences.get(ent) {
+        obj.type_is = "step_fence".to_string();
+        obj.polyline = points(&step_fence.fence.points);
+        properties.insert("step".to_string(), Value::from(step_fence.step as f64));
+    } else if let Some(fence) = data.fences.get(ent) {
+        obj.type_is = "fence".to_string();
+        obj.polyline = points(&fence.points);
+    } else if let Some(route) = data.routes.get(ent) {
+        obj.type_is = Route::tiled_type();
+        obj.polyline = points(&route.points);
+        properties.extend(route.wait_properties());
+    } else {
+        match data.shapes.get(ent) {
+            Some(Shape::Polygon { vertices }) | Some(Shape::Concave { vertices, .. }) => {
+                obj.polygon = points(vertices);
+            }
+            Some(Shape::Box { lower, upper }) => {
+                obj.x += lower.x;
+                obj.y += lower.y;
+                obj.width = upper.x - lower.x;
+                obj.height = upper.y - lower.y;
+            }
+            None => {}
+        }
+        if data.zones.get(ent).is_some() && data.sensors.get(ent).is_some() {
+            obj.type_is = Sensor::tiled_type();
+        } else if data.zones.get(ent).is_some() {
+            obj.type_is = "zone".to_string();
+        } else if data.barriers.get(ent).is_some() && data.sensors.get(ent).is_none() {
+            // Sensors are written with a sensor property instead
+            obj.type_is = "barrier".to_string();
+        }
+    }
+
+    if let Some(ObjectRenderingToggles(toggles)) = data.object_toggles.get(ent) {
+        for toggle in toggles.iter() {
+            properties.insert(toggle.property_str().to_string(), Value::Bool(true));
+        }
+    }
+    let mut properties: Vec<Property> = properties
+        .iter()
+        .map(|(name, value)| Property::from_value(name, value.clone()))
+        .collect();
+    properties.sort_by(|a, b| a.name.cmp(&b.name));
+    obj.properties = properties;
+
+    obj
+}
+
+
+/// The flattened leaf layers of a map, in the order `insert_map` assigns
+/// their ZLevels.
+enum Leaf {
+    Tiles,
+    Objects,
+}
+
+
+fn leaves(layers: &[Layer], out: &mut Vec<Leaf>) {
+    for layer in layers.iter() {
+        match &layer.layer_data {
+            LayerData::Tiles(_) => out.push(Leaf::Tiles),
+            LayerData::Objects(_) => out.push(Leaf::Objects),
+            LayerData::Layers(group) => leaves(&group.layers, out),
+        }
+    }
+}
+
+
+/// Rebuild the template's layers, filling each leaf layer with its exported
+/// tiles or objects.
+fn fill_layers(
+    layers: &[Layer],
+    z: &mut usize,
+    empty_tile: &GlobalTileIndex,
+    tiles: &mut HashMap<usize, Vec<GlobalTileIndex>>,
+    objects: &mut HashMap<usize, Vec<Object>>,
+) -> Vec<Layer> {
+    layers
+        .iter()
+        .map(|layer| {
+            let layer_data = match &layer.layer_data {
+                LayerData::Tiles(tile_data) => {
+                    // A layer without any tile entities left is written out empty
+                    let data = tiles.remove(z).unwrap_or_else(|| {
+                        vec![empty_tile.clone(); (tile_data.width * tile_data.height) as usize]
+                    });
+                    *z += 1;
+                    LayerData::Tiles(TileLayerData {
+                        data,
+                        ..tile_data.clone()
+                    })
+                }
+                LayerData::Objects(object_data) => {
+                    let objects = objects.remove(z).unwrap_or_default();
+                    *z += 1;
+                    LayerData::Objects(ObjectLayerData {
+                        draworder: object_data.draworder.clone(),
+                        objects,
+                    })
+                }
+                LayerData::Layers(group) => LayerData::Layers(LayerLayerData {
+                    layers: fill_layers(&group.layers, z, empty_tile, tiles, objects),
+                }),
+            };
+            Layer {
+                layer_data,
+                ..layer.clone()
+            }
+        })
+        .collect()
+}
+
+
+fn new_layer(name: String, type_is: &str, layer_data: LayerData) -> Layer {
+    Layer {
+        name,
+        type_is: type_is.to_string(),
+        visible: true,
+        x: 0,
+        y: 0,
+        properties: None,
+        opacity: 1.0,
+        layer_data,
+    }
+}
+
+
+/// Export the world into a Tiled map.
+///
+/// The template map provides the map's dimensions, its tilesets and its layer
+/// structure. Its tilesets must be hydrated for tile gids to be found. Tile
+/// entities are written into the tile layer they were loaded from, exiled tiles
+/// are left out and tile layers without any tiles are written empty. Objects
+/// are placed in the object layer matching their ZLevel, or the nearest object
+/// layer below it. Objects that were not loaded from a map are given new ids
+/// and any entities that don't fit in the template's layers are written into
+/// new layers at the top of the map.
+pub fn export_map(template: &Tiledmap, data: &ExportMapData) -> Tiledmap {
+    let mut leaf_kinds = vec![];
+    leaves(&template.layers, &mut leaf_kinds);
+
+    let width = template.width.max(0) as u32;
+    let height = template.height.max(0) as u32;
+    let empty_tile = GlobalTileIndex {
+        id: GlobalId(0),
+        is_flipped_horizontally: false,
+        is_flipped_vertically: false,
+        is_flipped_diagonally: false,
+    };
+
+    // Write the tiles into their layers
+    let mut tiles: HashMap<usize, Vec<GlobalTileIndex>> = HashMap::new();
+    for (ent, cell, ()) in (&data.entities, &data.tile_cells, !&data.exiles).join() {
+        if cell.x >= width || cell.y >= height {
+            warn!("tile cell {:?} is outside of the map", cell);
+            continue;
+        }
+        let layer = tiles
+            .entry(cell.layer as usize)
+            .or_insert_with(|| vec![empty_tile.clone(); (width * height) as usize]);
+        let gid = tile_index(template, data.renderings.get(ent), data.animations.get(ent));
+        if let Some(gid) = gid {
+            layer[(cell.y * width + cell.x) as usize] = gid;
+        }
+    }
+
+    // Write the objects, keeping their ids where possible
+    let mut next_id = template.nextobjectid.max(1) as u32;
+    for ObjectId(id) in (&data.object_ids).join() {
+        next_id = next_id.max(id + 1);
+    }
+    let mut objects: HashMap<usize, Vec<Object>> = HashMap::new();
+    let mut entities = (&data.entities, &data.positions, !&data.tile_cells)
+        .join()
+        .filter(|(ent, _, _)| {
+            data.object_ids.get(*ent).is_some()
+                || data.shapes.get(*ent).is_some()
+                || data.renderings.get(*ent).is_some()
+                || data.zones.get(*ent).is_some()
+                || data.fences.get(*ent).is_some()
+                || data.step_fences.get(*ent).is_some()
+                || data.barriers.get(*ent).is_some()
+                || data.names.get(*ent).is_some()
+                || data.jsons.get(*ent).is_some()
+        })
+        .collect::<Vec<_>>();
+    // Keep exported objects in a stable order
+    entities.sort_by_key(|(ent, _, _)| {
+        (
+            data.object_ids
+                .get(*ent)
+                .map(|ObjectId(id)| *id)
+                .unwrap_or(u32::MAX),
+            ent.id(),
+        )
+    });
+    for (ent, position, ()) in entities {
+        let id = if let Some(ObjectId(id)) = data.object_ids.get(ent) {
+            *id
+        } else {
+            next_id += 1;
+            next_id - 1
+        };
+        let z = data
+            .zlevels
+            .get(ent)
+            .map(|ZLevel(z)| z.floor().max(0.0) as usize)
+            .unwrap_or(0);
+        let layer = (0..=z.min(leaf_kinds.len()))
+            .rev()
+            .find(|i| matches!(leaf_kinds.get(*i), Some(Leaf::Objects)))
+            .unwrap_or_else(|| z.max(leaf_kinds.len()));
+        objects
+            .entry(layer)
+            .or_default()
+            .push(export_object(template, data, ent, id, position));
+    }
+
+    let mut z = 0;
+    let mut layers = fill_layers(
+        &template.layers,
+        &mut z,
+        &empty_tile,
+        &mut tiles,
+        &mut objects,
+    );
+
+    // Anything left over goes into new layers
+    let mut extra: Vec<usize> = tiles.keys().chain(objects.keys()).cloned().collect();
+    extra.sort();
+    extra.dedup();
+    for z in extra {
+        if let Some(data) = tiles.remove(&z) {
+            layers.push(new_layer(
+                format!("tiles {}", z),
+                "tilelayer",
+                LayerData::Tiles(TileLayerData {
+                    width,
+                    height,
+                    data,
+                }),
+            ));
+        }
+        if let Some(objects) = objects.remove(&z) {
+            layers.push(new_layer(
+                format!("objects {}", z),
+                "objectgroup",
+                LayerData::Objects(ObjectLayerData {
+                    draworder: "topdown".to_string(),
+                    objects,
+                }),
+            ));
+        }
+    }
+
+    Tiledmap {
+        layers,
+        nextobjectid: next_id as i32,
+        ..template.clone()
+    }
+}
+
+
+#[cfg(test)]
+mod export_tests {
+    use super::{
+        super::{insert_map, InsertMapData},
+        *,
+    };
+    use specs::{World, WorldExt, WriteStorage};
+
+    /// What should survive a round trip of an object.
+    #[derive(Debug, PartialEq)]
+    struct Summary {
+        id: u32,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        type_is: String,
+        properties: Vec<(String, Value)>,
+    }
+
+
+    fn summaries(layers: &[Layer], out: &mut Vec<Summary>) {
+        for layer in layers.iter() {
+            match &layer.layer_data {
+                LayerData::Objects(objects) => out.extend(objects.objects.iter().map(|obj| {
+                    let mut properties = obj
+                        .properties
+                        .iter()
+                        .map(|p| (p.name.clone(), p.value.clone()))
+                        .collect::<Vec<_>>();
+                    properties.sort_by(|a, b| a.0.cmp(&b.0));
+                    Summary {
+                        id: obj.id,
+                        x: obj.x,
+                        y: obj.y,
+                        width: obj.width,
+                        height: obj.height,
+                        type_is: obj.type_is.clone(),
+                        properties,
+                    }
+                })),
+                LayerData::Layers(group) => summaries(&group.layers, out),
+                LayerData::Tiles(_) => {}
+            }
+        }
+    }
+
+
+    fn object_summaries(map: &Tiledmap) -> Vec<Summary> {
+        let mut out = vec![];
+        summaries(&map.layers, &mut out);
+        out.sort_by_key(|summary| summary.id);
+        out
+    }
+
+
+    /// Insert the map into a new world and export it again.
+    fn reexport(map: &Tiledmap) -> Tiledmap {
+        let mut world = World::new();
+        InsertMapData::setup(&mut world);
+        ExportMapData::setup(&mut world);
+        insert_map(map, &mut world.system_data());
+        world.maintain();
+        let data = world.system_data();
+        export_map(map, &data)
+    }
+
+
+    #[test]
+    fn can_round_trip_a_map() {
+        let mut map = Tiledmap::new(std::path::Path::new(
+            "../examples/loading-maps/maps/full_test.json",
+        ))
+        .expect("could not load map");
+        // Objects with every property the exporter writes
+        let layer = serde_json::json!({
+            "name": "bodies",
+            "type": "objectgroup",
+            "visible": true,
+            "x": 0,
+            "y": 0,
+            "opacity": 1,
+            "draworder": "topdown",
+            "objects": [
+                {
+                    "id": 1001, "name": "walker", "type": "", "visible": true,
+                    "x": 16.0, "y": 32.0, "width": 8.0, "height": 12.0, "rotation": 0,
+                    "properties": [
+                        {"name": "mass", "type": "float", "value": 2.0},
+                        {"name": "friction", "type": "float", "value": 0.5},
+                        {"name": "drag", "type": "float", "value": 0.25},
+                        {"name": "restitution", "type": "float", "value": 0.75},
+                        {"name": "gravity_x", "type": "float", "value": 0.0},
+                        {"name": "gravity_y", "type": "float", "value": 8.0},
+                        {"name": "collision_category", "type": "int", "value": 2},
+                        {"name": "collision_mask", "type": "int", "value": -1},
+                        {"name": "collision_same_zlevel", "type": "bool", "value": false},
+                        {"name": "behavior", "type": "string", "value": "behaviors/walk.json"},
+                        {"name": "vision_range", "type": "float", "value": 64.0},
+                        {"name": "vision_fov", "type": "float", "value": 90.0},
+                        {"name": "facing", "type": "string", "value": "west"},
+                        {"name": "route", "type": "string", "value": "patrol"},
+                        {"name": "route_mode", "type": "string", "value": "loop"},
+                        {"name": "hit_points", "type": "int", "value": 3}
+                    ]
+                },
+                {
+                    "id": 1002, "name": "", "type": "barrier", "visible": true,
+                    "x": 48.0, "y": 64.0, "width": 32.0, "height": 4.0, "rotation": 0,
+                    "properties": [
+                        {"name": "one_way", "type": "string", "value": "north"}
+                    ]
+                },
+                {
+                    "id": 1003, "name": "", "type": "", "visible": true,
+                    "x": 80.0, "y": 16.0, "width": 16.0, "height": 16.0, "rotation": 0,
+                    "properties": [
+                        {"name": "sensor", "type": "bool", "value": true}
+                    ]
+                },
+                {
+                    "id": 1004, "name": "patrol", "type": "path", "visible": true,
+                    "x": 0.0, "y": 0.0, "width": 0.0, "height": 0.0, "rotation": 0,
+                    "polyline": [{"x": 0.0, "y": 0.0}, {"x": 32.0, "y": 0.0}],
+                    "properties": [
+                        {"name": "wait", "type": "float", "value": 1.0}
+                    ]
+                }
+            ]
+        });
+        map.layers
+            .push(serde_json::from_value(layer).expect("could not read layer"));
+        map.nextobjectid = 1005;
+
+        let exported = reexport(&map);
+        let text = exported.to_text().expect("could not serialize map");
+        let reloaded = Tiledmap::from_text(&text).expect("could not deserialize map");
+
+        let tile_data = |map: &Tiledmap| {
+            map.layers
+                .iter()
+                .flat_map(|layer| match &layer.layer_data {
+                    LayerData::Tiles(tiles) => tiles.data.iter().map(|t| t.to_bits()).collect(),
+                    _ => vec![],
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tile_data(&map), tile_data(&reloaded));
+        assert!(reloaded.nextobjectid >= map.nextobjectid);
+
+        let original = object_summaries(&map);
+        let once = object_summaries(&reloaded);
+        assert_eq!(
+            original.iter().map(|s| s.id).collect::<Vec<_>>(),
+            once.iter().map(|s| s.id).collect::<Vec<_>>()
+        );
+        // The bodies' properties are all read into components and written back
+        fn bodies(summaries: &[Summary]) -> Vec<&Summary> {
+            summaries.iter().filter(|s| s.id > 1000).collect()
+        }
+        assert_eq!(bodies(&original), bodies(&once));
+
+        // Everything that was exported comes back out the same
+        let twice = object_summaries(&reexport(&reloaded));
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn exports_an_exiled_tile_layer_as_empty() {
+        let map = Tiledmap::new(std::path::Path::new(
+            "../examples/loading-maps/maps/full_test.json",
+        ))
+        .expect("could not load map");
+
+        let mut world = World::new();
+        InsertMapData::setup(&mut world);
+        ExportMapData::setup(&mut world);
+        insert_map(&map, &mut world.system_data());
+        world.maintain();
+
+        // Exile every tile of the second floor's tile layer
+        {
+            let (entities, tile_cells, mut exiles): (
+                Entities,
+                ReadStorage<TileCell>,
+                WriteStorage<Exile>,
+            ) = world.system_data();
+            let exiled = (&entities, &tile_cells)
+                .join()
+                .filter(|(_, cell)| cell.layer == 2)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+            assert!(!exiled.is_empty());
+            for ent in exiled {
+                Exile::exile(ent, "test", &mut exiles);
+            }
+        }
+
+        let exported = export_map(&map, &world.system_data());
+        let tile_count = |layer: &Layer| match &layer.layer_data {
+            LayerData::Layers(group) => group
+                .layers
+                .iter()
+                .map(|layer| match &layer.layer_data {
+                    LayerData::Tiles(tiles) => {
+                        tiles.data.iter().filter(|t| t.id != GlobalId(0)).count()
+                    }
+                    _ => 0,
+                })
+                .sum::<usize>(),
+            _ => 0,
+        };
+        assert_eq!(tile_count(&exported.layers[0]), tile_count(&map.layers[0]));
+        assert!(tile_count(&map.layers[1]) > 0);
+        assert_eq!(tile_count(&exported.layers[1]), 0);
+    }
+}
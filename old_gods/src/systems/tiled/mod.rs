@@ -6,20 +6,29 @@
 use super::super::{
     fetch,
     prelude::{
-        Animation, Barrier, CanBeEmpty, Component, Either, Entities, Entity, Fence, Frame,
-        GlobalTileIndex, HashMapStorage, Join, Layer, LayerData, LoadStatus, LoadableResources,
-        Name, Object, ObjectGroup, ObjectLayerData, ObjectRenderingToggles, OriginOffset, Position,
-        Rendering, RenderingToggles, ResourceId, Resources, Shape, SharedResource, StepFence,
-        System, SystemData, TextureFrame, TileLayerData, Tiledmap, World, WriteStorage, ZLevel,
-        Zone, JSON, V2,
+        Animation, Barrier, CanBeEmpty, Cardinal, CollisionFilter, Component, Drag, Either,
+        Entities, Entity, Fence, Frame, Friction, GlobalTileIndex, Gravity, HashMapStorage, Join,
+        Layer, LayerData, LoadBehavior, LoadStatus, LoadableResources, MapGravity, Mass, Name,
+        NavMesh, Object, ObjectGroup, ObjectLayerData, ObjectRenderingToggles, OneWay,
+        OriginOffset, Position, Rendering, RenderingToggles, ResourceId, Resources, Restitution,
+        Route, RouteFollower, Sensor, Shape, SharedResource, StepFence, System, SystemData,
+        TextureFrame, TileGrid, TileLayerData, Tiledmap, Velocity, Vision, World, Write,
+        WriteStorage, ZLevel, Zone, AABB, JSON, V2,
     },
     resources,
 };
 use log::{trace, warn};
 use serde_json::Value;
-use std::{collections::HashMap, iter::FromIterator};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::FromIterator,
+};
 use wasm_bindgen_futures::spawn_local;
 
+mod export;
+mod terrain;
+pub use self::{export::*, terrain::*};
+
 
 pub struct TiledmapResources {
     base_url: String,
@@ -92,6 +101,33 @@ impl Component for LoadMap {
 }
 
 
+/// The id of the Tiled object an entity was created from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjectId(pub u32);
+
+
+impl Component for ObjectId {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// The cell of the tile layer a tile entity was created from.
+///
+/// `layer` is the index of the tile's layer after all group layers have been
+/// flattened, which is also the tile's initial ZLevel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileCell {
+    pub layer: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+
+impl Component for TileCell {
+    type Storage = HashMapStorage<Self>;
+}
+
+
 /// Return a rendering for the tile with the given GlobalId.
 pub fn get_rendering(
     tm: &Tiledmap,
@@ -174,6 +210,72 @@ pub fn add_barrier(
 }
 
 
+/// Read the physical properties of a body out of an object's properties,
+/// removing them. An object with a mass becomes a dynamic body.
+fn add_body(ent: Entity, properties: &mut HashMap<String, Value>, data: &mut InsertMapData) {
+    let category = properties
+        .remove(&CollisionFilter::tiled_category_property())
+        .and_then(|v| v.as_i64());
+    let mask = properties
+        .remove(&CollisionFilter::tiled_mask_property())
+        .and_then(|v| v.as_i64());
+    let same_zlevel = properties
+        .remove(&CollisionFilter::tiled_same_zlevel_property())
+        .and_then(|v| v.as_bool());
+    if category.is_some() || mask.is_some() || same_zlevel.is_some() {
+        // Tiled ints are signed, so a mask of -1 has every bit set
+        let default = CollisionFilter::default();
+        let filter = CollisionFilter {
+            category: category.map(|c| c as u32).unwrap_or(default.category),
+            mask: mask.map(|m| m as u32).unwrap_or(default.mask),
+            same_zlevel: same_zlevel.unwrap_or(default.same_zlevel),
+        };
+        let _ = data.collision_filters.insert(ent, filter);
+    }
+
+    let mut take = |name: String| {
+        properties
+            .remove(&name)
+            .and_then(|v| v.as_f64())
+            .map(|f| f as f32)
+    };
+    if let Some(mass) = take(Mass::tiled_property()) {
+        let _ = data.masses.insert(ent, Mass(mass));
+        if !data.velocities.contains(ent) {
+            let _ = data.velocities.insert(ent, Velocity(V2::origin()));
+        }
+    }
+    if let Some(friction) = take(Friction::tiled_property()) {
+        let _ = data.frictions.insert(ent, Friction(friction));
+    }
+    if let Some(drag) = take(Drag::tiled_property()) {
+        let _ = data.drags.insert(ent, Drag(drag));
+    }
+    if let Some(restitution) = take(Restitution::tiled_property()) {
+        let _ = data.restitutions.insert(ent, Restitution(restitution));
+    }
+    if let Some(gravity) = Gravity::from_properties(properties) {
+        let _ = data.gravities.insert(ent, gravity);
+    }
+    if let Some(side) = properties.remove(&OneWay::tiled_property()) {
+        match side.as_str().and_then(Cardinal::try_from_str) {
+            Some(side) => {
+                let _ = data.one_ways.insert(ent, OneWay(side));
+            }
+            None => warn!("'{}' is not a direction for a one way barrier", side),
+        }
+    }
+    let is_sensor = properties
+        .remove(&Sensor::tiled_type())
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    if is_sensor {
+        let _ = data.barriers.insert(ent, Barrier);
+        let _ = data.sensors.insert(ent, Sensor);
+    }
+}
+
+
 pub struct TiledmapSystem {
     resources: TiledmapResources,
 }
@@ -193,16 +295,35 @@ pub struct InsertMapData<'s> {
     entities: Entities<'s>,
     animations: WriteStorage<'s, Animation>,
     barriers: WriteStorage<'s, Barrier>,
+    cardinals: WriteStorage<'s, Cardinal>,
+    collision_filters: WriteStorage<'s, CollisionFilter>,
+    drags: WriteStorage<'s, Drag>,
     fences: WriteStorage<'s, Fence>,
+    frictions: WriteStorage<'s, Friction>,
+    gravities: WriteStorage<'s, Gravity>,
     jsons: WriteStorage<'s, JSON>,
+    load_behaviors: WriteStorage<'s, LoadBehavior>,
+    map_gravity: Write<'s, MapGravity>,
+    masses: WriteStorage<'s, Mass>,
+    nav_mesh: Write<'s, NavMesh>,
     names: WriteStorage<'s, Name>,
     objects: WriteStorage<'s, Object>,
+    object_ids: WriteStorage<'s, ObjectId>,
     object_toggles: WriteStorage<'s, ObjectRenderingToggles>,
     offsets: WriteStorage<'s, OriginOffset>,
+    one_ways: WriteStorage<'s, OneWay>,
     positions: WriteStorage<'s, Position>,
     renderings: WriteStorage<'s, Rendering>,
+    restitutions: WriteStorage<'s, Restitution>,
+    route_followers: WriteStorage<'s, RouteFollower>,
+    routes: WriteStorage<'s, Route>,
+    sensors: WriteStorage<'s, Sensor>,
     shapes: WriteStorage<'s, Shape>,
     step_fences: WriteStorage<'s, StepFence>,
+    tile_cells: WriteStorage<'s, TileCell>,
+    tile_grid: Write<'s, TileGrid>,
+    velocities: WriteStorage<'s, Velocity>,
+    visions: WriteStorage<'s, Vision>,
     zlevels: WriteStorage<'s, ZLevel>,
     zones: WriteStorage<'s, Zone>,
 }
@@ -248,6 +369,29 @@ pub fn insert_map(map: &Tiledmap, data: &mut InsertMapData) {
         layers_out
     };
 
+    // The whole map is walkable, barriers are carved out of it later
+    data.nav_mesh.set_bounds(AABB::new(
+        0.0,
+        0.0,
+        (map.width * map.tilewidth) as f32,
+        (map.height * map.tileheight) as f32,
+    ));
+
+    // As is every tile, until a tile barrier blocks it
+    *data.tile_grid = TileGrid::new(
+        map.width as u32,
+        map.height as u32,
+        map.tilewidth as f32,
+        map.tileheight as f32,
+    );
+    // The cells that a tile has given a movement cost
+    let mut costed = HashSet::new();
+
+    // Maps without gravity are top-down
+    *data.map_gravity = Gravity::from_map_properties(&map.properties)
+        .map(|Gravity(gravity)| MapGravity(gravity))
+        .unwrap_or_default();
+
     // Here's an empty vec just in case we need a ref to an empty vec (we do).
     let empty_vec = vec![];
     // Insert the flattened layers of tiles and objects
@@ -270,6 +414,14 @@ pub fn insert_map(map: &Tiledmap, data: &mut InsertMapData) {
                         (map.tileheight * yndx) as f32,
                     );
                     let _ = data.positions.insert(tile_ent, Position(origin));
+                    let _ = data.tile_cells.insert(
+                        tile_ent,
+                        TileCell {
+                            layer: z as u32,
+                            x: xndx as u32,
+                            y: yndx as u32,
+                        },
+                    );
                     if let Some(rendering) = get_rendering(map, &global_ndx, None) {
                         let _ = data.renderings.insert(tile_ent, rendering);
                     }
@@ -290,6 +442,22 @@ pub fn insert_map(map: &Tiledmap, data: &mut InsertMapData) {
                             let _ = data.object_toggles.insert(tile_ent, debug_toggles);
                         }
 
+                        // The first tile to give a cell a cost sets it, then stacked
+                        // tiles take the cost of the costliest
+                        if let Some(cost) = properties
+                            .get(&TileGrid::tiled_cost_property())
+                            .and_then(|prop| prop.value.as_f64())
+                        {
+                            let cell = (xndx as u32, yndx as u32);
+                            let mut cost = cost as f32;
+                            if let Some(current) = data.tile_grid.cost(cell.0, cell.1) {
+                                if !costed.insert(cell) {
+                                    cost = current.max(cost);
+                                }
+                                data.tile_grid.set_cost(cell.0, cell.1, Some(cost));
+                            }
+                        }
+
                         for obj in tile
                             .object_group
                             .as_ref()
@@ -302,7 +470,16 @@ pub fn insert_map(map: &Tiledmap, data: &mut InsertMapData) {
                                     add_origin(tile_ent, obj.x, obj.y, &mut data.offsets)
                                 }
                                 "barrier" => {
-                                    add_barrier(tile_ent, obj, &mut data.barriers, &mut data.shapes)
+                                    add_barrier(
+                                        tile_ent,
+                                        obj,
+                                        &mut data.barriers,
+                                        &mut data.shapes,
+                                    );
+                                    data.tile_grid.block_aabb(
+                                        &AABB::new(obj.x, obj.y, obj.width, obj.height)
+                                            .translate(&origin),
+                                    );
                                 }
                                 "shape" => {
                                     let lower = V2::new(obj.x, obj.y);
@@ -323,6 +500,7 @@ pub fn insert_map(map: &Tiledmap, data: &mut InsertMapData) {
                 for obj in objects.iter() {
                     let obj_ent = data.entities.create();
                     let _ = data.zlevels.insert(obj_ent, ZLevel(z as f32));
+                    let _ = data.object_ids.insert(obj_ent, ObjectId(obj.id));
                     if let Some(name) = obj.name.non_empty() {
                         let _ = data.names.insert(obj_ent, Name(name.clone()));
                     }
@@ -384,7 +562,7 @@ pub fn insert_map(map: &Tiledmap, data: &mut InsertMapData) {
                         } else if let Some(polygon) = &obj.polygon {
                             // Polygon
                             let vertices = polygon.iter().map(|p| V2::new(p.x, p.y)).collect();
-                            let shape = Shape::Polygon { vertices };
+                            let shape = Shape::polygon(vertices);
                             let _ = data.shapes.insert(obj_ent, shape);
                         } else {
                             // Rectangle
@@ -412,6 +590,34 @@ pub fn insert_map(map: &Tiledmap, data: &mut InsertMapData) {
 
                     let mut properties: HashMap<String, Value> =
                         properties.into_iter().map(|(k, p)| (k, p.value)).collect();
+                    add_body(obj_ent, &mut properties, data);
+                    if let Some(file) = properties
+                        .remove(&LoadBehavior::tiled_property())
+                        .and_then(|v| v.as_str().map(|s| s.to_string()))
+                    {
+                        let _ = data.load_behaviors.insert(obj_ent, LoadBehavior { file });
+                    }
+                    if let Some(vision) = Vision::from_properties(&mut properties) {
+                        let _ = data.visions.insert(obj_ent, vision);
+                    }
+                    if let Some(facing) = properties.remove(&Vision::tiled_facing_property()) {
+                        match facing.as_str().and_then(Cardinal::try_from_str) {
+                            Some(facing) => {
+                                let _ = data.cardinals.insert(obj_ent, facing);
+                            }
+                            None => warn!("'{}' is not a direction to face", facing),
+                        }
+                    }
+                    match RouteFollower::from_properties(&mut properties) {
+                        Some(Ok(follower)) => {
+                            let _ = data.route_followers.insert(obj_ent, follower);
+                            if !data.velocities.contains(obj_ent) {
+                                let _ = data.velocities.insert(obj_ent, Velocity(V2::origin()));
+                            }
+                        }
+                        Some(Err(msg)) => warn!("{:?} can't follow its route: {}", obj.name, msg),
+                        None => {}
+                    }
 
                     match obj.get_deep_type(map).as_str() {
                         //"sprite" => Sprite::read(self, map, object),
@@ -419,6 +625,12 @@ pub fn insert_map(map: &Tiledmap, data: &mut InsertMapData) {
                             let _ = data.zones.insert(obj_ent, Zone { inside: vec![] });
                         }
 
+                        "sensor" => {
+                            let _ = data.zones.insert(obj_ent, Zone { inside: vec![] });
+                            let _ = data.barriers.insert(obj_ent, Barrier);
+                            let _ = data.sensors.insert(obj_ent, Sensor);
+                        }
+
                         "fence" => {
                             if let Some(polyline) = &obj.polyline {
                                 let _ = data.fences.insert(
@@ -431,6 +643,17 @@ pub fn insert_map(map: &Tiledmap, data: &mut InsertMapData) {
                                 panic!("a fence must be a polyline");
                             }
                         }
+                        "path" => {
+                            if let Some(polyline) = &obj.polyline {
+                                let points = polyline.iter().map(|p| V2::new(p.x, p.y)).collect();
+                                let _ = data.routes.insert(
+                                    obj_ent,
+                                    Route::from_properties(points, &mut properties),
+                                );
+                            } else {
+                                warn!("path {:?} must be a polyline", obj.name);
+                            }
+                        }
                         "step_fence" => {
                             if let Some(polyline) = &obj.polyline {
                                 let _ = data.step_fences.insert(
@@ -0,0 +1,223 @@
+//! Painting terrain onto tile layers at runtime.
+//!
+//! Terrain is tracked at the corners of each tile, the same way Tiled's
+//! terrain brush works. Painting a cell sets all four of its corners to the
+//! new terrain, and every cell touching those corners is given the tile from
+//! the tileset whose corners best match.
+use super::{
+    super::super::prelude::{
+        Animation, Entities, Entity, GlobalId, GlobalTileIndex, Join, LocalId, ReadStorage,
+        Rendering, ResourceId, SystemData, Tiledmap, Tileset, World, WriteStorage,
+    },
+    get_animation, get_rendering, TileCell,
+};
+use std::collections::HashMap;
+
+
+#[derive(SystemData)]
+pub struct TerrainData<'s> {
+    animations: WriteStorage<'s, Animation>,
+    entities: Entities<'s>,
+    renderings: WriteStorage<'s, Rendering>,
+    tile_cells: ReadStorage<'s, TileCell>,
+}
+
+
+type Corners<'a> = [Option<&'a str>; 4];
+
+
+/// Return the tile whose corners best match the given corners, if any match
+/// at all. Ties go to the lowest tile id.
+fn best_tile(tiles: &[(LocalId, Corners)], corners: &Corners) -> Option<LocalId> {
+    tiles
+        .iter()
+        .map(|(id, tile)| {
+            let score = tile
+                .iter()
+                .zip(corners.iter())
+                .filter(|(a, b)| a == b)
+                .count();
+            (score, id)
+        })
+        .filter(|(score, _)| *score > 0)
+        .max_by(|(a_score, a_id), (b_score, b_id)| a_score.cmp(b_score).then(b_id.0.cmp(&a_id.0)))
+        .map(|(_, id)| id.clone())
+}
+
+
+/// Paint the given cells of a tile layer with a terrain and update the
+/// renderings of the painted cells and their neighbors to match.
+///
+/// `layer` is the index of the tile layer after groups have been flattened,
+/// the same as `TileCell::layer`. The terrain is looked up by name in the
+/// map's tilesets, from either terrain or corner Wang set data. Neighboring
+/// cells that use a different tileset are left alone.
+pub fn paint_terrain(
+    map: &Tiledmap,
+    data: &mut TerrainData,
+    layer: u32,
+    cells: &[(u32, u32)],
+    terrain: &str,
+) -> Result<(), String> {
+    let (firstgid, tileset): (&GlobalId, &Tileset) = map
+        .tilesets
+        .iter()
+        .filter_map(|item| item.tileset().map(|set| (&item.firstgid, set)))
+        .find(|(_, set)| {
+            set.tile_corners()
+                .iter()
+                .any(|(_, corners)| corners.contains(&Some(terrain)))
+        })
+        .ok_or_else(|| format!("no tileset has a terrain named '{}'", terrain))?;
+    let tiles = tileset.tile_corners();
+
+    let mut index: HashMap<(u32, u32), Entity> = HashMap::new();
+    for (ent, cell) in (&data.entities, &data.tile_cells).join() {
+        if cell.layer == layer {
+            index.insert((cell.x, cell.y), ent);
+        }
+    }
+
+    // Find the current corners of a cell, if it is drawn from our tileset.
+    let renderings = &data.renderings;
+    let current_corners = |ent: Entity| -> Option<Corners> {
+        let frame = renderings.get(ent)?.as_frame()?;
+        let gid = map.get_gid_by_frame(&frame.sprite_sheet, &frame.source_aabb)?;
+        let (set_firstgid, _) = map.get_tileset_by_gid(&gid)?;
+        if set_firstgid != firstgid {
+            return None;
+        }
+        let lid = firstgid.convert_to_local(&gid);
+        Some(
+            tiles
+                .iter()
+                .find(|(id, _)| *id == lid)
+                .map(|(_, corners)| *corners)
+                .unwrap_or([None; 4]),
+        )
+    };
+
+    // Paint the corners
+    let mut painted: HashMap<(u32, u32), &str> = HashMap::new();
+    for (x, y) in cells.iter() {
+        for &point in [(*x, *y), (x + 1, *y), (*x, y + 1), (x + 1, y + 1)].iter() {
+            painted.insert(point, terrain);
+        }
+    }
+
+    // Find the new tiles of the painted cells and their neighbors
+    let mut updates: HashMap<Entity, LocalId> = HashMap::new();
+    for (x, y) in cells.iter() {
+        for ny in y.saturating_sub(1)..=y + 1 {
+            for nx in x.saturating_sub(1)..=x + 1 {
+                let ent = if let Some(ent) = index.get(&(nx, ny)) {
+                    *ent
+                } else {
+                    continue;
+                };
+                if updates.contains_key(&ent) {
+                    continue;
+                }
+                let is_painted = cells.contains(&(nx, ny));
+                let mut corners = if let Some(corners) = current_corners(ent) {
+                    corners
+                } else if is_painted {
+                    [None; 4]
+                } else {
+                    continue;
+                };
+                let points = [(nx, ny), (nx + 1, ny), (nx, ny + 1), (nx + 1, ny + 1)];
+                for (corner, point) in corners.iter_mut().zip(points.iter()) {
+                    if let Some(t) = painted.get(point) {
+                        *corner = Some(t);
+                    }
+                }
+                if let Some(lid) = best_tile(&tiles, &corners) {
+                    updates.insert(ent, lid);
+                }
+            }
+        }
+    }
+
+    for (ent, LocalId(lid)) in updates.into_iter() {
+        let gid = GlobalTileIndex::from_bits(firstgid.0 + lid);
+        if let Some(rendering) = get_rendering(map, &gid, None) {
+            let _ = data.renderings.insert(ent, rendering);
+        }
+        if let Some(animation) = get_animation(map, &gid, None) {
+            let _ = data.animations.insert(ent, animation);
+        } else {
+            data.animations.remove(ent);
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod terrain_tests {
+    use super::{
+        super::{
+            super::super::prelude::{LayerBuilder, TiledmapBuilder, TilesetBuilder},
+            insert_map, InsertMapData,
+        },
+        *,
+    };
+    use specs::WorldExt;
+
+    /// A tileset with grass (0) and water (1) corners.
+    fn tileset() -> Tileset {
+        let mut builder = TilesetBuilder::new("terrain", "terrain.png", 64, 64, 16, 16)
+            .terrain("grass", 0)
+            .terrain("water", 15);
+        // Every combination of corners, each bit is a water corner
+        for id in 0..16 {
+            let corner = |bit: u32| if id & (1 << bit) > 0 { 1 } else { 0 };
+            builder = builder.tile_terrain(id, [corner(0), corner(1), corner(2), corner(3)]);
+        }
+        builder.build()
+    }
+
+    fn tile_at(map: &Tiledmap, world: &World, x: u32, y: u32) -> u32 {
+        let (entities, cells, renderings): (
+            Entities,
+            ReadStorage<TileCell>,
+            ReadStorage<Rendering>,
+        ) = world.system_data();
+        let (ent, _) = (&entities, &cells)
+            .join()
+            .find(|(_, cell)| (cell.x, cell.y) == (x, y))
+            .unwrap();
+        let frame = renderings.get(ent).unwrap().as_frame().unwrap();
+        map.get_gid_by_frame(&frame.sprite_sheet, &frame.source_aabb)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn can_paint_terrain() {
+        let map = TiledmapBuilder::new(3, 3, 16, 16)
+            .tileset(tileset())
+            .layer(
+                LayerBuilder::tiles("ground", 3, 3)
+                    .tile_bits(&[1; 9])
+                    .build(),
+            )
+            .build();
+        let mut world = World::new();
+        InsertMapData::setup(&mut world);
+        TerrainData::setup(&mut world);
+        insert_map(&map, &mut world.system_data());
+
+        paint_terrain(&map, &mut world.system_data(), 0, &[(1, 1)], "water").unwrap();
+        // The painted cell is all water
+        assert_eq!(tile_at(&map, &world, 1, 1), 1 + 0b1111);
+        // Its neighbors take on the water at their shared corners
+        assert_eq!(tile_at(&map, &world, 0, 0), 1 + 0b1000);
+        assert_eq!(tile_at(&map, &world, 1, 0), 1 + 0b1100);
+        assert_eq!(tile_at(&map, &world, 2, 2), 1 + 0b0001);
+
+        assert!(paint_terrain(&map, &mut world.system_data(), 0, &[(1, 1)], "lava").is_err());
+    }
+}
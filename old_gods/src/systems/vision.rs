@@ -0,0 +1,491 @@
+//! Lets characters see.
+//!
+//! An entity with a `Vision` sees things within its range and inside its view
+//! cone, which points the way its `Cardinal` faces. Barriers block the view,
+//! except for sensors and exiled barriers. Entities without a `Cardinal` face
+//! south.
+//!
+//! Each frame the `VisionSystem` finds every viewer's visibility polygon, the
+//! area it can see, and the characters and objects it can see. Tiles and
+//! barriers that aren't characters are never counted as seen, and only things
+//! with a `Shape` are found to be seen at all. To ask whether
+//! one thing can see another at any time use `SightData::can_see`.
+use serde_json::Value;
+use specs::prelude::*;
+use std::{cmp::Ordering, collections::HashMap, f32::consts::PI};
+
+use super::super::prelude::{
+    entity_local_origin, AABBTree, Barrier, Cardinal, Exile, Npc, OriginOffset, Player, Position,
+    Sensor, Shape, TileCell, AABB, V2,
+};
+
+
+/// How far things see by default.
+pub const VISION_RANGE: f32 = 128.0;
+
+
+/// How wide a view cone is by default, in degrees.
+pub const VISION_FOV: f32 = 90.0;
+
+
+/// How many rays to cast around a full circle to round off the edge of a
+/// visibility polygon. Cones get their share.
+const VISION_ARC_RAYS: usize = 32;
+
+
+/// How far to either side of a barrier's corner to cast the rays that look
+/// past it, in radians.
+const VISION_CORNER_ANGLE: f32 = 0.0001;
+
+
+/// The ability to see.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vision {
+    /// How far the entity can see.
+    pub range: f32,
+
+    /// How wide the view cone is in degrees. 360 or more sees all around.
+    pub fov: f32,
+
+    /// The area seen last frame, relative to the entity's eye.
+    pub polygon: Vec<V2>,
+
+    /// The characters and objects seen last frame.
+    pub seen: Vec<Entity>,
+}
+
+
+impl Default for Vision {
+    fn default() -> Vision {
+        Vision::new(VISION_RANGE, VISION_FOV)
+    }
+}
+
+
+impl Vision {
+    pub fn new(range: f32, fov: f32) -> Vision {
+        Vision {
+            range,
+            fov,
+            polygon: vec![],
+            seen: vec![],
+        }
+    }
+
+    pub fn tiled_range_property() -> String {
+        "vision_range".to_string()
+    }
+
+    pub fn tiled_fov_property() -> String {
+        "vision_fov".to_string()
+    }
+
+    /// The property that sets which way a seeing object faces.
+    pub fn tiled_facing_property() -> String {
+        "facing".to_string()
+    }
+
+    /// Read and remove a Vision from an object's properties. Objects with
+    /// neither a range nor a fov can't see.
+    pub fn from_properties(properties: &mut HashMap<String, Value>) -> Option<Vision> {
+        let mut take = |name: String| {
+            properties
+                .remove(&name)
+                .and_then(|v| v.as_f64())
+                .map(|f| f as f32)
+        };
+        let range = take(Vision::tiled_range_property());
+        let fov = take(Vision::tiled_fov_property());
+        if range.is_none() && fov.is_none() {
+            return None;
+        }
+        Some(Vision::new(
+            range.unwrap_or(VISION_RANGE),
+            fov.unwrap_or(VISION_FOV),
+        ))
+    }
+
+    /// Whether the entity sees all around it.
+    pub fn is_all_around(&self) -> bool {
+        self.fov >= 360.0
+    }
+
+    /// Whether a point is inside the view cone of an eye facing the given
+    /// direction. Range and barriers are not considered.
+    pub fn in_cone(&self, eye: V2, facing: &Cardinal, point: V2) -> bool {
+        if self.is_all_around() {
+            return true;
+        }
+        match (point - eye).unitize() {
+            Some(dir) => {
+                let half_fov = (self.fov / 2.0).to_radians();
+                dir.dot(facing.as_v2()) >= half_fov.cos() - f32::EPSILON
+            }
+            None => true,
+        }
+    }
+}
+
+
+impl Component for Vision {
+    type Storage = HashMapStorage<Self>;
+}
+
+
+/// Keep an angle between -PI and PI.
+fn wrap_angle(angle: f32) -> f32 {
+    angle.sin().atan2(angle.cos())
+}
+
+
+/// Everything needed to work out what can be seen.
+#[derive(SystemData)]
+pub struct SightData<'a> {
+    pub aabb_tree: Read<'a, AABBTree>,
+    pub barriers: ReadStorage<'a, Barrier>,
+    pub cardinals: ReadStorage<'a, Cardinal>,
+    pub entities: Entities<'a>,
+    pub exiles: ReadStorage<'a, Exile>,
+    pub offsets: ReadStorage<'a, OriginOffset>,
+    pub positions: ReadStorage<'a, Position>,
+    pub sensors: ReadStorage<'a, Sensor>,
+    pub shapes: ReadStorage<'a, Shape>,
+}
+
+
+impl<'a> SightData<'a> {
+    /// Where an entity sees from, or where it is seen, which is its origin
+    /// on the map.
+    pub fn eye(&self, ent: Entity) -> Option<V2> {
+        let Position(position) = self.positions.get(ent)?;
+        Some(*position + entity_local_origin(ent, &self.shapes, &self.offsets))
+    }
+
+    /// The way an entity is facing.
+    pub fn facing(&self, ent: Entity) -> Cardinal {
+        self.cardinals.get(ent).cloned().unwrap_or(Cardinal::South)
+    }
+
+    /// Whether the entity blocks the view of things behind it. Anything in
+    /// `ignore` never does.
+    fn blocks_view(&self, ent: Entity, ignore: &[Entity]) -> bool {
+        self.barriers.contains(ent)
+            && !self.sensors.contains(ent)
+            && !self.exiles.contains(ent)
+            && !ignore.contains(&ent)
+    }
+
+    /// How far one can see from `eye` along `dir` before a barrier gets in
+    /// the way, up to `range`.
+    fn sight_distance(&self, eye: V2, dir: V2, range: f32, ignore: &[Entity]) -> f32 {
+        self.aabb_tree
+            .raycast(
+                &self.entities,
+                eye,
+                dir,
+                range,
+                &self.shapes,
+                &self.positions,
+                |ent| self.blocks_view(ent, ignore),
+            )
+            .map(|hit| hit.distance)
+            .unwrap_or(range)
+    }
+
+    /// Whether nothing blocks the view between the two entities. Range and
+    /// view cones are not considered.
+    pub fn line_of_sight(&self, viewer: Entity, target: Entity) -> bool {
+        let (eye, point) = match (self.eye(viewer), self.eye(target)) {
+            (Some(eye), Some(point)) => (eye, point),
+            _ => return false,
+        };
+        let distance = eye.distance_to(&point);
+        match (point - eye).unitize() {
+            Some(dir) => self.sight_distance(eye, dir, distance, &[viewer, target]) >= distance,
+            None => true,
+        }
+    }
+
+    /// Whether `viewer`, seeing with `vision`, can see `target`.
+    pub fn can_see(&self, viewer: Entity, vision: &Vision, target: Entity) -> bool {
+        if viewer == target || self.exiles.contains(target) {
+            return false;
+        }
+        let (eye, point) = match (self.eye(viewer), self.eye(target)) {
+            (Some(eye), Some(point)) => (eye, point),
+            _ => return false,
+        };
+        eye.distance_to(&point) <= vision.range
+            && vision.in_cone(eye, &self.facing(viewer), point)
+            && self.line_of_sight(viewer, target)
+    }
+
+    /// The area `viewer` can see with `vision`, relative to its eye. View
+    /// cones include the eye as their first point.
+    pub fn visibility_polygon(&self, viewer: Entity, vision: &Vision) -> Vec<V2> {
+        let eye = match self.eye(viewer) {
+            Some(eye) => eye,
+            None => return vec![],
+        };
+        let all_around = vision.is_all_around();
+        let facing = self.facing(viewer).as_v2().angle_radians();
+        let half_fov = if all_around {
+            PI
+        } else {
+            (vision.fov / 2.0).to_radians()
+        };
+
+        // Sweep the arc at the edge of the range...
+        let arc_rays = ((VISION_ARC_RAYS as f32 * half_fov / PI).ceil() as usize).max(1);
+        let mut angles: Vec<f32> = (0..=arc_rays)
+            .map(|i| -half_fov + 2.0 * half_fov * i as f32 / arc_rays as f32)
+            .collect();
+        if all_around {
+            // ...where the last ray would be the first again
+            angles.pop();
+        }
+        // ...and look at and just past every corner in range
+        let bounds = AABB::new(
+            eye.x - vision.range,
+            eye.y - vision.range,
+            vision.range * 2.0,
+            vision.range * 2.0,
+        );
+        let ignore = [viewer];
+        for (ent, _) in self
+            .aabb_tree
+            .query(&self.entities, &bounds, &viewer)
+            .into_iter()
+        {
+            if !self.blocks_view(ent, &ignore) {
+                continue;
+            }
+            let (shape, Position(position)) = match (self.shapes.get(ent), self.positions.get(ent))
+            {
+                (Some(shape), Some(position)) => (shape, position),
+                _ => continue,
+            };
+            for vertex in shape.vertices().into_iter() {
+                let corner = wrap_angle((*position + vertex - eye).angle_radians() - facing);
+                for nudge in [-VISION_CORNER_ANGLE, 0.0, VISION_CORNER_ANGLE].iter() {
+                    let angle = corner + nudge;
+                    if all_around {
+                        angles.push(wrap_angle(angle));
+                    } else if angle.abs() <= half_fov {
+                        angles.push(angle);
+                    }
+                }
+            }
+        }
+        angles.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        angles.dedup_by(|a, b| (*a - *b).abs() < f32::EPSILON);
+
+        let mut polygon = if all_around {
+            vec![]
+        } else {
+            vec![V2::origin()]
+        };
+        for angle in angles.into_iter() {
+            let angle = facing + angle;
+            let dir = V2::new(angle.cos(), angle.sin());
+            let distance = self.sight_distance(eye, dir, vision.range, &ignore);
+            polygon.push(dir.scalar_mul(distance));
+        }
+        polygon
+    }
+}
+
+
+pub struct VisionSystem;
+
+
+#[derive(SystemData)]
+pub struct VisionSystemData<'a> {
+    npcs: ReadStorage<'a, Npc>,
+    players: ReadStorage<'a, Player>,
+    sight: SightData<'a>,
+    tile_cells: ReadStorage<'a, TileCell>,
+    visions: WriteStorage<'a, Vision>,
+}
+
+
+impl<'a> VisionSystemData<'a> {
+    /// Whether the entity is worth noticing, which is any character, or any
+    /// object that isn't a tile or a barrier.
+    fn is_noticeable(&self, ent: Entity) -> bool {
+        self.players.contains(ent)
+            || self.npcs.contains(ent)
+            || !(self.tile_cells.contains(ent) || self.sight.barriers.contains(ent))
+    }
+}
+
+
+impl<'a> System<'a> for VisionSystem {
+    type SystemData = VisionSystemData<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        let mut views = vec![];
+        for (viewer, vision, ()) in
+            (&data.sight.entities, &data.visions, !&data.sight.exiles).join()
+        {
+            let sight = &data.sight;
+            let polygon = sight.visibility_polygon(viewer, vision);
+            // Only things in the tree within range can be seen
+            let seen = match sight.eye(viewer) {
+                Some(eye) => {
+                    let bounds = AABB::new(
+                        eye.x - vision.range,
+                        eye.y - vision.range,
+                        vision.range * 2.0,
+                        vision.range * 2.0,
+                    );
+                    sight
+                        .aabb_tree
+                        .query(&sight.entities, &bounds, &viewer)
+                        .into_iter()
+                        .map(|(target, _)| target)
+                        .filter(|target| {
+                            data.is_noticeable(*target) && sight.can_see(viewer, vision, *target)
+                        })
+                        .collect()
+                }
+                None => vec![],
+            };
+            views.push((viewer, polygon, seen));
+        }
+        for (viewer, polygon, seen) in views {
+            if let Some(vision) = data.visions.get_mut(viewer) {
+                vision.polygon = polygon;
+                vision.seen = seen;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod vision_tests {
+    use super::{super::super::prelude::AI, *};
+
+    /// Place a thing, a barrier if it's given a shape.
+    fn place(world: &mut World, position: V2, shape: Option<Shape>) -> Entity {
+        let (shape, barrier) = match shape {
+            Some(shape) => (shape, true),
+            None => (Shape::box_with_size(0.0, 0.0), false),
+        };
+        let aabb = shape.aabb().translate(&position);
+        let mut builder = world.create_entity().with(Position(position)).with(shape);
+        if barrier {
+            builder = builder.with(Barrier);
+        }
+        let ent = builder.build();
+        world.write_resource::<AABBTree>().insert(ent, aabb);
+        ent
+    }
+
+    #[test]
+    fn guards_see_ahead_of_them_until_a_wall() {
+        let mut world = World::new();
+        System::setup(&mut VisionSystem, &mut world);
+        let guard = place(&mut world, V2::origin(), None);
+        world
+            .write_storage::<Vision>()
+            .insert(guard, Vision::new(100.0, 90.0))
+            .unwrap();
+        world
+            .write_storage::<Cardinal>()
+            .insert(guard, Cardinal::East)
+            .unwrap();
+        let ahead = place(&mut world, V2::new(50.0, 10.0), None);
+        let behind = place(&mut world, V2::new(-50.0, 0.0), None);
+        let too_far = place(&mut world, V2::new(150.0, 0.0), None);
+        let hidden = place(&mut world, V2::new(50.0, -40.0), None);
+        let wall = place(
+            &mut world,
+            V2::new(20.0, -40.0),
+            Some(Shape::polygon(vec![
+                V2::new(0.0, 0.0),
+                V2::new(10.0, 0.0),
+                V2::new(10.0, 30.0),
+                V2::new(0.0, 30.0),
+            ])),
+        );
+
+        VisionSystem.run_now(&world);
+        let visions = world.read_storage::<Vision>();
+        let vision = visions.get(guard).unwrap();
+        assert!(vision.seen.contains(&ahead));
+        for ent in [behind, too_far, hidden, wall].iter() {
+            assert!(!vision.seen.contains(ent));
+        }
+        {
+            let sight: SightData = world.system_data();
+            assert!(!sight.line_of_sight(guard, hidden));
+            assert!(sight.line_of_sight(guard, behind));
+        }
+
+        // The cone starts at the eye, stays in range and stops at the wall
+        assert_eq!(vision.polygon[0], V2::origin());
+        for point in vision.polygon.iter() {
+            assert!(point.magnitude() <= 100.0 + 0.001);
+            assert!(point.x >= -0.001, "{:?} is behind the guard", point);
+        }
+        let blocked = vision
+            .polygon
+            .iter()
+            .filter(|p| p.y < -10.0 && p.x > 20.0 - 0.001 && p.x < 20.0 + 0.001)
+            .count();
+        assert!(blocked > 0, "{:?}", vision.polygon);
+    }
+
+    #[test]
+    fn walls_and_tiles_are_not_seen() {
+        let mut world = World::new();
+        System::setup(&mut VisionSystem, &mut world);
+        let guard = place(&mut world, V2::origin(), None);
+        world
+            .write_storage::<Vision>()
+            .insert(guard, Vision::new(100.0, 360.0))
+            .unwrap();
+        let square = || Shape::box_with_size(10.0, 10.0);
+        let wall = place(&mut world, V2::new(30.0, 0.0), Some(square()));
+        let tile = place(&mut world, V2::new(0.0, 30.0), None);
+        world
+            .write_storage::<TileCell>()
+            .insert(
+                tile,
+                TileCell {
+                    layer: 0,
+                    x: 0,
+                    y: 1,
+                },
+            )
+            .unwrap();
+        let npc = place(&mut world, V2::new(-30.0, 0.0), Some(square()));
+        world
+            .write_storage::<Npc>()
+            .insert(npc, Npc::new(AI::Idle))
+            .unwrap();
+        let item = place(&mut world, V2::new(0.0, -30.0), None);
+
+        VisionSystem.run_now(&world);
+        let visions = world.read_storage::<Vision>();
+        let seen = &visions.get(guard).unwrap().seen;
+        assert!(!seen.contains(&wall), "{:?}", seen);
+        assert!(!seen.contains(&tile), "{:?}", seen);
+        assert!(seen.contains(&npc), "{:?}", seen);
+        assert!(seen.contains(&item), "{:?}", seen);
+    }
+
+    #[test]
+    fn vision_reads_from_properties() {
+        let mut properties: HashMap<String, Value> = HashMap::new();
+        assert_eq!(Vision::from_properties(&mut properties), None);
+        properties.insert(Vision::tiled_fov_property(), Value::from(360));
+        let vision = Vision::from_properties(&mut properties).unwrap();
+        assert!(properties.is_empty());
+        assert_eq!(vision.range, VISION_RANGE);
+        assert!(vision.is_all_around());
+        assert!(vision.in_cone(V2::origin(), &Cardinal::North, V2::new(0.0, 10.0)));
+    }
+}
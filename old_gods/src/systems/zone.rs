@@ -4,7 +4,7 @@
 //! zone's shape.
 use specs::prelude::*;
 
-use super::super::prelude::{AABBTree, Exile, Position, Shape};
+use super::super::prelude::{AABBTree, Barrier, CollisionFilter, Exile, Position, Sensor, Shape};
 
 
 /// A Zone is an area that can hold some entities. In order to work properly
@@ -23,15 +23,20 @@ impl Component for Zone {
 /// The ZoneSystem keeps track of any entities that are within the boundaries of
 /// any zone.
 /// To be within a zone means that one's shape intersects the zone's shape.
+/// Zones that are also sensors only hold barriers their CollisionFilter
+/// accepts, the same things the sensor sends collision events for.
 pub struct ZoneSystem;
 
 
 #[derive(SystemData)]
 pub struct ZoneSystemData<'a> {
     aabb_tree: Read<'a, AABBTree>,
+    barriers: ReadStorage<'a, Barrier>,
+    collision_filters: ReadStorage<'a, CollisionFilter>,
     entities: Entities<'a>,
     exiles: ReadStorage<'a, Exile>,
     positions: ReadStorage<'a, Position>,
+    sensors: ReadStorage<'a, Sensor>,
     shapes: ReadStorage<'a, Shape>,
     zones: WriteStorage<'a, Zone>,
 }
@@ -43,13 +48,24 @@ impl<'a> System<'a> for ZoneSystem {
     fn run(&mut self, mut data: Self::SystemData) {
         // Do some generic zone upkeep
         let exiles = &data.exiles;
+        let barriers = &data.barriers;
+        let filters = &data.collision_filters;
+        let filter_of = |ent| filters.get(ent).cloned().unwrap_or_default();
         for (zone_ent, mut zone, ()) in (&data.entities, &mut data.zones, !exiles).join() {
+            let sensor_filter = if data.sensors.contains(zone_ent) {
+                Some(filter_of(zone_ent))
+            } else {
+                None
+            };
             let intersections: Vec<Entity> = data
                 .aabb_tree
                 .query_intersecting_shapes(&data.entities, &zone_ent, &data.shapes, &data.positions)
                 .into_iter()
                 .filter_map(|(e, _, _)| {
-                    if e == zone_ent || exiles.contains(e) {
+                    let is_sensed = sensor_filter
+                        .map(|filter| barriers.contains(e) && filter.accepts(&filter_of(e)))
+                        .unwrap_or(true);
+                    if e == zone_ent || exiles.contains(e) || !is_sensed {
                         None
                     } else {
                         Some(e)
@@ -159,10 +159,18 @@ impl FPSCounter {
     }
 
     /// Return the last frame's delta in seconds.
+    ///
+    /// While the engine runs its fixed simulation steps this is the length
+    /// of a step instead.
     pub fn last_delta(&self) -> f32 {
         self.last_dt
     }
 
+    /// Override the delta returned by `last_delta` until the next frame.
+    pub fn set_last_delta(&mut self, dt: f32) {
+        self.last_dt = dt;
+    }
+
     pub fn second_averages(&self) -> &[f32; FPS_COUNTER_BUFFER_SIZE] {
         self.averages.frames()
     }
@@ -173,3 +181,87 @@ impl Default for FPSCounter {
         FPSCounter::new()
     }
 }
+
+
+/// The default number of simulation steps per second.
+pub const DEFAULT_STEPS_PER_SECOND: f32 = 60.0;
+
+
+/// The most simulation steps run in one frame. If a frame takes longer than
+/// this many steps the rest of the time is dropped and the simulation slows
+/// down, rather than spending ever longer catching up.
+pub const MAX_STEPS_PER_FRAME: u32 = 8;
+
+
+/// Keeps the simulation running at a fixed rate regardless of frame rate.
+///
+/// Each frame's delta is added to an accumulator, which is then spent in
+/// whole steps. Whatever is left over carries on to the next frame and is
+/// used to interpolate between the last two steps when rendering.
+pub struct FixedTimestep {
+    step: f32,
+    accumulator: f32,
+}
+
+
+impl FixedTimestep {
+    pub fn new(steps_per_second: f32) -> FixedTimestep {
+        FixedTimestep {
+            step: 1.0 / steps_per_second,
+            accumulator: 0.0,
+        }
+    }
+
+    /// The length of one step in seconds.
+    pub fn step(&self) -> f32 {
+        self.step
+    }
+
+    pub fn set_steps_per_second(&mut self, steps_per_second: f32) {
+        self.step = 1.0 / steps_per_second;
+    }
+
+    /// Add a frame's delta in seconds and return how many steps to run.
+    pub fn accumulate(&mut self, dt: f32) -> u32 {
+        self.accumulator += dt;
+        let mut steps = (self.accumulator / self.step).floor() as u32;
+        if steps > MAX_STEPS_PER_FRAME {
+            self.accumulator %= self.step;
+            steps = MAX_STEPS_PER_FRAME;
+        } else {
+            self.accumulator -= steps as f32 * self.step;
+        }
+        steps
+    }
+
+    /// How far the current time is between the last step and the next, from
+    /// 0.0 to 1.0.
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.step).clamp(0.0, 1.0)
+    }
+}
+
+
+impl Default for FixedTimestep {
+    fn default() -> FixedTimestep {
+        FixedTimestep::new(DEFAULT_STEPS_PER_SECOND)
+    }
+}
+
+
+#[cfg(test)]
+mod time_tests {
+    use super::*;
+
+    #[test]
+    fn fixed_timestep_accumulates() {
+        let mut fixed = FixedTimestep::new(10.0);
+        assert_eq!(fixed.accumulate(0.05), 0);
+        assert!((fixed.alpha() - 0.5).abs() < 0.001);
+        assert_eq!(fixed.accumulate(0.2), 2);
+        assert!((fixed.alpha() - 0.5).abs() < 0.001);
+        // A long frame drops the time it can't catch up on
+        assert_eq!(fixed.accumulate(10.0), MAX_STEPS_PER_FRAME);
+        assert!(fixed.alpha() < 1.0);
+    }
+}